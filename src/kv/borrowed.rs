@@ -0,0 +1,450 @@
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::mem;
+
+use bumpalo::Bump;
+
+use super::token_reader::{Token, TokenReader};
+use super::{DuplicateKeyPolicy, FlagExpr, ParseOptions, ReaderError, Result};
+
+/// A KV entry flag whose text borrows from the parsed input, like `Flag`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BorrowedFlag<'s> {
+    None,
+    Normal(Cow<'s, str>),
+    Negated(Cow<'s, str>),
+    /// A condition combining defines with `&&` or `||`, such as `[$WIN32&&!$LOW]`.
+    Compound(FlagExpr),
+}
+
+/// A KV value whose strings borrow from the parsed input, like `Value`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum BorrowedValue<'s> {
+    String(Cow<'s, str>),
+    Object(BorrowedObject<'s>),
+}
+
+/// A KV object parsed from a `&str` without copying its text.
+///
+/// Keys, values and flags are slices of the input, except for text which reading changed, such
+/// as unquoted text containing `\` escapes, which is copied into an owned string. Entries are
+/// kept in source order, including repeated keys unless `ParseOptions::duplicate_keys` says
+/// otherwise. Parsing follows the same rules as `KeyValues::from_io_with_options`, except that
+/// values are always strings, so `ParseOptions::typed_numbers` is ignored, as are the options
+/// which only fill in details of a `KeyValues`: `collect_duplicate_keys`, `record_lines` and
+/// `detect_indent`.
+///
+/// # Examples
+/// ```
+/// use std::borrow::Cow;
+/// use srcrs::kv::{BorrowedObject, BorrowedValue};
+///
+/// let text = r#""LightmappedGeneric" { "$basetexture" "brick/wall" }"#;
+/// let root = BorrowedObject::parse(text).unwrap();
+///
+/// let Some(BorrowedValue::Object(material)) = root.get("LightmappedGeneric") else {
+///     panic!()
+/// };
+/// assert!(matches!(
+///     material.get("$basetexture"),
+///     Some(BorrowedValue::String(Cow::Borrowed("brick/wall")))
+/// ));
+/// ```
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BorrowedObject<'s> {
+    entries: Vec<(Cow<'s, str>, BorrowedFlag<'s>, BorrowedValue<'s>)>,
+    case_insensitive: bool,
+}
+
+impl<'s> BorrowedObject<'s> {
+    pub fn parse(text: &'s str) -> Result<BorrowedObject<'s>> {
+        Self::parse_with_options(text, &ParseOptions::default())
+    }
+
+    pub fn parse_with_options(text: &'s str, options: &ParseOptions) -> Result<BorrowedObject<'s>> {
+        // Only holds the text of the token being read, which is freed again once it has been
+        // compared with the input.
+        let allocator = Bump::new();
+        let token_reader = TokenReader::from_io_with_options(text.as_bytes(), &allocator, options)?;
+
+        let mut parser = Parser {
+            text,
+            options,
+            token_reader,
+        };
+        parser.parse_document()
+    }
+
+    /// Returns the value of the first entry under `key`, falling back to ignoring ASCII case if
+    /// the object was parsed with `ParseOptions::case_insensitive_keys`.
+    pub fn get(&self, key: &str) -> Option<&BorrowedValue<'s>> {
+        let find = |matches: &dyn Fn(&str) -> bool| {
+            self.entries
+                .iter()
+                .find(|(entry_key, _, _)| matches(entry_key))
+                .map(|(_, _, value)| value)
+        };
+
+        find(&|entry_key| entry_key == key).or_else(|| {
+            if self.case_insensitive {
+                find(&|entry_key| entry_key.eq_ignore_ascii_case(key))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Iterates over every entry in source order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &BorrowedFlag<'s>, &BorrowedValue<'s>)> {
+        self.entries
+            .iter()
+            .map(|(key, flag, value)| (key.as_ref(), flag, value))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Drops all but the last entry under each key, which stays where it was written, as for
+    /// `DuplicateKeyPolicy::KeepLast`.
+    fn keep_last_entries(&mut self) {
+        let mut seen = HashSet::new();
+
+        // Backwards, so the first entry seen under each key is the one kept.
+        let mut kept: Vec<_> = mem::take(&mut self.entries)
+            .into_iter()
+            .rev()
+            .filter(|(key, _, _)| seen.insert(key.clone()))
+            .collect();
+        kept.reverse();
+
+        self.entries = kept;
+    }
+}
+
+struct Parser<'s, 'a> {
+    text: &'s str,
+    options: &'a ParseOptions,
+    token_reader: TokenReader<'a, &'s [u8]>,
+}
+
+impl<'s> Parser<'s, '_> {
+    fn parse_document(&mut self) -> Result<BorrowedObject<'s>> {
+        if !self.options.allow_anonymous_root
+            || !matches!(self.token_reader.peek(), Token::OpenBlock)
+        {
+            return self.parse_object();
+        }
+
+        self.token_reader.advance()?;
+        let object = self.parse_object()?;
+        self.parse_close_block()?;
+
+        match self.token_reader.peek() {
+            Token::Eof => Ok(object),
+            _ => Err(self.token_reader.invalid_token()),
+        }
+    }
+
+    fn parse_object(&mut self) -> Result<BorrowedObject<'s>> {
+        let mut object = BorrowedObject {
+            entries: Vec::new(),
+            case_insensitive: self.options.case_insensitive_keys,
+        };
+        // Keys already in the object, only tracked for `DuplicateKeyPolicy::KeepFirst`.
+        let mut keys = HashSet::new();
+
+        loop {
+            match self.token_reader.peek() {
+                Token::Eof | Token::CloseBlock => break,
+                Token::Text(..) => {}
+                _ => return Err(self.token_reader.invalid_token()),
+            }
+
+            let key = self.parse_key()?;
+            if self.options.equals_separators
+                && matches!(self.token_reader.peek(), Token::Text(text, false) if text == "=")
+            {
+                self.token_reader.advance()?;
+            }
+
+            let value = match self.token_reader.peek() {
+                Token::Eof => return Err(ReaderError::MissingValue(key.into_owned())),
+                Token::OpenBlock => {
+                    self.token_reader.advance()?;
+                    let object = self.parse_object()?;
+                    self.parse_close_block()?;
+
+                    BorrowedValue::Object(object)
+                }
+                Token::Text(..) => BorrowedValue::String(self.take_text()?),
+                _ => return Err(self.token_reader.invalid_token()),
+            };
+
+            let flag = self.parse_flag()?;
+            self.token_reader.count_entry()?;
+
+            if self.options.duplicate_keys == DuplicateKeyPolicy::KeepFirst
+                && !keys.insert(key.clone())
+            {
+                continue;
+            }
+            object.entries.push((key, flag, value));
+        }
+
+        if self.options.duplicate_keys == DuplicateKeyPolicy::KeepLast {
+            object.keep_last_entries();
+        }
+
+        Ok(object)
+    }
+
+    fn parse_close_block(&mut self) -> Result<()> {
+        // `parse_object` also stops at the end of the document, so a block may be unterminated.
+        match self.token_reader.peek() {
+            Token::CloseBlock => self.token_reader.advance()?,
+            Token::Eof => return Err(self.token_reader.unexpected_eof()),
+            _ => return Err(self.token_reader.invalid_token()),
+        }
+
+        Ok(())
+    }
+
+    fn parse_key(&mut self) -> Result<Cow<'s, str>> {
+        let mut key = self.take_text()?;
+
+        if self.options.trim_quoted_keys {
+            key = match key {
+                Cow::Borrowed(key) => Cow::Borrowed(key.trim()),
+                Cow::Owned(key) if key.trim().len() != key.len() => {
+                    Cow::Owned(key.trim().to_string())
+                }
+                key => key,
+            };
+        }
+
+        if self.options.lowercase_keys && key.bytes().any(|byte| byte.is_ascii_uppercase()) {
+            key.to_mut().make_ascii_lowercase();
+        }
+
+        Ok(key)
+    }
+
+    fn parse_flag(&mut self) -> Result<BorrowedFlag<'s>> {
+        if !matches!(self.token_reader.peek(), Token::OpenFlag) {
+            return Ok(BorrowedFlag::None);
+        }
+
+        let position = self.token_reader.token_position();
+        self.token_reader.advance()?;
+
+        let negated = matches!(self.token_reader.peek(), Token::Negate);
+        if negated {
+            self.token_reader.advance()?;
+        }
+
+        let text = match self.token_reader.peek() {
+            Token::Text(text, _) if text.is_empty() => return Err(ReaderError::EmptyFlag),
+            Token::Text(..) => self.take_text()?,
+            Token::CloseFlag => return Err(ReaderError::EmptyFlag),
+            Token::Eof => return Err(self.token_reader.unexpected_eof()),
+            _ => return Err(self.token_reader.invalid_token()),
+        };
+
+        if matches!(self.token_reader.peek(), Token::CloseFlag) && !FlagExpr::is_compound(&text) {
+            self.token_reader.advance()?;
+
+            return if negated {
+                Ok(BorrowedFlag::Negated(text))
+            } else {
+                Ok(BorrowedFlag::Normal(text))
+            };
+        }
+
+        // As for `KeyValues`, a compound condition may span several tokens.
+        let mut condition = String::from(if negated { "!" } else { "" });
+        condition.push_str(&text);
+
+        loop {
+            match self.token_reader.peek() {
+                Token::Text(text, _) => condition.push_str(text),
+                Token::Negate => condition.push('!'),
+                Token::CloseFlag => break,
+                Token::Eof => return Err(self.token_reader.unexpected_eof()),
+                _ => return Err(self.token_reader.invalid_token()),
+            }
+            self.token_reader.advance()?;
+        }
+        self.token_reader.advance()?;
+
+        match FlagExpr::parse(&condition) {
+            Some(expr) if FlagExpr::is_compound(&condition) => Ok(BorrowedFlag::Compound(expr)),
+            _ => Err(ReaderError::InvalidToken(condition, position)),
+        }
+    }
+
+    /// Takes the current text token and moves past it, borrowing the text from the input when
+    /// it was read unchanged. Otherwise an escape, NUL byte or comment was removed from it, and
+    /// it is copied.
+    fn take_text(&mut self) -> Result<Cow<'s, str>> {
+        debug_assert!(matches!(self.token_reader.peek(), Token::Text(..)));
+
+        let start = self.token_reader.token_start() as usize;
+        // The token's own string is freed once compared, so the next token reuses its space.
+        let text = match mem::replace(self.token_reader.peek(), Token::Eof) {
+            Token::Text(text, quoted) => {
+                let start = start + usize::from(quoted);
+
+                match self.text.get(start..start + text.len()) {
+                    Some(raw) if raw == text.as_str() => Cow::Borrowed(raw),
+                    _ => Cow::Owned(text.to_string()),
+                }
+            }
+            _ => Cow::Borrowed(""),
+        };
+
+        self.token_reader.advance()?;
+        Ok(text)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use super::{BorrowedFlag, BorrowedObject, BorrowedValue};
+    use crate::kv::{
+        DuplicateKeyPolicy, FlagExpr, LimitKind, ParseLimits, ParseOptions, ReaderError,
+    };
+
+    fn is_slice_of(value: &BorrowedValue, input: &str) -> bool {
+        match value {
+            BorrowedValue::String(Cow::Borrowed(string)) => {
+                input.as_bytes().as_ptr_range().contains(&string.as_ptr())
+            }
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn values_borrow_input() {
+        let text = r#"
+        "VertexLitGeneric"
+        {
+            // A comment
+            $basetexture "models/props/crate"
+            $surfaceprop metal [$X360]
+            "Proxies" { Sine { sinemin 0 } }
+        }
+        "#;
+
+        let root = BorrowedObject::parse(text).unwrap();
+        let Some(BorrowedValue::Object(material)) = root.get("VertexLitGeneric") else {
+            panic!()
+        };
+
+        let base_texture = material.get("$basetexture").unwrap();
+        assert_eq!(
+            *base_texture,
+            BorrowedValue::String(Cow::Borrowed("models/props/crate"))
+        );
+        assert!(is_slice_of(base_texture, text));
+
+        let (key, flag, value) = material.iter().nth(1).unwrap();
+        assert_eq!(key, "$surfaceprop");
+        assert_eq!(*flag, BorrowedFlag::Normal(Cow::Borrowed("$X360")));
+        assert!(is_slice_of(value, text));
+
+        assert_eq!(material.len(), 3);
+    }
+
+    #[test]
+    fn escapes_and_errors() {
+        // Only unquoted text has escapes; a backslash between quotes is kept as it is.
+        let root = BorrowedObject::parse(r#"path a\"b\\c literal "a\n""#).unwrap();
+        assert_eq!(
+            root.get("path"),
+            Some(&BorrowedValue::String(Cow::Owned("a\"b\\c".to_string())))
+        );
+        assert_eq!(
+            root.get("literal"),
+            Some(&BorrowedValue::String(Cow::Borrowed("a\\n")))
+        );
+
+        let root = BorrowedObject::parse("\u{FEFF}key value").unwrap();
+        assert_eq!(
+            root.get("key"),
+            Some(&BorrowedValue::String(Cow::Borrowed("value")))
+        );
+
+        assert!(matches!(
+            BorrowedObject::parse("a { b c"),
+            Err(ReaderError::UnexpectedEof(position)) if position.offset == 7
+        ));
+        assert!(matches!(
+            BorrowedObject::parse("a b [ ]"),
+            Err(ReaderError::EmptyFlag)
+        ));
+        assert!(matches!(
+            BorrowedObject::parse("a"),
+            Err(ReaderError::MissingValue(key)) if key == "a"
+        ));
+    }
+
+    #[test]
+    fn follows_parse_options() {
+        let options = ParseOptions {
+            allow_anonymous_root: true,
+            duplicate_keys: DuplicateKeyPolicy::KeepLast,
+            lowercase_keys: true,
+            block_comments: true,
+            ..Default::default()
+        };
+        let text = "{ Key first Other x KEY last tex/*note*/ture y }";
+        let root = BorrowedObject::parse_with_options(text, &options).unwrap();
+
+        let keys: Vec<_> = root.iter().map(|(key, _, _)| key).collect();
+        assert_eq!(keys, ["other", "key", "texture"]);
+        assert!(is_slice_of(root.get("key").unwrap(), text));
+        assert_eq!(
+            root.get("texture"),
+            Some(&BorrowedValue::String(Cow::Borrowed("y")))
+        );
+        assert!(BorrowedObject::parse("{ a b }").is_err());
+
+        let options = ParseOptions {
+            duplicate_keys: DuplicateKeyPolicy::KeepFirst,
+            case_insensitive_keys: true,
+            escape_sequences: true,
+            ..Default::default()
+        };
+        let root = BorrowedObject::parse_with_options(r#"$Key "a\tb" $key c [$A&&!$B]"#, &options)
+            .unwrap();
+        assert_eq!(root.len(), 2);
+        assert_eq!(
+            root.get("$KEY"),
+            Some(&BorrowedValue::String(Cow::Owned("a\tb".to_string())))
+        );
+        let (_, flag, _) = root.iter().nth(1).unwrap();
+        assert_eq!(
+            flag.clone(),
+            BorrowedFlag::Compound(FlagExpr::parse("$A&&!$B").unwrap())
+        );
+
+        let options = ParseOptions {
+            limits: ParseLimits {
+                max_depth: Some(1),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert!(matches!(
+            BorrowedObject::parse_with_options("a { b { c d } }", &options),
+            Err(ReaderError::LimitExceeded(LimitKind::Depth))
+        ));
+    }
+}
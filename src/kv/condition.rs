@@ -0,0 +1,220 @@
+use std::borrow::Borrow;
+use std::collections::HashSet;
+use std::fmt;
+use std::hash::Hash;
+
+/// A compound flag condition such as `$WIN32||$X360` or `$WIN32&&!$LOW`, stored in
+/// `Flag::Compound`. `!` binds tightest, then `&&`, then `||`, and parentheses group.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FlagExpr {
+    /// Holds when the define is set.
+    Define(String),
+    Not(Box<FlagExpr>),
+    And(Box<FlagExpr>, Box<FlagExpr>),
+    Or(Box<FlagExpr>, Box<FlagExpr>),
+}
+
+impl FlagExpr {
+    /// Parses a condition written between a flag's brackets, with any whitespace removed.
+    /// Returns `None` if it is malformed, such as `$A||` or `$A&&&&$B`.
+    pub fn parse(condition: &str) -> Option<FlagExpr> {
+        let mut parser = Parser { rest: condition };
+        let expr = parser.or()?;
+
+        parser.rest.is_empty().then_some(expr)
+    }
+
+    /// Whether a condition written between a flag's brackets is compound, rather than a single
+    /// define with an optional `!`.
+    pub(super) fn is_compound(condition: &str) -> bool {
+        condition.contains("&&") || condition.contains("||")
+    }
+
+    /// Whether the condition holds when exactly `defines` are set.
+    pub fn evaluate<T>(&self, defines: &HashSet<T>) -> bool
+    where
+        T: Borrow<str> + Hash + Eq,
+    {
+        match self {
+            FlagExpr::Define(define) => defines.contains(define.as_str()),
+            FlagExpr::Not(expr) => !expr.evaluate(defines),
+            FlagExpr::And(left, right) => left.evaluate(defines) && right.evaluate(defines),
+            FlagExpr::Or(left, right) => left.evaluate(defines) || right.evaluate(defines),
+        }
+    }
+
+    /// Visits each define the condition refers to, with whether it appears directly under a
+    /// `!`.
+    pub(super) fn for_each_define<F: FnMut(&str, bool)>(&self, f: &mut F) {
+        match self {
+            FlagExpr::Define(define) => f(define, false),
+            FlagExpr::Not(expr) => match expr.as_ref() {
+                FlagExpr::Define(define) => f(define, true),
+                expr => expr.for_each_define(f),
+            },
+            FlagExpr::And(left, right) | FlagExpr::Or(left, right) => {
+                left.for_each_define(f);
+                right.for_each_define(f);
+            }
+        }
+    }
+
+    fn precedence(&self) -> u8 {
+        match self {
+            FlagExpr::Or(..) => 0,
+            FlagExpr::And(..) => 1,
+            FlagExpr::Not(_) | FlagExpr::Define(_) => 2,
+        }
+    }
+
+    fn fmt_operand(&self, f: &mut fmt::Formatter<'_>, precedence: u8) -> fmt::Result {
+        if self.precedence() < precedence {
+            write!(f, "({self})")
+        } else {
+            write!(f, "{self}")
+        }
+    }
+}
+
+/// Writes the condition as it is written between a flag's brackets, with parentheses only where
+/// they are needed.
+impl fmt::Display for FlagExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FlagExpr::Define(define) => write!(f, "{define}"),
+            FlagExpr::Not(expr) => {
+                write!(f, "!")?;
+                expr.fmt_operand(f, 2)
+            }
+            FlagExpr::And(left, right) => {
+                left.fmt_operand(f, 1)?;
+                write!(f, "&&")?;
+                right.fmt_operand(f, 2)
+            }
+            FlagExpr::Or(left, right) => {
+                left.fmt_operand(f, 0)?;
+                write!(f, "||")?;
+                right.fmt_operand(f, 1)
+            }
+        }
+    }
+}
+
+/// A recursive descent parser, one function per precedence level.
+struct Parser<'s> {
+    rest: &'s str,
+}
+
+impl Parser<'_> {
+    fn eat(&mut self, symbol: &str) -> bool {
+        match self.rest.strip_prefix(symbol) {
+            Some(rest) => {
+                self.rest = rest;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn or(&mut self) -> Option<FlagExpr> {
+        let mut expr = self.and()?;
+
+        while self.eat("||") {
+            expr = FlagExpr::Or(Box::new(expr), Box::new(self.and()?));
+        }
+
+        Some(expr)
+    }
+
+    fn and(&mut self) -> Option<FlagExpr> {
+        let mut expr = self.not()?;
+
+        while self.eat("&&") {
+            expr = FlagExpr::And(Box::new(expr), Box::new(self.not()?));
+        }
+
+        Some(expr)
+    }
+
+    fn not(&mut self) -> Option<FlagExpr> {
+        if self.eat("!") {
+            return Some(FlagExpr::Not(Box::new(self.not()?)));
+        }
+
+        if self.eat("(") {
+            let expr = self.or()?;
+            return self.eat(")").then_some(expr);
+        }
+
+        let end = self
+            .rest
+            .find(['&', '|', '!', '(', ')'])
+            .unwrap_or(self.rest.len());
+        if end == 0 {
+            return None;
+        }
+
+        let (define, rest) = self.rest.split_at(end);
+        self.rest = rest;
+        Some(FlagExpr::Define(define.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::FlagExpr;
+
+    fn define(name: &str) -> Box<FlagExpr> {
+        Box::new(FlagExpr::Define(name.to_string()))
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            FlagExpr::parse("$A||$B&&!$C"),
+            Some(FlagExpr::Or(
+                define("$A"),
+                Box::new(FlagExpr::And(
+                    define("$B"),
+                    Box::new(FlagExpr::Not(define("$C")))
+                ))
+            ))
+        );
+
+        for malformed in ["", "$A||", "&&$A", "$A&&&&$B", "($A||$B", "$A)", "!"] {
+            assert_eq!(FlagExpr::parse(malformed), None, "{malformed:?}");
+        }
+    }
+
+    #[test]
+    fn display() {
+        for condition in [
+            "$A||$B&&!$C",
+            "($A||$B)&&$C",
+            "!($A&&$B)||!$C",
+            "$A&&($B||$C)",
+        ] {
+            assert_eq!(FlagExpr::parse(condition).unwrap().to_string(), condition);
+        }
+
+        assert_eq!(
+            FlagExpr::parse("(($A))&&(!$B)").unwrap().to_string(),
+            "$A&&!$B"
+        );
+    }
+
+    #[test]
+    fn evaluate() {
+        let defines: HashSet<&str> = ["$A", "$C"].into();
+        let holds = |condition| FlagExpr::parse(condition).unwrap().evaluate(&defines);
+
+        assert!(holds("$A||$B"));
+        assert!(!holds("$A&&$B"));
+        assert!(holds("$A&&!$B"));
+        assert!(holds("!($A&&$B)"));
+        assert!(!holds("!$A||$B"));
+        assert!(holds("($B||$C)&&$A"));
+    }
+}
@@ -0,0 +1,88 @@
+use super::Value;
+
+/// Separates the fields of an entity output in newer VMFs, which allows commas in parameters.
+const ESC_SEPARATOR: char = '\x1b';
+
+/// An entity I/O connection, as stored in a VMF entity's `connections` block:
+/// `target,input,parameter,delay,times`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Connection {
+    pub target: String,
+    pub input: String,
+    pub parameter: String,
+    pub delay: f32,
+    /// Number of times the output fires, or -1 for no limit.
+    pub times: i32,
+}
+
+impl Connection {
+    /// Parses a connection written with either `,` or `\x1b` separators. With `,`, any extra
+    /// fields are taken to be commas inside the parameter.
+    pub fn parse(text: &str) -> Option<Connection> {
+        let fields: Vec<&str> = if text.contains(ESC_SEPARATOR) {
+            text.split(ESC_SEPARATOR).collect()
+        } else {
+            let mut front = text.splitn(3, ',');
+            let (target, input, rest) = (front.next()?, front.next()?, front.next()?);
+
+            let mut back = rest.rsplitn(3, ',');
+            let (times, delay, parameter) = (back.next()?, back.next()?, back.next()?);
+
+            vec![target, input, parameter, delay, times]
+        };
+
+        match fields[..] {
+            [target, input, parameter, delay, times] => Some(Connection {
+                target: target.to_string(),
+                input: input.to_string(),
+                parameter: parameter.to_string(),
+                delay: delay.trim().parse().ok()?,
+                times: times.trim().parse().ok()?,
+            }),
+            _ => None,
+        }
+    }
+}
+
+impl<'a> Value<'a> {
+    /// Parses an entity output value into its fields. Returns `None` for anything but a string,
+    /// and for strings which are not a well-formed connection.
+    pub fn as_connection(&self) -> Option<Connection> {
+        match self {
+            Value::String(string) => Connection::parse(string),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Connection;
+
+    #[test]
+    fn separators() {
+        let expected = Connection {
+            target: "relay".to_string(),
+            input: "AddOutput".to_string(),
+            parameter: "rendercolor 255,0,0".to_string(),
+            delay: 1.5,
+            times: 1,
+        };
+
+        assert_eq!(
+            Connection::parse("relay\x1bAddOutput\x1brendercolor 255,0,0\x1b1.5\x1b1"),
+            Some(expected.clone())
+        );
+        assert_eq!(
+            Connection::parse("relay,AddOutput,rendercolor 255,0,0,1.5,1"),
+            Some(expected)
+        );
+
+        assert_eq!(
+            Connection::parse("relay,Trigger,,0,-1").unwrap().parameter,
+            ""
+        );
+        assert_eq!(Connection::parse("relay,Trigger,0,-1"), None);
+        assert_eq!(Connection::parse("relay,Trigger,,soon,-1"), None);
+    }
+}
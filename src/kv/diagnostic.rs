@@ -0,0 +1,101 @@
+use super::{KeyValues, ParseOptions, Position, ReaderError};
+
+impl ReaderError {
+    /// Renders the error as a compiler style diagnostic for `src`, the text which failed to
+    /// parse: the message, then the offending line with a caret under the column where parsing
+    /// stopped.
+    ///
+    /// Errors which record where they happened are placed there. For the others `src` is parsed
+    /// again with the default options to find the failing token. Where that does not fail the
+    /// same way, as for IO errors, or errors which depend on other options, only the message is
+    /// rendered.
+    pub fn render_with_source(&self, src: &str) -> String {
+        let mut rendered = format!("error: {self}");
+
+        let offset = match self {
+            ReaderError::NulByte(offset) | ReaderError::InvalidUtf8(offset) => Some(*offset),
+            ReaderError::InvalidToken(_, position) | ReaderError::UnexpectedEof(position) => {
+                Some(position.offset)
+            }
+            ReaderError::IO(_) | ReaderError::IncludeCycle(_) | ReaderError::LimitExceeded(_) => {
+                None
+            }
+            _ => KeyValues::locate_error(src.as_bytes(), &ParseOptions::default()),
+        };
+
+        if let Some(offset) = offset {
+            let offset = (offset as usize).min(src.len());
+            // Offsets count bytes, which may fall inside a multi-byte character.
+            let offset = (0..=offset)
+                .rev()
+                .find(|&offset| src.is_char_boundary(offset))
+                .unwrap_or(0);
+
+            let line_start = src[..offset].rfind('\n').map_or(0, |newline| newline + 1);
+            let line_end = src[offset..]
+                .find('\n')
+                .map_or(src.len(), |newline| offset + newline);
+            let line = src[line_start..line_end].trim_end_matches('\r');
+
+            let Position {
+                line: line_number,
+                column,
+                ..
+            } = Position::locate(src, offset);
+            let before = &src[line_start..offset];
+
+            // Keep tabs in the caret's indent, so it lines up however they are displayed.
+            let indent: String = before
+                .chars()
+                .map(|ch| if ch == '\t' { '\t' } else { ' ' })
+                .collect();
+            let gutter = " ".repeat(line_number.to_string().len());
+
+            rendered.push_str(&format!(
+                "\n{gutter}--> line {line_number}, column {column}\n\
+                 {gutter} |\n\
+                 {line_number} | {line}\n\
+                 {gutter} | {indent}^"
+            ));
+        }
+
+        rendered
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::kv::{KeyValues, ReaderError};
+
+    #[test]
+    fn render_with_source() {
+        let src = "material\n{\n\t$basetexture brick ]\n}\n";
+        let err = KeyValues::from_io(src.as_bytes()).err().unwrap();
+
+        assert_eq!(
+            err.render_with_source(src),
+            "error: Invalid token: CloseFlag at line 3, column 21\n\
+             \x20--> line 3, column 21\n\
+             \x20 |\n\
+             3 | \t$basetexture brick ]\n\
+             \x20 | \t                   ^"
+        );
+
+        let src = "a 1\nkey value [$X360 }\n";
+        let err = KeyValues::from_io(src.as_bytes()).err().unwrap();
+        let rendered = err.render_with_source(src);
+
+        assert!(rendered.contains("--> line 2, column 18"), "{rendered}");
+        assert!(rendered.ends_with(&format!("2 | key value [$X360 }}\n  | {}^", " ".repeat(17))));
+
+        let src = "key\n\t\"val\0ue\"";
+        let err = KeyValues::from_io(src.as_bytes()).err().unwrap();
+        assert!(matches!(err, ReaderError::NulByte(9)));
+        assert!(err
+            .render_with_source(src)
+            .ends_with("2 | \t\"val\0ue\"\n  | \t    ^"));
+
+        let err = ReaderError::IO(std::io::ErrorKind::BrokenPipe.into());
+        assert!(!err.render_with_source("key value").contains('^'));
+    }
+}
@@ -0,0 +1,113 @@
+use std::borrow::Cow;
+
+const ESCAPE: char = '\\';
+
+/// Escapes `text` for writing between quotes, as Source's `KeyValues` writer does: `"` becomes
+/// `\"` and `\` becomes `\\`. Newlines and tabs are left as they are, since quoted text may
+/// span lines. The quoted text reads back as `text` with `ParseOptions::escape_sequences`.
+pub fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+
+    for ch in text.chars() {
+        if matches!(ch, '"' | ESCAPE) {
+            escaped.push(ESCAPE);
+        }
+        escaped.push(ch);
+    }
+
+    escaped
+}
+
+/// Reverses `escape`, also decoding the `\n` and `\t` written by some tools. Any other escaped
+/// character stands for itself, and a trailing `\` is kept. Text without escapes is borrowed.
+///
+/// The reader only decodes escapes in quoted text with `ParseOptions::escape_sequences`, as
+/// Source does not by default, so this is for text read without it which is known to have been
+/// written with escape sequences.
+pub fn unescape(text: &str) -> Cow<'_, str> {
+    if !text.contains(ESCAPE) {
+        return Cow::Borrowed(text);
+    }
+
+    let mut unescaped = String::with_capacity(text.len());
+    let mut chars = text.chars();
+
+    while let Some(ch) = chars.next() {
+        if ch != ESCAPE {
+            unescaped.push(ch);
+            continue;
+        }
+
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('t') => unescaped.push('\t'),
+            Some(escaped) => unescaped.push(escaped),
+            None => unescaped.push(ESCAPE),
+        }
+    }
+
+    Cow::Owned(unescaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use super::{escape, unescape};
+    use crate::kv::{KeyValues, ParseOptions};
+
+    #[test]
+    fn round_trip() {
+        for text in [
+            "plain",
+            r#"say "hello""#,
+            r"C:\path\to\file",
+            "\\\"",
+            "line one\nline two\ttabbed",
+            r"trailing\",
+            "",
+        ] {
+            assert_eq!(unescape(&escape(text)), text);
+        }
+
+        assert_eq!(escape(r#"a "b" \c"#), r#"a \"b\" \\c"#);
+    }
+
+    #[test]
+    fn parse_round_trip() {
+        let options = ParseOptions {
+            escape_sequences: true,
+            ..Default::default()
+        };
+
+        for text in [
+            r#"say "hello""#,
+            r"C:\path\to\file",
+            "line one\nline two\ttabbed",
+            r"trailing\",
+            "",
+        ] {
+            let written = format!("\"key\" \"{}\"", escape(text));
+            let kv = KeyValues::from_io_with_options(written.as_bytes(), &options).unwrap();
+            assert_eq!(kv.get("key").and_then(|value| value.raw_str()), Some(text));
+        }
+
+        // Without the option, quoted text is taken as it is.
+        let kv = KeyValues::from_io(r#"key "a\b""#.as_bytes()).unwrap();
+        assert_eq!(
+            kv.get("key").and_then(|value| value.raw_str()),
+            Some(r"a\b")
+        );
+    }
+
+    #[test]
+    fn unescape_sequences() {
+        assert!(matches!(
+            unescape("no escapes"),
+            Cow::Borrowed("no escapes")
+        ));
+        assert_eq!(unescape(r"a\nb\tc"), "a\nb\tc");
+        assert_eq!(unescape(r"\q\\"), r"q\");
+        assert_eq!(unescape(r"end\"), r"end\");
+    }
+}
@@ -0,0 +1,322 @@
+use std::io::Read;
+use std::mem;
+
+use bumpalo::Bump;
+use ouroboros::self_referencing;
+
+use super::token_reader::{Token, TokenReader};
+use super::{Flag, KeyValues, ParseOptions, ReaderError, Result};
+
+/// A piece of a document, as returned by `KvEvents::next_event`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KvEvent<'e> {
+    /// The key of an entry, followed by its value as a `ValueString` or an `ObjectStart`.
+    KeyBegin(&'e str),
+    ValueString(&'e str),
+    /// The start of an object value, whose entries follow until the matching `ObjectEnd`.
+    ObjectStart,
+    ObjectEnd,
+    /// The flag after an entry's value, as written between its brackets without whitespace,
+    /// such as `$X360`, `!$X360` or `$WIN32&&!$LOW`. `FlagExpr::parse` reads any of them.
+    Flag(&'e str),
+    /// The end of the document, after which `next_event` returns `None`.
+    Eof,
+}
+
+/// A pull parser, which reports a document as a sequence of `KvEvent`s instead of building a
+/// tree, for scanning files too large to keep in memory such as big VMFs.
+///
+/// Only the current token is held: the text of each event is copied into a buffer which is
+/// reused for the next, so memory use does not grow with the document. Parsing follows the same
+/// rules as `KeyValues::from_io` with the default `ParseOptions`, and fails with the same errors.
+///
+/// # Examples
+/// ```
+/// use srcrs::kv::{KvEvent, KvEvents};
+///
+/// let text = r#"world { solid { id 1 } } entity { classname light }"#;
+/// let mut events = KvEvents::new(text.as_bytes()).unwrap();
+///
+/// let mut classnames = Vec::new();
+/// let mut in_classname = false;
+/// while let Some(event) = events.next_event().unwrap() {
+///     match event {
+///         KvEvent::KeyBegin(key) => in_classname = key == "classname",
+///         KvEvent::ValueString(value) if in_classname => classnames.push(value.to_string()),
+///         _ => {}
+///     }
+/// }
+/// assert_eq!(classnames, ["light"]);
+/// ```
+pub struct KvEvents<R: Read> {
+    inner: EventsInner<R>,
+}
+
+#[self_referencing]
+struct EventsInner<R: Read> {
+    allocator: Bump,
+    state: EventState,
+
+    #[borrows(allocator)]
+    #[not_covariant]
+    token_reader: TokenReader<'this, R>,
+}
+
+/// What the parser expects next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Expect {
+    /// A key, the end of the enclosing object or the end of the document.
+    Entry,
+    /// The value of the key just read.
+    Value,
+    /// An optional flag after a value.
+    Flag,
+    Done,
+}
+
+/// The event `EventState::step` found, whose text is left in `EventState::text`.
+enum EventKind {
+    KeyBegin,
+    ValueString,
+    ObjectStart,
+    ObjectEnd,
+    Flag,
+    Eof,
+}
+
+struct EventState {
+    expect: Expect,
+    depth: usize,
+    text: String,
+}
+
+impl<R: Read> KvEvents<R> {
+    pub fn new(read: R) -> Result<KvEvents<R>> {
+        let inner = EventsInnerTryBuilder {
+            allocator: Bump::new(),
+            state: EventState {
+                expect: Expect::Entry,
+                depth: 0,
+                text: String::new(),
+            },
+            token_reader_builder: |allocator: &Bump| {
+                // A token's string is freed whole before the next is read, and a shrunk one
+                // would only give back the part it kept.
+                let options = ParseOptions {
+                    keep_string_capacity: true,
+                    ..Default::default()
+                };
+                TokenReader::from_io_with_options(read, allocator, &options)
+            },
+        }
+        .try_build()?;
+
+        Ok(KvEvents { inner })
+    }
+
+    /// Parses up to the next event. Returns `KvEvent::Eof` once at the end of the document, then
+    /// `None`, which is also returned after an error.
+    pub fn next_event(&mut self) -> Result<Option<KvEvent<'_>>> {
+        let kind = self.inner.with_mut(|fields| {
+            let kind = fields.state.step(fields.token_reader);
+            if kind.is_err() {
+                fields.state.expect = Expect::Done;
+            }
+
+            kind
+        })?;
+
+        let text = self.inner.borrow_state().text.as_str();
+        Ok(kind.map(|kind| match kind {
+            EventKind::KeyBegin => KvEvent::KeyBegin(text),
+            EventKind::ValueString => KvEvent::ValueString(text),
+            EventKind::ObjectStart => KvEvent::ObjectStart,
+            EventKind::ObjectEnd => KvEvent::ObjectEnd,
+            EventKind::Flag => KvEvent::Flag(text),
+            EventKind::Eof => KvEvent::Eof,
+        }))
+    }
+
+    /// Bytes allocated by the arena tokens are read into. This stays around the size of the
+    /// largest token, however long the document is.
+    pub fn allocated_bytes(&self) -> usize {
+        self.inner.borrow_allocator().allocated_bytes()
+    }
+}
+
+impl EventState {
+    fn step<R: Read>(
+        &mut self,
+        token_reader: &mut TokenReader<'_, R>,
+    ) -> Result<Option<EventKind>> {
+        loop {
+            match self.expect {
+                Expect::Done => return Ok(None),
+                Expect::Entry => return self.entry(token_reader).map(Some),
+                Expect::Value => return self.value(token_reader).map(Some),
+                Expect::Flag => {
+                    self.expect = Expect::Entry;
+
+                    if matches!(token_reader.peek(), Token::OpenFlag) {
+                        self.flag(token_reader)?;
+                        return Ok(Some(EventKind::Flag));
+                    }
+                }
+            }
+        }
+    }
+
+    fn entry<R: Read>(&mut self, token_reader: &mut TokenReader<'_, R>) -> Result<EventKind> {
+        match token_reader.peek() {
+            Token::Eof if self.depth > 0 => Err(token_reader.unexpected_eof()),
+            // Like the tree parser, a `}` at the top level ends the document.
+            Token::Eof | Token::CloseBlock if self.depth == 0 => {
+                self.expect = Expect::Done;
+                Ok(EventKind::Eof)
+            }
+            Token::CloseBlock => {
+                token_reader.advance()?;
+                self.depth -= 1;
+                self.expect = Expect::Flag;
+                Ok(EventKind::ObjectEnd)
+            }
+            Token::Text(..) => {
+                self.take_text(token_reader)?;
+                token_reader.count_entry()?;
+
+                if matches!(token_reader.peek(), Token::Eof) {
+                    return Err(ReaderError::MissingValue(self.text.clone()));
+                }
+
+                self.expect = Expect::Value;
+                Ok(EventKind::KeyBegin)
+            }
+            _ => Err(token_reader.invalid_token()),
+        }
+    }
+
+    fn value<R: Read>(&mut self, token_reader: &mut TokenReader<'_, R>) -> Result<EventKind> {
+        match token_reader.peek() {
+            Token::OpenBlock => {
+                token_reader.advance()?;
+                self.depth += 1;
+                self.expect = Expect::Entry;
+                Ok(EventKind::ObjectStart)
+            }
+            Token::Text(..) => {
+                self.take_text(token_reader)?;
+                self.expect = Expect::Flag;
+                Ok(EventKind::ValueString)
+            }
+            _ => Err(token_reader.invalid_token()),
+        }
+    }
+
+    fn flag<R: Read>(&mut self, token_reader: &mut TokenReader<'_, R>) -> Result<()> {
+        self.text.clear();
+
+        match KeyValues::visit_flag(token_reader)? {
+            Flag::Normal(condition) => self.text.push_str(&condition),
+            Flag::Negated(condition) => {
+                self.text.push('!');
+                self.text.push_str(&condition);
+            }
+            Flag::Compound(expr) => self.text.push_str(&expr.to_string()),
+            Flag::None => {}
+        }
+
+        Ok(())
+    }
+
+    /// Copies the current text token into `text` and moves past it. The token's own string is
+    /// freed first, so the next token reuses its space in the arena.
+    fn take_text<R: Read>(&mut self, token_reader: &mut TokenReader<'_, R>) -> Result<()> {
+        if let Token::Text(text, _) = mem::replace(token_reader.peek(), Token::Eof) {
+            self.text.clear();
+            self.text.push_str(&text);
+        }
+
+        token_reader.advance()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{KvEvent, KvEvents};
+    use crate::kv::ReaderError;
+
+    fn events(text: &str) -> Vec<String> {
+        let mut events = KvEvents::new(text.as_bytes()).unwrap();
+        let mut found = Vec::new();
+
+        while let Some(event) = events.next_event().unwrap() {
+            found.push(format!("{event:?}"));
+        }
+
+        found
+    }
+
+    #[test]
+    fn events_and_flags() {
+        let text = r#"
+        "LightmappedGeneric"
+        {
+            $basetexture "brick/wall" [$X360]
+            $envmap env_cubemap [!$LOW]
+            Proxies { Sine { sinemin 0 } } [ $WIN32 && !$LOW ]
+        }
+        "#;
+
+        assert_eq!(
+            events(text),
+            [
+                r#"KeyBegin("LightmappedGeneric")"#,
+                "ObjectStart",
+                r#"KeyBegin("$basetexture")"#,
+                r#"ValueString("brick/wall")"#,
+                r#"Flag("$X360")"#,
+                r#"KeyBegin("$envmap")"#,
+                r#"ValueString("env_cubemap")"#,
+                r#"Flag("!$LOW")"#,
+                r#"KeyBegin("Proxies")"#,
+                "ObjectStart",
+                r#"KeyBegin("Sine")"#,
+                "ObjectStart",
+                r#"KeyBegin("sinemin")"#,
+                r#"ValueString("0")"#,
+                "ObjectEnd",
+                "ObjectEnd",
+                r#"Flag("$WIN32&&!$LOW")"#,
+                "ObjectEnd",
+                "Eof",
+            ]
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let fails = |text: &str| {
+            let mut events = KvEvents::new(text.as_bytes()).unwrap();
+
+            loop {
+                match events.next_event() {
+                    Ok(Some(KvEvent::Eof)) | Ok(None) => panic!("{text:?} parsed"),
+                    Ok(Some(_)) => {}
+                    Err(err) => {
+                        assert!(events.next_event().unwrap().is_none());
+                        return err;
+                    }
+                }
+            }
+        };
+
+        assert!(matches!(fails("a { b c"), ReaderError::UnexpectedEof(_)));
+        assert!(matches!(
+            fails("a b ]"),
+            ReaderError::InvalidToken(token, position) if token == "CloseFlag" && position.column == 5
+        ));
+        assert!(matches!(fails("a b [ ]"), ReaderError::EmptyFlag));
+        assert!(matches!(fails("a { b"), ReaderError::MissingValue(key) if key == "b"));
+    }
+}
@@ -0,0 +1,697 @@
+use std::mem;
+
+use bumpalo::collections::String;
+use bumpalo::Bump;
+
+use super::reader::PathTracker;
+use super::token_reader::{IndentStats, LimitKind, ParseLimits, Token, TokenReader};
+use super::{
+    DuplicateKeyPolicy, Flag, FlagExpr, KeyValues, Object, ParseOptions, Position, ReaderError,
+    Result, Value,
+};
+
+/// Whether the input given to a `KvParser` so far could be a whole document.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FeedStatus {
+    /// The input ends part way through a token, block or entry.
+    NeedMoreInput,
+    /// The input so far is a whole document, which `finish` would return. More may still be fed,
+    /// as a document has no terminator.
+    Complete,
+}
+
+/// A push-based parser, for input which arrives a piece at a time, such as from a socket.
+///
+/// The tree is built as tokens arrive. Each `feed` carries on from the last token it could
+/// finish, keeping where it was in the document, so input already parsed is not scanned again;
+/// only a token cut off by the end of the input so far is read again once more arrives.
+pub struct KvParser {
+    options: ParseOptions,
+    kv: KeyValues,
+
+    // Input from just past the last token handled, which may end part way through the next.
+    buffer: Vec<u8>,
+    // Where `buffer` starts in the input, for error positions.
+    offset: u64,
+    line: u32,
+    column: u32,
+
+    state: State,
+    // Objects open below the root, each the value of the last entry of the one above it.
+    depth: usize,
+    anonymous_root: bool,
+    started: bool,
+    entries: usize,
+    paths: PathTracker,
+    indent: IndentStats,
+}
+
+/// What the parser expects next, as `KeyValues::visit_object` and its helpers would.
+enum State {
+    Key,
+    Value {
+        key: std::string::String,
+        line: u32,
+        // Whether an `=` after the key has been skipped.
+        separated: bool,
+    },
+    // After a value, where a flag may follow.
+    Flag,
+    FlagStart {
+        position: Position,
+        negated: bool,
+    },
+    FlagText {
+        position: Position,
+        negated: bool,
+        text: std::string::String,
+    },
+    Condition {
+        position: Position,
+        condition: std::string::String,
+    },
+    // After the closing `}` of an anonymous root.
+    End,
+}
+
+/// The flag of an entry, before it is copied into the document.
+enum EntryFlag {
+    None,
+    Text {
+        text: std::string::String,
+        negated: bool,
+    },
+    Compound(FlagExpr),
+}
+
+impl Default for KvParser {
+    fn default() -> Self {
+        KvParser::with_options(ParseOptions::default())
+    }
+}
+
+impl KvParser {
+    pub fn new() -> KvParser {
+        KvParser::default()
+    }
+
+    pub fn with_options(options: ParseOptions) -> KvParser {
+        let mut kv = KeyValues::empty();
+        kv.edit_root(|root| root.case_insensitive = options.case_insensitive_keys);
+
+        KvParser {
+            paths: PathTracker::new(&options),
+            options,
+            kv,
+
+            buffer: Vec::new(),
+            offset: 0,
+            line: 1,
+            column: 1,
+
+            state: State::Key,
+            depth: 0,
+            anonymous_root: false,
+            started: false,
+            entries: 0,
+            indent: IndentStats::default(),
+        }
+    }
+
+    /// Adds `bytes` to the input. Fails as soon as the input can no longer be a valid document,
+    /// such as on an unmatched `}`.
+    pub fn feed(&mut self, bytes: &[u8]) -> Result<FeedStatus> {
+        self.buffer.extend_from_slice(bytes);
+
+        let fed = self.offset + self.buffer.len() as u64;
+        if self.options.limits.max_bytes.is_some_and(|max| fed > max) {
+            return Err(ReaderError::LimitExceeded(LimitKind::Bytes));
+        }
+
+        let tokens_complete = self.read(false)?;
+        let complete = tokens_complete
+            && matches!(self.state, State::Key | State::Flag | State::End)
+            && self.depth == 0
+            && !self.anonymous_root;
+
+        Ok(if complete {
+            FeedStatus::Complete
+        } else {
+            FeedStatus::NeedMoreInput
+        })
+    }
+
+    /// Finishes the document with everything fed so far.
+    pub fn finish(mut self) -> Result<KeyValues> {
+        self.read(true)?;
+        let end = self.position_at(self.buffer.len());
+
+        match mem::replace(&mut self.state, State::End) {
+            State::Key | State::End => {}
+            State::Value { key, .. } => return Err(ReaderError::MissingValue(key)),
+            State::Flag => self.finish_entry(EntryFlag::None)?,
+            State::FlagStart { .. } | State::FlagText { .. } | State::Condition { .. } => {
+                return Err(ReaderError::UnexpectedEof(end))
+            }
+        }
+
+        if self.depth > 0 || self.anonymous_root {
+            return Err(ReaderError::UnexpectedEof(end));
+        }
+
+        if self.options.duplicate_keys == DuplicateKeyPolicy::KeepLast {
+            self.kv.edit_root(|root| root.keep_last_entries());
+        }
+
+        self.kv
+            .set_parse_details(self.indent.dominant(), self.paths);
+        Ok(self.kv)
+    }
+
+    /// Handles the tokens in the buffer which can no longer change, then drops the input they
+    /// were read from. Unless `at_end`, a token running up to the end of the input so far is left
+    /// for the next feed, as is a character cut off part way through. Returns whether every
+    /// token was handled, and nothing was left open.
+    fn read(&mut self, at_end: bool) -> Result<bool> {
+        let mut len = self.buffer.len();
+        if !at_end {
+            if let Err(err) = std::str::from_utf8(&self.buffer) {
+                if err.error_len().is_none() {
+                    len = err.valid_up_to();
+                }
+            }
+        }
+
+        let buffer = mem::take(&mut self.buffer);
+        let read = self.read_tokens(&buffer[..len], at_end);
+        self.buffer = buffer;
+        let (consumed, tokens_complete) = read?;
+
+        let Position {
+            offset,
+            line,
+            column,
+        } = self.position_at(consumed);
+        (self.offset, self.line, self.column) = (offset, line, column);
+        self.buffer.drain(..consumed);
+
+        Ok(tokens_complete)
+    }
+
+    /// Steps through the tokens of `input`, returning how many bytes of it were consumed and
+    /// whether all its tokens were.
+    fn read_tokens(&mut self, input: &[u8], at_end: bool) -> Result<(usize, bool)> {
+        let allocator = Bump::new();
+        // Limits on the whole document are checked here, as the reader only sees part of it.
+        let options = ParseOptions {
+            limits: ParseLimits {
+                max_string_length: self.options.limits.max_string_length,
+                ..Default::default()
+            },
+            ..self.options.clone()
+        };
+
+        let token_reader = if self.offset == 0 {
+            TokenReader::from_io_with_options(input, &allocator, &options)
+        } else {
+            TokenReader::resume_with_options(input, &allocator, &options)
+        };
+        let mut token_reader = token_reader.map_err(|err| self.rebase(err))?;
+
+        let mut consumed = 0;
+        let mut indent = IndentStats::default();
+
+        let tokens_complete = loop {
+            let token_end = token_reader.token_end() as usize;
+            let settled = match token_reader.peek() {
+                Token::Eof => {
+                    if at_end {
+                        indent = token_reader.indent_stats();
+                    }
+                    break !token_reader.unclosed_at_end();
+                }
+                // Text may carry on in the next piece of input, unless something follows it.
+                Token::Text(_, quoted) => {
+                    at_end
+                        || token_end < input.len()
+                        || (*quoted && !token_reader.unclosed_at_end())
+                }
+                _ => true,
+            };
+
+            if !settled {
+                break false;
+            }
+
+            let position = self.rebase_position(token_reader.token_position());
+            let token = mem::replace(token_reader.peek(), Token::Eof);
+            self.step(token, position)?;
+
+            indent = token_reader.indent_stats();
+            consumed = token_end;
+            token_reader.advance().map_err(|err| self.rebase(err))?;
+        };
+
+        self.indent.add(&indent);
+        Ok((consumed, tokens_complete))
+    }
+
+    fn step(&mut self, token: Token, position: Position) -> Result<()> {
+        let first = !mem::replace(&mut self.started, true);
+
+        match mem::replace(&mut self.state, State::Key) {
+            State::Key => match token {
+                Token::Text(mut key, _) => {
+                    KeyValues::normalize_key(&mut key, &self.options);
+                    self.state = State::Value {
+                        key: key.to_string(),
+                        line: position.line,
+                        separated: false,
+                    };
+                }
+                Token::OpenBlock if first && self.options.allow_anonymous_root => {
+                    self.anonymous_root = true;
+                    self.check_depth()?;
+                }
+                Token::CloseBlock if self.depth > 0 => self.close_object(),
+                Token::CloseBlock if self.anonymous_root => {
+                    self.anonymous_root = false;
+                    self.state = State::End;
+                }
+                token => return Err(invalid_token(&token, position)),
+            },
+            State::Value {
+                key,
+                line,
+                separated,
+            } => match token {
+                Token::Text(text, false)
+                    if self.options.equals_separators && !separated && text == "=" =>
+                {
+                    self.state = State::Value {
+                        key,
+                        line,
+                        separated: true,
+                    };
+                }
+                Token::OpenBlock => {
+                    self.start_entry(&key, line, None);
+                    self.depth += 1;
+                    self.check_depth()?;
+                }
+                Token::Text(text, _) => {
+                    self.start_entry(&key, line, Some(&text));
+                    self.paths.leave();
+                    self.state = State::Flag;
+                }
+                token => return Err(invalid_token(&token, position)),
+            },
+            State::Flag => match token {
+                Token::OpenFlag => {
+                    self.state = State::FlagStart {
+                        position,
+                        negated: false,
+                    };
+                }
+                token => {
+                    self.finish_entry(EntryFlag::None)?;
+                    return self.step(token, position);
+                }
+            },
+            State::FlagStart {
+                position: start,
+                negated,
+            } => match token {
+                Token::Negate if !negated => {
+                    self.state = State::FlagStart {
+                        position: start,
+                        negated: true,
+                    };
+                }
+                Token::Text(text, _) if text.is_empty() => return Err(ReaderError::EmptyFlag),
+                Token::Text(text, _) => {
+                    self.state = State::FlagText {
+                        position: start,
+                        negated,
+                        text: text.to_string(),
+                    };
+                }
+                Token::CloseFlag => return Err(ReaderError::EmptyFlag),
+                token => return Err(invalid_token(&token, position)),
+            },
+            State::FlagText {
+                position: start,
+                negated,
+                text,
+            } => match token {
+                Token::CloseFlag if !FlagExpr::is_compound(&text) => {
+                    self.finish_entry(EntryFlag::Text { text, negated })?;
+                }
+                // A `!` inside a compound condition ends the text before it, so the condition
+                // can span several tokens.
+                token => {
+                    let negation = if negated { "!" } else { "" };
+                    self.state = State::Condition {
+                        position: start,
+                        condition: format!("{negation}{text}"),
+                    };
+                    return self.step(token, position);
+                }
+            },
+            State::Condition {
+                position: start,
+                mut condition,
+            } => match token {
+                Token::Text(text, _) => {
+                    condition.push_str(&text);
+                    self.state = State::Condition {
+                        position: start,
+                        condition,
+                    };
+                }
+                Token::Negate => {
+                    condition.push('!');
+                    self.state = State::Condition {
+                        position: start,
+                        condition,
+                    };
+                }
+                Token::CloseFlag => match FlagExpr::parse(&condition) {
+                    Some(expr) if FlagExpr::is_compound(&condition) => {
+                        self.finish_entry(EntryFlag::Compound(expr))?;
+                    }
+                    _ => return Err(ReaderError::InvalidToken(condition, start)),
+                },
+                token => return Err(invalid_token(&token, position)),
+            },
+            State::End => return Err(invalid_token(&token, position)),
+        }
+
+        Ok(())
+    }
+
+    /// Adds an entry to the innermost open object, with `text` as its value, or an empty object
+    /// which becomes the innermost open one.
+    fn start_entry(&mut self, key: &str, line: u32, text: Option<&str>) {
+        self.paths.enter(key);
+        if self.paths.record_lines {
+            self.paths.record_line(line);
+        }
+
+        let (depth, options) = (self.depth, &self.options);
+        self.kv.edit_root(|root| {
+            let object = open_object(root, depth);
+            let allocator = object.allocator;
+
+            let value = match text {
+                None => {
+                    let mut child = Object::new_in(allocator);
+                    child.case_insensitive = options.case_insensitive_keys;
+                    Value::Object(child)
+                }
+                Some(text) => options
+                    .typed_numbers
+                    .then(|| KeyValues::typed_number(text))
+                    .flatten()
+                    .unwrap_or_else(|| Value::String(String::from_str_in(text, allocator))),
+            };
+
+            object.push_entry(allocator.alloc_str(key), Flag::None, value);
+        });
+    }
+
+    /// Gives the last entry of the innermost open object its flag, once it is known.
+    fn finish_entry(&mut self, flag: EntryFlag) -> Result<()> {
+        self.entries += 1;
+        if (self.options.limits.max_entries).is_some_and(|max| self.entries > max) {
+            return Err(ReaderError::LimitExceeded(LimitKind::Entries));
+        }
+
+        let keep_first = self.options.duplicate_keys == DuplicateKeyPolicy::KeepFirst;
+        let (depth, paths) = (self.depth, &mut self.paths);
+
+        self.kv.edit_root(|root| {
+            let object = open_object(root, depth);
+            let flag = match flag {
+                EntryFlag::None => Flag::None,
+                EntryFlag::Text { text, negated } => {
+                    let text = String::from_str_in(&text, object.allocator);
+                    if negated {
+                        Flag::Negated(text)
+                    } else {
+                        Flag::Normal(text)
+                    }
+                }
+                EntryFlag::Compound(expr) => Flag::Compound(expr),
+            };
+
+            let Some((key, _, _)) = object.last_entry_mut() else {
+                unreachable!("an entry is finished after it is started");
+            };
+            let entries = object.kv.get_vec(key).map_or(&[][..], Vec::as_slice);
+            let earlier = &entries[..entries.len().saturating_sub(1)];
+
+            if paths.collect_duplicates {
+                paths.check(earlier, key, &flag);
+            }
+
+            if keep_first && !earlier.is_empty() {
+                object.pop_entry();
+            } else if let Some((_, entry_flag, _)) = object.last_entry_mut() {
+                *entry_flag = flag;
+            }
+        });
+
+        self.state = State::Key;
+        Ok(())
+    }
+
+    fn close_object(&mut self) {
+        if self.options.duplicate_keys == DuplicateKeyPolicy::KeepLast {
+            let depth = self.depth;
+            self.kv
+                .edit_root(|root| open_object(root, depth).keep_last_entries());
+        }
+
+        self.depth -= 1;
+        self.paths.leave();
+        self.state = State::Flag;
+    }
+
+    fn check_depth(&self) -> Result<()> {
+        let depth = self.depth + usize::from(self.anonymous_root);
+
+        match self.options.limits.max_depth {
+            Some(max) if depth > max => Err(ReaderError::LimitExceeded(LimitKind::Depth)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Where `at`, an offset into the buffer, is in the whole input.
+    fn position_at(&self, at: usize) -> Position {
+        let before = &self.buffer[..at];
+        // Counts characters, without needing `before` to be valid UTF-8.
+        let chars = |bytes: &[u8]| bytes.iter().filter(|&&byte| byte & 0xC0 != 0x80).count();
+        let offset = self.offset + at as u64;
+
+        match before.iter().rposition(|&byte| byte == b'\n') {
+            Some(newline) => Position {
+                offset,
+                line: self.line + before.iter().filter(|&&byte| byte == b'\n').count() as u32,
+                column: chars(&before[newline + 1..]) as u32 + 1,
+            },
+            None => Position {
+                offset,
+                line: self.line,
+                column: self.column + chars(before) as u32,
+            },
+        }
+    }
+
+    /// Moves a position within the buffer, as a token reader over it reports, to where it is in
+    /// the whole input.
+    fn rebase_position(&self, position: Position) -> Position {
+        Position {
+            offset: self.offset + position.offset,
+            line: self.line + position.line - 1,
+            column: if position.line == 1 {
+                self.column + position.column - 1
+            } else {
+                position.column
+            },
+        }
+    }
+
+    fn rebase(&self, err: ReaderError) -> ReaderError {
+        match err {
+            ReaderError::InvalidToken(token, position) => {
+                ReaderError::InvalidToken(token, self.rebase_position(position))
+            }
+            ReaderError::UnexpectedEof(position) => {
+                ReaderError::UnexpectedEof(self.rebase_position(position))
+            }
+            ReaderError::NulByte(offset) => ReaderError::NulByte(self.offset + offset),
+            ReaderError::InvalidUtf8(offset) => ReaderError::InvalidUtf8(self.offset + offset),
+            err => err,
+        }
+    }
+}
+
+/// The innermost of the `depth` objects open below `root`.
+fn open_object<'o, 'a>(root: &'o mut Object<'a>, depth: usize) -> &'o mut Object<'a> {
+    let mut object = root;
+
+    for _ in 0..depth {
+        let Some((_, _, Value::Object(child))) = object.last_entry_mut() else {
+            unreachable!("each open object is the value of the last entry of its parent");
+        };
+        object = child;
+    }
+
+    object
+}
+
+fn invalid_token(token: &Token, position: Position) -> ReaderError {
+    ReaderError::InvalidToken(format!("{token:?}"), position)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{FeedStatus, KvParser};
+    use crate::kv::{DuplicateKeyPolicy, Indent, KeyValues, ParseOptions, ReaderError};
+
+    #[test]
+    fn byte_at_a_time() {
+        let text = br#"
+        "LightmappedGeneric"
+        {
+            "$basetexture" "brick/wall"   // comment { with braces }
+            $surfaceprop brick [$X360]
+            proxies { animatedtexture { animatedtexturevar $basetexture } }
+        }
+        other value
+        "#;
+
+        let mut parser = KvParser::new();
+        let mut statuses = Vec::new();
+        for byte in text {
+            statuses.push(parser.feed(&[*byte]).unwrap());
+        }
+
+        assert_eq!(statuses.last(), Some(&FeedStatus::Complete));
+        // Never complete inside the first block.
+        let block_end = text.iter().position(|&byte| byte == b'}').unwrap();
+        assert!(statuses[10..block_end]
+            .iter()
+            .all(|&status| status == FeedStatus::NeedMoreInput));
+
+        let kv = parser.finish().unwrap();
+        let expected = KeyValues::from_io(text.as_slice()).unwrap();
+        assert_eq!(kv.root(), expected.root());
+    }
+
+    #[test]
+    fn partial_input() {
+        let mut parser = KvParser::new();
+        assert_eq!(parser.feed(b"key val").unwrap(), FeedStatus::NeedMoreInput);
+        assert_eq!(parser.feed(b"ue ").unwrap(), FeedStatus::Complete);
+        assert_eq!(parser.feed(b"\"quoted").unwrap(), FeedStatus::NeedMoreInput);
+        assert_eq!(parser.feed(b" ").unwrap(), FeedStatus::NeedMoreInput);
+        assert_eq!(parser.feed(b"key\" ").unwrap(), FeedStatus::NeedMoreInput);
+        assert_eq!(parser.feed(b"{ }").unwrap(), FeedStatus::Complete);
+
+        let kv = parser.finish().unwrap();
+        assert_eq!(
+            kv.get("key").and_then(|value| value.raw_str()),
+            Some("value")
+        );
+        assert!(kv.get("quoted key").is_some_and(|value| value.is_object()));
+
+        let mut parser = KvParser::new();
+        assert!(matches!(
+            parser.feed(b"key value }"),
+            Err(ReaderError::InvalidToken(..))
+        ));
+
+        // Positions count from the start of everything fed, not the entries still being scanned.
+        let mut parser = KvParser::new();
+        assert_eq!(parser.feed(b"a 1\nb 2\n").unwrap(), FeedStatus::Complete);
+        let Err(ReaderError::InvalidToken(_, position)) = parser.feed(b"c ]") else {
+            panic!()
+        };
+        assert_eq!(
+            (position.offset, position.line, position.column),
+            (10, 3, 3)
+        );
+    }
+
+    #[test]
+    fn keeps_only_unfinished_input() {
+        let entry = b"\"key\" \"a longer value\" [$WIN32]\n";
+        let mut parser = KvParser::new();
+
+        for _ in 0..100 {
+            for byte in entry {
+                parser.feed(&[*byte]).unwrap();
+                // At most the token being read, and the whitespace before it.
+                assert!(parser.buffer.len() <= 17);
+            }
+        }
+
+        assert_eq!(parser.finish().unwrap().get_all("key").count(), 100);
+    }
+
+    #[test]
+    fn follows_parse_options() {
+        let text = "\
+{
+    width = 640 // pixels
+    name \"first\"
+    nested
+    {
+        x 1 [$WIN32&&!$X360]
+        x 2 [!$OSX]
+        y /* skipped */ 0.5
+    }
+    name second
+}
+";
+        let options = ParseOptions {
+            allow_anonymous_root: true,
+            equals_separators: true,
+            block_comments: true,
+            typed_numbers: true,
+            collect_duplicate_keys: true,
+            record_lines: true,
+            detect_indent: true,
+            ..Default::default()
+        };
+
+        for policy in [
+            DuplicateKeyPolicy::KeepAll,
+            DuplicateKeyPolicy::KeepFirst,
+            DuplicateKeyPolicy::KeepLast,
+        ] {
+            let options = ParseOptions {
+                duplicate_keys: policy,
+                ..options.clone()
+            };
+            let expected = KeyValues::from_io_with_options(text.as_bytes(), &options).unwrap();
+
+            for split in 0..text.len() {
+                let mut parser = KvParser::with_options(options.clone());
+                parser.feed(&text.as_bytes()[..split]).unwrap();
+                parser.feed(&text.as_bytes()[split..]).unwrap();
+                let kv = parser.finish().unwrap();
+
+                assert_eq!(kv.root(), expected.root(), "split at {split}");
+                assert_eq!(kv.duplicate_keys(), expected.duplicate_keys());
+                assert_eq!(kv.line_of("nested/y"), Some(8));
+                assert_eq!(kv.detected_indent(), Indent::Spaces(4));
+            }
+        }
+
+        let mut parser = KvParser::with_options(options);
+        parser.feed(b"{ a 1 } b 2").unwrap_err();
+    }
+}
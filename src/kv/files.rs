@@ -0,0 +1,103 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::{KeyValues, Result};
+
+impl KeyValues {
+    /// Parses the KV file at `path`. `#include` and `#base` directives name files relative to
+    /// the including file, and are handled as by `from_search_paths`.
+    pub fn from_path(path: &Path) -> Result<KeyValues> {
+        Self::load_resolving(path, |path| {
+            Ok((fs::canonicalize(path)?, fs::File::open(path)?))
+        })
+    }
+
+    /// Parses every file under `dir`, recursively, whose name matches `pattern`, such as
+    /// `*.vmt`. Each file is parsed as it is reached, and a file which fails to parse does not
+    /// stop the rest, so the results are per file. A directory which cannot be read is reported
+    /// as an error against its own path.
+    ///
+    /// Patterns match the file name only, ignoring ASCII case as Source does. `*` matches any
+    /// run of characters and `?` any single one. Files are visited in sorted path order.
+    pub fn from_dir_glob(
+        dir: &Path,
+        pattern: &str,
+    ) -> impl Iterator<Item = (PathBuf, Result<KeyValues>)> {
+        let pattern: Vec<char> = pattern.chars().collect();
+        let mut found = Vec::new();
+        collect_matching(dir, &pattern, &mut found);
+
+        found.into_iter().map(|(path, listed)| {
+            let kv = listed.and_then(|()| Self::from_path(&path));
+            (path, kv)
+        })
+    }
+}
+
+/// Adds every file under `dir` matching `pattern` to `found`, with `Ok(())`, or `dir` itself
+/// with the error if it cannot be read.
+fn collect_matching(dir: &Path, pattern: &[char], found: &mut Vec<(PathBuf, Result<()>)>) {
+    let entries =
+        match fs::read_dir(dir).and_then(|entries| entries.collect::<io::Result<Vec<_>>>()) {
+            Ok(entries) => entries,
+            Err(err) => {
+                found.push((dir.to_path_buf(), Err(err.into())));
+                return;
+            }
+        };
+
+    let mut paths: Vec<PathBuf> = entries.iter().map(|entry| entry.path()).collect();
+    paths.sort();
+
+    for path in paths {
+        if path.is_dir() {
+            collect_matching(&path, pattern, found);
+        } else if path
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| glob_match(pattern, &name.chars().collect::<Vec<_>>()))
+        {
+            found.push((path, Ok(())));
+        }
+    }
+}
+
+fn glob_match(pattern: &[char], name: &[char]) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, _) => name.is_empty(),
+        (Some('*'), _) => {
+            glob_match(&pattern[1..], name) || (!name.is_empty() && glob_match(pattern, &name[1..]))
+        }
+        (Some('?'), Some(_)) => glob_match(&pattern[1..], &name[1..]),
+        (Some(expected), Some(actual)) if expected.eq_ignore_ascii_case(actual) => {
+            glob_match(&pattern[1..], &name[1..])
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::glob_match;
+
+    fn matches(pattern: &str, name: &str) -> bool {
+        let pattern: Vec<char> = pattern.chars().collect();
+        let name: Vec<char> = name.chars().collect();
+
+        glob_match(&pattern, &name)
+    }
+
+    #[test]
+    fn patterns() {
+        assert!(matches("*.vmt", "brick.vmt"));
+        assert!(matches("*.vmt", "Brick.VMT"));
+        assert!(matches("*.vmt", ".vmt"));
+        assert!(!matches("*.vmt", "brick.vtf"));
+        assert!(!matches("*.vmt", "brick.vmt.bak"));
+        assert!(matches("brick??.vmt", "brick01.vmt"));
+        assert!(!matches("brick??.vmt", "brick1.vmt"));
+        assert!(matches("*", "anything"));
+        assert!(matches("a*b*c", "a-b-b-c"));
+    }
+}
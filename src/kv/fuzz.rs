@@ -0,0 +1,291 @@
+//! Randomised tests: generated documents must parse back to the tree they were generated from,
+//! and arbitrary input must never panic the parser.
+
+use std::fmt::Write;
+use std::io::{self, Read};
+
+use super::{
+    BorrowedFlag, BorrowedObject, BorrowedValue, KeyValues, LazyKeyValues, Object, ParseLimits,
+    ParseOptions, Value,
+};
+
+/// xorshift64*, so failures reproduce from the seed without pulling in a dependency.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    fn chance(&mut self, percent: usize) -> bool {
+        self.below(100) < percent
+    }
+
+    fn pick<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.below(items.len())]
+    }
+}
+
+/// Serves its data a few bytes per read, to exercise buffer refills.
+struct Trickle<'a> {
+    data: &'a [u8],
+    chunk: usize,
+}
+
+impl Read for Trickle<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.chunk.min(buf.len()).min(self.data.len());
+        buf[..len].copy_from_slice(&self.data[..len]);
+        self.data = &self.data[len..];
+
+        Ok(len)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Node {
+    String(String),
+    Object(Vec<Entry>),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Entry {
+    key: String,
+    flag: Option<String>,
+    value: Node,
+}
+
+const UNQUOTED_CHARS: &[u8] = b"abcXYZ019_$.-";
+// Anything but a quote can go between quotes, including characters which end unquoted text.
+const QUOTED_CHARS: &[u8] = b"abcXYZ019_$.- \t\n{}[]!\\/";
+
+fn random_text(rng: &mut Rng, chars: &[u8], min_len: usize) -> String {
+    let len = min_len + rng.below(8);
+    (0..len).map(|_| *rng.pick(chars) as char).collect()
+}
+
+fn random_entries(rng: &mut Rng, depth: usize) -> Vec<Entry> {
+    (0..rng.below(6))
+        .map(|_| {
+            // A small key space, so repeated keys are common.
+            let key = if rng.chance(70) {
+                rng.pick(&["a", "B", "$key", "solid", "side"]).to_string()
+            } else {
+                random_text(rng, QUOTED_CHARS, 1)
+            };
+
+            let value = if depth < 4 && rng.chance(30) {
+                Node::Object(random_entries(rng, depth + 1))
+            } else {
+                Node::String(random_text(rng, QUOTED_CHARS, 0))
+            };
+
+            let flag = match rng.below(6) {
+                0 => Some(format!("[{}]", rng.pick(&["$WIN32", "$X360"]))),
+                1 => Some(format!("[!{}]", rng.pick(&["$WIN32", "$POSIX"]))),
+                _ => None,
+            };
+
+            Entry { key, flag, value }
+        })
+        .collect()
+}
+
+/// Writes a string quoted, or unquoted where that reads back the same.
+fn render_text(rng: &mut Rng, out: &mut String, text: &str) {
+    let unquoted_ok = !text.is_empty() && text.bytes().all(|byte| UNQUOTED_CHARS.contains(&byte));
+
+    if unquoted_ok && rng.chance(50) {
+        out.push_str(text);
+    } else if !text.is_empty() && rng.chance(20) {
+        // Unquoted, escaping everything which would otherwise end the text.
+        for ch in text.chars() {
+            if !UNQUOTED_CHARS.contains(&(ch as u8)) {
+                out.push('\\');
+            }
+            out.push(ch);
+        }
+    } else {
+        write!(out, "\"{text}\"").unwrap();
+    }
+}
+
+fn render_separator(rng: &mut Rng, out: &mut String) {
+    let separators = [" ", "\t", "\n", "\r\n", "  \n\t", " // comment\n"];
+    out.push_str(rng.pick::<&str>(&separators));
+}
+
+fn render_entries(rng: &mut Rng, out: &mut String, entries: &[Entry]) {
+    for entry in entries {
+        render_separator(rng, out);
+        render_text(rng, out, &entry.key);
+        render_separator(rng, out);
+
+        match &entry.value {
+            Node::String(value) => {
+                // Unquoted text can't be empty, so an empty value is always quoted.
+                render_text(rng, out, value);
+            }
+            Node::Object(children) => {
+                out.push('{');
+                render_entries(rng, out, children);
+                render_separator(rng, out);
+                out.push('}');
+            }
+        }
+
+        if let Some(flag) = &entry.flag {
+            if rng.chance(50) {
+                out.push(' ');
+            }
+            out.push_str(flag);
+        }
+    }
+}
+
+/// Converts a parsed object back into entries. Entries under different keys come out in hash
+/// order, so both sides are sorted by key before comparing, which keeps the order of repeated
+/// keys.
+fn to_entries(object: &Object) -> Vec<Entry> {
+    let mut entries: Vec<Entry> = object
+        .kv
+        .iter_all()
+        .flat_map(|(key, values)| {
+            values.iter().map(move |(flag, value)| Entry {
+                key: key.to_string(),
+                flag: flag.to_suffix(),
+                value: match value {
+                    Value::String(string) => Node::String(string.to_string()),
+                    Value::Object(object) => Node::Object(to_entries(object)),
+                    Value::Int(_) | Value::Float(_) => unreachable!("typed numbers are off"),
+                },
+            })
+        })
+        .collect();
+
+    entries.sort_by(|a, b| a.key.cmp(&b.key));
+    entries
+}
+
+fn borrowed_to_entries(object: &BorrowedObject) -> Vec<Entry> {
+    let mut entries: Vec<Entry> = object
+        .iter()
+        .map(|(key, flag, value)| Entry {
+            key: key.to_string(),
+            flag: match flag {
+                BorrowedFlag::None => None,
+                BorrowedFlag::Normal(flag) => Some(format!("[{flag}]")),
+                BorrowedFlag::Negated(flag) => Some(format!("[!{flag}]")),
+                BorrowedFlag::Compound(expr) => Some(format!("[{expr}]")),
+            },
+            value: match value {
+                BorrowedValue::String(string) => Node::String(string.to_string()),
+                BorrowedValue::Object(object) => Node::Object(borrowed_to_entries(object)),
+            },
+        })
+        .collect();
+
+    entries.sort_by(|a, b| a.key.cmp(&b.key));
+    entries
+}
+
+fn sorted(entries: &[Entry]) -> Vec<Entry> {
+    let mut entries: Vec<Entry> = entries
+        .iter()
+        .map(|entry| Entry {
+            value: match &entry.value {
+                Node::Object(children) => Node::Object(sorted(children)),
+                string => string.clone(),
+            },
+            ..entry.clone()
+        })
+        .collect();
+
+    entries.sort_by(|a, b| a.key.cmp(&b.key));
+    entries
+}
+
+#[test]
+fn generated_documents_round_trip() {
+    let mut rng = Rng(0x5eed_1950);
+
+    for iteration in 0..500 {
+        let entries = random_entries(&mut rng, 0);
+
+        let mut text = String::new();
+        render_entries(&mut rng, &mut text, &entries);
+
+        let expected = sorted(&entries);
+        let context = format!("iteration {iteration}: {text:?}");
+
+        let parsed = KeyValues::from_io(text.as_bytes()).expect(&context);
+        assert_eq!(to_entries(parsed.root()), expected, "{context}");
+
+        let borrowed = BorrowedObject::parse(&text).expect(&context);
+        assert_eq!(borrowed_to_entries(&borrowed), expected, "{context}");
+
+        let trickle = Trickle {
+            data: text.as_bytes(),
+            chunk: 1 + rng.below(4),
+        };
+        let parsed = KeyValues::from_io(trickle).expect(&context);
+        assert_eq!(to_entries(parsed.root()), expected, "{context}");
+    }
+}
+
+#[test]
+fn random_input_does_not_panic() {
+    let mut rng = Rng(0xf0_22ed);
+
+    // Mostly characters with meaning to the tokenizer, so the input gets past the first token.
+    let alphabet = b"ab\"{}[]!\\/* \t\r\n=#$";
+
+    let options = ParseOptions {
+        trim_quoted_keys: true,
+        allow_anonymous_root: true,
+        equals_separators: true,
+        block_comments: true,
+        limits: ParseLimits {
+            max_depth: Some(8),
+            ..Default::default()
+        },
+        ..Default::default()
+    };
+
+    for _ in 0..2000 {
+        let len = rng.below(64);
+        let data: Vec<u8> = (0..len)
+            .map(|_| {
+                if rng.chance(90) {
+                    *rng.pick(alphabet)
+                } else {
+                    rng.next() as u8
+                }
+            })
+            .collect();
+
+        let _ = KeyValues::from_io(data.as_slice());
+        let _ = KeyValues::from_io_with_options(data.as_slice(), &options);
+        let _ = KeyValues::from_io_rooted(data.as_slice());
+        let _ = KeyValues::from_io(Trickle {
+            data: &data,
+            chunk: 1 + rng.below(3),
+        });
+
+        let _ = BorrowedObject::parse(&String::from_utf8_lossy(&data));
+
+        if let Ok(lazy) = LazyKeyValues::from_io(data.as_slice()) {
+            let keys: Vec<String> = lazy.keys().map(str::to_string).collect();
+            for key in keys {
+                let _ = lazy.get(&key);
+            }
+        }
+    }
+}
@@ -0,0 +1,242 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Error, ErrorKind, Read};
+use std::path::{Component, Path, PathBuf};
+
+use bumpalo::Bump;
+
+use super::{KeyValues, Object, ParseOptions, ReaderError, Result, Value};
+
+const BASE_DIRECTIVE: &str = "#base";
+const INCLUDE_DIRECTIVE: &str = "#include";
+
+/// An ordered list of directories KV files are looked up in, mirroring how Source mounts game,
+/// mod and DLC content. Directories added first take priority.
+#[derive(Debug, Clone, Default)]
+pub struct SearchPaths {
+    roots: Vec<PathBuf>,
+}
+
+impl SearchPaths {
+    pub fn new() -> SearchPaths {
+        SearchPaths::default()
+    }
+
+    /// Adds a directory with a lower priority than every directory already added.
+    pub fn push<P: Into<PathBuf>>(&mut self, root: P) {
+        self.roots.push(root.into());
+    }
+
+    pub fn roots(&self) -> &[PathBuf] {
+        &self.roots
+    }
+
+    /// Returns the full path of `path` in the highest priority directory containing it.
+    pub fn find(&self, path: &Path) -> Option<PathBuf> {
+        self.roots
+            .iter()
+            .map(|root| root.join(path))
+            .find(|full_path| full_path.is_file())
+    }
+}
+
+impl KeyValues {
+    /// Parses the KV file at `path`, looked up through `search_paths`.
+    ///
+    /// Top-level `#include "file"` and `#base "file"` directives are resolved relative to the
+    /// including file's directory, through the same search paths:
+    ///
+    /// - `#include` appends the file's entries after the including file's own, as siblings.
+    ///   Nothing is merged or replaced, so a key defined in both appears twice, with the
+    ///   including file's entry first and so returned by `get`. A file which cannot be found
+    ///   is an error.
+    /// - `#base` merges the file underneath the including file: keys it already defines take
+    ///   precedence, with nested objects merged recursively. A file which cannot be found is
+    ///   skipped, as in Source.
+    ///
+    /// Includes are applied before bases, so a base also merges into entries which only came
+    /// from an include. A file which includes itself, directly or not, is an
+    /// `ReaderError::IncludeCycle`.
+    pub fn from_search_paths(search_paths: &SearchPaths, path: &Path) -> Result<KeyValues> {
+        Self::load_resolving(path, |path| {
+            let full_path = search_paths.find(path).ok_or_else(|| {
+                Error::new(
+                    ErrorKind::NotFound,
+                    format!("{} not found in search paths", path.display()),
+                )
+            })?;
+
+            Ok((fs::canonicalize(&full_path)?, fs::File::open(full_path)?))
+        })
+    }
+
+    /// Parses the file `path` with `resolver`, which opens it and any file it names in
+    /// `#include` or `#base` directives, such as out of a VPK. Directives are handled as by
+    /// `from_search_paths`: the resolver is given each target joined onto the including file's
+    /// directory, with `.` and `..` resolved, and a `#base` target it fails to open with
+    /// `ErrorKind::NotFound` is skipped.
+    pub fn from_resolver<F, R>(path: &str, mut resolver: F) -> Result<KeyValues>
+    where
+        F: FnMut(&str) -> io::Result<R>,
+        R: Read,
+    {
+        Self::load_resolving(Path::new(path), |path| {
+            let path = normalize(path);
+            let read = resolver(&path.to_string_lossy())?;
+
+            Ok((path, read))
+        })
+    }
+
+    /// Parses the file at `path` with `open`, which returns a path identifying the file, to
+    /// detect cycles, and a reader over it.
+    pub(super) fn load_resolving<F, R>(path: &Path, mut open: F) -> Result<KeyValues>
+    where
+        F: FnMut(&Path) -> io::Result<(PathBuf, R)>,
+        R: Read,
+    {
+        let options = ParseOptions::default();
+
+        Self::build(|allocator| {
+            let (identity, read) = open(path)?;
+
+            Self::load_with_directives(
+                &mut open,
+                path,
+                identity,
+                read,
+                allocator,
+                &options,
+                &mut Vec::new(),
+            )
+        })
+    }
+
+    fn load_with_directives<'bump, F, R>(
+        open: &mut F,
+        path: &Path,
+        identity: PathBuf,
+        read: R,
+        allocator: &'bump Bump,
+        options: &ParseOptions,
+        include_stack: &mut Vec<PathBuf>,
+    ) -> Result<Object<'bump>>
+    where
+        F: FnMut(&Path) -> io::Result<(PathBuf, R)>,
+        R: Read,
+    {
+        if include_stack.contains(&identity) {
+            return Err(ReaderError::IncludeCycle(path.to_path_buf()));
+        }
+
+        let mut object = Self::parse_object(read, allocator, options)?;
+        let includes = object.remove_entries(INCLUDE_DIRECTIVE).unwrap_or_default();
+        let bases = object.remove_entries(BASE_DIRECTIVE).unwrap_or_default();
+
+        include_stack.push(identity);
+
+        let directory = path.parent().unwrap_or_else(|| Path::new(""));
+        for (is_base, (_, target)) in includes
+            .into_iter()
+            .map(|entry| (false, entry))
+            .chain(bases.into_iter().map(|entry| (true, entry)))
+        {
+            let target_path = match target {
+                Value::String(target) => directory.join(target.as_str()),
+                _ => continue,
+            };
+
+            let (target_identity, target_read) = match open(&target_path) {
+                Ok(opened) => opened,
+                Err(err) if is_base && err.kind() == ErrorKind::NotFound => continue,
+                Err(err) => return Err(err.into()),
+            };
+
+            let target_object = Self::load_with_directives(
+                open,
+                &target_path,
+                target_identity,
+                target_read,
+                allocator,
+                options,
+                include_stack,
+            )?;
+
+            if is_base {
+                object.merge_base(target_object);
+            } else {
+                object.append_included(target_object);
+            }
+        }
+
+        include_stack.pop();
+
+        Ok(object)
+    }
+}
+
+/// Resolves `.` and `..` in a relative path without touching the file system. `..` past the
+/// start is kept.
+fn normalize(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if matches!(
+                    normalized.components().next_back(),
+                    Some(Component::Normal(_))
+                ) {
+                    normalized.pop();
+                } else {
+                    normalized.push("..");
+                }
+            }
+            component => normalized.push(component),
+        }
+    }
+
+    normalized
+}
+
+impl<'a> Object<'a> {
+    /// Appends every entry of `included` after this object's entries under the same key.
+    fn append_included(&mut self, mut included: Object<'a>) {
+        for (key, flag, value) in included.take_entries() {
+            self.push_entry(key, flag, value);
+        }
+    }
+
+    /// Merges `base` underneath this object. Keys already present here are kept, except that
+    /// when both sides hold an object the two are merged recursively.
+    fn merge_base(&mut self, mut base: Object<'a>) {
+        // Whether each of the base's keys was missing here, in which case all of its entries are
+        // added, after this object's own.
+        let mut missing = HashMap::new();
+
+        for (key, flag, value) in base.take_entries() {
+            if let Some(&missing) = missing.get(key) {
+                if missing {
+                    self.push_entry(key, flag, value);
+                }
+                continue;
+            }
+
+            match self.kv.get_vec_mut(key) {
+                None => {
+                    missing.insert(key, true);
+                    self.push_entry(key, flag, value);
+                }
+                Some(entries) => {
+                    if let (Some((_, Value::Object(object))), Value::Object(base_object)) =
+                        (entries.first_mut(), value)
+                    {
+                        object.merge_base(base_object);
+                    }
+                    missing.insert(key, false);
+                }
+            }
+        }
+    }
+}
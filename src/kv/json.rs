@@ -0,0 +1,162 @@
+use std::collections::HashSet;
+use std::fmt::Write;
+
+use super::{KeyValues, Object, Value};
+
+const INDENT: &str = "  ";
+
+impl KeyValues {
+    /// Converts the document to pretty-printed JSON, for use with `jq` and other JSON tooling.
+    ///
+    /// Objects become JSON objects and strings become JSON strings. A key which appears more than
+    /// once becomes an array of its values, in source order. Flags are not represented.
+    pub fn to_json_string(&self) -> String {
+        let mut json = String::new();
+        write_object(&mut json, self.root(), 0);
+
+        json
+    }
+}
+
+fn write_object(json: &mut String, object: &Object, depth: usize) {
+    if object.kv.is_empty() {
+        json.push_str("{}");
+        return;
+    }
+
+    json.push_str("{\n");
+
+    // Each key is written where it first appears, with all of its values.
+    let mut written = HashSet::new();
+    let keys = object.iter().map(|(key, _, _)| key);
+
+    for (index, key) in keys.filter(|key| written.insert(*key)).enumerate() {
+        let entries = object.kv.get_vec(key).unwrap();
+        if index > 0 {
+            json.push_str(",\n");
+        }
+
+        push_indent(json, depth + 1);
+        write_string(json, key);
+        json.push_str(": ");
+
+        if entries.len() == 1 {
+            write_value(json, &entries[0].1, depth + 1);
+        } else {
+            json.push_str("[\n");
+
+            for (index, (_, value)) in entries.iter().enumerate() {
+                if index > 0 {
+                    json.push_str(",\n");
+                }
+
+                push_indent(json, depth + 2);
+                write_value(json, value, depth + 2);
+            }
+
+            json.push('\n');
+            push_indent(json, depth + 1);
+            json.push(']');
+        }
+    }
+
+    json.push('\n');
+    push_indent(json, depth);
+    json.push('}');
+}
+
+fn write_value(json: &mut String, value: &Value, depth: usize) {
+    match value {
+        Value::String(string) => write_string(json, string),
+        Value::Object(object) => write_object(json, object, depth),
+        Value::Int(int) => write!(json, "{int}").unwrap(),
+        Value::Float(float) => write!(json, "{float}").unwrap(),
+    }
+}
+
+fn write_string(json: &mut String, string: &str) {
+    json.push('"');
+
+    for ch in string.chars() {
+        match ch {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            ch if ch.is_control() => write!(json, "\\u{:04x}", ch as u32).unwrap(),
+            ch => json.push(ch),
+        }
+    }
+
+    json.push('"');
+}
+
+fn push_indent(json: &mut String, depth: usize) {
+    for _ in 0..depth {
+        json.push_str(INDENT);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::kv::KeyValues;
+
+    #[test]
+    fn compound_kv_json() {
+        let kv = r#"
+        comp {
+            key1 val1
+            key2 val2
+        }
+        "#
+        .as_bytes();
+
+        let json = KeyValues::from_io(kv).unwrap().to_json_string();
+        let parsed: serde_json::Value = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(
+            parsed,
+            json!({ "comp": { "key1": "val1", "key2": "val2" } })
+        );
+    }
+
+    #[test]
+    fn duplicates_and_escapes() {
+        let kv = r#"
+        solid { id 1 }
+        solid { id 2 }
+        "tabbed" "a	b"
+        "path" "C:\dir\file"
+        empty { }
+        "#
+        .as_bytes();
+
+        let json = KeyValues::from_io(kv).unwrap().to_json_string();
+        let parsed: serde_json::Value = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(
+            parsed,
+            json!({
+                "solid": [{ "id": "1" }, { "id": "2" }],
+                "tabbed": "a\tb",
+                "path": "C:\\dir\\file",
+                "empty": {}
+            })
+        );
+    }
+
+    #[test]
+    fn source_order() {
+        let kv = "zed 1 alpha { b 2 a 3 } zed 4".as_bytes();
+
+        let json = KeyValues::from_io(kv).unwrap().to_json_string();
+
+        assert_eq!(
+            json,
+            "{\n  \"zed\": [\n    \"1\",\n    \"4\"\n  ],\n  \"alpha\": {\n    \"b\": \"2\",\n    \"a\": \"3\"\n  }\n}"
+        );
+    }
+}
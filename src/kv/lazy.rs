@@ -0,0 +1,426 @@
+use std::cell::OnceCell;
+use std::io::{self, Read};
+use std::mem;
+use std::ops::Range;
+
+use bumpalo::Bump;
+use ouroboros::self_referencing;
+
+use super::token_reader::{Token, TokenReader};
+use super::{KeyValues, Result, Value};
+
+/// A KV document whose top-level entries are only parsed when first accessed.
+///
+/// The top level is tokenized up front to find where each entry starts and ends, but the value
+/// of an entry is not built until `get` is called for its key, after which it is cached. This is
+/// much cheaper than a full parse for large files where only a few blocks are needed.
+pub struct LazyKeyValues {
+    data: Vec<u8>,
+    entries: Vec<LazyEntry>,
+}
+
+struct LazyEntry {
+    key: String,
+    range: Range<usize>,
+    parsed: OnceCell<KeyValues>,
+}
+
+impl LazyKeyValues {
+    /// Reads the whole of `read` into memory and indexes its top-level entries.
+    pub fn from_io<R: Read>(mut read: R) -> Result<LazyKeyValues> {
+        let mut data = Vec::new();
+        read.read_to_end(&mut data)?;
+
+        let entries = Self::index_entries(&data)?;
+
+        Ok(LazyKeyValues { data, entries })
+    }
+
+    /// Returns the top-level keys in the order they appear in the document.
+    pub fn keys(&self) -> impl Iterator<Item = &str> {
+        self.entries.iter().map(|entry| entry.key.as_str())
+    }
+
+    /// Gets the value for a top-level key, parsing it if it has not been accessed before.
+    pub fn get(&self, k: &str) -> Result<Option<&Value<'_>>> {
+        let entry = match self.entries.iter().find(|entry| entry.key == k) {
+            None => return Ok(None),
+            Some(entry) => entry,
+        };
+
+        let parsed = match entry.parsed.get() {
+            Some(parsed) => parsed,
+            None => {
+                let parsed = KeyValues::from_io(&self.data[entry.range.clone()])?;
+                entry.parsed.get_or_init(|| parsed)
+            }
+        };
+
+        Ok(parsed.get(k))
+    }
+
+    fn index_entries(data: &[u8]) -> Result<Vec<LazyEntry>> {
+        // Token text is only needed for keys, so everything is allocated in a scratch arena.
+        let allocator = Bump::new();
+        let mut token_reader = TokenReader::from_io(data, &allocator)?;
+
+        let mut entries = Vec::new();
+
+        loop {
+            let start = token_reader.token_start() as usize;
+
+            let key = match token_reader.peek() {
+                Token::Eof => break,
+                Token::Text(key, _) => key.to_string(),
+                _ => return Err(token_reader.invalid_token()),
+            };
+            token_reader.advance()?;
+
+            Self::skip_value(&mut token_reader)?;
+            Self::skip_flag(&mut token_reader)?;
+
+            entries.push(LazyEntry {
+                key,
+                range: start..token_reader.token_start() as usize,
+                parsed: OnceCell::new(),
+            });
+        }
+
+        Ok(entries)
+    }
+
+    pub(super) fn skip_value<R: Read>(token_reader: &mut TokenReader<'_, R>) -> Result<()> {
+        match token_reader.peek() {
+            Token::Text(..) => {
+                token_reader.advance()?;
+                Ok(())
+            }
+            Token::OpenBlock => {
+                let mut depth = 0usize;
+
+                loop {
+                    match token_reader.peek() {
+                        Token::OpenBlock => depth += 1,
+                        Token::CloseBlock => depth -= 1,
+                        Token::Eof => return Err(token_reader.unexpected_eof()),
+                        _ => {}
+                    }
+
+                    token_reader.advance()?;
+
+                    if depth == 0 {
+                        return Ok(());
+                    }
+                }
+            }
+            Token::Eof => Err(token_reader.unexpected_eof()),
+            _ => Err(token_reader.invalid_token()),
+        }
+    }
+
+    pub(super) fn skip_flag<R: Read>(token_reader: &mut TokenReader<'_, R>) -> Result<()> {
+        if !matches!(token_reader.peek(), Token::OpenFlag) {
+            return Ok(());
+        }
+
+        while !matches!(token_reader.peek(), Token::CloseFlag) {
+            if matches!(token_reader.peek(), Token::Eof) {
+                return Err(token_reader.unexpected_eof());
+            }
+
+            token_reader.advance()?;
+        }
+
+        token_reader.advance()?;
+        Ok(())
+    }
+}
+
+impl KeyValues {
+    /// Returns the top-level keys of a document in the order they appear, without parsing their
+    /// values. Object values are skipped by counting braces, so this is much faster than a full
+    /// parse when only the outermost names are needed, such as the shader of a VMT.
+    pub fn top_level_keys<R: Read>(read: R) -> Result<Vec<String>> {
+        let allocator = Bump::new();
+        let mut token_reader = TokenReader::from_io(read, &allocator)?;
+
+        let mut keys = Vec::new();
+
+        loop {
+            match token_reader.peek() {
+                Token::Eof => break,
+                Token::Text(key, _) => keys.push(key.to_string()),
+                _ => return Err(token_reader.invalid_token()),
+            }
+            token_reader.advance()?;
+
+            LazyKeyValues::skip_value(&mut token_reader)?;
+            LazyKeyValues::skip_flag(&mut token_reader)?;
+        }
+
+        Ok(keys)
+    }
+
+    /// Counts the entries of a document, in every object, as a cheap size probe before a full
+    /// parse. Only the structure is scanned: no objects are built and text is not kept. Fails
+    /// where a full parse would fail on the structure.
+    pub fn count_entries<R: Read>(read: R) -> Result<usize> {
+        let allocator = Bump::new();
+        let mut token_reader = TokenReader::from_io(read, &allocator)?;
+        token_reader.set_discard_text(true);
+
+        let mut entries = 0usize;
+        let mut depth = 0usize;
+
+        loop {
+            match token_reader.peek() {
+                Token::Eof if depth == 0 => break,
+                Token::Eof => return Err(token_reader.unexpected_eof()),
+                Token::CloseBlock if depth > 0 => {
+                    depth -= 1;
+                    token_reader.advance()?;
+                    LazyKeyValues::skip_flag(&mut token_reader)?;
+                    continue;
+                }
+                Token::Text(..) => token_reader.advance()?,
+                _ => return Err(token_reader.invalid_token()),
+            }
+
+            entries += 1;
+
+            match token_reader.peek() {
+                Token::Text(..) => {
+                    token_reader.advance()?;
+                    LazyKeyValues::skip_flag(&mut token_reader)?;
+                }
+                Token::OpenBlock => {
+                    depth += 1;
+                    token_reader.advance()?;
+                }
+                Token::Eof => return Err(token_reader.unexpected_eof()),
+                _ => return Err(token_reader.invalid_token()),
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// Parses a stream of documents written back to back, each a single top-level entry such as
+    /// a concatenation of VMTs, yielding one `KeyValues` per entry. Only the entry being parsed
+    /// is held in memory. Iteration stops at the end of the input, or after the first error, as
+    /// there is no telling where the next document would start.
+    pub fn parse_stream<R: Read>(read: R) -> impl Iterator<Item = Result<KeyValues>> {
+        DocumentStream {
+            read: Some(read),
+            inner: None,
+            done: false,
+        }
+    }
+}
+
+/// Records everything read through it, and serves what it recorded from `served` on before
+/// reading more, so a token reader reset onto it picks up what the last one read ahead.
+struct Recording<R> {
+    read: Option<R>,
+    recorded: Vec<u8>,
+    served: usize,
+}
+
+impl<R: Read> Read for Recording<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let unserved = &self.recorded[self.served..];
+        if !unserved.is_empty() {
+            let num_read = unserved.len().min(buf.len());
+            buf[..num_read].copy_from_slice(&unserved[..num_read]);
+            self.served += num_read;
+
+            return Ok(num_read);
+        }
+
+        let Some(read) = &mut self.read else {
+            return Ok(0);
+        };
+        let num_read = read.read(buf)?;
+        self.recorded.extend_from_slice(&buf[..num_read]);
+        self.served += num_read;
+
+        Ok(num_read)
+    }
+}
+
+struct DocumentStream<R: Read> {
+    // Taken when the token reader is created for the first document.
+    read: Option<R>,
+    inner: Option<StreamInner<R>>,
+    done: bool,
+}
+
+/// A token reader kept for the whole stream, which is reset for each document so that its buffer
+/// is reused.
+#[self_referencing]
+struct StreamInner<R: Read> {
+    allocator: Bump,
+
+    #[borrows(allocator)]
+    #[not_covariant]
+    token_reader: TokenReader<'this, Recording<R>>,
+}
+
+impl<R: Read> DocumentStream<R> {
+    /// Finds the bytes of the next document by skipping over it with the token reader, keeping
+    /// what the reader buffered past its end for the next call.
+    fn next_document(&mut self) -> Result<Option<Vec<u8>>> {
+        let inner = match &mut self.inner {
+            Some(inner) => {
+                inner.with_token_reader_mut(|token_reader| {
+                    // What the reader buffered past the end of the last document starts the next.
+                    let recording = token_reader.get_mut();
+                    let next = Recording {
+                        read: recording.read.take(),
+                        recorded: mem::take(&mut recording.recorded),
+                        served: 0,
+                    };
+
+                    token_reader.reset(next)
+                })?;
+
+                inner
+            }
+            None => {
+                let recording = Recording {
+                    read: self.read.take(),
+                    recorded: Vec::new(),
+                    served: 0,
+                };
+                let inner = StreamInnerTryBuilder {
+                    allocator: Bump::new(),
+                    token_reader_builder: |allocator: &Bump| {
+                        TokenReader::from_io(recording, allocator)
+                    },
+                }
+                .try_build()?;
+
+                self.inner.insert(inner)
+            }
+        };
+
+        inner.with_token_reader_mut(Self::skip_document)
+    }
+
+    fn skip_document(token_reader: &mut TokenReader<'_, Recording<R>>) -> Result<Option<Vec<u8>>> {
+        let start = token_reader.token_start() as usize;
+        match token_reader.peek() {
+            Token::Eof => return Ok(None),
+            Token::Text(..) => token_reader.advance()?,
+            _ => return Err(token_reader.invalid_token()),
+        }
+
+        LazyKeyValues::skip_value(token_reader)?;
+        LazyKeyValues::skip_flag(token_reader)?;
+
+        let end = token_reader.token_start() as usize;
+        let recording = token_reader.get_mut();
+        let mut recorded = mem::take(&mut recording.recorded);
+        recording.recorded = recorded.split_off(end);
+        recorded.drain(..start);
+
+        Ok(Some(recorded))
+    }
+}
+
+impl<R: Read> Iterator for DocumentStream<R> {
+    type Item = Result<KeyValues>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+
+        let document = self
+            .next_document()
+            .and_then(|document| {
+                document
+                    .map(|data| KeyValues::from_io(data.as_slice()))
+                    .transpose()
+            })
+            .transpose();
+
+        if !matches!(document, Some(Ok(_))) {
+            self.done = true;
+        }
+
+        document
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LazyKeyValues;
+    use crate::kv::KeyValues;
+
+    #[test]
+    fn lazy_matches_eager() {
+        let kv = r#"
+        first {
+            key1 val1
+            nested { key2 val2 }
+        }
+        // comment between entries
+        "second" "value" [$WIN32]
+        third { }
+        "#
+        .as_bytes();
+
+        let lazy = LazyKeyValues::from_io(kv).unwrap();
+        let eager = KeyValues::from_io(kv).unwrap();
+
+        assert_eq!(
+            lazy.keys().collect::<Vec<_>>(),
+            vec!["first", "second", "third"]
+        );
+
+        for key in ["first", "second", "third"] {
+            assert_eq!(lazy.get(key).unwrap(), eager.get(key));
+        }
+
+        assert!(lazy.get("missing").unwrap().is_none());
+    }
+
+    #[test]
+    fn unterminated_block() {
+        assert!(LazyKeyValues::from_io("first { key val".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn document_stream() {
+        // Long enough that the token reader's buffer ends part way through the second document.
+        let long_value = "x".repeat(1500);
+        let text = format!(
+            r#""LightmappedGeneric" {{ "$basetexture" "brick/wall" }}"VertexLitGeneric"
+            {{
+                "$basetexture" "{long_value}"
+            }} // trailing comment
+            last value"#
+        );
+
+        let documents: Vec<KeyValues> = KeyValues::parse_stream(text.as_bytes())
+            .collect::<Result<_, _>>()
+            .unwrap();
+        assert_eq!(documents.len(), 3);
+
+        let expected = [
+            r#""LightmappedGeneric" { "$basetexture" "brick/wall" }"#.to_string(),
+            format!(r#""VertexLitGeneric" {{ "$basetexture" "{long_value}" }}"#),
+            "last value".to_string(),
+        ];
+        for (document, expected) in documents.iter().zip(expected) {
+            let expected = KeyValues::from_io(expected.as_bytes()).unwrap();
+            assert_eq!(document.root(), expected.root());
+        }
+
+        let mut stream = KeyValues::parse_stream("first { } } second { }".as_bytes());
+        assert!(stream.next().unwrap().is_ok());
+        assert!(stream.next().unwrap().is_err());
+        assert!(stream.next().is_none());
+    }
+}
@@ -0,0 +1,284 @@
+use std::io::{self, Read, Write};
+use std::ops::Range;
+
+use bumpalo::collections::String as BumpString;
+use bumpalo::Bump;
+
+use super::minify::text_token;
+use super::token_reader::{Token, TokenReader};
+use super::{KeyValues, ParseOptions, Result, Value};
+
+/// A document parsed with `KeyValues::parse_lossless`, which keeps the text it was parsed from
+/// so it can be written back out exactly.
+///
+/// Lossless here means byte for byte: comments, quoting, whitespace and indentation, line
+/// endings, key order, flags and any text after the last entry all come back as they were read.
+/// The tree itself is parsed with the default `ParseOptions`.
+///
+/// The only edit is `set_value`, which replaces a string value in both the text and the tree,
+/// leaving everything around it as it was. The tree is only lent out through `kv`, so it cannot
+/// be edited any other way: keys, flags and objects cannot be added, removed or renamed, and
+/// there is no writing out a tree built or changed elsewhere with the formatting of this one.
+pub struct LosslessKeyValues {
+    source: Vec<u8>,
+    kv: KeyValues,
+    values: Vec<ValueSpan>,
+}
+
+/// Where a string value was written in the source.
+struct ValueSpan {
+    /// The `/`-joined keys leading to the value, its own key last.
+    path: String,
+    /// The position of the entry in each object leading to the value, as `Object::iter` gives
+    /// them, the value's own entry last.
+    entries: Vec<usize>,
+    range: Range<usize>,
+    quoted: bool,
+}
+
+impl KeyValues {
+    /// Parses a document, keeping its text so it can be edited and written back out unchanged
+    /// apart from the edits. See `LosslessKeyValues`.
+    pub fn parse_lossless<R: Read>(mut read: R) -> Result<LosslessKeyValues> {
+        let mut source = Vec::new();
+        read.read_to_end(&mut source)?;
+
+        LosslessKeyValues::from_source(source)
+    }
+}
+
+impl LosslessKeyValues {
+    fn from_source(source: Vec<u8>) -> Result<LosslessKeyValues> {
+        let kv = KeyValues::from_io(source.as_slice())?;
+        let values = Self::find_values(&source)?;
+
+        Ok(LosslessKeyValues { source, kv, values })
+    }
+
+    /// Walks the tokens of a document which is known to parse, recording where each string
+    /// value is.
+    fn find_values(source: &[u8]) -> Result<Vec<ValueSpan>> {
+        let allocator = Bump::new();
+        let mut token_reader =
+            TokenReader::from_io_with_options(source, &allocator, &ParseOptions::default())?;
+
+        let mut values = Vec::new();
+        let mut path: Vec<String> = Vec::new();
+        let mut key: Option<String> = None;
+        let mut in_flag = false;
+        // The position of the current entry in each open object, and the number of entries seen
+        // so far in the innermost.
+        let mut entries: Vec<usize> = Vec::new();
+        let mut seen = 0;
+
+        loop {
+            let range = token_reader.token_start() as usize..token_reader.token_end() as usize;
+
+            match token_reader.peek() {
+                Token::Eof => break,
+                Token::OpenFlag => in_flag = true,
+                Token::CloseFlag => in_flag = false,
+                _ if in_flag => {}
+                Token::OpenBlock => {
+                    path.push(key.take().unwrap_or_default());
+                    entries.push(seen - 1);
+                    seen = 0;
+                }
+                Token::CloseBlock => {
+                    path.pop();
+                    seen = entries.pop().map_or(0, |entry| entry + 1);
+                }
+                Token::Text(text, quoted) => match key.take() {
+                    None => {
+                        key = Some(text.to_string());
+                        seen += 1;
+                    }
+                    Some(key) => {
+                        let quoted = *quoted;
+                        path.push(key);
+                        entries.push(seen - 1);
+                        values.push(ValueSpan {
+                            path: path.join("/"),
+                            entries: entries.clone(),
+                            range,
+                            quoted,
+                        });
+                        path.pop();
+                        entries.pop();
+                    }
+                },
+                Token::Negate => {}
+            }
+
+            token_reader.advance()?;
+        }
+
+        Ok(values)
+    }
+
+    /// The parsed tree, which reflects any edits.
+    pub fn kv(&self) -> &KeyValues {
+        &self.kv
+    }
+
+    /// The document's text, including any edits.
+    pub fn source(&self) -> &[u8] {
+        &self.source
+    }
+
+    /// Replaces the first string value at `path`, the `/`-joined keys leading to it such as
+    /// `material/$basetexture`, editing only the value's own text. A quoted value stays quoted
+    /// and an unquoted one stays unquoted where it can. Returns whether there was such a value;
+    /// objects cannot be replaced this way. The document is not parsed again.
+    pub fn set_value(&mut self, path: &str, value: &str) -> Result<bool> {
+        let Some(found) = self.values.iter().position(|span| span.path == path) else {
+            return Ok(false);
+        };
+        let span = &self.values[found];
+
+        let needs_quotes = value.is_empty()
+            || value.starts_with('"')
+            || value.contains("//")
+            || value.contains(|ch: char| {
+                ch.is_whitespace() || matches!(ch, '{' | '}' | '[' | ']' | '!' | '\\')
+            });
+        let quoted = span.quoted || needs_quotes;
+        let token = if quoted {
+            text_token(value)
+        } else {
+            value.to_string()
+        };
+
+        let old_range = span.range.clone();
+        let new_end = old_range.start + token.len();
+        self.source.splice(old_range.clone(), token.into_bytes());
+
+        // Values after the edited one have moved along by the difference in length.
+        for span in &mut self.values[found + 1..] {
+            span.range = span.range.start + new_end - old_range.end
+                ..span.range.end + new_end - old_range.end;
+        }
+        let span = &mut self.values[found];
+        span.range = old_range.start..new_end;
+        span.quoted = quoted;
+
+        let entries = &span.entries;
+        self.kv.edit_root(|root| {
+            let mut object = root;
+            let Some((last, parents)) = entries.split_last() else {
+                unreachable!("a value is always in an entry");
+            };
+
+            for &entry in parents {
+                let Some((_, _, Value::Object(child))) = object.entry_mut_at(entry) else {
+                    unreachable!("the spans were found in the parsed text");
+                };
+                object = child;
+            }
+
+            let allocator = object.allocator;
+            if let Some((_, _, entry_value)) = object.entry_mut_at(*last) {
+                *entry_value = Value::String(BumpString::from_str_in(value, allocator));
+            }
+        });
+
+        Ok(true)
+    }
+
+    /// Writes the document out, exactly as it was read apart from any edits.
+    pub fn write_lossless<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        w.write_all(&self.source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::kv::{KeyValues, Value};
+
+    const MATERIAL: &str = "// Brick wall, used in the courtyard.\r
+\"LightmappedGeneric\"\r
+{\r
+    \"$basetexture\"    \"brick/wall01\"   // the diffuse\r
+    $bumpmap          brick/wall01_normal\r
+\r
+    $surfaceprop brick [$X360]\r
+    \"Proxies\"\r
+    {\r
+\t\t\"Sine\" { sinemin 0  sinemax \"1\" }\r
+    }\r
+}\r
+// trailing comment";
+
+    #[test]
+    fn round_trip() {
+        let lossless = KeyValues::parse_lossless(MATERIAL.as_bytes()).unwrap();
+
+        let mut written = Vec::new();
+        lossless.write_lossless(&mut written).unwrap();
+        assert_eq!(written, MATERIAL.as_bytes());
+
+        assert_eq!(
+            lossless.kv().root(),
+            KeyValues::from_io(MATERIAL.as_bytes()).unwrap().root()
+        );
+    }
+
+    #[test]
+    fn set_value() {
+        let mut lossless = KeyValues::parse_lossless(MATERIAL.as_bytes()).unwrap();
+
+        // Longer than the value it replaces, so everything after it moves along.
+        assert!(lossless
+            .set_value("LightmappedGeneric/$basetexture", "brick/courtyard_wall")
+            .unwrap());
+        assert!(lossless
+            .set_value("LightmappedGeneric/$bumpmap", "brick/wall02_normal")
+            .unwrap());
+        assert!(lossless
+            .set_value("LightmappedGeneric/Proxies/Sine/sinemin", "-1 0")
+            .unwrap());
+        assert!(!lossless
+            .set_value("LightmappedGeneric/missing", "x")
+            .unwrap());
+        assert!(!lossless
+            .set_value("LightmappedGeneric/Proxies", "x")
+            .unwrap());
+
+        let expected = MATERIAL
+            .replace("\"brick/wall01\"", "\"brick/courtyard_wall\"")
+            .replace("brick/wall01_normal", "brick/wall02_normal")
+            .replace("sinemin 0 ", "sinemin \"-1 0\" ");
+        assert_eq!(std::str::from_utf8(lossless.source()).unwrap(), expected);
+
+        // The tree is edited along with the text, rather than parsed from it again.
+        assert_eq!(
+            lossless.kv().root(),
+            KeyValues::from_io(expected.as_bytes()).unwrap().root()
+        );
+
+        let Some(Value::Object(material)) = lossless.kv().get("LightmappedGeneric") else {
+            panic!()
+        };
+        assert_eq!(
+            material.get("$bumpmap").and_then(Value::raw_str),
+            Some("brick/wall02_normal")
+        );
+    }
+
+    #[test]
+    fn set_repeated_value() {
+        let text = "a { x 1 } a { x 2 y \"3\" }";
+        let mut lossless = KeyValues::parse_lossless(text.as_bytes()).unwrap();
+
+        // Only the first value at a path is replaced, in the tree as in the text.
+        assert!(lossless.set_value("a/x", "10").unwrap());
+        assert!(lossless.set_value("a/y", "").unwrap());
+
+        let expected = "a { x 10 } a { x 2 y \"\" }";
+        assert_eq!(std::str::from_utf8(lossless.source()).unwrap(), expected);
+        assert_eq!(
+            lossless.kv().root(),
+            KeyValues::from_io(expected.as_bytes()).unwrap().root()
+        );
+    }
+}
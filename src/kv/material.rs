@@ -0,0 +1,67 @@
+use std::path::PathBuf;
+
+/// Turns a texture value from a material, such as `concrete/concretefloor001a`, into the path of
+/// its file within the game's search paths, `materials/concrete/concretefloor001a.vtf`, as stored
+/// in a VPK. Backslashes become forward slashes and the path is lowercased, as VPK paths are. A
+/// value which already has an extension or the `materials/` prefix keeps it.
+pub fn texture_path(texture: &str) -> PathBuf {
+    let mut path = texture.trim().replace('\\', "/").to_ascii_lowercase();
+    path = path.trim_start_matches('/').to_string();
+
+    if !path.starts_with("materials/") {
+        path.insert_str(0, "materials/");
+    }
+
+    let file_name = path.rsplit('/').next().unwrap_or_default();
+    if !file_name.contains('.') {
+        path.push_str(".vtf");
+    }
+
+    PathBuf::from(path)
+}
+
+impl<'a> super::Object<'a> {
+    /// Returns the path of the texture a material parameter such as `$basetexture` or `$envmap`
+    /// names, ready to look up in a VPK; see `texture_path`. Returns `None` if the parameter is
+    /// missing, is not a string, or names a render target such as `_rt_WaterRefraction` or the
+    /// `env_cubemap` placeholder, which have no file.
+    pub fn resolve_texture(&self, param: &str) -> Option<PathBuf> {
+        let texture = self.get(param)?.raw_str()?;
+
+        if texture.is_empty()
+            || texture.starts_with("_rt_")
+            || texture.eq_ignore_ascii_case("env_cubemap")
+        {
+            return None;
+        }
+
+        Some(texture_path(texture))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::texture_path;
+
+    #[test]
+    fn texture_paths() {
+        assert_eq!(
+            texture_path("concrete/concretefloor001a"),
+            Path::new("materials/concrete/concretefloor001a.vtf")
+        );
+        assert_eq!(
+            texture_path("Concrete\\ConcreteFloor001a.vtf"),
+            Path::new("materials/concrete/concretefloor001a.vtf")
+        );
+        assert_eq!(
+            texture_path("materials/dev/dev_measuregeneric01b"),
+            Path::new("materials/dev/dev_measuregeneric01b.vtf")
+        );
+        assert_eq!(
+            texture_path("maps/map.01/cubemap"),
+            Path::new("materials/maps/map.01/cubemap.vtf")
+        );
+    }
+}
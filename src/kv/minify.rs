@@ -0,0 +1,230 @@
+use super::{Flag, KeyValues, Object, Value};
+
+const ESCAPE: char = '\\';
+const QUOTE: char = '"';
+/// Characters of an object's minified form kept by `Value::to_display_string`.
+const DISPLAY_LIMIT: usize = 80;
+
+/// Characters which end unquoted text, or start something other than text.
+fn is_delimiter(ch: char) -> bool {
+    matches!(ch, '{' | '}' | '[' | ']' | '!' | '/') || ch.is_whitespace()
+}
+
+/// Writes `text` unquoted, escaping anything which would end it or start another token.
+fn push_escaped(out: &mut String, text: &str) {
+    for ch in text.chars() {
+        if is_delimiter(ch) || matches!(ch, QUOTE | ESCAPE) {
+            out.push(ESCAPE);
+        }
+        out.push(ch);
+    }
+}
+
+/// Renders `text` as a single token which reads back as exactly `text`: quoted as it is, or,
+/// as quoted text cannot contain a quote, unquoted with its delimiters escaped.
+pub(super) fn text_token(text: &str) -> String {
+    let mut token = String::with_capacity(text.len() + 2);
+
+    if text.contains(QUOTE) {
+        push_escaped(&mut token, text);
+    } else {
+        token.push(QUOTE);
+        token.push_str(text);
+        token.push(QUOTE);
+    }
+
+    token
+}
+
+/// The length of `text_token(text)`, without building it.
+pub(super) fn token_size(text: &str) -> usize {
+    if text.contains(QUOTE) {
+        text.len()
+            + text
+                .chars()
+                .filter(|&ch| is_delimiter(ch) || matches!(ch, QUOTE | ESCAPE))
+                .count()
+    } else {
+        text.len() + 2
+    }
+}
+
+/// Builds the minified form, tracking whether the last thing written was unquoted text, as only
+/// then does the next text need a space before it.
+struct Minifier {
+    out: String,
+    after_unquoted: bool,
+}
+
+impl Minifier {
+    /// Writes `text` unquoted where it reads back the same, otherwise quoted. The reader takes
+    /// quoted text as it is, so text containing a quote is written unquoted with each delimiter
+    /// escaped instead.
+    fn text(&mut self, text: &str) {
+        if self.after_unquoted {
+            self.out.push(' ');
+        }
+
+        let needs_quotes = text.is_empty() || text.contains(is_delimiter) || text.contains(ESCAPE);
+
+        if !needs_quotes && !text.starts_with(QUOTE) {
+            self.out.push_str(text);
+            self.after_unquoted = true;
+        } else if !text.contains(QUOTE) {
+            self.out.push(QUOTE);
+            self.out.push_str(text);
+            self.out.push(QUOTE);
+            self.after_unquoted = false;
+        } else {
+            push_escaped(&mut self.out, text);
+            self.after_unquoted = true;
+        }
+    }
+
+    fn symbol(&mut self, symbol: &str) {
+        self.out.push_str(symbol);
+        self.after_unquoted = false;
+    }
+
+    fn object(&mut self, object: &Object) {
+        for (key, flag, value) in object.iter() {
+            self.text(key);
+
+            match value {
+                Value::String(string) => self.text(string),
+                Value::Int(int) => self.text(&int.to_string()),
+                Value::Float(float) => self.text(&format!("{float:?}")),
+                Value::Object(object) => {
+                    self.symbol("{");
+                    self.object(object);
+                    self.symbol("}");
+                }
+            }
+
+            match flag {
+                Flag::None => {}
+                Flag::Normal(flag) => {
+                    self.symbol("[");
+                    self.text(flag);
+                    self.symbol("]");
+                }
+                Flag::Negated(flag) => {
+                    self.symbol("[!");
+                    self.text(flag);
+                    self.symbol("]");
+                }
+                Flag::Compound(expr) => self.symbol(&format!("[{expr}]")),
+            }
+        }
+    }
+}
+
+impl<'a> Object<'a> {
+    /// Writes the object on a single line with as little whitespace as reads back the same:
+    /// text is only quoted where it has to be and spaces only separate unquoted text. Entries
+    /// come out in source order.
+    pub fn to_minified_string(&self) -> String {
+        let mut minifier = Minifier {
+            out: String::new(),
+            after_unquoted: false,
+        };
+        minifier.object(self);
+
+        minifier.out
+    }
+}
+
+impl KeyValues {
+    /// Writes the document on a single line. See `Object::to_minified_string`.
+    pub fn to_minified_string(&self) -> String {
+        self.root().to_minified_string()
+    }
+}
+
+impl<'a> Value<'a> {
+    /// Renders any value as readable text for logs and debugging dumps: a string as it is, a
+    /// number as written by `to_minified_string`, and an object minified, cut short after
+    /// `DISPLAY_LIMIT` characters with `...` added.
+    pub fn to_display_string(&self) -> String {
+        match self {
+            Value::String(string) => string.to_string(),
+            Value::Int(int) => int.to_string(),
+            Value::Float(float) => format!("{float:?}"),
+            Value::Object(object) => {
+                let mut minified = object.to_minified_string();
+
+                if let Some((end, _)) = minified.char_indices().nth(DISPLAY_LIMIT) {
+                    minified.truncate(end);
+                    minified.push_str("...");
+                }
+
+                format!("{{{minified}}}")
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::kv::{KeyValues, Value};
+
+    #[test]
+    fn minified() {
+        let kv = KeyValues::from_io(
+            r#"
+            "material"
+            {
+                "$basetexture"  "brick/wall01"
+                "$surfaceprop"  "brick"      [$X360]
+                "$envmaptint"   "[ .5 .5 .5 ]"
+                quoted          say\ \"hi\"
+                "empty"         ""
+            }
+            "#
+            .as_bytes(),
+        )
+        .unwrap();
+
+        let minified = kv.to_minified_string();
+        assert!(!minified.contains('\n'));
+        assert!(minified.starts_with("material{"));
+        assert!(minified.ends_with('}'));
+
+        for part in [
+            r#"$basetexture "brick/wall01""#,
+            "$surfaceprop brick[$X360]",
+            r#"$envmaptint "[ .5 .5 .5 ]""#,
+            r#"quoted say\ \"hi\""#,
+            r#"empty """#,
+        ] {
+            assert!(minified.contains(part), "{part:?} not in {minified:?}");
+        }
+
+        let reparsed = KeyValues::from_io(minified.as_bytes()).unwrap();
+        assert_eq!(reparsed.root(), kv.root());
+    }
+
+    #[test]
+    fn display_string() {
+        let kv = KeyValues::from_io(
+            r#"
+            "$basetexture" "brick/wall01"
+            proxies { sine { sinemin 0 sinemax 1 } }
+            long { key "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa" }
+            "#
+            .as_bytes(),
+        )
+        .unwrap();
+
+        let display = |key| kv.get(key).unwrap().to_display_string();
+
+        assert_eq!(display("$basetexture"), "brick/wall01");
+        assert_eq!(display("proxies"), "{sine{sinemin 0 sinemax 1}}");
+
+        let long = display("long");
+        assert_eq!(long.chars().count(), 85);
+        assert!(long.starts_with("{key aaa") && long.ends_with("aaa...}"));
+
+        assert_eq!(Value::Float(0.5).to_display_string(), "0.5");
+    }
+}
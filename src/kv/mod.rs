@@ -1,4 +1,38 @@
+mod borrowed;
+mod condition;
+mod connection;
+mod diagnostic;
+mod escape;
+mod events;
+mod feed;
+mod files;
+#[cfg(test)]
+mod fuzz;
+mod include;
+#[cfg(feature = "json")]
+mod json;
+mod lazy;
+mod lossless;
+mod material;
+mod minify;
 mod reader;
+mod schema;
+mod sound;
 mod token_reader;
+mod typed;
 
+pub use borrowed::*;
+pub use condition::*;
+pub use connection::*;
+pub use escape::*;
+pub use events::*;
+pub use feed::*;
+pub use include::*;
+pub use lazy::*;
+pub use lossless::*;
+pub use material::*;
 pub use reader::*;
+pub use schema::*;
+pub use sound::*;
+pub use token_reader::{Indent, LimitKind, NulBytes, ParseLimits};
+pub use typed::*;
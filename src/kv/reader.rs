@@ -1,26 +1,75 @@
 use std::borrow::Borrow;
-use std::collections::HashSet;
+use std::cell::Cell;
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fmt;
 use std::hash::Hash;
 use std::io::Read;
 use std::mem;
+use std::path::PathBuf;
 
 use bumpalo::collections::String;
 use bumpalo::Bump;
 use multimap::MultiMap;
 use ouroboros::self_referencing;
 
-use super::token_reader::{Token, TokenReader};
+use super::minify::{text_token, token_size};
+use super::token_reader::{Indent, LimitKind, NulBytes, ParseLimits, Token, TokenReader};
+use super::{FlagExpr, ValueKind};
 
 #[derive(Debug)]
 pub enum ReaderError {
     IO(std::io::Error),
-    InvalidToken(std::string::String),
-    UnexpectedEof,
+    /// A token which cannot appear where it was found.
+    InvalidToken(std::string::String, Position),
+    /// The document ended part way through a block or flag.
+    UnexpectedEof(Position),
+    IncludeCycle(PathBuf),
+    InvalidRoot(std::string::String),
+    /// A `[]` or `[!]` flag, which names no condition.
+    EmptyFlag,
+    /// The document ended after the given key, before its value.
+    MissingValue(std::string::String),
+    /// One of the `ParseLimits` was exceeded.
+    LimitExceeded(LimitKind),
+    /// A NUL byte at this offset into the input, rejected under `NulBytes::Reject`.
+    NulByte(u64),
+    /// Input which is not valid UTF-8, starting at this offset.
+    InvalidUtf8(u64),
 }
 pub type Result<T> = std::result::Result<T, ReaderError>;
 
+/// Where in the input a token starts, as attached to `ReaderError`s.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Position {
+    /// Byte offset into the input.
+    pub offset: u64,
+    /// Line, counting from 1.
+    pub line: u32,
+    /// Column in characters, counting from 1, with a tab counting as one.
+    pub column: u32,
+}
+
+impl Position {
+    /// The position of byte `offset` into `text`, which must be on a character boundary.
+    pub(super) fn locate(text: &str, offset: usize) -> Position {
+        let before = &text[..offset];
+        let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+
+        Position {
+            offset: offset as u64,
+            line: before[..line_start].matches('\n').count() as u32 + 1,
+            column: before[line_start..].chars().count() as u32 + 1,
+        }
+    }
+}
+
+impl fmt::Display for Position {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}, column {}", self.line, self.column)
+    }
+}
+
 impl From<std::io::Error> for ReaderError {
     fn from(err: std::io::Error) -> ReaderError {
         ReaderError::IO(err)
@@ -31,10 +80,23 @@ impl fmt::Display for ReaderError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             ReaderError::IO(err) => {
-                write!(f, "IO error encountered in reading:\n\t{}", err.to_string())
+                write!(f, "IO error encountered in reading:\n\t{}", err)
+            }
+            ReaderError::InvalidToken(data, position) => {
+                write!(f, "Invalid token: {data} at {position}")
             }
-            ReaderError::InvalidToken(data) => write!(f, "Invalid token: {data}"),
-            ReaderError::UnexpectedEof => write!(f, "Unexpected EOF"),
+            ReaderError::UnexpectedEof(position) => write!(f, "Unexpected EOF at {position}"),
+            ReaderError::IncludeCycle(path) => {
+                write!(f, "Cyclic include of {}", path.display())
+            }
+            ReaderError::InvalidRoot(reason) => write!(f, "Invalid root: {reason}"),
+            ReaderError::EmptyFlag => write!(f, "Empty flag"),
+            ReaderError::MissingValue(key) => {
+                write!(f, "Unexpected EOF: {key} has no value")
+            }
+            ReaderError::LimitExceeded(kind) => write!(f, "Exceeded the {kind}"),
+            ReaderError::NulByte(offset) => write!(f, "NUL byte at offset {offset}"),
+            ReaderError::InvalidUtf8(offset) => write!(f, "Invalid UTF-8 at offset {offset}"),
         }
     }
 }
@@ -43,8 +105,15 @@ impl Error for ReaderError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
         match self {
             ReaderError::IO(ref err) => Some(err),
-            ReaderError::InvalidToken(_) => None,
-            ReaderError::UnexpectedEof => None,
+            ReaderError::InvalidToken(..) => None,
+            ReaderError::UnexpectedEof(_) => None,
+            ReaderError::IncludeCycle(_) => None,
+            ReaderError::InvalidRoot(_) => None,
+            ReaderError::EmptyFlag => None,
+            ReaderError::MissingValue(_) => None,
+            ReaderError::LimitExceeded(_) => None,
+            ReaderError::NulByte(_) => None,
+            ReaderError::InvalidUtf8(_) => None,
         }
     }
 }
@@ -52,6 +121,9 @@ impl Error for ReaderError {
 #[self_referencing]
 pub struct KeyValues {
     allocator: Bump,
+    indent: Indent,
+    duplicate_keys: Vec<DuplicateKey>,
+    lines: HashMap<std::string::String, u32>,
 
     #[borrows(allocator)]
     #[covariant]
@@ -59,31 +131,305 @@ pub struct KeyValues {
 }
 
 /// Represents a generic KV object.
-#[derive(Debug, Default)]
 pub struct Object<'a> {
-    kv: MultiMap<String<'a>, (Flag<'a>, Value<'a>)>,
+    pub(super) allocator: &'a Bump,
+    pub(super) kv: MultiMap<&'a str, (Flag<'a>, Value<'a>)>,
+    // Every entry in `kv`, in the order they were added, for `iter`: its key, which is the same
+    // arena string `kv` holds, and its index among the entries under that key.
+    order: Vec<(&'a str, usize)>,
+    // Set from `ParseOptions::case_insensitive_keys`.
+    pub(super) case_insensitive: bool,
+    // Set by the mutating methods. A `Cell` so that writing the document out can clear it.
+    dirty: Cell<bool>,
+}
+
+/// Prints the object as an indented tree, as `Display` does, so test failures are readable.
+impl<'a> fmt::Debug for Object<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Object {{")?;
+        self.dump_at(f, 1, &[])?;
+        write!(f, "}}")
+    }
+}
+
+/// Writes the object as KV text, as `Object::write_to` does.
+impl<'a> fmt::Display for Object<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.dump_at(f, 0, &[])
+    }
+}
+
+impl<'a> PartialEq for Object<'a> {
+    fn eq(&self, other: &Self) -> bool {
+        self.kv == other.kv
+    }
 }
 
 /// Represents a generic KV value.
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum Value<'a> {
     String(String<'a>),
     Object(Object<'a>),
+    /// A whole number, only produced when parsing with `ParseOptions::typed_numbers`.
+    Int(i64),
+    /// A decimal number, only produced when parsing with `ParseOptions::typed_numbers`.
+    Float(f64),
 }
 
 /// Represents a KV entry flag
-#[derive(Debug)]
+#[derive(Debug, PartialEq)]
 pub enum Flag<'a> {
     None,
     Normal(String<'a>),
     Negated(String<'a>),
+    /// A condition combining defines with `&&` or `||`, such as `[$WIN32&&!$LOW]`.
+    Compound(FlagExpr),
+}
+
+/// A condition referenced by an entry flag, as reported by `Object::collect_flags`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum FlagCondition {
+    /// `[$FLAG]`, which applies when the flag is set.
+    Normal(std::string::String),
+    /// `[!$FLAG]`, which applies when the flag is not set.
+    Negated(std::string::String),
+}
+
+/// How entries which repeat a key already present in the same object are stored.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DuplicateKeyPolicy {
+    /// Every entry is kept, in source order. `get` returns the first.
+    #[default]
+    KeepAll,
+    /// Later entries are dropped.
+    KeepFirst,
+    /// Later entries replace earlier ones.
+    KeepLast,
+}
+
+/// A key repeated with the same flag within one object, as collected with
+/// `ParseOptions::collect_duplicate_keys`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DuplicateKey {
+    /// The `/`-joined keys of the object containing the duplicate, empty for the root.
+    pub path: std::string::String,
+    pub key: std::string::String,
+}
+
+/// Tracks the path to the object being parsed, when collecting duplicate keys or recording
+/// lines.
+#[derive(Default)]
+pub(super) struct PathTracker {
+    pub(super) collect_duplicates: bool,
+    pub(super) record_lines: bool,
+    path: Vec<std::string::String>,
+    found: Vec<DuplicateKey>,
+    lines: HashMap<std::string::String, u32>,
+}
+
+impl PathTracker {
+    pub(super) fn new(options: &ParseOptions) -> PathTracker {
+        PathTracker {
+            collect_duplicates: options.collect_duplicate_keys,
+            record_lines: options.record_lines,
+            ..Default::default()
+        }
+    }
+
+    pub(super) fn enter(&mut self, key: &str) {
+        if self.collect_duplicates || self.record_lines {
+            self.path.push(key.to_string());
+        }
+    }
+
+    /// Records the line of the key just entered, unless an earlier entry at the same path has
+    /// one.
+    pub(super) fn record_line(&mut self, line: u32) {
+        self.lines.entry(self.path.join("/")).or_insert(line);
+    }
+
+    pub(super) fn leave(&mut self) {
+        self.path.pop();
+    }
+
+    /// Records `key` as a duplicate if one of the `earlier` entries under it has the same flag.
+    pub(super) fn check(&mut self, earlier: &[(Flag, Value)], key: &str, flag: &Flag) {
+        let repeated = earlier.iter().any(|(entry_flag, _)| entry_flag == flag);
+
+        if repeated {
+            self.found.push(DuplicateKey {
+                path: self.path.join("/"),
+                key: key.to_string(),
+            });
+        }
+    }
+}
+
+/// Options controlling how KV text is parsed.
+#[derive(Debug, Clone, Default)]
+pub struct ParseOptions {
+    /// Trims leading and trailing whitespace from quoted keys, so `"  key  "` is stored as `key`.
+    /// Values are never trimmed.
+    pub trim_quoted_keys: bool,
+    /// Accepts a document consisting of a single anonymous `{ ... }` block, whose entries become
+    /// the root. This is not standard KV, but some tools emit it.
+    pub allow_anonymous_root: bool,
+    /// How repeated keys are handled. Flags are not taken into account, so keeping a single
+    /// entry also drops platform-conditional alternatives.
+    pub duplicate_keys: DuplicateKeyPolicy,
+    /// Leaves parsed strings with their full initial capacity instead of shrinking each one to
+    /// fit, which is slightly faster but uses more of the arena. Each string is read into a
+    /// buffer with room for 1024 bytes, and shrinking it returns the unused space to the arena.
+    pub keep_string_capacity: bool,
+    /// Limits on the size and shape of the document, all unlimited by default.
+    pub limits: ParseLimits,
+    /// Separates tokens on any Unicode whitespace instead of only ASCII whitespace, which is
+    /// what Source does, so a non-breaking space also ends unquoted text.
+    pub unicode_whitespace: bool,
+    /// Skips an unquoted `=` between a key and its value, so `width = 640` reads as `width 640`.
+    /// The `=` has to stand alone; `width=640` is still a single token. A quoted `"="` is always
+    /// a value.
+    pub equals_separators: bool,
+    /// Skips `/* */` comments as well as `//` ones, which Source does not do. A block comment
+    /// separates tokens like whitespace, except inside unquoted text, where it is removed and the
+    /// text carries on after it, so `tex/*note*/ture` reads as `texture`. In quoted text it is
+    /// kept as it is. An unterminated block comment runs to the end of the input.
+    pub block_comments: bool,
+    /// Stores numeric values as `Value::Int` or `Value::Float` instead of `Value::String`,
+    /// whether or not they were quoted. Only the plain decimal forms `7`, `-12` and `0.5` are
+    /// numbers: text with a leading zero (`007`), a `+`, an exponent, a bare `.5` or `5.`, or
+    /// which does not fit an `i64` stays a string, as does `-0`. The original text of a float is
+    /// not kept, so `0.50` comes back as `0.5`.
+    pub typed_numbers: bool,
+    /// Records every key repeated with the same flag within one object, returned by
+    /// `KeyValues::duplicate_keys`. The tree is built as it would be without this, following
+    /// `duplicate_keys`. Flagged alternatives such as `key a [$X360] key b [!$X360]` are not
+    /// reported, but keys which are meant to repeat, like VMF outputs, are.
+    pub collect_duplicate_keys: bool,
+    /// Stores every key in ASCII lowercase, as Source compares keys, so exact lookups such as
+    /// `get("basetexture")` find `$BaseTexture` without folding case on every lookup. Values and
+    /// flags are never folded.
+    pub lowercase_keys: bool,
+    /// What to do with NUL bytes, which are rejected by default.
+    pub nul_bytes: NulBytes,
+    /// Records the line each key is written on, returned by `KeyValues::line_of`.
+    pub record_lines: bool,
+    /// Makes lookups such as `get` and `get_with_flags` on the parsed objects fall back to
+    /// ignoring ASCII case when no key matches exactly, as Source compares keys. Unlike
+    /// `lowercase_keys`, keys are stored as written, so they are written back out unchanged.
+    /// Among keys differing only in case, the first written is found.
+    pub case_insensitive_keys: bool,
+    /// Decodes escape sequences in quoted text, so text written with `escape` reads back as it
+    /// was, as Source does once told to with `KeyValues::UsesEscapeSequences`. `\n` and `\t`
+    /// become a newline and a tab, and a `\` before any other character, such as `\"` or `\\`,
+    /// stands for that character. Otherwise a `\` in quoted text is kept.
+    pub escape_sequences: bool,
+    /// Works out how the document is indented, returned by `KeyValues::detected_indent`. This
+    /// tallies the leading whitespace of each line, which costs a little on every character read.
+    pub detect_indent: bool,
+}
+
+impl fmt::Display for KeyValues {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self.root(), f)
+    }
+}
+
+impl Default for KeyValues {
+    fn default() -> Self {
+        Self::empty()
+    }
+}
+
+/// Collects `(key, value)` string pairs into a new document. Repeated keys are all kept, as when
+/// parsing. Values other than strings, such as objects, can be collected as `&Value` instead.
+///
+/// # Examples
+/// ```
+/// use srcrs::kv::{KeyValues, Value};
+///
+/// let kv: KeyValues = [("$basetexture", "brick/brickwall001"), ("$surfaceprop", "brick")]
+///     .into_iter()
+///     .collect();
+///
+/// assert!(matches!(kv.get("$surfaceprop").unwrap(), Value::String(v) if v == "brick"));
+/// ```
+impl<K: AsRef<str>, V: AsRef<str>> FromIterator<(K, V)> for KeyValues {
+    fn from_iter<I: IntoIterator<Item = (K, V)>>(iter: I) -> Self {
+        KeyValuesBuilder {
+            allocator: Bump::new(),
+            indent: Indent::default(),
+            duplicate_keys: Vec::new(),
+            lines: HashMap::new(),
+            root_builder: |allocator: &Bump| {
+                let mut root = Object::new_in(allocator);
+
+                for (key, value) in iter {
+                    let key = allocator.alloc_str(key.as_ref());
+                    let value = String::from_str_in(value.as_ref(), allocator);
+
+                    root.push_entry(key, Flag::None, Value::String(value));
+                }
+
+                root
+            },
+        }
+        .build()
+    }
+}
+
+/// Collects `(key, value)` pairs into a new document, copying each value, with any objects
+/// nested in it, into the document's arena. Repeated keys are all kept, as when parsing.
+///
+/// # Examples
+/// ```
+/// use srcrs::kv::KeyValues;
+///
+/// let material = KeyValues::from_io(r#"Proxies { Sine { sinemin 0 } }"#.as_bytes()).unwrap();
+///
+/// let kv: KeyValues = material.root().iter().map(|(key, _, value)| (key, value)).collect();
+///
+/// assert_eq!(kv.root(), material.root());
+/// ```
+impl<'v, K: AsRef<str>> FromIterator<(K, &'v Value<'v>)> for KeyValues {
+    fn from_iter<I: IntoIterator<Item = (K, &'v Value<'v>)>>(iter: I) -> Self {
+        KeyValuesBuilder {
+            allocator: Bump::new(),
+            indent: Indent::default(),
+            duplicate_keys: Vec::new(),
+            lines: HashMap::new(),
+            root_builder: |allocator: &Bump| {
+                let mut root = Object::new_in(allocator);
+
+                for (key, value) in iter {
+                    let key = allocator.alloc_str(key.as_ref());
+                    root.push_entry(key, Flag::None, value.copy_in(allocator));
+                }
+
+                root
+            },
+        }
+        .build()
+    }
 }
 
 impl KeyValues {
+    /// Creates an empty document.
+    pub fn empty() -> KeyValues {
+        KeyValuesBuilder {
+            allocator: Bump::new(),
+            indent: Indent::default(),
+            duplicate_keys: Vec::new(),
+            lines: HashMap::new(),
+            root_builder: |allocator: &Bump| Object::new_in(allocator),
+        }
+        .build()
+    }
+
     /// Parses a Keyvalues object from an `std::io::Read` object.
     /// # Examples
     /// ```
-    /// use srcrs::kv::{Object, Value};
+    /// use srcrs::kv::{KeyValues, Value};
     ///
     /// let kv = r#"
     ///    comp {
@@ -92,27 +438,217 @@ impl KeyValues {
     ///    }
     /// "#.as_bytes();
     ///
-    /// let object = Object::from_io(kv).unwrap();
+    /// let object = KeyValues::from_io(kv).unwrap();
     ///
-    /// match object.kv.get("comp").unwrap() {
+    /// match object.get("comp").unwrap() {
     ///     Value::Object(comp) => {
-    ///         assert!(*comp.kv.get("key1").unwrap() == Value::String("val1".into()));
-    ///         assert!(*comp.kv.get("key2").unwrap() == Value::String("val2".into()));
+    ///         assert!(matches!(comp.get("key1").unwrap(), Value::String(v) if v == "val1"));
+    ///         assert!(matches!(comp.get("key2").unwrap(), Value::String(v) if v == "val2"));
     ///     }
     ///     _ => panic!(),
     /// }
     /// ```
     pub fn from_io<'c, 'b: 'c, R: Read>(read: R) -> Result<KeyValues> {
+        Self::from_io_with_options(read, &ParseOptions::default())
+    }
+
+    /// Parses a Keyvalues object from an `std::io::Read` object, using the given parse options.
+    pub fn from_io_with_options<R: Read>(read: R, options: &ParseOptions) -> Result<KeyValues> {
+        Self::from_token_reader(
+            |allocator| TokenReader::from_io_with_options(read, allocator, options),
+            options,
+        )
+    }
+
+    /// Parses a Keyvalues object from untrusted input, failing with `ReaderError::LimitExceeded`
+    /// as soon as the document exceeds any of `limits`.
+    pub fn from_io_with_limits<R: Read>(read: R, limits: &ParseLimits) -> Result<KeyValues> {
+        let options = ParseOptions {
+            limits: limits.clone(),
+            ..Default::default()
+        };
+
+        Self::from_io_with_options(read, &options)
+    }
+
+    fn from_token_reader<R, F>(token_reader: F, options: &ParseOptions) -> Result<KeyValues>
+    where
+        R: Read,
+        F: for<'bump> FnOnce(&'bump Bump) -> Result<TokenReader<'bump, R>>,
+    {
+        let mut indent = Indent::default();
+        let mut paths = PathTracker::new(options);
+
+        let mut kv = Self::build(|allocator| {
+            let mut token_reader = token_reader(allocator)?;
+
+            let root = Self::visit_document(&mut token_reader, options, &mut paths)?;
+            indent = token_reader.detected_indent();
+
+            Ok(root)
+        })?;
+
+        kv.set_parse_details(indent, paths);
+        Ok(kv)
+    }
+
+    /// Edits the root object in place, for parsers building a document a piece at a time.
+    pub(super) fn edit_root<T>(
+        &mut self,
+        edit: impl for<'this> FnOnce(&mut Object<'this>) -> T,
+    ) -> T {
+        self.with_root_mut(edit)
+    }
+
+    /// Stores what was found about the text while parsing it, for `detected_indent`,
+    /// `duplicate_keys` and `line_of`.
+    pub(super) fn set_parse_details(&mut self, indent: Indent, paths: PathTracker) {
+        self.with_indent_mut(|kv_indent| *kv_indent = indent);
+        self.with_duplicate_keys_mut(|duplicate_keys| *duplicate_keys = paths.found);
+        self.with_lines_mut(|lines| *lines = paths.lines);
+    }
+
+    /// Bytes allocated by the document's arena, including space not yet used by any string or
+    /// object.
+    pub fn allocated_bytes(&self) -> usize {
+        self.borrow_allocator().allocated_bytes()
+    }
+
+    /// The dominant indentation of the parsed text, for reformatting in the file's own style.
+    /// Only detected with `ParseOptions::detect_indent`; otherwise, as for documents which were
+    /// not parsed from text, this is `Indent::Tabs`.
+    pub fn detected_indent(&self) -> Indent {
+        *self.borrow_indent()
+    }
+
+    /// The keys repeated within an object of the parsed text, in the order they were found.
+    /// Always empty unless parsed with `ParseOptions::collect_duplicate_keys`.
+    pub fn duplicate_keys(&self) -> &[DuplicateKey] {
+        self.borrow_duplicate_keys()
+    }
+
+    /// Returns the line, counting from 1, on which the key at `path` is written, where `path`
+    /// is the `/`-joined keys leading to it, such as `material/$basetexture`. A repeated key
+    /// gives the line of its first entry. Always `None` unless parsed with
+    /// `ParseOptions::record_lines`.
+    pub fn line_of(&self, path: &str) -> Option<u32> {
+        self.borrow_lines().get(path).copied()
+    }
+
+    /// Parses a document of the conventional `"RootName" { ... }` shape, returning the root name
+    /// and a document holding the contents of the root block. Errors if the document does not
+    /// contain exactly one top-level entry, or if that entry is not an object.
+    ///
+    /// # Examples
+    /// ```
+    /// use srcrs::kv::{KeyValues, Value};
+    ///
+    /// let (shader, body) =
+    ///     KeyValues::from_io_rooted(r#""Water" { "$abovewater" 0 }"#.as_bytes()).unwrap();
+    ///
+    /// assert_eq!(shader, "Water");
+    /// assert!(matches!(body.get("$abovewater").unwrap(), Value::String(v) if v == "0"));
+    /// ```
+    pub fn from_io_rooted<R: Read>(read: R) -> Result<(std::string::String, KeyValues)> {
+        let mut root_name = None;
+
+        let kv = Self::build(|allocator| {
+            let root = Self::parse_object(read, allocator, &ParseOptions::default())?;
+
+            if root.kv.len() != 1 {
+                return Err(ReaderError::InvalidRoot(format!(
+                    "expected a single top-level entry, found {}",
+                    root.kv.len()
+                )));
+            }
+
+            let (name, mut entries) = root.kv.into_iter().next().unwrap();
+            if entries.len() != 1 {
+                return Err(ReaderError::InvalidRoot(format!(
+                    "{} is defined {} times",
+                    name,
+                    entries.len()
+                )));
+            }
+
+            match entries.pop().unwrap() {
+                (_, Value::Object(object)) => {
+                    root_name = Some(name.to_string());
+                    Ok(object)
+                }
+                _ => Err(ReaderError::InvalidRoot(format!(
+                    "{} is not an object",
+                    name
+                ))),
+            }
+        })?;
+
+        Ok((root_name.unwrap(), kv))
+    }
+
+    /// Creates a document, building its root object in the document's own arena.
+    pub(super) fn build<F>(root_builder: F) -> Result<KeyValues>
+    where
+        F: for<'this> FnOnce(&'this Bump) -> Result<Object<'this>>,
+    {
         KeyValuesTryBuilder {
             allocator: Bump::with_capacity(1024),
-            root_builder: |allocator: &Bump| {
-                let mut token_reader = TokenReader::from_io(read, allocator)?;
-                Self::visit_object(&mut token_reader)
-            },
+            indent: Indent::default(),
+            duplicate_keys: Vec::new(),
+            lines: HashMap::new(),
+            root_builder,
         }
         .try_build()
     }
 
+    pub(super) fn parse_object<'bump, R: Read>(
+        read: R,
+        allocator: &'bump Bump,
+        options: &ParseOptions,
+    ) -> Result<Object<'bump>> {
+        let mut token_reader = TokenReader::from_io_with_options(read, allocator, options)?;
+
+        Self::visit_document(&mut token_reader, options, &mut PathTracker::new(options))
+    }
+
+    /// Parses `read` again to find the byte offset of the token at which parsing fails, or
+    /// `None` if it parses.
+    pub(super) fn locate_error<R: Read>(read: R, options: &ParseOptions) -> Option<u64> {
+        let allocator = Bump::new();
+        let mut token_reader = TokenReader::from_io_with_options(read, &allocator, options).ok()?;
+
+        let parsed =
+            Self::visit_document(&mut token_reader, options, &mut PathTracker::new(options));
+        parsed.err().map(|_| token_reader.token_start())
+    }
+
+    fn visit_document<'bump, R: Read>(
+        token_reader: &mut TokenReader<'bump, R>,
+        options: &ParseOptions,
+        paths: &mut PathTracker,
+    ) -> Result<Object<'bump>> {
+        if options.allow_anonymous_root && matches!(token_reader.peek(), Token::OpenBlock) {
+            Self::visit_anonymous_root(token_reader, options, paths)
+        } else {
+            Self::visit_object(token_reader, options, paths)
+        }
+    }
+
+    fn visit_anonymous_root<'bump, R: Read>(
+        token_reader: &mut TokenReader<'bump, R>,
+        options: &ParseOptions,
+        paths: &mut PathTracker,
+    ) -> Result<Object<'bump>> {
+        Self::visit_open_block(token_reader)?;
+        let object = Self::visit_object(token_reader, options, paths)?;
+        Self::visit_close_block(token_reader)?;
+
+        match token_reader.peek() {
+            Token::Eof => Ok(object),
+            _ => Err(token_reader.invalid_token()),
+        }
+    }
+
     #[inline]
     fn visit_open_block<'bump, R: Read>(token_reader: &mut TokenReader<'bump, R>) -> Result<()> {
         debug_assert!(*token_reader.peek() == Token::OpenBlock);
@@ -123,8 +659,12 @@ impl KeyValues {
 
     #[inline]
     fn visit_close_block<'bump, R: Read>(token_reader: &mut TokenReader<'bump, R>) -> Result<()> {
-        debug_assert!(*token_reader.peek() == Token::CloseBlock);
-        token_reader.advance()?;
+        // `visit_object` also stops at the end of the document, so a block may be unterminated.
+        match token_reader.peek() {
+            Token::CloseBlock => token_reader.advance()?,
+            Token::Eof => return Err(token_reader.unexpected_eof()),
+            _ => return Err(token_reader.invalid_token()),
+        }
 
         Ok(())
     }
@@ -161,228 +701,2660 @@ impl KeyValues {
     fn visit_text<'bump, R: Read>(
         token_reader: &mut TokenReader<'bump, R>,
     ) -> Result<String<'bump>> {
-        debug_assert!(matches!(*token_reader.peek(), Token::Text(_)));
+        debug_assert!(matches!(*token_reader.peek(), Token::Text(..)));
 
         let text = token_reader.peek().unwrap_text();
         token_reader.advance()?;
         Ok(text)
     }
 
-    fn visit_flag<'bump, R: Read>(token_reader: &mut TokenReader<'bump, R>) -> Result<Flag<'bump>> {
+    pub(super) fn visit_flag<'bump, R: Read>(
+        token_reader: &mut TokenReader<'bump, R>,
+    ) -> Result<Flag<'bump>> {
         if !matches!(token_reader.peek(), Token::OpenFlag) {
             return Ok(Flag::None);
         }
 
+        let position = token_reader.token_position();
         Self::visit_open_flag(token_reader)?;
         let negated = Self::visit_flag_negation(token_reader)?;
-        let text = Self::visit_text(token_reader)?;
+
+        let text = match token_reader.peek() {
+            Token::Text(text, _) if text.is_empty() => return Err(ReaderError::EmptyFlag),
+            Token::Text(..) => Self::visit_text(token_reader)?,
+            Token::CloseFlag => return Err(ReaderError::EmptyFlag),
+            Token::Eof => return Err(token_reader.unexpected_eof()),
+            _ => return Err(token_reader.invalid_token()),
+        };
+
+        if matches!(token_reader.peek(), Token::CloseFlag) && !FlagExpr::is_compound(&text) {
+            Self::visit_close_flag(token_reader)?;
+
+            return if negated {
+                Ok(Flag::Negated(text))
+            } else {
+                Ok(Flag::Normal(text))
+            };
+        }
+
+        // A `!` inside a compound condition ends the text before it, so the condition can span
+        // several tokens, which may also be split by whitespace.
+        let mut condition = std::string::String::from(if negated { "!" } else { "" });
+        condition.push_str(&text);
+
+        loop {
+            match token_reader.peek() {
+                Token::Text(text, _) => condition.push_str(text),
+                Token::Negate => condition.push('!'),
+                Token::CloseFlag => break,
+                Token::Eof => return Err(token_reader.unexpected_eof()),
+                _ => return Err(token_reader.invalid_token()),
+            }
+            token_reader.advance()?;
+        }
         Self::visit_close_flag(token_reader)?;
 
-        if negated {
-            Ok(Flag::Negated(text))
-        } else {
-            Ok(Flag::Normal(text))
+        match FlagExpr::parse(&condition) {
+            Some(expr) if FlagExpr::is_compound(&condition) => Ok(Flag::Compound(expr)),
+            _ => Err(ReaderError::InvalidToken(condition, position)),
+        }
+    }
+
+    fn visit_key<'bump, R: Read>(
+        token_reader: &mut TokenReader<'bump, R>,
+        options: &ParseOptions,
+    ) -> Result<&'bump str> {
+        let mut key = Self::visit_text(token_reader)?;
+        Self::normalize_key(&mut key, options);
+
+        Ok(key.into_bump_str())
+    }
+
+    /// Applies `ParseOptions::trim_quoted_keys` and `ParseOptions::lowercase_keys` to a key.
+    pub(super) fn normalize_key(key: &mut String<'_>, options: &ParseOptions) {
+        if options.trim_quoted_keys {
+            let trimmed = key.trim();
+
+            if trimmed.len() != key.len() {
+                *key = String::from_str_in(trimmed, key.bump());
+            }
+        }
+
+        if options.lowercase_keys {
+            key.make_ascii_lowercase();
         }
     }
 
     fn visit_value<'bump, R: Read>(
         token_reader: &mut TokenReader<'bump, R>,
+        options: &ParseOptions,
+        paths: &mut PathTracker,
     ) -> Result<Value<'bump>> {
         match token_reader.peek() {
             Token::OpenBlock => {
                 Self::visit_open_block(token_reader)?;
-                let object = Self::visit_object(token_reader)?;
+                let object = Self::visit_object(token_reader, options, paths)?;
                 Self::visit_close_block(token_reader)?;
 
                 Ok(Value::Object(object))
             }
-            Token::Text(text) => {
+            Token::Text(text, _) => {
                 let moved = mem::replace(text, String::new_in(text.bump()));
-
                 token_reader.advance()?;
+
+                if options.typed_numbers {
+                    if let Some(number) = Self::typed_number(&moved) {
+                        return Ok(number);
+                    }
+                }
+
                 Ok(Value::String(moved))
             }
-            _ => Err(ReaderError::InvalidToken(format!(
-                "{:?}",
-                *token_reader.peek()
-            ))),
+            _ => Err(token_reader.invalid_token()),
+        }
+    }
+
+    /// Classifies a value for `ParseOptions::typed_numbers`.
+    pub(super) fn typed_number<'bump>(text: &str) -> Option<Value<'bump>> {
+        let digits = text.strip_prefix('-').unwrap_or(text);
+        let (whole, fraction) = match digits.split_once('.') {
+            Some((whole, fraction)) => (whole, Some(fraction)),
+            None => (digits, None),
+        };
+
+        let all_digits = |part: &str| !part.is_empty() && part.bytes().all(|b| b.is_ascii_digit());
+        if !all_digits(whole) || (whole.len() > 1 && whole.starts_with('0')) {
+            return None;
+        }
+
+        match fraction {
+            // `-0` would lose its sign.
+            None if digits == "0" && text.starts_with('-') => None,
+            None => text.parse().ok().map(Value::Int),
+            Some(fraction) if all_digits(fraction) => text.parse().ok().map(Value::Float),
+            Some(_) => None,
         }
     }
 
     fn visit_object<'bump, R: Read>(
         token_reader: &mut TokenReader<'bump, R>,
+        options: &ParseOptions,
+        paths: &mut PathTracker,
     ) -> Result<Object<'bump>> {
-        let mut new_obj = Object::default();
+        let mut new_obj = Object::new_in(token_reader.allocator());
+        new_obj.case_insensitive = options.case_insensitive_keys;
 
         while !matches!(token_reader.peek(), Token::Eof) {
             match token_reader.peek() {
                 Token::CloseBlock => break,
-                Token::Text(_) => {
-                    let key = Self::visit_text(token_reader)?;
-                    let value = Self::visit_value(token_reader)?;
+                Token::Text(..) => {
+                    let line = token_reader.token_line();
+                    let key = Self::visit_key(token_reader, options)?;
+                    if options.equals_separators
+                        && matches!(token_reader.peek(), Token::Text(text, false) if text == "=")
+                    {
+                        token_reader.advance()?;
+                    }
+
+                    if matches!(token_reader.peek(), Token::Eof) {
+                        return Err(ReaderError::MissingValue(key.to_string()));
+                    }
+
+                    paths.enter(key);
+                    if paths.record_lines {
+                        paths.record_line(line);
+                    }
+                    let value = Self::visit_value(token_reader, options, paths)?;
+                    paths.leave();
+
                     let flag = Self::visit_flag(token_reader)?;
+                    token_reader.count_entry()?;
 
-                    new_obj.kv.insert(key, (flag, value));
-                }
-                _ => {
-                    return Err(ReaderError::InvalidToken(format!(
-                        "{:?}",
-                        *token_reader.peek()
-                    )))
+                    if paths.collect_duplicates {
+                        let earlier = new_obj.kv.get_vec(key).map_or(&[][..], Vec::as_slice);
+                        paths.check(earlier, key, &flag);
+                    }
+
+                    match options.duplicate_keys {
+                        DuplicateKeyPolicy::KeepAll | DuplicateKeyPolicy::KeepLast => {
+                            new_obj.push_entry(key, flag, value)
+                        }
+                        DuplicateKeyPolicy::KeepFirst => {
+                            if !new_obj.kv.contains_key(key) {
+                                new_obj.push_entry(key, flag, value);
+                            }
+                        }
+                    }
                 }
+                _ => return Err(token_reader.invalid_token()),
             }
         }
 
+        if options.duplicate_keys == DuplicateKeyPolicy::KeepLast {
+            new_obj.keep_last_entries();
+        }
+
         Ok(new_obj)
     }
 
-    pub fn get<Q: ?Sized>(&self, k: &Q) -> Option<&Value>
+    /// Returns the top-level object of the document.
+    pub fn root(&self) -> &Object<'_> {
+        self.borrow_root()
+    }
+
+    pub fn get<Q>(&self, k: &Q) -> Option<&Value<'_>>
     where
-        for<'b> String<'b>: Borrow<Q>,
-        Q: Hash + Eq,
+        for<'b> &'b str: Borrow<Q>,
+        Q: Hash + Eq + AsRef<str> + ?Sized,
     {
         self.borrow_root().get(k)
     }
 
-    pub fn get_with_flags<Q: ?Sized, T: Sized>(&self, k: &Q, flags: &HashSet<T>) -> Option<&Value>
+    pub fn get_with_flags<Q, T>(&self, k: &Q, flags: &HashSet<T>) -> Option<&Value<'_>>
     where
-        for<'b> String<'b>: Borrow<Q>,
-        Q: Hash + Eq,
-        T: Borrow<str>,
-        T: Hash + Eq,
+        for<'b> &'b str: Borrow<Q>,
+        Q: Hash + Eq + AsRef<str> + ?Sized,
+        T: Borrow<str> + Hash + Eq,
     {
         self.borrow_root().get_with_flags(k, flags)
     }
-}
 
-impl<'a> Object<'a> {
-    pub fn get<Q: ?Sized>(&self, k: &Q) -> Option<&Value>
+    pub fn get_all<Q>(&self, k: &Q) -> impl Iterator<Item = &Value<'_>>
     where
-        String<'a>: Borrow<Q>,
-        Q: Hash + Eq,
+        for<'b> &'b str: Borrow<Q>,
+        Q: Hash + Eq + AsRef<str> + ?Sized,
     {
-        match self.kv.get(k) {
-            None => None,
-            Some(f_v) => Some(&f_v.1),
-        }
+        self.borrow_root().get_all(k)
     }
 
-    pub fn get_with_flags<Q: ?Sized, T: Sized>(&self, k: &Q, flags: &HashSet<T>) -> Option<&Value>
+    pub fn get_all_with_flags<'s, Q, T>(
+        &'s self,
+        k: &Q,
+        flags: &'s HashSet<T>,
+    ) -> impl Iterator<Item = &'s Value<'s>>
     where
-        String<'a>: Borrow<Q>,
-        Q: Hash + Eq,
-        T: Borrow<str>,
-        T: Hash + Eq,
+        for<'b> &'b str: Borrow<Q>,
+        Q: Hash + Eq + AsRef<str> + ?Sized,
+        T: Borrow<str> + Hash + Eq,
     {
-        match self.kv.get(k) {
-            None => return None,
-            Some(f_v) => match &f_v.0 {
-                Flag::None => Some(&f_v.1),
-                Flag::Normal(flag) => {
-                    if flags.contains(flag.as_str()) {
-                        Some(&f_v.1)
-                    } else {
-                        None
-                    }
-                }
-                Flag::Negated(flag) => {
-                    if !flags.contains(flag.as_str()) {
-                        Some(&f_v.1)
-                    } else {
-                        None
-                    }
-                }
-            },
-        }
+        self.borrow_root().get_all_with_flags(k, flags)
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use std::collections::HashSet;
+    pub fn get_excluding<Q, T>(&self, k: &Q, flags: &HashSet<T>) -> Option<&Value<'_>>
+    where
+        for<'b> &'b str: Borrow<Q>,
+        Q: Hash + Eq + AsRef<str> + ?Sized,
+        T: Borrow<str> + Hash + Eq,
+    {
+        self.borrow_root().get_excluding(k, flags)
+    }
 
-    use super::{KeyValues, Value};
+    pub fn find_all(&self, key: &str) -> Vec<(std::string::String, &Value<'_>)> {
+        self.borrow_root().find_all(key)
+    }
 
-    fn string_matches(val: &Value, expected: &str) -> bool {
-        match val {
-            Value::String(v) => v == expected,
-            _ => false,
-        }
+    pub fn collect_flags(&self) -> HashSet<FlagCondition> {
+        self.borrow_root().collect_flags()
     }
 
-    #[test]
-    fn single_kv() {
-        let object = KeyValues::from_io(r#"key "val""#.as_bytes()).unwrap();
+    pub fn walk(&self) -> impl Iterator<Item = (Vec<&str>, &Flag<'_>, &Value<'_>)> {
+        self.borrow_root().walk()
+    }
 
-        assert!(string_matches(object.get("key").unwrap(), "val"));
+    pub fn string_leaves(&self) -> impl Iterator<Item = (Vec<&str>, &str)> {
+        self.borrow_root().string_leaves()
     }
 
-    #[test]
-    fn double_kv() {
-        let kv = r#"
-        key1 val1
-        "key2" "val2"
-        "#
-        .as_bytes();
+    pub fn is_dirty(&self) -> bool {
+        self.borrow_root().is_dirty()
+    }
 
-        let object = KeyValues::from_io(kv).unwrap();
+    pub fn mark_clean(&self) {
+        self.borrow_root().mark_clean()
+    }
 
-        assert!(string_matches(object.get("key1").unwrap(), "val1"));
-        assert!(string_matches(object.get("key2").unwrap(), "val2"));
+    pub fn insert(&mut self, key: &str, value: &str) {
+        self.with_root_mut(|root| root.insert(key, value))
     }
 
-    #[test]
-    fn compound_kv() {
-        let kv = r#"
-        comp {
-            key1 val1
-            key2 val2
-        }
-        "#
-        .as_bytes();
+    pub fn collapse_singletons(&mut self) {
+        self.with_root_mut(|root| root.collapse_singletons())
+    }
 
-        let object = KeyValues::from_io(kv).unwrap();
+    pub fn rename_key(&mut self, old: &str, new: &str) -> bool {
+        self.with_root_mut(|root| root.rename_key(old, new))
+    }
 
-        match object.get("comp").unwrap() {
+    pub fn write_to<W: std::io::Write>(&self, w: &mut W) -> std::io::Result<()> {
+        self.borrow_root().write_to(w)
+    }
+
+    pub fn write_to_ordered<W: std::io::Write>(
+        &self,
+        w: &mut W,
+        order: &[&str],
+    ) -> std::io::Result<()> {
+        self.borrow_root().write_to_ordered(w, order)
+    }
+
+    pub fn set_flag(&mut self, key: &str, flag: &Flag<'_>) -> bool {
+        self.with_root_mut(|root| root.set_flag(key, flag))
+    }
+
+    pub fn retain<F>(&mut self, f: F)
+    where
+        F: FnMut(&str, &Flag<'_>, &Value<'_>) -> bool,
+    {
+        self.with_root_mut(|root| root.retain(f))
+    }
+
+    pub fn map_strings<F>(&mut self, f: F)
+    where
+        F: FnMut(&str) -> std::string::String,
+    {
+        self.with_root_mut(|root| root.map_strings(f))
+    }
+
+    pub fn retain_recursive<F>(&mut self, f: F)
+    where
+        F: FnMut(&str, &Flag<'_>, &Value<'_>) -> bool,
+    {
+        self.with_root_mut(|root| root.retain_recursive(f))
+    }
+}
+
+impl<'a> Object<'a> {
+    /// Creates an empty object whose future keys and values are allocated in `allocator`.
+    pub fn new_in(allocator: &'a Bump) -> Object<'a> {
+        Object {
+            allocator,
+            kv: MultiMap::new(),
+            order: Vec::new(),
+            case_insensitive: false,
+            dirty: Cell::new(false),
+        }
+    }
+
+    /// Appends an entry after every other entry, including those under the same key.
+    pub(super) fn push_entry(&mut self, key: &'a str, flag: Flag<'a>, value: Value<'a>) {
+        let index = self.kv.get_vec(key).map_or(0, Vec::len);
+        self.order.push((key, index));
+        self.kv.insert(key, (flag, value));
+    }
+
+    /// The entry added last, which is where a parser builds the entry it is reading.
+    pub(super) fn last_entry_mut(&mut self) -> Option<(&'a str, &mut Flag<'a>, &mut Value<'a>)> {
+        self.entry_mut_at(self.order.len().checked_sub(1)?)
+    }
+
+    /// The entry at `position` in the order `iter` gives.
+    pub(super) fn entry_mut_at(
+        &mut self,
+        position: usize,
+    ) -> Option<(&'a str, &mut Flag<'a>, &mut Value<'a>)> {
+        let &(key, index) = self.order.get(position)?;
+        let (flag, value) = self.kv.get_vec_mut(key)?.get_mut(index)?;
+
+        Some((key, flag, value))
+    }
+
+    /// Removes the entry added last.
+    pub(super) fn pop_entry(&mut self) {
+        let Some((key, _)) = self.order.pop() else {
+            return;
+        };
+
+        if let Some(entries) = self.kv.get_vec_mut(key) {
+            entries.pop();
+
+            if entries.is_empty() {
+                self.kv.remove(key);
+            }
+        }
+    }
+
+    /// Removes every entry under `key`, returning them in order.
+    pub(super) fn remove_entries(&mut self, key: &str) -> Option<Vec<(Flag<'a>, Value<'a>)>> {
+        let entries = self.kv.remove(key)?;
+        self.order.retain(|(entry_key, _)| *entry_key != key);
+
+        Some(entries)
+    }
+
+    /// Removes every entry, returning them in the order they were added.
+    pub(super) fn take_entries(&mut self) -> Vec<(&'a str, Flag<'a>, Value<'a>)> {
+        let mut kv = mem::take(&mut self.kv);
+
+        // Reversed, so each key's entries come off the end in the order they were added.
+        for (_, entries) in kv.iter_all_mut() {
+            entries.reverse();
+        }
+
+        mem::take(&mut self.order)
+            .into_iter()
+            .filter_map(|(key, _)| {
+                let (flag, value) = kv.get_vec_mut(key)?.pop()?;
+                Some((key, flag, value))
+            })
+            .collect()
+    }
+
+    /// Drops every entry but the last under each key, keeping the order of those left. Used
+    /// for `DuplicateKeyPolicy::KeepLast` once an object is parsed, rather than removing the
+    /// earlier entries as each repeat is found.
+    pub(super) fn keep_last_entries(&mut self) {
+        let duplicated = self.kv.iter_all().any(|(_, entries)| entries.len() > 1);
+        if !duplicated {
+            return;
+        }
+
+        // Backwards, so the first entry seen under each key is the one kept.
+        for (key, flag, value) in self.take_entries().into_iter().rev() {
+            if !self.kv.contains_key(key) {
+                self.push_entry(key, flag, value);
+            }
+        }
+
+        self.order.reverse();
+    }
+
+    /// Iterates over every entry in the order it was added, which for a parsed object is source
+    /// order, including repeated keys.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Flag<'a>, &Value<'a>)> {
+        self.order.iter().filter_map(move |&(key, index)| {
+            let (flag, value) = self.kv.get_vec(key)?.get(index)?;
+            Some((key, flag, value))
+        })
+    }
+
+    /// The entries stored under `k`, falling back to ignoring ASCII case if the object was
+    /// parsed with `ParseOptions::case_insensitive_keys`.
+    fn entries<Q>(&self, k: &Q) -> Option<&Vec<(Flag<'a>, Value<'a>)>>
+    where
+        &'a str: Borrow<Q>,
+        Q: Hash + Eq + AsRef<str> + ?Sized,
+    {
+        self.kv.get_vec(k).or_else(|| {
+            let k = k.as_ref();
+
+            self.case_insensitive
+                .then(|| self.iter().find(|(key, _, _)| key.eq_ignore_ascii_case(k)))
+                .flatten()
+                .and_then(|(key, _, _)| self.kv.get_vec::<str>(key))
+        })
+    }
+
+    pub fn get<Q>(&self, k: &Q) -> Option<&Value<'a>>
+    where
+        &'a str: Borrow<Q>,
+        Q: Hash + Eq + AsRef<str> + ?Sized,
+    {
+        self.entries(k)?.first().map(|(_, value)| value)
+    }
+
+    pub fn get_with_flags<Q, T>(&self, k: &Q, flags: &HashSet<T>) -> Option<&Value<'a>>
+    where
+        &'a str: Borrow<Q>,
+        Q: Hash + Eq + AsRef<str> + ?Sized,
+        T: Borrow<str> + Hash + Eq,
+    {
+        match self.entries(k)?.first() {
+            Some((flag, value)) if flag.is_satisfied(flags) => Some(value),
+            _ => None,
+        }
+    }
+
+    /// Returns every value stored under `k`, in source order. Only the first is returned by
+    /// `get`; the rest are repeated keys, such as the `solid` blocks of a VMF's `world`.
+    pub fn get_all<Q>(&self, k: &Q) -> impl Iterator<Item = &Value<'a>>
+    where
+        &'a str: Borrow<Q>,
+        Q: Hash + Eq + AsRef<str> + ?Sized,
+    {
+        self.entries(k)
+            .into_iter()
+            .flatten()
+            .map(|(_, value)| value)
+    }
+
+    /// Like `get_all`, but only the values whose flag is satisfied by `flags`.
+    pub fn get_all_with_flags<'s, Q, T>(
+        &'s self,
+        k: &Q,
+        flags: &'s HashSet<T>,
+    ) -> impl Iterator<Item = &'s Value<'a>>
+    where
+        &'a str: Borrow<Q>,
+        Q: Hash + Eq + AsRef<str> + ?Sized,
+        T: Borrow<str> + Hash + Eq,
+    {
+        self.entries(k)
+            .into_iter()
+            .flatten()
+            .filter(|(flag, _)| flag.is_satisfied(flags))
+            .map(|(_, value)| value)
+    }
+
+    /// The inverse of `get_with_flags`: returns the first entry under `k` whose flag is *not*
+    /// satisfied by `flags`. Entries without a flag are always satisfied, so are never returned.
+    pub fn get_excluding<Q, T>(&self, k: &Q, flags: &HashSet<T>) -> Option<&Value<'a>>
+    where
+        &'a str: Borrow<Q>,
+        Q: Hash + Eq + AsRef<str> + ?Sized,
+        T: Borrow<str> + Hash + Eq,
+    {
+        self.entries(k)?
+            .iter()
+            .find(|(flag, _)| !flag.is_satisfied(flags))
+            .map(|(_, value)| value)
+    }
+
+    /// Returns whether this object or any nested object has been changed through one of the
+    /// mutating methods since it was parsed or last marked clean.
+    pub fn is_dirty(&self) -> bool {
+        self.dirty.get()
+            || self.kv.iter_all().any(|(_, entries)| {
+                entries.iter().any(|(_, value)| match value {
+                    Value::Object(object) => object.is_dirty(),
+                    _ => false,
+                })
+            })
+    }
+
+    /// Clears the dirty state of this object and every nested object, e.g. once it has been
+    /// saved.
+    pub fn mark_clean(&self) {
+        self.dirty.set(false);
+
+        for (_, entries) in self.kv.iter_all() {
+            for (_, value) in entries {
+                if let Value::Object(object) = value {
+                    object.mark_clean();
+                }
+            }
+        }
+    }
+
+    /// Appends a string entry without a flag, after any entries already stored under `key`.
+    pub fn insert(&mut self, key: &str, value: &str) {
+        self.dirty.set(true);
+        self.push_entry(
+            self.allocator.alloc_str(key),
+            Flag::None,
+            Value::String(String::from_str_in(value, self.allocator)),
+        );
+    }
+
+    /// Returns the first object stored under `key`, appending an empty one if `key` holds no
+    /// object, so nested objects can be built up with chained calls:
+    /// `root.entry_object("Proxies").entry_object("Sine")`. The new object shares this
+    /// object's arena.
+    pub fn entry_object(&mut self, key: &str) -> &mut Object<'a> {
+        let has_object = self.kv.get_vec(key).is_some_and(|entries| {
+            entries
+                .iter()
+                .any(|(_, value)| matches!(value, Value::Object(_)))
+        });
+
+        if !has_object {
+            self.dirty.set(true);
+            self.push_entry(
+                self.allocator.alloc_str(key),
+                Flag::None,
+                Value::Object(Object::new_in(self.allocator)),
+            );
+        }
+
+        self.kv
+            .get_vec_mut(key)
+            .and_then(|entries| {
+                entries.iter_mut().find_map(|(_, value)| match value {
+                    Value::Object(object) => Some(object),
+                    _ => None,
+                })
+            })
+            .unwrap()
+    }
+
+    /// Replaces the flag of the first entry under `key`, copying it into this object's arena,
+    /// so `Flag::None` removes it. Returns whether there was such an entry.
+    pub fn set_flag(&mut self, key: &str, flag: &Flag<'_>) -> bool {
+        let allocator = self.allocator;
+
+        match self.kv.get_mut(key) {
+            None => false,
+            Some((stored, _)) => {
+                *stored = flag.copy_in(allocator);
+                self.dirty.set(true);
+                true
+            }
+        }
+    }
+
+    /// Renames every entry under `old` to `new`, keeping their flags and values.
+    /// Returns whether `old` existed.
+    pub fn rename_key(&mut self, old: &str, new: &str) -> bool {
+        if !self.kv.contains_key(old) {
+            return false;
+        }
+
+        // Taken in order and put back, so the renamed entries keep their places.
+        self.dirty.set(true);
+        let renamed = self.allocator.alloc_str(new);
+        for (key, flag, value) in self.take_entries() {
+            let key = if key == old { &*renamed } else { key };
+            self.push_entry(key, flag, value);
+        }
+
+        true
+    }
+
+    /// Keeps only the entries for which `f` returns true, like `HashMap::retain`. Nested objects
+    /// are left untouched; see `retain_recursive`.
+    pub fn retain<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&str, &Flag<'a>, &Value<'a>) -> bool,
+    {
+        let mut removed = false;
+        for (key, flag, value) in self.take_entries() {
+            if f(key, &flag, &value) {
+                self.push_entry(key, flag, value);
+            } else {
+                removed = true;
+            }
+        }
+
+        if removed {
+            self.dirty.set(true);
+        }
+    }
+
+    /// Like `retain`, but also filters the entries of every nested object which is kept.
+    pub fn retain_recursive<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&str, &Flag<'a>, &Value<'a>) -> bool,
+    {
+        self.retain_recursive_with(&mut f);
+    }
+
+    fn retain_recursive_with<F>(&mut self, f: &mut F)
+    where
+        F: FnMut(&str, &Flag<'a>, &Value<'a>) -> bool,
+    {
+        self.retain(&mut *f);
+
+        for (_, entries) in self.kv.iter_all_mut() {
+            for (_, value) in entries {
+                if let Value::Object(object) = value {
+                    object.retain_recursive_with(f);
+                }
+            }
+        }
+    }
+
+    /// Collapses chains of single-child objects, throughout this object and its nested objects.
+    ///
+    /// Where an entry's value is an object holding exactly one entry, and that entry is an object
+    /// without a flag, the two are merged into one entry whose key is both keys joined with `/`.
+    /// This repeats down the chain, so `a { b { c { d 1 } } }` becomes `"a/b/c" { d 1 }`. The
+    /// outer entry's flag is kept. Objects with a string entry, several entries or a flagged
+    /// entry are left as they are, so values are never renamed.
+    pub fn collapse_singletons(&mut self) {
+        let allocator = self.allocator;
+
+        for (mut key, flag, mut value) in self.take_entries() {
+            while let Value::Object(object) = &mut value {
+                let is_singleton = object.kv.len() == 1
+                    && matches!(
+                        object.kv.iter_all().next(),
+                        Some((_, entries)) if matches!(
+                            entries.as_slice(),
+                            [(Flag::None, Value::Object(_))]
+                        )
+                    );
+
+                if !is_singleton {
+                    object.collapse_singletons();
+                    break;
+                }
+
+                let (inner_key, _, inner_value) = object.take_entries().pop().unwrap();
+
+                key = allocator.alloc_str(&format!("{key}/{inner_key}"));
+                value = inner_value;
+                self.dirty.set(true);
+            }
+
+            self.push_entry(key, flag, value);
+        }
+    }
+
+    /// Replaces every string value in this object and its nested objects with the result of `f`.
+    /// Keys, flags and the structure of the tree are left as they are.
+    pub fn map_strings<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&str) -> std::string::String,
+    {
+        self.map_strings_with(&mut f);
+    }
+
+    fn map_strings_with<F>(&mut self, f: &mut F)
+    where
+        F: FnMut(&str) -> std::string::String,
+    {
+        let allocator = self.allocator;
+
+        for (_, entries) in self.kv.iter_all_mut() {
+            for (_, value) in entries {
+                match value {
+                    Value::String(string) => {
+                        let mapped = f(string);
+
+                        if mapped != string.as_str() {
+                            *string = String::from_str_in(&mapped, allocator);
+                            self.dirty.set(true);
+                        }
+                    }
+                    Value::Object(object) => object.map_strings_with(f),
+                    Value::Int(_) | Value::Float(_) => {}
+                }
+            }
+        }
+    }
+
+    /// Recursively searches for every entry named `key`, returning each match alongside its
+    /// `/`-joined path from this object. Keys are compared case-insensitively, as in Source.
+    pub fn find_all(&self, key: &str) -> Vec<(std::string::String, &Value<'a>)> {
+        let mut found = Vec::new();
+        self.find_all_into(key, "", &mut found);
+
+        found
+    }
+
+    fn find_all_into<'s>(
+        &'s self,
+        key: &str,
+        prefix: &str,
+        found: &mut Vec<(std::string::String, &'s Value<'a>)>,
+    ) {
+        for (entry_key, _, value) in self.iter() {
+            let path = if prefix.is_empty() {
+                entry_key.to_string()
+            } else {
+                format!("{prefix}/{entry_key}")
+            };
+
+            if entry_key.eq_ignore_ascii_case(key) {
+                found.push((path.clone(), value));
+            }
+
+            if let Value::Object(object) = value {
+                object.find_all_into(key, &path, found);
+            }
+        }
+    }
+
+    /// Visits every entry depth-first, yielding each with the keys leading to it from this
+    /// object, its own key last. An object's entries come directly after the object itself, in
+    /// source order. Uses an explicit stack, so deep trees are fine.
+    pub fn walk(&self) -> impl Iterator<Item = (Vec<&str>, &Flag<'a>, &Value<'a>)> {
+        let mut stack = Vec::new();
+        Self::push_entries(&mut stack, self, &[]);
+
+        std::iter::from_fn(move || {
+            let (path, flag, value) = stack.pop()?;
+
+            if let Value::Object(object) = value {
+                Self::push_entries(&mut stack, object, &path);
+            }
+
+            Some((path, flag, value))
+        })
+    }
+
+    /// Visits every string value, in the order `walk` does, with the keys leading to it. Numbers
+    /// stored with `ParseOptions::typed_numbers` are not strings, and are skipped.
+    pub fn string_leaves(&self) -> impl Iterator<Item = (Vec<&str>, &str)> {
+        self.walk().filter_map(|(path, _, value)| match value {
+            Value::String(string) => Some((path, string.as_str())),
+            _ => None,
+        })
+    }
+
+    fn push_entries<'s>(
+        stack: &mut Vec<(Vec<&'s str>, &'s Flag<'a>, &'s Value<'a>)>,
+        object: &'s Object<'a>,
+        prefix: &[&'s str],
+    ) {
+        let start = stack.len();
+
+        for (key, flag, value) in object.iter() {
+            let mut path = prefix.to_vec();
+            path.push(key);
+
+            stack.push((path, flag, value));
+        }
+
+        // Popped from the end, so reverse to visit entries in source order.
+        stack[start..].reverse();
+    }
+
+    /// Returns the values of an array-shaped object, whose keys are exactly `"0"`, `"1"`, ... with
+    /// each appearing once, in numeric order. Returns `None` for any other object.
+    pub fn as_array(&self) -> Option<Vec<&Value<'a>>> {
+        (0..self.kv.len())
+            .map(
+                |index| match self.kv.get_vec(index.to_string().as_str())?.as_slice() {
+                    [(_, value)] => Some(value),
+                    _ => None,
+                },
+            )
+            .collect()
+    }
+
+    /// Estimates the size in bytes of this object written out as a document, without writing it.
+    ///
+    /// The estimate assumes Valve's layout, as `write_to` writes it: quoted keys and values
+    /// separated by a tab, one tab of indentation per level, braces on their own lines and flags
+    /// after a space at the end of the entry. Text is counted as `write_to` writes it, so this is
+    /// the exact size of its output.
+    pub fn byte_size_estimate(&self) -> usize {
+        self.size_estimate_at(0)
+    }
+
+    /// Estimates the size of each top-level entry, as counted by `byte_size_estimate`, in source
+    /// order. The sizes add up to the estimate for the whole object.
+    pub fn entry_size_estimates(&self) -> Vec<(&str, usize)> {
+        self.iter()
+            .map(|(key, flag, value)| (key, entry_size_estimate(key, flag, value, 0)))
+            .collect()
+    }
+
+    fn size_estimate_at(&self, depth: usize) -> usize {
+        self.iter()
+            .map(|(key, flag, value)| entry_size_estimate(key, flag, value, depth))
+            .sum()
+    }
+
+    /// Writes the object as KV text which reads back through `KeyValues::from_io` as the same
+    /// tree, in Valve's layout: one entry per line with the key and value quoted and separated
+    /// by a tab, nested objects in braces on their own lines, indented a tab per level, and
+    /// flags after a space at the end of the entry. An empty object is still written as a pair
+    /// of braces.
+    ///
+    /// The reader takes quoted text exactly as it is, so nothing is escaped inside quotes. Text
+    /// containing a quote cannot be quoted, so is written unquoted instead, with a `\` before
+    /// each quote, backslash, whitespace or other character which would otherwise end it.
+    /// Numbers are written as quoted text, which reads back as the same number with
+    /// `ParseOptions::typed_numbers`, and floats keep a decimal point so they stay floats.
+    ///
+    /// Entries are written in the order `iter` gives them, which for a parsed object is source
+    /// order, so a document reads back and writes out with its entries where they were. Writing
+    /// marks the object clean; see `is_dirty`.
+    pub fn write_to<W: std::io::Write>(&self, w: &mut W) -> std::io::Result<()> {
+        self.write_to_ordered(w, &[])
+    }
+
+    /// Writes the object as `write_to` does, except that in every object, keys listed in
+    /// `order` come first, in that order, such as `$basetexture` first in a material. Keys are
+    /// matched ignoring ASCII case, and the rest follow in `iter` order.
+    pub fn write_to_ordered<W: std::io::Write>(
+        &self,
+        w: &mut W,
+        order: &[&str],
+    ) -> std::io::Result<()> {
+        let mut text = std::string::String::new();
+        self.dump_at(&mut text, 0, order)
+            .expect("writing to a String cannot fail");
+
+        w.write_all(text.as_bytes())?;
+        self.mark_clean();
+
+        Ok(())
+    }
+
+    /// Writes the tree in the layout `write_to` describes.
+    fn dump_at<W: fmt::Write>(&self, f: &mut W, depth: usize, order: &[&str]) -> fmt::Result {
+        let indent = "\t".repeat(depth);
+
+        let mut entries: Vec<_> = self.iter().collect();
+        if !order.is_empty() {
+            // Stable, so entries not in `order` keep their places relative to each other.
+            entries.sort_by_key(|(key, _, _)| {
+                order
+                    .iter()
+                    .position(|listed| listed.eq_ignore_ascii_case(key))
+                    .unwrap_or(order.len())
+            });
+        }
+
+        for (key, flag, value) in entries {
+            write!(f, "{indent}{}", text_token(key))?;
+
+            match value {
+                Value::String(string) => write!(f, "\t{}", text_token(string))?,
+                Value::Int(int) => write!(f, "\t\"{int}\"")?,
+                Value::Float(float) => write!(f, "\t\"{float:?}\"")?,
+                Value::Object(object) => {
+                    writeln!(f, "\n{indent}{{")?;
+                    object.dump_at(f, depth + 1, order)?;
+                    write!(f, "{indent}}}")?;
+                }
+            }
+
+            match flag.to_suffix() {
+                Some(suffix) => writeln!(f, " {suffix}")?,
+                None => writeln!(f)?,
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Recursively collects every flag condition referenced in this object. Each define in a
+    /// compound condition is reported on its own, as negated only when directly under a `!`.
+    pub fn collect_flags(&self) -> HashSet<FlagCondition> {
+        let mut flags = HashSet::new();
+        self.collect_flags_into(&mut flags);
+
+        flags
+    }
+
+    fn collect_flags_into(&self, flags: &mut HashSet<FlagCondition>) {
+        for (_, flag, value) in self.iter() {
+            match flag {
+                Flag::None => {}
+                Flag::Normal(flag) => {
+                    flags.insert(FlagCondition::Normal(flag.to_string()));
+                }
+                Flag::Negated(flag) => {
+                    flags.insert(FlagCondition::Negated(flag.to_string()));
+                }
+                Flag::Compound(expr) => expr.for_each_define(&mut |define, negated| {
+                    flags.insert(if negated {
+                        FlagCondition::Negated(define.to_string())
+                    } else {
+                        FlagCondition::Normal(define.to_string())
+                    });
+                }),
+            }
+
+            if let Value::Object(object) = value {
+                object.collect_flags_into(flags);
+            }
+        }
+    }
+}
+
+fn entry_size_estimate(key: &str, flag: &Flag, value: &Value, depth: usize) -> usize {
+    let flag_size = flag.to_suffix().map_or(0, |suffix| 1 + suffix.len());
+    // indent "key"\t"value" [flag]\n
+    let leaf_size = |text: &str| depth + token_size(key) + 1 + token_size(text) + flag_size + 1;
+
+    match value {
+        Value::String(string) => leaf_size(string),
+        Value::Int(int) => leaf_size(&int.to_string()),
+        Value::Float(float) => leaf_size(&format!("{float:?}")),
+        // indent "key"\n indent {\n ... indent } [flag]\n
+        Value::Object(object) => {
+            depth
+                + token_size(key)
+                + 1
+                + depth
+                + 2
+                + object.size_estimate_at(depth + 1)
+                + depth
+                + 1
+                + flag_size
+                + 1
+        }
+    }
+}
+
+impl<'a> Flag<'a> {
+    /// Copies the flag into `allocator`.
+    fn copy_in<'b>(&self, allocator: &'b Bump) -> Flag<'b> {
+        match self {
+            Flag::None => Flag::None,
+            Flag::Normal(flag) => Flag::Normal(String::from_str_in(flag, allocator)),
+            Flag::Negated(flag) => Flag::Negated(String::from_str_in(flag, allocator)),
+            Flag::Compound(expr) => Flag::Compound(expr.clone()),
+        }
+    }
+
+    /// Renders the flag as it is written after an entry, e.g. `[$WIN32]` or `[!$POSIX]`.
+    /// Returns `None` for `Flag::None`.
+    pub fn to_suffix(&self) -> Option<std::string::String> {
+        match self {
+            Flag::None => None,
+            Flag::Normal(flag) => Some(format!("[{flag}]")),
+            Flag::Negated(flag) => Some(format!("[!{flag}]")),
+            Flag::Compound(expr) => Some(format!("[{expr}]")),
+        }
+    }
+
+    /// Whether an entry with this flag applies when exactly `flags` are set.
+    fn is_satisfied<T>(&self, flags: &HashSet<T>) -> bool
+    where
+        T: Borrow<str> + Hash + Eq,
+    {
+        match self {
+            Flag::None => true,
+            Flag::Normal(flag) => flags.contains(flag.as_str()),
+            Flag::Negated(flag) => !flags.contains(flag.as_str()),
+            Flag::Compound(expr) => expr.evaluate(flags),
+        }
+    }
+}
+
+impl<'a> Value<'a> {
+    pub fn kind(&self) -> ValueKind {
+        match self {
+            Value::String(_) => ValueKind::String,
+            Value::Object(_) => ValueKind::Object,
+            Value::Int(_) => ValueKind::Int,
+            Value::Float(_) => ValueKind::Float,
+        }
+    }
+
+    pub fn is_string(&self) -> bool {
+        self.kind() == ValueKind::String
+    }
+
+    /// Copies the value, with any objects nested in it, into `allocator`.
+    fn copy_in<'b>(&self, allocator: &'b Bump) -> Value<'b> {
+        match self {
+            Value::String(string) => Value::String(String::from_str_in(string, allocator)),
+            Value::Object(object) => {
+                let mut copy = Object::new_in(allocator);
+                copy.case_insensitive = object.case_insensitive;
+
+                for (key, flag, value) in object.iter() {
+                    copy.push_entry(
+                        allocator.alloc_str(key),
+                        flag.copy_in(allocator),
+                        value.copy_in(allocator),
+                    );
+                }
+
+                Value::Object(copy)
+            }
+            Value::Int(int) => Value::Int(*int),
+            Value::Float(float) => Value::Float(*float),
+        }
+    }
+
+    /// Returns whether this is an object, for checking before descending into it.
+    ///
+    /// # Examples
+    /// ```
+    /// use srcrs::kv::{KeyValues, Value};
+    ///
+    /// let kv = KeyValues::from_io(r#"$basetexture "concrete" Proxies { Sine { } }"#.as_bytes())
+    ///     .unwrap();
+    ///
+    /// let mut nested = Vec::new();
+    /// for key in ["$basetexture", "Proxies"] {
+    ///     match kv.get(key) {
+    ///         Some(value) if value.is_object() => nested.push(key),
+    ///         _ => {}
+    ///     }
+    /// }
+    ///
+    /// assert_eq!(nested, ["Proxies"]);
+    /// assert!(kv.get("$basetexture").is_some_and(Value::is_string));
+    /// ```
+    pub fn is_object(&self) -> bool {
+        self.kind() == ValueKind::Object
+    }
+
+    /// Returns the string exactly as it was stored, without any trimming or conversion.
+    /// Returns `None` for objects, and for numbers, which no longer have their text.
+    pub fn raw_str(&self) -> Option<&str> {
+        match self {
+            Value::String(string) => Some(string.as_str()),
+            _ => None,
+        }
+    }
+
+    /// Splits a string value on whitespace, e.g. `"-8 -8 -8"` into `["-8", "-8", "-8"]`.
+    /// Returns `None` for anything but a string.
+    pub fn as_tokens(&self) -> Option<Vec<&str>> {
+        match self {
+            Value::String(string) => Some(string.split_whitespace().collect()),
+            _ => None,
+        }
+    }
+
+    /// Returns a number parsed with `ParseOptions::typed_numbers`, or parses a string as a whole
+    /// number. Returns `None` for objects, floats and strings which are not whole numbers.
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Value::Int(int) => Some(*int),
+            Value::String(string) => string.trim().parse().ok(),
+            _ => None,
+        }
+    }
+
+    /// Returns a number parsed with `ParseOptions::typed_numbers`, or parses a string as a
+    /// number. Returns `None` for objects and strings which are not numbers.
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Value::Float(float) => Some(*float),
+            Value::Int(int) => Some(*int as f64),
+            Value::String(string) => string.trim().parse().ok(),
+            Value::Object(_) => None,
+        }
+    }
+
+    /// Like `as_i64`, for numbers which fit an `i32`.
+    pub fn as_i32(&self) -> Option<i32> {
+        self.as_i64()?.try_into().ok()
+    }
+
+    /// Like `as_f64`, narrowed to an `f32`.
+    pub fn as_f32(&self) -> Option<f32> {
+        self.as_f64().map(|float| float as f32)
+    }
+
+    /// Parses a string of three whitespace separated floats, such as an `origin` of
+    /// `"0 -64 128"`. Surrounding whitespace and square brackets, as in `"[0 -64 128]"`, are
+    /// allowed. Returns `None` for anything else, including the wrong number of floats.
+    pub fn as_vec3(&self) -> Option<[f32; 3]> {
+        self.as_f32_array()
+    }
+
+    /// Like `as_vec3`, for four floats, such as a colour with alpha of `"255 128 0 255"`.
+    pub fn as_vec4(&self) -> Option<[f32; 4]> {
+        self.as_f32_array()
+    }
+
+    fn as_f32_array<const N: usize>(&self) -> Option<[f32; N]> {
+        let string = self.raw_str()?.trim();
+        let string = string
+            .strip_prefix('[')
+            .and_then(|string| string.strip_suffix(']'))
+            .unwrap_or(string);
+
+        let mut tokens = string.split_whitespace();
+        let mut array = [0.0; N];
+        for element in &mut array {
+            *element = tokens.next()?.parse().ok()?;
+        }
+
+        tokens.next().is_none().then_some(array)
+    }
+
+    /// Parses a whitespace separated list of floats of arbitrary length. A number parsed with
+    /// `ParseOptions::typed_numbers` is a list of one. Returns `None` for objects or if any token
+    /// is not a valid float.
+    pub fn as_f32_vec(&self) -> Option<Vec<f32>> {
+        match self {
+            Value::Int(int) => return Some(vec![*int as f32]),
+            Value::Float(float) => return Some(vec![*float as f32]),
+            _ => {}
+        }
+
+        self.as_tokens()?
+            .into_iter()
+            .map(|token| token.parse().ok())
+            .collect()
+    }
+
+    /// Parses an unsigned integer, accepting a leading `+` and `0x`-prefixed hexadecimal as
+    /// used by spawnflags and colours. Returns `None` for objects, floats or malformed numbers.
+    pub fn as_u32_auto(&self) -> Option<u32> {
+        let string = match self {
+            Value::String(string) => string.trim(),
+            Value::Int(int) => return u32::try_from(*int).ok(),
+            _ => return None,
+        };
+
+        let string = string.strip_prefix('+').unwrap_or(string);
+
+        match string
+            .strip_prefix("0x")
+            .or_else(|| string.strip_prefix("0X"))
+        {
+            Some(hex) => u32::from_str_radix(hex, 16).ok(),
+            None => string.parse().ok(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use bumpalo::collections::String;
+    use bumpalo::Bump;
+
+    use super::{
+        DuplicateKey, DuplicateKeyPolicy, Flag, FlagCondition, Indent, KeyValues, LimitKind,
+        NulBytes, ParseLimits, ParseOptions, Position, ReaderError, Value,
+    };
+
+    fn string_matches(val: &Value, expected: &str) -> bool {
+        match val {
+            Value::String(v) => v == expected,
+            _ => false,
+        }
+    }
+
+    #[test]
+    fn single_kv() {
+        let object = KeyValues::from_io(r#"key "val""#.as_bytes()).unwrap();
+
+        assert!(string_matches(object.get("key").unwrap(), "val"));
+    }
+
+    #[test]
+    fn double_kv() {
+        let kv = r#"
+        key1 val1
+        "key2" "val2"
+        "#
+        .as_bytes();
+
+        let object = KeyValues::from_io(kv).unwrap();
+
+        assert!(string_matches(object.get("key1").unwrap(), "val1"));
+        assert!(string_matches(object.get("key2").unwrap(), "val2"));
+    }
+
+    #[test]
+    fn compound_kv() {
+        let kv = r#"
+        comp {
+            key1 val1
+            key2 val2
+        }
+        "#
+        .as_bytes();
+
+        let object = KeyValues::from_io(kv).unwrap();
+
+        match object.get("comp").unwrap() {
             Value::Object(comp) => {
                 assert!(string_matches(comp.get("key1").unwrap(), "val1"));
                 assert!(string_matches(comp.get("key2").unwrap(), "val2"));
             }
-            _ => panic!(),
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn compound_kv_with_flags() {
+        let kv = r#"
+        comp {
+            key1 val1 [test]
+            key2 val2 [!test]
+        }
+        "#
+        .as_bytes();
+
+        let object = KeyValues::from_io(kv).unwrap();
+
+        match object.get("comp").unwrap() {
+            Value::Object(comp) => {
+                assert!(string_matches(comp.get("key1").unwrap(), "val1"));
+                assert!(string_matches(comp.get("key2").unwrap(), "val2"));
+            }
+            _ => panic!(),
+        }
+
+        let flags: HashSet<&str> = vec!["test"].into_iter().collect();
+
+        match object.get("comp").unwrap() {
+            Value::Object(comp) => {
+                assert!(string_matches(
+                    comp.get_with_flags("key1", &flags).unwrap(),
+                    "val1"
+                ));
+                assert!(comp.get_with_flags("key2", &flags).is_none());
+            }
+            _ => panic!(),
+        }
+    }
+
+    #[test]
+    fn float_list() {
+        let object = KeyValues::from_io(r#"mins "-8 -8.5 -8 16 16.25 32""#.as_bytes()).unwrap();
+        let mins = object.get("mins").unwrap();
+
+        assert_eq!(
+            mins.as_tokens().unwrap(),
+            vec!["-8", "-8.5", "-8", "16", "16.25", "32"]
+        );
+        assert_eq!(
+            mins.as_f32_vec().unwrap(),
+            vec![-8.0, -8.5, -8.0, 16.0, 16.25, 32.0]
+        );
+    }
+
+    #[test]
+    fn typed_accessors() {
+        let kv = KeyValues::from_io(
+            r#"
+            count " 12 "
+            big 3000000000
+            scale 0.25
+            origin "0 -64 128"
+            bracketed " [1 0 0.5] "
+            color "[255 128 0 255]"
+            short "1 2"
+            long "1 2 3 4"
+            unbalanced "[1 2 3"
+            block { }
+            "#
+            .as_bytes(),
+        )
+        .unwrap();
+        let get = |key| kv.get(key).unwrap();
+
+        assert_eq!(get("count").as_i32(), Some(12));
+        assert_eq!(get("big").as_i32(), None);
+        assert_eq!(get("scale").as_i32(), None);
+        assert_eq!(get("scale").as_f32(), Some(0.25));
+        assert_eq!(get("count").as_f32(), Some(12.0));
+
+        assert_eq!(get("origin").as_vec3(), Some([0.0, -64.0, 128.0]));
+        assert_eq!(get("bracketed").as_vec3(), Some([1.0, 0.0, 0.5]));
+        assert_eq!(get("color").as_vec4(), Some([255.0, 128.0, 0.0, 255.0]));
+        assert_eq!(get("origin").as_vec4(), None);
+        assert_eq!(get("short").as_vec3(), None);
+        assert_eq!(get("long").as_vec3(), None);
+        assert_eq!(get("unbalanced").as_vec3(), None);
+        assert_eq!(get("block").as_vec3(), None);
+        assert_eq!(get("block").as_f32(), None);
+    }
+
+    #[test]
+    fn invalid_float_list() {
+        let kv = r#"
+        mixed "1 two 3"
+        comp {
+            key val
+        }
+        "#
+        .as_bytes();
+
+        let object = KeyValues::from_io(kv).unwrap();
+        let mixed = object.get("mixed").unwrap();
+
+        assert_eq!(mixed.as_tokens().unwrap(), vec!["1", "two", "3"]);
+        assert!(mixed.as_f32_vec().is_none());
+
+        let comp = object.get("comp").unwrap();
+        assert!(comp.as_tokens().is_none());
+        assert!(comp.as_f32_vec().is_none());
+    }
+
+    #[test]
+    fn find_all_nested() {
+        let kv = r#"
+        $basetexture a
+        comp {
+            key val
+            inner {
+                $BaseTexture b
+            }
+        }
+        "#
+        .as_bytes();
+
+        let object = KeyValues::from_io(kv).unwrap();
+
+        let mut found = object.find_all("$basetexture");
+        found.sort_by(|a, b| a.0.cmp(&b.0));
+
+        assert_eq!(found.len(), 2);
+        assert_eq!(found[0].0, "$basetexture");
+        assert!(string_matches(found[0].1, "a"));
+        assert_eq!(found[1].0, "comp/inner/$BaseTexture");
+        assert!(string_matches(found[1].1, "b"));
+    }
+
+    #[test]
+    fn trim_quoted_keys() {
+        let kv = r#""  key  " "  val  ""#;
+
+        let untrimmed = KeyValues::from_io(kv.as_bytes()).unwrap();
+        assert!(untrimmed.get("key").is_none());
+        assert!(string_matches(untrimmed.get("  key  ").unwrap(), "  val  "));
+
+        let options = ParseOptions {
+            trim_quoted_keys: true,
+            ..Default::default()
+        };
+        let trimmed = KeyValues::from_io_with_options(kv.as_bytes(), &options).unwrap();
+        assert!(trimmed.get("  key  ").is_none());
+        assert!(string_matches(trimmed.get("key").unwrap(), "  val  "));
+    }
+
+    #[test]
+    fn u32_auto() {
+        let kv = r#"
+        hex 0xFF
+        upper_hex 0XfF
+        signed +5
+        plain 255
+        negative -5
+        invalid 0xZZ
+        "#
+        .as_bytes();
+
+        let object = KeyValues::from_io(kv).unwrap();
+
+        assert_eq!(object.get("hex").unwrap().as_u32_auto(), Some(255));
+        assert_eq!(object.get("upper_hex").unwrap().as_u32_auto(), Some(255));
+        assert_eq!(object.get("signed").unwrap().as_u32_auto(), Some(5));
+        assert_eq!(object.get("plain").unwrap().as_u32_auto(), Some(255));
+        assert_eq!(object.get("negative").unwrap().as_u32_auto(), None);
+        assert_eq!(object.get("invalid").unwrap().as_u32_auto(), None);
+    }
+
+    #[test]
+    fn rename_key() {
+        let kv = r#"
+        $basetexture tex [$WIN32]
+        other val
+        "#
+        .as_bytes();
+
+        let mut object = KeyValues::from_io(kv).unwrap();
+
+        assert!(object.rename_key("$basetexture", "$basetexture2"));
+        assert!(!object.rename_key("missing", "renamed"));
+
+        assert!(object.get("$basetexture").is_none());
+        assert!(string_matches(object.get("$basetexture2").unwrap(), "tex"));
+        assert!(string_matches(object.get("other").unwrap(), "val"));
+
+        let flags: HashSet<&str> = HashSet::new();
+        assert!(object.get_with_flags("$basetexture2", &flags).is_none());
+    }
+
+    #[test]
+    fn set_flag() {
+        let mut kv = KeyValues::empty();
+        kv.insert("$basetexture", "brick");
+        kv.mark_clean();
+
+        let bump = Bump::new();
+        let win32 = Flag::Normal(String::from_str_in("$WIN32", &bump));
+        assert!(kv.set_flag("$basetexture", &win32));
+        assert!(!kv.set_flag("missing", &win32));
+        assert!(kv.is_dirty());
+
+        let windows: HashSet<&str> = ["$WIN32"].into();
+        let linux: HashSet<&str> = ["$POSIX"].into();
+        assert!(string_matches(
+            kv.get_with_flags("$basetexture", &windows).unwrap(),
+            "brick"
+        ));
+        assert!(kv.get_with_flags("$basetexture", &linux).is_none());
+        assert_eq!(
+            kv.walk().next().unwrap().1.to_suffix().as_deref(),
+            Some("[$WIN32]")
+        );
+
+        assert!(kv.set_flag("$basetexture", &Flag::None));
+        assert!(kv.get_with_flags("$basetexture", &linux).is_some());
+    }
+
+    #[test]
+    fn raw_str() {
+        let kv = r#"
+        key "  internal   spaces	and tabs "
+        comp { }
+        "#
+        .as_bytes();
+
+        let object = KeyValues::from_io(kv).unwrap();
+
+        assert_eq!(
+            object.get("key").unwrap().raw_str(),
+            Some("  internal   spaces\tand tabs ")
+        );
+        assert_eq!(object.get("comp").unwrap().raw_str(), None);
+    }
+
+    #[test]
+    fn collect_pairs() {
+        let pairs = vec![
+            ("key1".to_string(), "val1".to_string()),
+            ("key2".to_string(), "val2".to_string()),
+            ("key1".to_string(), "val3".to_string()),
+        ];
+
+        let object: KeyValues = pairs.into_iter().collect();
+
+        assert!(string_matches(object.get("key1").unwrap(), "val1"));
+        assert!(string_matches(object.get("key2").unwrap(), "val2"));
+        assert_eq!(
+            object.to_string(),
+            "\"key1\"\t\"val1\"\n\"key2\"\t\"val2\"\n\"key1\"\t\"val3\"\n"
+        );
+
+        let empty: KeyValues = Vec::<(&str, &str)>::new().into_iter().collect();
+        assert!(empty.get("key1").is_none());
+        assert_eq!(empty.root(), KeyValues::empty().root());
+    }
+
+    #[test]
+    fn collect_values() {
+        let source = KeyValues::from_io_with_options(
+            r#"$basetexture "brick" Proxies { Sine { sinemin 0 } } $alpha 0.5"#.as_bytes(),
+            &ParseOptions {
+                typed_numbers: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+
+        let object: KeyValues = source
+            .root()
+            .iter()
+            .map(|(key, _, value)| (key.to_uppercase(), value))
+            .collect();
+
+        assert_eq!(
+            object.to_string(),
+            "\"$BASETEXTURE\"\t\"brick\"\n\"PROXIES\"\n{\n\t\"Sine\"\n\t{\n\t\t\"sinemin\"\t\"0\"\n\t}\n}\n\"$ALPHA\"\t\"0.5\"\n"
+        );
+    }
+
+    #[test]
+    fn multiline_quoted_value() {
+        let kv = "\"key\" \"line1\nline2\"\r\n\"next\" \"val\"".as_bytes();
+
+        let object = KeyValues::from_io(kv).unwrap();
+
+        assert!(string_matches(object.get("key").unwrap(), "line1\nline2"));
+        assert!(string_matches(object.get("next").unwrap(), "val"));
+    }
+
+    #[test]
+    fn collect_flags() {
+        let kv = r#"
+        key1 val1 [$WIN32]
+        comp {
+            key2 val2 [!$POSIX]
+            key3 val3 [$WIN32]
+        } [$X360]
+        key4 val4
+        "#
+        .as_bytes();
+
+        let object = KeyValues::from_io(kv).unwrap();
+
+        assert_eq!(
+            object.collect_flags(),
+            HashSet::from([
+                FlagCondition::Normal("$WIN32".to_string()),
+                FlagCondition::Negated("$POSIX".to_string()),
+                FlagCondition::Normal("$X360".to_string()),
+            ])
+        );
+    }
+
+    #[test]
+    fn flag_suffix() {
+        let allocator = Bump::new();
+
+        assert_eq!(Flag::None.to_suffix(), None);
+        assert_eq!(
+            Flag::Normal(String::from_str_in("$WIN32", &allocator)).to_suffix(),
+            Some("[$WIN32]".to_string())
+        );
+        assert_eq!(
+            Flag::Negated(String::from_str_in("$POSIX", &allocator)).to_suffix(),
+            Some("[!$POSIX]".to_string())
+        );
+    }
+
+    #[test]
+    fn anonymous_root() {
+        let kv = r#"
+        {
+            key1 val1
+            comp { key2 val2 }
+        }
+        "#
+        .as_bytes();
+
+        assert!(KeyValues::from_io(kv).is_err());
+
+        let options = ParseOptions {
+            allow_anonymous_root: true,
+            ..Default::default()
+        };
+        let object = KeyValues::from_io_with_options(kv, &options).unwrap();
+
+        assert!(string_matches(object.get("key1").unwrap(), "val1"));
+        assert!(matches!(object.get("comp"), Some(Value::Object(_))));
+
+        let trailing = "{ key1 val1 } key2 val2".as_bytes();
+        assert!(KeyValues::from_io_with_options(trailing, &options).is_err());
+    }
+
+    #[test]
+    fn comment_after_flag() {
+        let kv = "key1 val1 [$WIN32] // trailing\nkey2 val2 [!$X360]    \n\t\nkey3 val3[$OSX]//tight\nkey4 val4"
+            .as_bytes();
+
+        let object = KeyValues::from_io(kv).unwrap();
+
+        for (key, value) in [
+            ("key1", "val1"),
+            ("key2", "val2"),
+            ("key3", "val3"),
+            ("key4", "val4"),
+        ] {
+            assert!(string_matches(object.get(key).unwrap(), value));
+        }
+    }
+
+    #[test]
+    fn retain_by_prefix() {
+        let kv = r#"
+        LightmappedGeneric {
+            $basetexture brick
+            $detail detail/noise
+            $detailscale 4
+            proxies { $detailframe 1 animated 1 }
+        }
+        $detail top
+        "#
+        .as_bytes();
+
+        let mut object = KeyValues::from_io(kv).unwrap();
+        object.retain(|key, _, _| !key.starts_with("$detail"));
+        assert!(object.get("$detail").is_none());
+
+        let mut object = KeyValues::from_io(kv).unwrap();
+        object.retain_recursive(|key, _, _| !key.starts_with("$detail"));
+
+        assert_eq!(
+            object.root(),
+            KeyValues::from_io(
+                "LightmappedGeneric { $basetexture brick proxies { animated 1 } }".as_bytes()
+            )
+            .unwrap()
+            .root()
+        );
+    }
+
+    #[test]
+    fn detected_indent() {
+        let options = ParseOptions {
+            detect_indent: true,
+            ..Default::default()
+        };
+        let parse = |text: &str, options| KeyValues::from_io_with_options(text.as_bytes(), options);
+
+        let tabs = parse("comp {\n\tkey1 val1\n\tkey2 val2\n}", &options).unwrap();
+        let spaces = "comp {\n    key1 val1\n    key2 val2\n}";
+
+        assert_eq!(tabs.detected_indent(), Indent::Tabs);
+        assert_eq!(
+            parse(spaces, &options).unwrap().detected_indent(),
+            Indent::Spaces(4)
+        );
+
+        // Not worked out unless asked for.
+        let default = parse(spaces, &ParseOptions::default()).unwrap();
+        assert_eq!(default.detected_indent(), Indent::Tabs);
+    }
+
+    #[test]
+    fn byte_size_estimate() {
+        let kv = "\"LightmappedGeneric\"\n{\n\t\"$basetexture\"\t\"brick\\\\wall\"\n\t\"Proxies\"\n\t{\n\t\t\"Sine\"\n\t\t{\n\t\t}\n\t} [$WIN32]\n}\n\"other\"\t\"1\" [!$X360]\n";
+
+        let object = KeyValues::from_io(kv.as_bytes()).unwrap();
+        let estimate = object.root().byte_size_estimate();
+
+        assert!(estimate.abs_diff(kv.len()) <= kv.len() / 20);
+        assert_eq!(estimate, object.to_string().len());
+
+        let entries = object.root().entry_size_estimates();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(
+            entries.iter().map(|(_, size)| size).sum::<usize>(),
+            estimate
+        );
+    }
+
+    #[test]
+    fn duplicate_key_policies() {
+        let kv = "key 1 other x key 2 key 3".as_bytes();
+        let parse = |duplicate_keys| {
+            let options = ParseOptions {
+                duplicate_keys,
+                ..Default::default()
+            };
+
+            KeyValues::from_io_with_options(kv, &options).unwrap()
+        };
+
+        let values = |object: &KeyValues| {
+            object
+                .find_all("key")
+                .into_iter()
+                .map(|(_, value)| value.raw_str().unwrap().to_string())
+                .collect::<Vec<_>>()
+        };
+
+        let keep_all = parse(DuplicateKeyPolicy::KeepAll);
+        assert_eq!(values(&keep_all), vec!["1", "2", "3"]);
+
+        let keep_first = parse(DuplicateKeyPolicy::KeepFirst);
+        assert_eq!(values(&keep_first), vec!["1"]);
+
+        let keep_last = parse(DuplicateKeyPolicy::KeepLast);
+        assert_eq!(values(&keep_last), vec!["3"]);
+        assert!(string_matches(keep_last.get("other").unwrap(), "x"));
+
+        // The kept entry takes the place of the last one.
+        let keys: Vec<_> = keep_last.root().iter().map(|(key, _, _)| key).collect();
+        assert_eq!(keys, ["other", "key"]);
+    }
+
+    #[test]
+    fn map_strings() {
+        let kv = r#"
+        LightmappedGeneric {
+            $basetexture materials/old/brick
+            $surfaceprop brick
+            materials/old/key { $bumpmap materials/old/brick_normal }
+        }
+        "#
+        .as_bytes();
+
+        let mut object = KeyValues::from_io(kv).unwrap();
+        object.map_strings(|value| match value.strip_prefix("materials/old/") {
+            Some(rest) => format!("materials/new/{rest}"),
+            None => value.to_string(),
+        });
+
+        let expected = r#"
+        LightmappedGeneric {
+            $basetexture materials/new/brick
+            $surfaceprop brick
+            materials/old/key { $bumpmap materials/new/brick_normal }
+        }
+        "#
+        .as_bytes();
+
+        assert_eq!(object.root(), KeyValues::from_io(expected).unwrap().root());
+    }
+
+    #[test]
+    fn only_whitespace_and_comments() {
+        let block_comments = ParseOptions {
+            block_comments: true,
+            ..Default::default()
+        };
+        let anonymous_root = ParseOptions {
+            allow_anonymous_root: true,
+            ..Default::default()
+        };
+
+        for (text, options) in [
+            ("", &ParseOptions::default()),
+            ("  \t\r\n  \n", &ParseOptions::default()),
+            ("// just a comment", &ParseOptions::default()),
+            ("// just a comment\n   ", &ParseOptions::default()),
+            ("\u{feff}\n// one\n\t// two\r\n\n", &ParseOptions::default()),
+            ("\n/* block */ // line\n/* unterminated", &block_comments),
+            ("  // nothing here\n", &anonymous_root),
+        ] {
+            let kv = KeyValues::from_io_with_options(text.as_bytes(), options).unwrap();
+
+            assert!(kv.root().kv.is_empty(), "{text:?}");
+            assert_eq!(kv.root(), KeyValues::empty().root());
+        }
+    }
+
+    #[test]
+    fn empty_flags() {
+        for kv in ["key val []", "key val [!]", "key val [\"\"]"] {
+            assert!(matches!(
+                KeyValues::from_io(kv.as_bytes()),
+                Err(ReaderError::EmptyFlag)
+            ));
+        }
+
+        let object = KeyValues::from_io("key val [!x]".as_bytes()).unwrap();
+        assert!(matches!(
+            object.get_with_flags("key", &HashSet::from(["y"])),
+            Some(Value::String(v)) if v == "val"
+        ));
+        assert!(object
+            .get_with_flags("key", &HashSet::from(["x"]))
+            .is_none());
+    }
+
+    #[test]
+    fn write_to_ordered() {
+        let kv = KeyValues::from_io(
+            r#"
+            "LightmappedGeneric"
+            {
+                "$surfaceprop"  "brick"
+                "%keywords"     "wall"
+                "$BaseTexture"  "brick/wall01"
+                "$bumpmap"      "brick/wall01_normal"
+                "proxies"       { "sine" { "sinemax" "1" "resultvar" "$alpha" } }
+                "$detail"       "detail/noise"
+            }
+            "#
+            .as_bytes(),
+        )
+        .unwrap();
+
+        let mut written = Vec::new();
+        kv.write_to_ordered(&mut written, &["$basetexture", "$bumpmap", "resultvar"])
+            .unwrap();
+        let written = std::string::String::from_utf8(written).unwrap();
+
+        let keys: Vec<&str> = written
+            .lines()
+            .filter_map(|line| line.trim_start().strip_prefix('"'))
+            .filter_map(|line| line.split('"').next())
+            .collect();
+        assert_eq!(
+            keys,
+            [
+                "LightmappedGeneric",
+                "$BaseTexture",
+                "$bumpmap",
+                "$surfaceprop",
+                "%keywords",
+                "proxies",
+                "sine",
+                "resultvar",
+                "sinemax",
+                "$detail",
+            ]
+        );
+        assert!(written
+            .starts_with("\"LightmappedGeneric\"\n{\n\t\"$BaseTexture\"\t\"brick/wall01\"\n"));
+
+        let reparsed = KeyValues::from_io(written.as_bytes()).unwrap();
+        assert_eq!(reparsed.root(), kv.root());
+    }
+
+    #[test]
+    fn write_to_ordered_quoting() {
+        let mut kv = KeyValues::empty();
+        kv.insert("key with spaces", "value {with} braces");
+        kv.insert("path", "models\\props\\crate.mdl");
+        kv.insert("say \"hi\"", "a \"quoted\" [word]");
+        kv.insert("", "");
+
+        let mut written = Vec::new();
+        kv.write_to_ordered(&mut written, &[]).unwrap();
+        let written = std::string::String::from_utf8(written).unwrap();
+
+        assert!(written.contains("\"key with spaces\"\t\"value {with} braces\"\n"));
+        assert!(written.contains("\"path\"\t\"models\\props\\crate.mdl\"\n"));
+        assert!(written.contains("say\\ \\\"hi\\\"\ta\\ \\\"quoted\\\"\\ \\[word\\]"));
+
+        let reparsed = KeyValues::from_io(written.as_bytes()).unwrap();
+        assert_eq!(reparsed.root(), kv.root());
+    }
+
+    #[test]
+    fn compound_flags() {
+        let kv = KeyValues::from_io(
+            r#"
+            either   yes [$WIN32||$X360]
+            both     yes [$WIN32&&!$LOW]
+            neither  yes [!($WIN32 || $X360)]
+            "#
+            .as_bytes(),
+        )
+        .unwrap();
+
+        let holds = |key: &str, defines: &[&str]| {
+            kv.get_with_flags(key, &defines.iter().copied().collect::<HashSet<&str>>())
+                .is_some()
+        };
+
+        assert!(holds("either", &["$WIN32"]));
+        assert!(holds("either", &["$X360"]));
+        assert!(!holds("either", &["$POSIX"]));
+
+        assert!(holds("both", &["$WIN32"]));
+        assert!(!holds("both", &["$WIN32", "$LOW"]));
+        assert!(!holds("both", &[]));
+
+        assert!(holds("neither", &["$POSIX"]));
+        assert!(!holds("neither", &["$X360"]));
+
+        let suffixes: HashSet<std::string::String> = kv
+            .walk()
+            .filter_map(|(_, flag, _)| flag.to_suffix())
+            .collect();
+        assert_eq!(
+            suffixes,
+            HashSet::from([
+                "[$WIN32||$X360]".to_owned(),
+                "[$WIN32&&!$LOW]".to_owned(),
+                "[!($WIN32||$X360)]".to_owned(),
+            ])
+        );
+        assert!(kv
+            .collect_flags()
+            .contains(&FlagCondition::Negated("$LOW".to_owned())));
+
+        let reparsed = KeyValues::from_io(kv.to_string().as_bytes()).unwrap();
+        assert_eq!(reparsed.root(), kv.root());
+        let reparsed = KeyValues::from_io(kv.to_minified_string().as_bytes()).unwrap();
+        assert_eq!(reparsed.root(), kv.root());
+
+        for malformed in ["key val [$A||]", "key val [$A $B]", "key val [$A&&{]"] {
+            assert!(
+                matches!(
+                    KeyValues::from_io(malformed.as_bytes()),
+                    Err(ReaderError::InvalidToken(..))
+                ),
+                "{malformed:?}"
+            );
         }
     }
 
     #[test]
-    fn compound_kv_with_flags() {
+    fn keep_string_capacity() {
         let kv = r#"
         comp {
-            key1 val1 [test]
-            key2 val2 [!test]
+            key1 "val1"
+            key2 val2 [$X360]
+        }
+        "#
+        .as_bytes();
+
+        let options = ParseOptions {
+            keep_string_capacity: true,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            KeyValues::from_io_with_options(kv, &options)
+                .unwrap()
+                .root(),
+            KeyValues::from_io(kv).unwrap().root()
+        );
+    }
+
+    #[test]
+    fn missing_value() {
+        for (kv, expected_key) in [
+            ("key", "key"),
+            ("comp { key1 val1 key2", "key2"),
+            ("key1 val1 \"key 2\" // comment", "key 2"),
+        ] {
+            let err = KeyValues::from_io(kv.as_bytes()).err().unwrap();
+
+            assert!(matches!(&err, ReaderError::MissingValue(key) if key == expected_key));
+            assert!(err.to_string().contains("has no value"));
         }
+    }
+
+    #[test]
+    fn as_array() {
+        let kv = r#"
+        array { "2" { name c } "0" { name a } "1" { name b } }
+        gap { "0" a "2" c }
+        padded { "0" a "01" b }
+        duplicate { "0" a "0" b }
+        empty { }
         "#
         .as_bytes();
 
         let object = KeyValues::from_io(kv).unwrap();
+        let block = |key| match object.get(key).unwrap() {
+            Value::Object(block) => block,
+            _ => panic!(),
+        };
 
-        match object.get("comp").unwrap() {
-            Value::Object(comp) => {
-                assert!(string_matches(comp.get("key1").unwrap(), "val1"));
-                assert!(string_matches(comp.get("key2").unwrap(), "val2"));
+        let names: Vec<_> = block("array")
+            .as_array()
+            .unwrap()
+            .into_iter()
+            .map(|value| match value {
+                Value::Object(element) => element.get("name").unwrap().raw_str().unwrap(),
+                _ => panic!(),
+            })
+            .collect();
+        assert_eq!(names, vec!["a", "b", "c"]);
+
+        assert!(block("gap").as_array().is_none());
+        assert!(block("padded").as_array().is_none());
+        assert!(block("duplicate").as_array().is_none());
+        assert_eq!(block("empty").as_array(), Some(vec![]));
+    }
+
+    #[test]
+    fn parse_limits() {
+        let kv = r#"
+        outer {
+            inner { key "a longer value" }
+            other value
+        }
+        "#;
+
+        let limited = |limits: ParseLimits| KeyValues::from_io_with_limits(kv.as_bytes(), &limits);
+        let tripped = |limits: ParseLimits| match limited(limits) {
+            Err(ReaderError::LimitExceeded(kind)) => kind,
+            Err(err) => panic!("expected an exceeded limit, got {err}"),
+            Ok(_) => panic!("expected an exceeded limit"),
+        };
+
+        let generous = ParseLimits {
+            max_bytes: Some(kv.len() as u64),
+            max_entries: Some(4),
+            max_depth: Some(2),
+            max_string_length: Some(14),
+        };
+        assert!(limited(generous.clone()).is_ok());
+
+        let cases = [
+            (
+                ParseLimits {
+                    max_bytes: Some(kv.len() as u64 - 20),
+                    ..generous.clone()
+                },
+                LimitKind::Bytes,
+            ),
+            (
+                ParseLimits {
+                    max_entries: Some(3),
+                    ..generous.clone()
+                },
+                LimitKind::Entries,
+            ),
+            (
+                ParseLimits {
+                    max_depth: Some(1),
+                    ..generous.clone()
+                },
+                LimitKind::Depth,
+            ),
+            (
+                ParseLimits {
+                    max_string_length: Some(13),
+                    ..generous.clone()
+                },
+                LimitKind::StringLength,
+            ),
+        ];
+
+        for (limits, expected) in cases {
+            assert_eq!(tripped(limits), expected);
+        }
+    }
+
+    #[test]
+    fn allocated_bytes() {
+        let small = KeyValues::from_io("key val".as_bytes()).unwrap();
+
+        let large_kv: std::string::String = (0..1000)
+            .map(|i| format!("key{i} \"value number {i}\"\n"))
+            .collect();
+        let large = KeyValues::from_io(large_kv.as_bytes()).unwrap();
+
+        assert!(small.allocated_bytes() > 0);
+        assert!(large.allocated_bytes() > small.allocated_bytes());
+    }
+
+    #[test]
+    fn walk() {
+        let kv =
+            KeyValues::from_io("outer { first 1 second 2 } last 3 [$X360]".as_bytes()).unwrap();
+
+        let walked: Vec<(Vec<&str>, Option<std::string::String>)> = kv
+            .walk()
+            .map(|(path, flag, _)| (path, flag.to_suffix()))
+            .collect();
+
+        assert_eq!(
+            walked,
+            [
+                (vec!["outer"], None),
+                (vec!["outer", "first"], None),
+                (vec!["outer", "second"], None),
+                (vec!["last"], Some("[$X360]".to_owned())),
+            ]
+        );
+    }
+
+    #[test]
+    fn string_leaves() {
+        let kv = KeyValues::from_io(
+            "material { proxies { sine { resultvar $color } } $basetexture brick } other x"
+                .as_bytes(),
+        )
+        .unwrap();
+
+        let leaves: Vec<(Vec<&str>, &str)> = kv.string_leaves().collect();
+
+        assert_eq!(
+            leaves,
+            [
+                (vec!["material", "proxies", "sine", "resultvar"], "$color"),
+                (vec!["material", "$basetexture"], "brick"),
+                (vec!["other"], "x"),
+            ]
+        );
+    }
+
+    #[test]
+    fn entry_object() {
+        let mut kv = KeyValues::from_io(r#"Proxies "none""#.as_bytes()).unwrap();
+
+        kv.with_root_mut(|root| {
+            root.entry_object("Proxies")
+                .entry_object("Sine")
+                .insert("resultVar", "$alpha");
+            root.entry_object("Proxies")
+                .entry_object("Sine")
+                .insert("sinemax", "1");
+        });
+
+        let expected = KeyValues::from_io(
+            r#"Proxies "none" Proxies { Sine { resultVar $alpha sinemax 1 } }"#.as_bytes(),
+        )
+        .unwrap();
+
+        assert_eq!(kv.root(), expected.root());
+    }
+
+    #[test]
+    fn equals_separators() {
+        let options = ParseOptions {
+            equals_separators: true,
+            ..Default::default()
+        };
+
+        let kv = KeyValues::from_io_with_options(
+            r#"a = b video { width = 640 "=" = "=" } c d"#.as_bytes(),
+            &options,
+        )
+        .unwrap();
+        let expected =
+            KeyValues::from_io(r#"a b video { width 640 "=" "=" } c d"#.as_bytes()).unwrap();
+        assert_eq!(kv.root(), expected.root());
+
+        // Without the option, the `=` is the value and `b` becomes a key.
+        let kv = KeyValues::from_io("a = b c".as_bytes()).unwrap();
+        assert!(matches!(kv.get("a"), Some(Value::String(value)) if value == "="));
+        assert!(matches!(kv.get("b"), Some(Value::String(value)) if value == "c"));
+    }
+
+    #[test]
+    fn block_comments() {
+        let options = ParseOptions {
+            block_comments: true,
+            ..Default::default()
+        };
+
+        let kv = KeyValues::from_io_with_options(
+            "/* generated */ material { $basetexture tex/*note*/ture /* $envmap env */ }"
+                .as_bytes(),
+            &options,
+        )
+        .unwrap();
+        let expected = KeyValues::from_io("material { $basetexture texture }".as_bytes()).unwrap();
+        assert_eq!(kv.root(), expected.root());
+    }
+
+    #[test]
+    fn typed_numbers() {
+        let options = ParseOptions {
+            typed_numbers: true,
+            ..Default::default()
+        };
+
+        let kv = KeyValues::from_io_with_options(
+            r#"
+            int 7
+            negative "-12"
+            float 0.5
+            padded "007"
+            exponent 1e5
+            signed +1
+            vector "1 2 3"
+            huge 99999999999999999999
+            minus_zero -0
+            name brick
+            "#
+            .as_bytes(),
+            &options,
+        )
+        .unwrap();
+
+        assert_eq!(kv.get("int"), Some(&Value::Int(7)));
+        assert_eq!(kv.get("negative"), Some(&Value::Int(-12)));
+        assert_eq!(kv.get("float"), Some(&Value::Float(0.5)));
+
+        for key in [
+            "padded",
+            "exponent",
+            "signed",
+            "vector",
+            "huge",
+            "minus_zero",
+            "name",
+        ] {
+            assert!(kv.get(key).unwrap().is_string(), "{key}");
+        }
+
+        assert_eq!(kv.get("int").unwrap().as_f32_vec(), Some(vec![7.0]));
+        assert_eq!(kv.get("float").unwrap().as_f64(), Some(0.5));
+        assert_eq!(kv.get("padded").unwrap().as_i64(), Some(7));
+
+        // Off by default.
+        let kv = KeyValues::from_io("int 7".as_bytes()).unwrap();
+        assert!(string_matches(kv.get("int").unwrap(), "7"));
+    }
+
+    #[test]
+    fn dirty_tracking() {
+        let mut kv = KeyValues::from_io("a b nested { c d }".as_bytes()).unwrap();
+        assert!(!kv.is_dirty());
+
+        // Changes which leave the tree as it was don't count.
+        kv.retain(|_, _, _| true);
+        kv.map_strings(|string| string.to_string());
+        assert!(!kv.rename_key("missing", "other"));
+        assert!(!kv.is_dirty());
+
+        kv.insert("e", "f");
+        assert!(kv.is_dirty());
+
+        kv.mark_clean();
+        assert!(!kv.is_dirty());
+
+        // Nested changes are seen from the root.
+        kv.with_root_mut(|root| root.entry_object("nested").insert("g", "h"));
+        assert!(kv.is_dirty());
+        kv.mark_clean();
+
+        kv.map_strings(|string| string.to_uppercase());
+        assert!(kv.is_dirty());
+    }
+
+    #[test]
+    fn get_excluding() {
+        let kv = KeyValues::from_io(
+            r#"
+            console "1" [$WIN32]
+            plain "2"
+            "#
+            .as_bytes(),
+        )
+        .unwrap();
+
+        let without: HashSet<&str> = HashSet::new();
+        let with = HashSet::from(["$WIN32"]);
+
+        assert!(
+            matches!(kv.get_excluding("console", &without), Some(Value::String(v)) if v == "1")
+        );
+        assert!(kv.get_excluding("console", &with).is_none());
+        assert!(kv.get_excluding("plain", &without).is_none());
+        assert!(kv.get_excluding("missing", &without).is_none());
+    }
+
+    #[test]
+    fn unterminated_block() {
+        for kv in ["a {", "a { b c", "a { b { c d }"] {
+            assert!(matches!(
+                KeyValues::from_io(kv.as_bytes()),
+                Err(ReaderError::UnexpectedEof(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn iter_source_order() {
+        let text = "
+        \"replace\"
+        {
+            zeta 1
+            alpha 2
+            \"$basetexture\" brick [$X360]
+            mid { inner 3 }
+            alpha 4
+            beta 5
+        }";
+        let mut kv = KeyValues::from_io(text.as_bytes()).unwrap();
+
+        let Some(Value::Object(block)) = kv.get("replace") else {
+            panic!()
+        };
+        let keys: Vec<_> = block.iter().map(|(key, _, _)| key).collect();
+        assert_eq!(
+            keys,
+            ["zeta", "alpha", "$basetexture", "mid", "alpha", "beta"]
+        );
+
+        let (_, flag, value) = block.iter().nth(2).unwrap();
+        assert_eq!(
+            *flag,
+            Flag::Normal(String::from_str_in("$X360", &Bump::new()))
+        );
+        assert!(string_matches(value, "brick"));
+        let alphas: Vec<_> = block
+            .iter()
+            .filter(|(key, _, _)| *key == "alpha")
+            .filter_map(|(_, _, value)| value.raw_str())
+            .collect();
+        assert_eq!(alphas, ["2", "4"]);
+
+        // Edits keep the order of the entries they leave in place.
+        kv.with_root_mut(|root| {
+            let block = root.entry_object("replace");
+            block.rename_key("alpha", "omega");
+            block.retain(|key, _, _| key != "mid");
+            block.insert("gamma", "6");
+        });
+
+        let Some(Value::Object(block)) = kv.get("replace") else {
+            panic!()
+        };
+        let keys: Vec<_> = block.iter().map(|(key, _, _)| key).collect();
+        assert_eq!(
+            keys,
+            ["zeta", "omega", "$basetexture", "omega", "beta", "gamma"]
+        );
+
+        // And are written out in that order.
+        let written = block.to_string();
+        let written: Vec<_> = written
+            .lines()
+            .map(|line| line.split('\t').next().unwrap().trim_matches('"'))
+            .collect();
+        assert_eq!(written, keys);
+    }
+
+    #[test]
+    fn error_positions() {
+        let vmf =
+            "world\n{\n\t\"id\" \"1\"\n\tsolid\n\t{\n\t\tside { plane \"(0 0 0)\" ] }\n\t}\n}\n";
+        let Err(err) = KeyValues::from_io(vmf.as_bytes()) else {
+            panic!()
+        };
+
+        let position = Position {
+            offset: vmf.find(']').unwrap() as u64,
+            line: 6,
+            column: 26,
+        };
+        assert!(matches!(&err, ReaderError::InvalidToken(_, at) if *at == position));
+        assert_eq!(
+            err.to_string(),
+            "Invalid token: CloseFlag at line 6, column 26"
+        );
+
+        // Columns count characters rather than bytes, and skip a leading BOM.
+        let Err(err) = KeyValues::from_io("\u{FEFF}é ü ]".as_bytes()) else {
+            panic!()
+        };
+        assert_eq!(
+            err.to_string(),
+            "Invalid token: CloseFlag at line 1, column 5"
+        );
+
+        let Err(err) = KeyValues::from_io("a\n{\n  b c\n".as_bytes()) else {
+            panic!()
+        };
+        assert_eq!(err.to_string(), "Unexpected EOF at line 4, column 1");
+    }
+
+    #[test]
+    fn quoted_key_with_object_value() {
+        let expected = KeyValues::from_io("key { inner value } after 1".as_bytes()).unwrap();
+
+        for kv in [
+            r#""key" { "inner" "value" } "after" "1""#,
+            r#""key"{"inner""value"}"after""1""#,
+            "\"key\"\n{\n\t\"inner\" \"value\"\n}\n\"after\" 1",
+        ] {
+            let parsed = KeyValues::from_io(kv.as_bytes()).unwrap();
+            assert_eq!(parsed.root(), expected.root(), "{kv}");
+
+            match parsed.get("key").unwrap() {
+                Value::Object(key) => assert!(string_matches(key.get("inner").unwrap(), "value")),
+                _ => panic!(),
             }
-            _ => panic!(),
         }
+    }
 
-        let flags: HashSet<&str> = vec!["test"].into_iter().collect();
+    #[test]
+    fn collapse_singletons() {
+        let mut kv = KeyValues::from_io(
+            r#"
+            a { b { c { d 1 } } }
+            flagged { inner { x 1 } } [$X360]
+            kept { inner { x 1 } [$WIN32] }
+            branch { left { x 1 } right { y { z 2 } } }
+            string { only 1 }
+            "#
+            .as_bytes(),
+        )
+        .unwrap();
 
-        match object.get("comp").unwrap() {
-            Value::Object(comp) => {
-                assert!(string_matches(
-                    comp.get_with_flags("key1", &flags).unwrap(),
-                    "val1"
-                ));
-                assert!(comp.get_with_flags("key2", &flags).is_none());
+        kv.collapse_singletons();
+
+        let expected = KeyValues::from_io(
+            r#"
+            "a/b/c" { d 1 }
+            "flagged/inner" { x 1 } [$X360]
+            kept { inner { x 1 } [$WIN32] }
+            branch { left { x 1 } "right/y" { z 2 } }
+            string { only 1 }
+            "#
+            .as_bytes(),
+        )
+        .unwrap();
+
+        assert_eq!(kv.root(), expected.root());
+        assert!(kv.is_dirty());
+    }
+
+    #[test]
+    fn duplicate_keys() {
+        let text = r#"
+            a 1
+            a 2
+            nested
+            {
+                b 1
+                c x [$X360]
+                c y
+                b 2
             }
-            _ => panic!(),
+        "#;
+
+        let options = ParseOptions {
+            collect_duplicate_keys: true,
+            ..Default::default()
+        };
+        let kv = KeyValues::from_io_with_options(text.as_bytes(), &options).unwrap();
+
+        assert_eq!(
+            kv.duplicate_keys(),
+            [
+                DuplicateKey {
+                    path: "".into(),
+                    key: "a".into(),
+                },
+                DuplicateKey {
+                    path: "nested".into(),
+                    key: "b".into(),
+                },
+            ]
+        );
+
+        let plain = KeyValues::from_io(text.as_bytes()).unwrap();
+        assert_eq!(kv.root(), plain.root());
+        assert!(plain.duplicate_keys().is_empty());
+    }
+
+    #[test]
+    fn line_of() {
+        let text = r#""material"
+{
+    $basetexture brick // note
+
+    /* not a key */ $bumpmap
+    "brick_normal"
+    $surfaceprop brick
+    proxies { sine
+    { } }
+    $surfaceprop concrete
+}
+"#;
+
+        let mut options = ParseOptions {
+            record_lines: true,
+            block_comments: true,
+            ..Default::default()
+        };
+        let kv = KeyValues::from_io_with_options(text.as_bytes(), &options).unwrap();
+
+        assert_eq!(kv.line_of("material"), Some(1));
+        assert_eq!(kv.line_of("material/$basetexture"), Some(3));
+        assert_eq!(kv.line_of("material/$bumpmap"), Some(5));
+        assert_eq!(kv.line_of("material/$surfaceprop"), Some(7));
+        assert_eq!(kv.line_of("material/proxies/sine"), Some(8));
+        assert_eq!(kv.line_of("material/missing"), None);
+
+        options.record_lines = false;
+        let plain = KeyValues::from_io_with_options(text.as_bytes(), &options).unwrap();
+        assert_eq!(plain.line_of("material"), None);
+    }
+
+    #[test]
+    fn display_tree() {
+        let kv = KeyValues::from_io(
+            r#"
+            world
+            {
+                classname worldspawn
+                "message" "C:\maps"
+                solid
+                {
+                    id 2 [$X360]
+                }
+            }
+            "#
+            .as_bytes(),
+        )
+        .unwrap();
+
+        let dumped = kv.to_string();
+        for line in [
+            "\"world\"\n{\n",
+            "\t\"classname\"\t\"worldspawn\"\n",
+            "\t\"message\"\t\"C:\\maps\"\n",
+            "\t\"solid\"\n\t{\n\t\t\"id\"\t\"2\" [$X360]\n\t}\n",
+        ] {
+            assert!(dumped.contains(line), "{line:?} not in {dumped}");
         }
+        assert!(dumped.ends_with("}\n"));
+
+        let debug = format!("{:?}", kv.root());
+        assert!(debug.starts_with("Object {\n\t\"world\"\n\t{\n"), "{debug}");
+    }
+
+    #[test]
+    fn lowercase_keys() {
+        let options = ParseOptions {
+            lowercase_keys: true,
+            ..Default::default()
+        };
+
+        let kv = KeyValues::from_io_with_options(
+            r#"
+            "VertexLitGeneric"
+            {
+                $BaseTexture "Models/Props/Crate"
+                Proxies { AnimatedTexture { animatedTextureVar $BaseTexture } }
+                $EnvMap env_cubemap [$WIN32]
+            }
+            "#
+            .as_bytes(),
+            &options,
+        )
+        .unwrap();
+
+        let Some(Value::Object(material)) = kv.get("vertexlitgeneric") else {
+            panic!()
+        };
+        assert!(kv.get("VertexLitGeneric").is_none());
+        assert_eq!(
+            material.get("$basetexture").and_then(Value::raw_str),
+            Some("Models/Props/Crate")
+        );
+
+        let Some(Value::Object(proxies)) = material.get("proxies") else {
+            panic!()
+        };
+        let Some(Value::Object(animated)) = proxies.get("animatedtexture") else {
+            panic!()
+        };
+        assert_eq!(
+            animated.get("animatedtexturevar").and_then(Value::raw_str),
+            Some("$BaseTexture")
+        );
+
+        assert!(material.get("$envmap").is_some());
+        assert_eq!(
+            material.collect_flags(),
+            HashSet::from([FlagCondition::Normal("$WIN32".into())])
+        );
+    }
+
+    #[test]
+    fn case_insensitive_keys() {
+        let vmt = r#"
+            "VertexLitGeneric"
+            {
+                $BaseTexture "Models/Props/Crate"
+                $BumpMap models/props/crate_normal
+                $EnvMap env_cubemap [$WIN32]
+                $EnvMap "" [$X360]
+                Proxies { AnimatedTexture { AnimatedTextureVar $BaseTexture } }
+            }
+            "#;
+        let options = ParseOptions {
+            case_insensitive_keys: true,
+            ..Default::default()
+        };
+        let kv = KeyValues::from_io_with_options(vmt.as_bytes(), &options).unwrap();
+
+        let Some(Value::Object(material)) = kv.get("vertexlitgeneric") else {
+            panic!()
+        };
+        assert_eq!(
+            material.get("$basetexture").and_then(Value::raw_str),
+            Some("Models/Props/Crate")
+        );
+        assert!(material.get("$BUMPMAP").is_some());
+        assert!(material.get("$detail").is_none());
+
+        let win32 = HashSet::from(["$WIN32"]);
+        assert_eq!(
+            material
+                .get_with_flags("$envmap", &win32)
+                .and_then(Value::raw_str),
+            Some("env_cubemap")
+        );
+        assert_eq!(material.get_all("$ENVMAP").count(), 2);
+
+        let Some(Value::Object(proxies)) = material.get("proxies") else {
+            panic!()
+        };
+        assert!(proxies.get("animatedtexture").is_some());
+
+        // Keys keep their case, so the document is written out as it was.
+        let plain = KeyValues::from_io(vmt.as_bytes()).unwrap();
+        assert_eq!(kv.to_string(), plain.to_string());
+        assert!(plain.get("vertexlitgeneric").is_none());
+    }
+
+    #[test]
+    fn nul_bytes() {
+        let text = b"ke\0y \"va\0lue\" \0 other x\0";
+
+        assert!(matches!(
+            KeyValues::from_io(text.as_slice()),
+            Err(ReaderError::NulByte(2))
+        ));
+
+        let strip = ParseOptions {
+            nul_bytes: NulBytes::Strip,
+            ..Default::default()
+        };
+        let kv = KeyValues::from_io_with_options(text.as_slice(), &strip).unwrap();
+        let expected = KeyValues::from_io("key value other x".as_bytes()).unwrap();
+        assert_eq!(kv.root(), expected.root());
+
+        let keep = ParseOptions {
+            nul_bytes: NulBytes::Keep,
+            ..Default::default()
+        };
+        let kv = KeyValues::from_io_with_options(text.as_slice(), &keep);
+        assert!(matches!(kv, Err(ReaderError::MissingValue(key)) if key == "x\0"));
+
+        let text = b"ke\0y \"va\0lue\" \0 other";
+        let kv = KeyValues::from_io_with_options(text.as_slice(), &keep).unwrap();
+        assert_eq!(kv.get("ke\0y").and_then(Value::raw_str), Some("va\0lue"));
+        assert_eq!(kv.get("\0").and_then(Value::raw_str), Some("other"));
     }
 }
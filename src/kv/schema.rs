@@ -0,0 +1,240 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+
+use super::{Object, Value};
+
+/// Which variant a value is, as returned by `Value::kind` and expected by schemas.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ValueKind {
+    String,
+    Object,
+    Int,
+    Float,
+}
+
+impl fmt::Display for ValueKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValueKind::String => write!(f, "string"),
+            ValueKind::Object => write!(f, "object"),
+            ValueKind::Int => write!(f, "integer"),
+            ValueKind::Float => write!(f, "float"),
+        }
+    }
+}
+
+/// A problem found when validating an object against a `Schema`. Paths are `/`-joined keys from
+/// the validated object.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationError {
+    MissingKey {
+        path: String,
+    },
+    UnexpectedKey {
+        path: String,
+    },
+    WrongKind {
+        path: String,
+        expected: ValueKind,
+        found: ValueKind,
+    },
+}
+
+impl fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValidationError::MissingKey { path } => write!(f, "Missing required key {path}"),
+            ValidationError::UnexpectedKey { path } => write!(f, "Unexpected key {path}"),
+            ValidationError::WrongKind {
+                path,
+                expected,
+                found,
+            } => write!(f, "Expected {path} to be a {expected}, found a {found}"),
+        }
+    }
+}
+
+impl Error for ValidationError {}
+
+#[derive(Debug, Clone)]
+struct KeySchema {
+    key: String,
+    kind: ValueKind,
+    required: bool,
+    children: Option<Schema>,
+}
+
+/// Describes the keys expected in an object, for catching typos and mistakes in hand-authored
+/// files. Keys are matched case-insensitively, as in Source.
+///
+/// # Examples
+/// ```
+/// use srcrs::kv::{KeyValues, Schema};
+///
+/// let schema = Schema::new().object(
+///     "LightmappedGeneric",
+///     true,
+///     Schema::new().string("$basetexture", true),
+/// );
+///
+/// let kv = KeyValues::from_io(r#"LightmappedGeneric { $basetexture "brick" }"#.as_bytes()).unwrap();
+/// assert!(schema.validate(kv.root()).is_empty());
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Schema {
+    keys: Vec<KeySchema>,
+    deny_unknown_keys: bool,
+}
+
+impl Schema {
+    pub fn new() -> Schema {
+        Schema::default()
+    }
+
+    /// Reports keys which are not described by this schema.
+    pub fn deny_unknown_keys(mut self) -> Schema {
+        self.deny_unknown_keys = true;
+        self
+    }
+
+    /// Expects `key` to hold a string. Numbers parsed with `ParseOptions::typed_numbers` are
+    /// accepted too, as they would have been strings without it.
+    pub fn string(mut self, key: &str, required: bool) -> Schema {
+        self.keys.push(KeySchema {
+            key: key.to_string(),
+            kind: ValueKind::String,
+            required,
+            children: None,
+        });
+        self
+    }
+
+    /// Expects `key` to hold an object, itself validated against `children`.
+    pub fn object(mut self, key: &str, required: bool, children: Schema) -> Schema {
+        self.keys.push(KeySchema {
+            key: key.to_string(),
+            kind: ValueKind::Object,
+            required,
+            children: Some(children),
+        });
+        self
+    }
+
+    /// Validates `object`, returning every problem found.
+    pub fn validate(&self, object: &Object) -> Vec<ValidationError> {
+        let mut errors = Vec::new();
+        self.validate_into(object, "", &mut errors);
+
+        errors
+    }
+
+    fn validate_into(&self, object: &Object, prefix: &str, errors: &mut Vec<ValidationError>) {
+        let join = |key: &str| {
+            if prefix.is_empty() {
+                key.to_string()
+            } else {
+                format!("{prefix}/{key}")
+            }
+        };
+
+        for key_schema in &self.keys {
+            let mut found = false;
+
+            for (key, _, value) in object.iter() {
+                if !key.eq_ignore_ascii_case(&key_schema.key) {
+                    continue;
+                }
+
+                found = true;
+                let path = join(key);
+
+                match (value, &key_schema.children) {
+                    (Value::Object(child), Some(children)) => {
+                        children.validate_into(child, &path, errors)
+                    }
+                    (Value::Object(_), None) | (_, Some(_)) => {
+                        errors.push(ValidationError::WrongKind {
+                            path,
+                            expected: key_schema.kind,
+                            found: value.kind(),
+                        })
+                    }
+                    (_, None) => {}
+                }
+            }
+
+            if !found && key_schema.required {
+                errors.push(ValidationError::MissingKey {
+                    path: join(&key_schema.key),
+                });
+            }
+        }
+
+        if self.deny_unknown_keys {
+            let mut reported = HashSet::new();
+
+            for (key, _, _) in object.iter().filter(|(key, _, _)| reported.insert(*key)) {
+                let known = self
+                    .keys
+                    .iter()
+                    .any(|key_schema| key.eq_ignore_ascii_case(&key_schema.key));
+
+                if !known {
+                    errors.push(ValidationError::UnexpectedKey { path: join(key) });
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Schema, ValidationError, ValueKind};
+    use crate::kv::KeyValues;
+
+    #[test]
+    fn validate_vmt() {
+        let kv = r#"
+        LightmappedGeneric {
+            $BaseTexture { nested value }
+            $surfaceprop concrete
+            $typo 1
+        }
+        "#
+        .as_bytes();
+
+        let schema = Schema::new().object(
+            "lightmappedgeneric",
+            true,
+            Schema::new()
+                .string("$basetexture", true)
+                .string("$surfaceprop", false)
+                .string("$basetexturetransform", false)
+                .object("proxies", true, Schema::new())
+                .deny_unknown_keys(),
+        );
+
+        let object = KeyValues::from_io(kv).unwrap();
+
+        let mut errors = schema.validate(object.root());
+        errors.sort_by_key(|error| error.to_string());
+
+        assert_eq!(
+            errors,
+            vec![
+                ValidationError::WrongKind {
+                    path: "LightmappedGeneric/$BaseTexture".to_string(),
+                    expected: ValueKind::String,
+                    found: ValueKind::Object,
+                },
+                ValidationError::MissingKey {
+                    path: "LightmappedGeneric/proxies".to_string(),
+                },
+                ValidationError::UnexpectedKey {
+                    path: "LightmappedGeneric/$typo".to_string(),
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,102 @@
+use super::{Object, Value};
+
+/// Characters which may precede a wave path in a sound script to change how it is played, such
+/// as `*` for streamed audio or `)` for spatialized stereo. They are part of the value.
+pub const WAVE_PREFIXES: &[char] = &['*', '?', '!', '#', '>', '<', '^', '@', ')', '}'];
+
+/// Splits the playback prefix characters off a wave path, returning `(prefixes, path)`.
+pub fn split_wave_prefixes(wave: &str) -> (&str, &str) {
+    let path = wave.trim_start_matches(WAVE_PREFIXES);
+    (&wave[..wave.len() - path.len()], path)
+}
+
+impl<'a> Object<'a> {
+    /// Returns the wave paths of a `game_sounds` or soundscape entry: each `wave` value, then
+    /// each `wave` inside an `rndwave` block, in the order they are written within each. Keys
+    /// are matched ignoring case, as the engine does. Paths keep their playback prefixes; see
+    /// `split_wave_prefixes`.
+    pub fn waves(&self) -> Vec<&str> {
+        let mut waves: Vec<&str> = self.string_values("wave").collect();
+
+        for (key, _, value) in self.iter() {
+            if let Value::Object(random_waves) = value {
+                if key.eq_ignore_ascii_case("rndwave") {
+                    waves.extend(random_waves.string_values("wave"));
+                }
+            }
+        }
+
+        waves
+    }
+
+    fn string_values<'s>(&'s self, key: &'s str) -> impl Iterator<Item = &'s str> {
+        self.iter()
+            .filter(move |(entry_key, _, _)| entry_key.eq_ignore_ascii_case(key))
+            .filter_map(|(_, _, value)| match value {
+                Value::String(string) => Some(string.as_str()),
+                _ => None,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::split_wave_prefixes;
+    use crate::kv::{KeyValues, Value};
+
+    #[test]
+    fn game_sounds() {
+        let kv = KeyValues::from_io(
+            r#"
+            "Weapon_Pistol.Single"
+            {
+                "channel"    "CHAN_WEAPON"
+                "soundlevel" "SNDLVL_GUNFIRE"
+                "RndWave"
+                {
+                    "Wave" ")weapons/pistol/pistol_fire2.wav"
+                    wave   ^weapons/pistol/pistol_fire3.wav
+                    wave   >weapons/pistol/pistol_fire4.wav
+                }
+            }
+            Ambient.Drone
+            {
+                wave *#ambient/atmosphere/drone1lp.wav
+                WAVE <ambient/levels/citadel/drone2.wav
+            }
+            "#
+            .as_bytes(),
+        )
+        .unwrap();
+
+        let Some(Value::Object(pistol)) = kv.get("Weapon_Pistol.Single") else {
+            panic!()
+        };
+        assert_eq!(
+            pistol.waves(),
+            [
+                ")weapons/pistol/pistol_fire2.wav",
+                "^weapons/pistol/pistol_fire3.wav",
+                ">weapons/pistol/pistol_fire4.wav",
+            ]
+        );
+
+        let Some(Value::Object(drone)) = kv.get("Ambient.Drone") else {
+            panic!()
+        };
+        let waves = drone.waves();
+        assert_eq!(
+            waves,
+            [
+                "*#ambient/atmosphere/drone1lp.wav",
+                "<ambient/levels/citadel/drone2.wav"
+            ]
+        );
+
+        assert_eq!(
+            split_wave_prefixes(waves[0]),
+            ("*#", "ambient/atmosphere/drone1lp.wav")
+        );
+        assert_eq!(split_wave_prefixes("plain.wav"), ("", "plain.wav"));
+    }
+}
@@ -1,15 +1,20 @@
-use std::io::{Read, Result};
+use std::fmt;
+use std::io::{ErrorKind, Read};
 
 use bumpalo::collections::String;
 use bumpalo::Bump;
 use std::mem;
 
-const REWIND_SIZE: usize = 1;
+use super::{ParseOptions, Position, ReaderError, Result};
+
+/// Default number of bytes which can always be stepped back over, even across a buffer refill.
+pub const DEFAULT_REWIND_SIZE: usize = 1;
 const READ_SIZE: usize = 1024;
 
 #[derive(Debug, PartialEq, Clone)]
 pub enum Token<'a> {
-    Text(String<'a>),
+    /// A key, value or flag, and whether it was written in quotes.
+    Text(String<'a>, bool),
     OpenBlock,
     CloseBlock,
     OpenFlag,
@@ -22,12 +27,106 @@ impl<'a> Token<'a> {
     #[inline]
     pub fn unwrap_text(&mut self) -> String<'a> {
         match self {
-            Self::Text(data) => mem::replace(data, String::new_in(data.bump())),
+            Self::Text(data, _) => mem::replace(data, String::new_in(data.bump())),
             _ => panic!("called Token::unwrap_text() on {:?}", self),
         }
     }
 }
 
+/// The unit a document is indented with, as detected while reading it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Indent {
+    /// One tab per level. This is what Valve's own tools write, so it is also the fallback when
+    /// nothing in the document is indented.
+    #[default]
+    Tabs,
+    /// The given number of spaces per level.
+    Spaces(usize),
+}
+
+/// Caps on the resources parsing a document may use, for handling untrusted input. `None`
+/// means unlimited, which is the default for every limit.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ParseLimits {
+    /// Maximum number of bytes read from the input.
+    pub max_bytes: Option<u64>,
+    /// Maximum number of entries, counted across every object in the document.
+    pub max_entries: Option<usize>,
+    /// Maximum nesting depth of `{ }` blocks.
+    pub max_depth: Option<usize>,
+    /// Maximum length in bytes of a single key, value or flag.
+    pub max_string_length: Option<usize>,
+}
+
+/// Which of the `ParseLimits` was exceeded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitKind {
+    Bytes,
+    Entries,
+    Depth,
+    StringLength,
+}
+
+impl fmt::Display for LimitKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LimitKind::Bytes => write!(f, "maximum input size"),
+            LimitKind::Entries => write!(f, "maximum number of entries"),
+            LimitKind::Depth => write!(f, "maximum nesting depth"),
+            LimitKind::StringLength => write!(f, "maximum string length"),
+        }
+    }
+}
+
+/// What to do with NUL bytes in a document. Text files never contain them, so they usually mean
+/// binary data or corruption was fed to the parser.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum NulBytes {
+    /// Fail with `ReaderError::NulByte`.
+    #[default]
+    Reject,
+    /// Drop them, reading the document as if they were not there.
+    Strip,
+    /// Read them like any other character, so they end up in keys and values.
+    Keep,
+}
+
+/// Tallies the leading whitespace of each indented line.
+#[derive(Debug, Default, Clone, Copy)]
+pub(super) struct IndentStats {
+    tab_lines: usize,
+    space_lines: usize,
+    min_spaces: Option<usize>,
+}
+
+impl IndentStats {
+    fn record(&mut self, first: char, width: usize) {
+        if first == '\t' {
+            self.tab_lines += 1;
+        } else {
+            self.space_lines += 1;
+            self.min_spaces = Some(self.min_spaces.map_or(width, |min| min.min(width)));
+        }
+    }
+
+    /// Adds the lines tallied by `other`, read from another part of the same document.
+    pub(super) fn add(&mut self, other: &IndentStats) {
+        self.tab_lines += other.tab_lines;
+        self.space_lines += other.space_lines;
+        self.min_spaces = match (self.min_spaces, other.min_spaces) {
+            (Some(a), Some(b)) => Some(a.min(b)),
+            (a, b) => a.or(b),
+        };
+    }
+
+    pub(super) fn dominant(&self) -> Indent {
+        match self.min_spaces {
+            Some(width) if self.space_lines > self.tab_lines => Indent::Spaces(width),
+            _ => Indent::Tabs,
+        }
+    }
+}
+
 pub struct TokenReader<'a, R>
 where
     R: Read,
@@ -35,12 +134,60 @@ where
     reader: R,
     allocator: &'a Bump,
 
-    last_read: [u8; READ_SIZE + REWIND_SIZE],
+    // The first `rewind_size` bytes hold the tail of the previous read, so that rewinds stay
+    // valid immediately after a refill.
+    last_read: Box<[u8]>,
+    rewind_size: usize,
     last_token: Token<'a>,
     position: usize,
     max_read: usize,
 
     num_read: u64,
+    // Bytes read from the input so far, checked against `ParseLimits::max_bytes` as each read
+    // comes in, rather than as each character is consumed.
+    input_read: u64,
+    token_start: u64,
+    line: u32,
+    token_line: u32,
+    column: u32,
+    token_column: u32,
+
+    // Only kept up to date with `detect_indent`.
+    at_line_start: bool,
+    indent_stats: IndentStats,
+    detect_indent: bool,
+
+    shrink_strings: bool,
+    unicode_whitespace: bool,
+    block_comments: bool,
+    escape_sequences: bool,
+    discard_text: bool,
+    nul_bytes: NulBytes,
+    // Whether the input ran out inside quoted text or a block comment.
+    unclosed_at_end: bool,
+    // Set when the input carries on a document from straight after one of its tokens.
+    mid_document: bool,
+
+    limits: ParseLimits,
+    depth: usize,
+    entries: usize,
+}
+
+/// Collects the characters of a piece of text, or only counts them while text is discarded.
+struct TextBuffer<'a> {
+    text: String<'a>,
+    len: usize,
+    discard: bool,
+}
+
+impl<'a> TextBuffer<'a> {
+    fn push(&mut self, ch: char) {
+        self.len += ch.len_utf8();
+
+        if !self.discard {
+            self.text.push(ch);
+        }
+    }
 }
 
 const BASE_STRING_SIZE: usize = 1024;
@@ -52,28 +199,190 @@ const CLOSE_BLOCK: char = '}';
 const OPEN_FLAG: char = '[';
 const CLOSE_FLAG: char = ']';
 const NEGATE: char = '!';
+const NUL: char = '\0';
+const BLOCK_COMMENT: char = '*';
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+const MAX_CHAR_WIDTH: usize = 4;
+
+/// The width of the UTF-8 sequence `first` starts, going by its first byte alone. Bytes which
+/// cannot start a sequence count as one byte, and fail to decode.
+fn char_width(first: u8) -> usize {
+    match first {
+        0xC0..=0xDF => 2,
+        0xE0..=0xEF => 3,
+        0xF0..=0xF7 => 4,
+        _ => 1,
+    }
+}
 
 impl<'a, R: Read> TokenReader<'a, R> {
-    pub fn from_io(mut read: R, allocator: &'a Bump) -> Result<Self> {
-        let mut last_read = [0u8; READ_SIZE + REWIND_SIZE];
-        let max_read: usize = read.read(&mut last_read[REWIND_SIZE..])? + REWIND_SIZE;
+    pub fn from_io(read: R, allocator: &'a Bump) -> Result<Self> {
+        Self::from_io_with_rewind_size(read, allocator, DEFAULT_REWIND_SIZE)
+    }
+
+    /// Creates a reader which can step back over up to `rewind_size` bytes at any point. At least
+    /// `DEFAULT_REWIND_SIZE` bytes are kept regardless, as the tokenizer steps back itself.
+    pub fn from_io_with_rewind_size(
+        read: R,
+        allocator: &'a Bump,
+        rewind_size: usize,
+    ) -> Result<Self> {
+        Self::with_buffer_sizes(
+            read,
+            allocator,
+            READ_SIZE,
+            rewind_size,
+            &ParseOptions::default(),
+        )
+    }
 
-        let mut new_self = Self {
+    /// Like `from_io`, with the tokenizing settings of `options` in effect from the first token.
+    /// Setting them afterwards is too late for the first token, which is read on creation.
+    pub(super) fn from_io_with_options(
+        read: R,
+        allocator: &'a Bump,
+        options: &ParseOptions,
+    ) -> Result<Self> {
+        Self::with_buffer_sizes(read, allocator, READ_SIZE, DEFAULT_REWIND_SIZE, options)
+    }
+
+    /// Like `from_io_with_options`, for input which carries on a document from straight after
+    /// one of its tokens, such as the rest of a document arriving in pieces. A BOM is not skipped
+    /// and the first line is not taken to be indented. Offsets and lines count from the start of
+    /// `read`.
+    pub(super) fn resume_with_options(
+        read: R,
+        allocator: &'a Bump,
+        options: &ParseOptions,
+    ) -> Result<Self> {
+        let mut new_self =
+            Self::unstarted(read, allocator, READ_SIZE, DEFAULT_REWIND_SIZE, options);
+        new_self.mid_document = true;
+        new_self.start()?;
+
+        Ok(new_self)
+    }
+
+    fn with_buffer_sizes(
+        read: R,
+        allocator: &'a Bump,
+        read_size: usize,
+        rewind_size: usize,
+        options: &ParseOptions,
+    ) -> Result<Self> {
+        let mut new_self = Self::unstarted(read, allocator, read_size, rewind_size, options);
+        new_self.start()?;
+
+        Ok(new_self)
+    }
+
+    fn unstarted(
+        read: R,
+        allocator: &'a Bump,
+        read_size: usize,
+        rewind_size: usize,
+        options: &ParseOptions,
+    ) -> Self {
+        // A `/` which turns out not to start a comment is stepped back over once the character
+        // after it has been peeked, which may only be in the buffer after a refill.
+        let rewind_size = rewind_size.max(DEFAULT_REWIND_SIZE);
+
+        Self {
             reader: read,
-            allocator: allocator,
+            allocator,
 
-            last_read: last_read,
+            // Room for at least one whole character after the rewind region.
+            last_read: vec![0u8; read_size.max(MAX_CHAR_WIDTH) + rewind_size].into_boxed_slice(),
+            rewind_size,
             last_token: Token::Eof,
-            position: REWIND_SIZE,
-            max_read: max_read,
+            position: rewind_size,
+            max_read: rewind_size,
 
             num_read: 0,
-        };
+            input_read: 0,
+            token_start: 0,
+            line: 1,
+            token_line: 1,
+            column: 1,
+            token_column: 1,
+
+            at_line_start: true,
+            indent_stats: IndentStats::default(),
+            detect_indent: options.detect_indent,
+
+            shrink_strings: !options.keep_string_capacity,
+            unicode_whitespace: options.unicode_whitespace,
+            block_comments: options.block_comments,
+            escape_sequences: options.escape_sequences,
+            discard_text: false,
+            nul_bytes: options.nul_bytes,
+            unclosed_at_end: false,
+            mid_document: false,
+
+            limits: options.limits.clone(),
+            depth: 0,
+            entries: 0,
+        }
+    }
+
+    /// Starts reading a new document from `read`, reusing the existing buffer. Settings such as
+    /// limits are kept, while offsets, depth, entry counts and indentation start over.
+    pub fn reset(&mut self, read: R) -> Result<()> {
+        self.reader = read;
+        self.start()
+    }
+
+    fn start(&mut self) -> Result<()> {
+        self.max_read = self.rewind_size;
+        self.input_read = 0;
+        self.fill(UTF8_BOM.len())?;
+        self.position = self.rewind_size;
+        self.last_token = Token::Eof;
+        self.settle()?;
+
+        self.num_read = 0;
+        self.token_start = 0;
+        self.line = 1;
+        self.token_line = 1;
+        self.column = 1;
+        self.token_column = 1;
+
+        self.at_line_start = !self.mid_document;
+        self.indent_stats = IndentStats::default();
+
+        self.depth = 0;
+        self.entries = 0;
+
+        // Files saved by Windows editors often start with a BOM, which would otherwise be read as
+        // part of the first key. It still counts towards offsets, as it is part of the input.
+        if !self.mid_document && self.last_read[self.position..self.max_read].starts_with(UTF8_BOM)
+        {
+            self.advance_char()?;
+            self.at_line_start = true;
+            self.column = 1;
+        }
 
         // Initialise last_token, reading until there is no whitespace
-        new_self.advance()?;
+        self.advance()
+    }
 
-        Ok(new_self)
+    /// Reads until at least `len` bytes are buffered, the buffer is full or the input ends, as a
+    /// single read may return fewer bytes than are available.
+    fn fill(&mut self, len: usize) -> Result<()> {
+        let wanted = usize::min(self.rewind_size + len, self.last_read.len());
+
+        while self.max_read < wanted {
+            match self.read_into(self.max_read)? {
+                0 => break,
+                num_read => {
+                    self.max_read += num_read;
+                    self.input_read += num_read as u64;
+                    self.check_limit(LimitKind::Bytes)?;
+                }
+            }
+        }
+
+        Ok(())
     }
 
     #[inline]
@@ -81,18 +390,142 @@ impl<'a, R: Read> TokenReader<'a, R> {
         &mut self.last_token
     }
 
+    /// The input being read, which may have been read some way past the current token.
+    pub(super) fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    #[inline]
+    pub fn allocator(&self) -> &'a Bump {
+        self.allocator
+    }
+
+    /// Byte offset into the input at which the current token starts.
+    #[inline]
+    pub fn token_start(&self) -> u64 {
+        self.token_start
+    }
+
+    /// Byte offset into the input just past the end of the current token. Whitespace and
+    /// comments after a token are only skipped when the next one is read.
+    #[inline]
+    pub(super) fn token_end(&self) -> u64 {
+        self.peek_pos()
+    }
+
+    /// Line of the input, counting from 1, on which the current token starts.
+    #[inline]
+    pub fn token_line(&self) -> u32 {
+        self.token_line
+    }
+
+    /// Where the current token starts, as attached to `ReaderError`s.
+    pub fn token_position(&self) -> Position {
+        Position {
+            offset: self.token_start,
+            line: self.token_line,
+            column: self.token_column,
+        }
+    }
+
+    /// An `InvalidToken` error for the current token.
+    pub(super) fn invalid_token(&self) -> ReaderError {
+        ReaderError::InvalidToken(format!("{:?}", self.last_token), self.token_position())
+    }
+
+    /// An `UnexpectedEof` error at the end of the input.
+    pub(super) fn unexpected_eof(&self) -> ReaderError {
+        ReaderError::UnexpectedEof(self.token_position())
+    }
+
+    /// Sets whether text tokens are read empty, for scanning a document's structure without
+    /// allocating its text. Length limits still apply. Applies from the next token read.
+    pub(super) fn set_discard_text(&mut self, discard_text: bool) {
+        self.discard_text = discard_text;
+    }
+
+    /// Whether the input ended inside quoted text or a block comment, which the tokens read
+    /// alone do not show.
+    pub(super) fn unclosed_at_end(&self) -> bool {
+        self.unclosed_at_end
+    }
+
+    #[inline]
+    fn is_whitespace(&self, ch: char) -> bool {
+        if self.unicode_whitespace {
+            ch.is_whitespace()
+        } else {
+            ch.is_ascii_whitespace()
+        }
+    }
+
+    /// Counts an entry of the document being built against `ParseLimits::max_entries`.
+    pub(super) fn count_entry(&mut self) -> Result<()> {
+        self.entries += 1;
+        self.check_limit(LimitKind::Entries)
+    }
+
+    fn check_limit(&self, kind: LimitKind) -> Result<()> {
+        let exceeded = match kind {
+            LimitKind::Bytes => self
+                .limits
+                .max_bytes
+                .is_some_and(|max| self.input_read > max),
+            LimitKind::Entries => self
+                .limits
+                .max_entries
+                .is_some_and(|max| self.entries > max),
+            LimitKind::Depth => self.limits.max_depth.is_some_and(|max| self.depth > max),
+            LimitKind::StringLength => false,
+        };
+
+        if exceeded {
+            return Err(ReaderError::LimitExceeded(kind));
+        }
+
+        Ok(())
+    }
+
+    #[inline]
+    fn check_string_length(&self, length: usize) -> Result<()> {
+        match self.limits.max_string_length {
+            Some(max) if length > max => Err(ReaderError::LimitExceeded(LimitKind::StringLength)),
+            _ => Ok(()),
+        }
+    }
+
+    /// The dominant indentation of the lines read so far, or `Indent::Tabs` unless
+    /// `ParseOptions::detect_indent` is set.
+    pub fn detected_indent(&self) -> Indent {
+        self.indent_stats.dominant()
+    }
+
+    /// The indentation tallied so far, as used for `detected_indent`.
+    pub(super) fn indent_stats(&self) -> IndentStats {
+        self.indent_stats
+    }
+
     pub fn advance(&mut self) -> Result<()> {
         loop {
+            self.token_start = self.peek_pos();
+            self.token_line = self.line;
+            self.token_column = self.column;
+
             match self.peek_char() {
                 None => self.last_token = Token::Eof,
                 Some(ch) => match ch {
                     OPEN_BLOCK => {
                         self.last_token = Token::OpenBlock;
                         self.advance_char()?;
+
+                        self.depth += 1;
+                        self.check_limit(LimitKind::Depth)?;
                     }
                     CLOSE_BLOCK => {
                         self.last_token = Token::CloseBlock;
                         self.advance_char()?;
+
+                        self.depth = self.depth.saturating_sub(1);
                     }
                     OPEN_FLAG => {
                         self.last_token = Token::OpenFlag;
@@ -107,7 +540,11 @@ impl<'a, R: Read> TokenReader<'a, R> {
                         self.advance_char()?;
                     }
                     _ => {
-                        if ch.is_whitespace() {
+                        if ch == NUL && self.skip_nul()? {
+                            continue;
+                        }
+
+                        if self.is_whitespace(ch) {
                             self.consume_whitespace()?;
                             continue;
                         }
@@ -115,26 +552,34 @@ impl<'a, R: Read> TokenReader<'a, R> {
                         if ch == COMMENT {
                             self.advance_char()?;
 
-                            let new_peek = self.peek_char();
-                            if new_peek.is_none() {
-                                let mut new_string = String::with_capacity_in(1, self.allocator);
-                                new_string.push(ch);
-                                self.last_token = Token::Text(new_string);
-                                break;
-                            } else if new_peek.unwrap() != COMMENT {
-                                self.rewind_char(new_peek.unwrap());
-                                continue;
-                            } else {
-                                // Properly formed comment
-                                self.consume_comment()?;
-                                continue;
+                            match self.peek_char() {
+                                None => {
+                                    let mut new_string =
+                                        String::with_capacity_in(1, self.allocator);
+                                    new_string.push(ch);
+                                    self.last_token = Token::Text(new_string, false);
+                                    break;
+                                }
+                                Some(COMMENT) => {
+                                    // Properly formed comment
+                                    self.consume_comment()?;
+                                    continue;
+                                }
+                                Some(BLOCK_COMMENT) if self.block_comments => {
+                                    self.consume_block_comment()?;
+                                    continue;
+                                }
+                                Some(_) => {
+                                    // Not a comment, so the / starts a piece of text.
+                                    self.rewind_n(1);
+                                }
                             }
                         }
 
                         if ch == QUOTE {
-                            self.last_token = Token::Text(self.read_quoted_text()?);
+                            self.last_token = Token::Text(self.read_quoted_text()?, true);
                         } else {
-                            self.last_token = Token::Text(self.read_unquoted_text()?);
+                            self.last_token = Token::Text(self.read_unquoted_text()?, false);
                         }
                     }
                 },
@@ -162,15 +607,61 @@ impl<'a, R: Read> TokenReader<'a, R> {
         Ok(())
     }
 
+    fn consume_block_comment(&mut self) -> Result<()> {
+        // Assumes peek_char() gives us the *. Tracking the previous character rather than
+        // peeking means `/*/` does not close itself.
+        self.advance_char()?;
+        let mut after_star = false;
+
+        while let Some(data) = self.peek_char() {
+            self.advance_char()?;
+
+            if after_star && data == COMMENT {
+                return Ok(());
+            }
+            after_star = data == BLOCK_COMMENT;
+        }
+
+        self.unclosed_at_end = true;
+        Ok(())
+    }
+
     #[inline]
     fn consume_whitespace(&mut self) -> Result<()> {
-        self.advance_char()?;
+        if !self.detect_indent {
+            while self
+                .peek_char()
+                .is_some_and(|data| self.is_whitespace(data))
+            {
+                self.advance_char()?;
+            }
+
+            return Ok(());
+        }
+
+        // The first character and width of the current line's leading whitespace.
+        let mut line_indent: Option<(char, usize)> = None;
 
         while let Some(data) = self.peek_char() {
-            if !data.is_whitespace() {
+            if !self.is_whitespace(data) {
+                if let (true, Some((first, width))) = (self.at_line_start, line_indent) {
+                    self.indent_stats.record(first, width);
+                }
+
                 break;
             }
 
+            match data {
+                '\n' => line_indent = None,
+                ' ' | '\t' if self.at_line_start => {
+                    line_indent = match line_indent {
+                        None => Some((data, 1)),
+                        Some((first, width)) => Some((first, width + 1)),
+                    };
+                }
+                _ => {}
+            }
+
             self.advance_char()?;
         }
 
@@ -179,31 +670,63 @@ impl<'a, R: Read> TokenReader<'a, R> {
 
     fn read_quoted_text(&mut self) -> Result<String<'a>> {
         self.advance_char()?;
-        let mut new_string = String::with_capacity_in(BASE_STRING_SIZE, self.allocator);
+        let mut new_string = self.text_buffer();
+        let mut closed = false;
 
         while let Some(data) = self.peek_char() {
+            if data == NUL && self.skip_nul()? {
+                continue;
+            }
+
             self.advance_char()?;
 
             if data == '"' {
+                closed = true;
                 break;
             }
 
+            if data == ESCAPE && self.escape_sequences {
+                match self.peek_char() {
+                    None => new_string.push(ESCAPE),
+                    Some(escaped) => {
+                        new_string.push(match escaped {
+                            'n' => '\n',
+                            't' => '\t',
+                            _ => escaped,
+                        });
+                        self.advance_char()?;
+                    }
+                }
+
+                continue;
+            }
+
             new_string.push(data);
         }
+        self.unclosed_at_end |= !closed;
 
-        new_string.shrink_to_fit();
+        // Checked once the token is read, rather than as each character is added.
+        self.check_string_length(new_string.len)?;
+        let mut new_string = new_string.text;
+        if self.shrink_strings {
+            new_string.shrink_to_fit();
+        }
 
         Ok(new_string)
     }
 
     fn read_unquoted_text(&mut self) -> Result<String<'a>> {
-        let mut new_string = String::with_capacity_in(BASE_STRING_SIZE, self.allocator);
+        let mut new_string = self.text_buffer();
 
         while let Some(data) = self.peek_char() {
+            if data == NUL && self.skip_nul()? {
+                continue;
+            }
+
             match data {
                 OPEN_BLOCK | CLOSE_BLOCK | OPEN_FLAG | CLOSE_FLAG | NEGATE => break,
                 _ => {
-                    if data.is_whitespace() {
+                    if self.is_whitespace(data) {
                         break;
                     }
                 }
@@ -211,15 +734,19 @@ impl<'a, R: Read> TokenReader<'a, R> {
 
             self.advance_char()?;
 
+            // `advance_char` refills the buffer once it is used up, so the escaped character can
+            // be peeked even when the escape was the last byte read. `None` is the end of input.
             if data == ESCAPE {
                 match self.peek_char() {
                     None => {
                         new_string.push(ESCAPE);
                         break;
                     }
+                    Some(NUL) if self.skip_nul()? => continue,
                     Some(new_peek) => {
                         new_string.push(new_peek);
                         self.advance_char()?;
+                        continue;
                     }
                 }
             }
@@ -234,6 +761,10 @@ impl<'a, R: Read> TokenReader<'a, R> {
                         self.consume_comment()?;
                         break;
                     }
+                    Some(BLOCK_COMMENT) if self.block_comments => {
+                        self.consume_block_comment()?;
+                        continue;
+                    }
                     _ => {}
                 }
             }
@@ -241,32 +772,635 @@ impl<'a, R: Read> TokenReader<'a, R> {
             new_string.push(data);
         }
 
-        new_string.shrink_to_fit();
+        self.check_string_length(new_string.len)?;
+        let mut new_string = new_string.text;
+        if self.shrink_strings {
+            new_string.shrink_to_fit();
+        }
         Ok(new_string)
     }
 
-    fn rewind_char(&mut self, rewind: char) {
-        self.last_read[self.position] = rewind as u8;
-        self.position -= 1;
+    /// Handles the NUL byte `peek_char` returns, as set by `NulBytes`. Returns whether it was
+    /// stripped, or fails if NUL bytes are rejected.
+    fn skip_nul(&mut self) -> Result<bool> {
+        match self.nul_bytes {
+            NulBytes::Reject => Err(ReaderError::NulByte(self.peek_pos())),
+            NulBytes::Strip => {
+                self.advance_char()?;
+                Ok(true)
+            }
+            NulBytes::Keep => Ok(false),
+        }
+    }
+
+    fn text_buffer(&self) -> TextBuffer<'a> {
+        TextBuffer {
+            text: if self.discard_text {
+                String::new_in(self.allocator)
+            } else {
+                String::with_capacity_in(BASE_STRING_SIZE, self.allocator)
+            },
+            len: 0,
+            discard: self.discard_text,
+        }
+    }
+
+    /// Steps back `n` bytes. Up to the rewind size can be stepped back at any point, including
+    /// directly after a refill. Newlines stepped back over are still counted, as the reader only
+    /// ever steps back over the `/` of a possible comment, which is also why columns can be
+    /// stepped back by bytes.
+    fn rewind_n(&mut self, n: usize) {
+        assert!(
+            n <= self.position,
+            "cannot rewind {} bytes with a rewind size of {}",
+            n,
+            self.rewind_size
+        );
+
+        self.position -= n;
+        self.num_read -= n as u64;
+        self.column = self.column.saturating_sub(n as u32);
     }
 
     fn advance_char(&mut self) -> Result<()> {
-        self.position += 1;
-        self.num_read += 1;
+        let Some(ch) = self.peek_char() else {
+            return Ok(());
+        };
 
-        if self.position >= self.max_read {
-            self.max_read = self.reader.read(&mut self.last_read[REWIND_SIZE..])? + REWIND_SIZE;
-            self.position = REWIND_SIZE;
+        match ch {
+            '\n' => {
+                self.at_line_start = true;
+                self.line = self.line.saturating_add(1);
+                self.column = 0;
+            }
+            _ if self.detect_indent && !self.is_whitespace(ch) => self.at_line_start = false,
+            _ => {}
+        }
+        self.column = self.column.saturating_add(1);
+
+        self.position += ch.len_utf8();
+        self.num_read += ch.len_utf8() as u64;
+
+        self.settle()
+    }
+
+    /// Makes sure the whole character at the current position is buffered, refilling once the
+    /// buffer is used up or ends part way through a character, and that it is valid UTF-8.
+    fn settle(&mut self) -> Result<()> {
+        loop {
+            let buffered = self.max_read - self.position;
+            let width = match buffered {
+                0 => 1,
+                _ => char_width(self.last_read[self.position]),
+            };
+
+            if buffered >= width {
+                break;
+            }
+
+            // Carry the tail of this read, and any part of a character, over into the rewind
+            // region and straight after it before refilling.
+            let keep_from = self.position - self.rewind_size;
+            self.last_read.copy_within(keep_from..self.max_read, 0);
+            self.position = self.rewind_size;
+            self.max_read -= keep_from;
+
+            // Reading nothing leaves `position == max_read`, which `peek_char` reads as the end
+            // of the input, with the rewind region still intact. Only the first byte of a
+            // character may have arrived, so go round again to fetch the rest.
+            self.fill(width)?;
+            if self.max_read - self.position == buffered {
+                break;
+            }
+        }
+
+        if self.position < self.max_read && self.decode_char().is_none() {
+            return Err(ReaderError::InvalidUtf8(self.peek_pos()));
         }
 
         Ok(())
     }
 
+    /// Reads into the buffer from `start`, returning how many bytes were read, 0 only at the end
+    /// of the input. Interrupted reads are retried, rather than failing the parse.
+    fn read_into(&mut self, start: usize) -> std::io::Result<usize> {
+        loop {
+            match self.reader.read(&mut self.last_read[start..]) {
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                read => return read,
+            }
+        }
+    }
+
+    /// Byte offset into the input of the character `peek_char` returns.
+    #[inline]
+    fn peek_pos(&self) -> u64 {
+        self.num_read
+    }
+
+    /// The character at the current position, which `settle` has checked, or `None` at the end of
+    /// the input.
     fn peek_char(&self) -> Option<char> {
-        if self.max_read == REWIND_SIZE {
+        if self.position >= self.max_read {
             return None;
         }
 
-        return Some(self.last_read[self.position] as char);
+        self.decode_char()
+    }
+
+    /// Decodes the UTF-8 character at the current position, if it is valid and all buffered.
+    fn decode_char(&self) -> Option<char> {
+        let first = self.last_read[self.position];
+        if first.is_ascii() {
+            return Some(first as char);
+        }
+
+        let end = self.position + char_width(first);
+        let bytes = self.last_read[..self.max_read].get(self.position..end)?;
+
+        std::str::from_utf8(bytes).ok()?.chars().next()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Read, Result};
+
+    use bumpalo::collections::String;
+    use bumpalo::Bump;
+
+    use super::{Indent, Token, TokenReader};
+    use crate::kv::{ParseOptions, ReaderError};
+
+    /// The text of a `Text` token, or `None` for any other token.
+    fn text_of<'t>(token: &'t Token) -> Option<&'t str> {
+        match token {
+            Token::Text(text, _) => Some(text.as_str()),
+            _ => None,
+        }
+    }
+
+    /// Serves its data a few bytes per read, to force buffer refills.
+    struct ChunkedReader<'a> {
+        data: &'a [u8],
+        chunk_size: usize,
+    }
+
+    impl<'a> Read for ChunkedReader<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            let num_read = self.chunk_size.min(buf.len()).min(self.data.len());
+            buf[..num_read].copy_from_slice(&self.data[..num_read]);
+            self.data = &self.data[num_read..];
+
+            Ok(num_read)
+        }
+    }
+
+    /// Fails every other read as interrupted, serving the rest a chunk at a time.
+    struct InterruptingReader<'a> {
+        inner: ChunkedReader<'a>,
+        interrupt: bool,
+    }
+
+    impl<'a> Read for InterruptingReader<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+            self.interrupt = !self.interrupt;
+            if self.interrupt {
+                return Err(std::io::ErrorKind::Interrupted.into());
+            }
+
+            self.inner.read(buf)
+        }
+    }
+
+    fn read_all_tokens<R: Read>(token_reader: &mut TokenReader<'_, R>) -> Vec<std::string::String> {
+        let mut tokens = Vec::new();
+
+        while *token_reader.peek() != Token::Eof {
+            tokens.push(format!("{:?}", token_reader.peek()));
+            token_reader.advance().unwrap();
+        }
+
+        // Staying at the end, without reading again as if it were a zero byte.
+        token_reader.advance().unwrap();
+        assert_eq!(*token_reader.peek(), Token::Eof);
+        assert_eq!(token_reader.peek_char(), None);
+
+        tokens
+    }
+
+    #[test]
+    fn eof_at_buffer_boundary() {
+        let read_size = super::READ_SIZE;
+
+        // Each input ends with its last token exactly filling the buffer, once for each kind of
+        // token which can end a document.
+        let padding = |suffix: &str| "x".repeat(read_size - suffix.len() - 1) + " " + suffix;
+        let inputs = [
+            padding("a"),
+            padding("\"ab\""),
+            padding("{}"),
+            padding("[a]"),
+            padding("//"),
+            "k".repeat(read_size),
+            "k".repeat(read_size * 2),
+        ];
+
+        for text in &inputs {
+            assert_eq!(text.len() % read_size, 0);
+
+            let expected = {
+                let allocator = Bump::new();
+                let mut token_reader = TokenReader::with_buffer_sizes(
+                    text.as_bytes(),
+                    &allocator,
+                    text.len() * 2,
+                    super::DEFAULT_REWIND_SIZE,
+                    &Default::default(),
+                )
+                .unwrap();
+                read_all_tokens(&mut token_reader)
+            };
+            assert!(!expected.is_empty());
+
+            let allocator = Bump::new();
+            let mut token_reader = TokenReader::from_io(text.as_bytes(), &allocator).unwrap();
+            assert_eq!(read_all_tokens(&mut token_reader), expected, "{text:?}");
+
+            for chunk_size in [1, 2, read_size / 2, read_size] {
+                let allocator = Bump::new();
+                let read = InterruptingReader {
+                    inner: ChunkedReader {
+                        data: text.as_bytes(),
+                        chunk_size,
+                    },
+                    interrupt: false,
+                };
+
+                let mut token_reader = TokenReader::from_io(read, &allocator).unwrap();
+                assert_eq!(read_all_tokens(&mut token_reader), expected, "{chunk_size}");
+            }
+        }
+    }
+
+    #[test]
+    fn rewind_across_refill() {
+        let allocator = Bump::new();
+        let read = ChunkedReader {
+            data: b"abc def",
+            chunk_size: 3,
+        };
+
+        let mut token_reader = TokenReader::from_io_with_rewind_size(read, &allocator, 2).unwrap();
+        assert_eq!(text_of(token_reader.peek()), Some("abc"));
+
+        // The first read ended at "c", so the space after it was read in by a refill.
+        assert_eq!(token_reader.peek_char(), Some(' '));
+        token_reader.rewind_n(2);
+        assert_eq!(token_reader.peek_char(), Some('b'));
+        token_reader.advance_char().unwrap();
+        assert_eq!(token_reader.peek_char(), Some('c'));
+        token_reader.advance_char().unwrap();
+        assert_eq!(token_reader.peek_char(), Some(' '));
+
+        token_reader.advance().unwrap();
+        assert_eq!(text_of(token_reader.peek()), Some("def"));
+    }
+
+    #[test]
+    fn unquoted_text_across_refills() {
+        let allocator = Bump::new();
+        let value: std::string::String = (0..3000)
+            .map(|i| char::from(b'a' + (i % 26) as u8))
+            .collect();
+        let text = format!("key {value} next");
+
+        let mut token_reader = TokenReader::from_io(text.as_bytes(), &allocator).unwrap();
+        token_reader.advance().unwrap();
+        assert_eq!(text_of(token_reader.peek()), Some(value.as_str()));
+        token_reader.advance().unwrap();
+        assert_eq!(text_of(token_reader.peek()), Some("next"));
+    }
+
+    #[test]
+    fn escapes_across_refills() {
+        let cases: [(&[u8], &str); 4] = [
+            (br"a\\b", r"a\b"),
+            (br"a\\\\b", r"a\\b"),
+            (br"a\ b\}c", "a b}c"),
+            (br"a\", r"a\"),
+        ];
+
+        for (text, expected) in cases {
+            for chunk_size in 1..=3 {
+                let allocator = Bump::new();
+                let read = ChunkedReader {
+                    data: text,
+                    chunk_size,
+                };
+
+                let token_reader = TokenReader::from_io(read, &allocator).unwrap();
+                assert_eq!(text_of(&token_reader.last_token), Some(expected));
+            }
+        }
+
+        // The escape is the last byte of the first read, and what it escapes the first of the
+        // next, both for an escaped delimiter and an escaped escape.
+        for (escaped, expected) in [(" ", " "), ("\\", "\\")] {
+            let padding = "x".repeat(super::READ_SIZE - 1);
+            let text = format!("{padding}\\{escaped}y next");
+
+            let allocator = Bump::new();
+            let mut token_reader = TokenReader::from_io(text.as_bytes(), &allocator).unwrap();
+            assert_eq!(
+                text_of(token_reader.peek()),
+                Some(format!("{padding}{expected}y").as_str())
+            );
+            token_reader.advance().unwrap();
+            assert_eq!(text_of(token_reader.peek()), Some("next"));
+        }
+    }
+
+    #[test]
+    fn char_offsets() {
+        let allocator = Bump::new();
+        let text = "a  // comment\n\t\"b\"";
+        let mut token_reader = TokenReader::from_io(text.as_bytes(), &allocator).unwrap();
+
+        // The reader has just read "a", so step over the whitespace and comment after it.
+        token_reader.consume_whitespace().unwrap();
+        assert_eq!(token_reader.peek_pos(), text.find('/').unwrap() as u64);
+
+        token_reader.advance_char().unwrap();
+        token_reader.consume_comment().unwrap();
+        token_reader.consume_whitespace().unwrap();
+
+        assert_eq!(token_reader.peek_char(), Some('"'));
+        assert_eq!(token_reader.peek_pos(), text.find('"').unwrap() as u64);
+    }
+
+    #[test]
+    fn slash_starting_text() {
+        let allocator = Bump::new();
+        let mut token_reader =
+            TokenReader::from_io("/a/b // comment".as_bytes(), &allocator).unwrap();
+
+        assert_eq!(text_of(token_reader.peek()), Some("/a/b"));
+        token_reader.advance().unwrap();
+        assert_eq!(*token_reader.peek(), Token::Eof);
+    }
+
+    #[test]
+    fn slash_at_refill() {
+        // The `/` is the last byte of the first read, so the rewind after peeking at the `c`
+        // following it comes straight after a refill.
+        for rewind_size in [0, 1, 4] {
+            let allocator = Bump::new();
+            let read = ChunkedReader {
+                data: b"ab /cd e",
+                chunk_size: 4,
+            };
+
+            let mut token_reader =
+                TokenReader::from_io_with_rewind_size(read, &allocator, rewind_size).unwrap();
+            token_reader.advance().unwrap();
+            assert_eq!(text_of(token_reader.peek()), Some("/cd"));
+            token_reader.advance().unwrap();
+            assert_eq!(text_of(token_reader.peek()), Some("e"));
+        }
+
+        let padding = "x".repeat(super::READ_SIZE - 2);
+        let text = format!("{padding} /cd");
+
+        let allocator = Bump::new();
+        let mut token_reader = TokenReader::from_io(text.as_bytes(), &allocator).unwrap();
+        token_reader.advance().unwrap();
+        assert_eq!(text_of(token_reader.peek()), Some("/cd"));
+    }
+
+    #[test]
+    fn multiline_quoted_text() {
+        let allocator = Bump::new();
+        let mut token_reader =
+            TokenReader::from_io("\"line1\nline2\"\n{".as_bytes(), &allocator).unwrap();
+
+        assert_eq!(text_of(token_reader.peek()), Some("line1\nline2"));
+        token_reader.advance().unwrap();
+
+        // The embedded newline is counted like any other byte.
+        assert_eq!(*token_reader.peek(), Token::OpenBlock);
+        assert_eq!(token_reader.token_start(), 14);
+    }
+
+    #[test]
+    fn detected_indent() {
+        let allocator = Bump::new();
+        let detect = |text: &str| {
+            let options = ParseOptions {
+                detect_indent: true,
+                ..Default::default()
+            };
+            let mut token_reader =
+                TokenReader::from_io_with_options(text.as_bytes(), &allocator, &options).unwrap();
+            while *token_reader.peek() != Token::Eof {
+                token_reader.advance().unwrap();
+            }
+
+            token_reader.detected_indent()
+        };
+
+        assert_eq!(detect("key val"), Indent::Tabs);
+        assert_eq!(detect("a {\n\tb {\n\t\tc d\n\t}\n}"), Indent::Tabs);
+        assert_eq!(
+            detect("a {\r\n    b {\r\n        c d // x\r\n        e f\r\n    }\r\n}"),
+            Indent::Spaces(4)
+        );
+    }
+
+    #[test]
+    fn reset_between_documents() {
+        let allocator = Bump::new();
+        let mut reader = TokenReader::from_io("first { a b".as_bytes(), &allocator).unwrap();
+
+        for _ in 0..3 {
+            reader.advance().unwrap();
+        }
+        assert_eq!(text_of(reader.peek()), Some("b"));
+
+        reader.reset("second\n\t{ }".as_bytes()).unwrap();
+        assert_eq!(reader.token_start(), 0);
+
+        let mut tokens = vec![reader.peek().clone()];
+        while *reader.peek() != Token::Eof {
+            reader.advance().unwrap();
+            tokens.push(reader.peek().clone());
+        }
+
+        assert_eq!(
+            tokens,
+            [
+                Token::Text(String::from_str_in("second", &allocator), false),
+                Token::OpenBlock,
+                Token::CloseBlock,
+                Token::Eof,
+            ]
+        );
+        assert_eq!(reader.detected_indent(), Indent::Tabs);
+    }
+
+    #[test]
+    fn quoted_text() {
+        let allocator = Bump::new();
+        let mut reader = TokenReader::from_io(r#""foo" foo"#.as_bytes(), &allocator).unwrap();
+
+        let quoted = reader.peek().clone();
+        reader.advance().unwrap();
+        let unquoted = reader.peek().clone();
+
+        assert_eq!(text_of(&quoted), text_of(&unquoted));
+        assert_ne!(quoted, unquoted);
+        assert!(matches!(quoted, Token::Text(_, true)));
+        assert!(matches!(unquoted, Token::Text(_, false)));
+    }
+
+    #[test]
+    fn ascii_whitespace() {
+        let allocator = Bump::new();
+        // A non-breaking space.
+        let text = "key a\u{a0}b".as_bytes();
+
+        let mut reader = TokenReader::from_io(text, &allocator).unwrap();
+        reader.advance().unwrap();
+        assert_eq!(text_of(reader.peek()), Some("a\u{a0}b"));
+
+        let options = ParseOptions {
+            unicode_whitespace: true,
+            ..Default::default()
+        };
+        let mut reader = TokenReader::from_io_with_options(text, &allocator, &options).unwrap();
+        reader.advance().unwrap();
+        assert_eq!(text_of(reader.peek()), Some("a"));
+    }
+
+    #[test]
+    fn utf8_text() {
+        let allocator = Bump::new();
+        let text = "\"café ↔\" {} naïve 𝄞";
+
+        let mut reader = TokenReader::from_io(text.as_bytes(), &allocator).unwrap();
+        let mut chars = Vec::new();
+        reader.rewind_n(reader.peek_pos() as usize);
+        while let Some(ch) = reader.peek_char() {
+            chars.push(ch);
+            reader.advance_char().unwrap();
+        }
+        assert_eq!(chars, text.chars().collect::<Vec<_>>());
+        assert_eq!(reader.peek_pos(), text.len() as u64);
+
+        // Characters split across reads, whichever byte the split falls on.
+        for chunk_size in 1..=4 {
+            let read = ChunkedReader {
+                data: text.as_bytes(),
+                chunk_size,
+            };
+            let mut reader = TokenReader::from_io(read, &allocator).unwrap();
+            assert_eq!(text_of(reader.peek()), Some("café ↔"));
+            reader.advance().unwrap();
+            reader.advance().unwrap();
+            reader.advance().unwrap();
+            assert_eq!(text_of(reader.peek()), Some("naïve"));
+            assert_eq!(reader.token_start(), 15);
+            reader.advance().unwrap();
+            assert_eq!(text_of(reader.peek()), Some("𝄞"));
+        }
+
+        // And across refills of the buffer, at every offset into a four byte character.
+        for shift in 0..4 {
+            let padding = "x".repeat(super::READ_SIZE - 3 + shift);
+            let text = format!("{padding} 𝄞𝄞");
+
+            let mut reader = TokenReader::from_io(text.as_bytes(), &allocator).unwrap();
+            reader.advance().unwrap();
+            assert_eq!(text_of(reader.peek()), Some("𝄞𝄞"));
+        }
+    }
+
+    #[test]
+    fn invalid_utf8() {
+        let allocator = Bump::new();
+
+        for (text, offset) in [
+            (&b"key caf\xe9"[..], 7),
+            (b"key \"\xff\"", 5),
+            (b"key va\xc3", 6),
+            (b"\xed\xa0\x80", 0),
+        ] {
+            let result = TokenReader::from_io(text, &allocator).and_then(|mut reader| {
+                while *reader.peek() != Token::Eof {
+                    reader.advance()?;
+                }
+                Ok(())
+            });
+
+            assert!(matches!(result, Err(ReaderError::InvalidUtf8(at)) if at == offset));
+        }
+    }
+
+    #[test]
+    fn leading_bom() {
+        let allocator = Bump::new();
+        let text = b"\xEF\xBB\xBF\"key\" value";
+
+        // Also when the BOM arrives over more than one read.
+        for chunk_size in [1, 2, text.len()] {
+            let read = ChunkedReader {
+                data: text,
+                chunk_size,
+            };
+
+            let mut reader = TokenReader::from_io(read, &allocator).unwrap();
+            assert_eq!(text_of(reader.peek()), Some("key"));
+            assert_eq!(reader.token_start(), 3);
+        }
+
+        // Only at the very start of the input.
+        let mut reader =
+            TokenReader::from_io(b"key \xEF\xBB\xBFvalue".as_slice(), &allocator).unwrap();
+        reader.advance().unwrap();
+        assert_eq!(text_of(reader.peek()), Some("\u{feff}value"));
+
+        let err = TokenReader::from_io(b"\xEF\xBB".as_slice(), &allocator)
+            .err()
+            .unwrap();
+        assert!(matches!(err, ReaderError::InvalidUtf8(0)));
+    }
+
+    #[test]
+    fn block_comments() {
+        let allocator = Bump::new();
+        let text = r#"/* header */ key tex/*note*/ture "quoted /* kept */" a/*/ b */c/d /* open"#;
+
+        let tokens = |block_comments| {
+            let options = ParseOptions {
+                block_comments,
+                ..Default::default()
+            };
+            let mut reader =
+                TokenReader::from_io_with_options(text.as_bytes(), &allocator, &options).unwrap();
+
+            let mut tokens = Vec::new();
+            while let Some(text) = text_of(reader.peek()) {
+                tokens.push(text.to_string());
+                reader.advance().unwrap();
+            }
+
+            tokens
+        };
+
+        assert_eq!(
+            tokens(true),
+            ["key", "texture", "quoted /* kept */", "ac/d"]
+        );
+        assert_eq!(tokens(false)[..3], ["/*", "header", "*/"]);
     }
 }
@@ -0,0 +1,191 @@
+use super::{Object, ValidationError, Value, ValueKind};
+
+#[cfg(feature = "derive")]
+pub use srcrs_derive::FromKeyValues;
+
+/// Builds a value from a parsed object, without keeping anything borrowed from its arena.
+///
+/// With the `derive` feature this can be derived for structs with named fields. Each field is
+/// read from the entries under the field's name, compared case-insensitively: strings from string
+/// values, nested structs from objects, `Option<T>` from a key which may be missing and `Vec<T>`
+/// from every entry under a repeated key. `#[kv(rename = "$key")]` reads a field from a
+/// different key.
+///
+/// # Examples
+/// ```
+/// # #[cfg(feature = "derive")]
+/// # {
+/// use srcrs::kv::{FromKeyValues, KeyValues};
+///
+/// #[derive(FromKeyValues)]
+/// struct Material {
+///     #[kv(rename = "$basetexture")]
+///     base_texture: String,
+///     #[kv(rename = "$surfaceprop")]
+///     surface_prop: Option<String>,
+/// }
+///
+/// let kv = KeyValues::from_io(r#"$basetexture "brick/brickwall001""#.as_bytes()).unwrap();
+/// let material = Material::from_object(kv.root()).unwrap();
+///
+/// assert_eq!(material.base_texture, "brick/brickwall001");
+/// assert_eq!(material.surface_prop, None);
+/// # }
+/// ```
+pub trait FromKeyValues: Sized {
+    /// Builds `Self` from `object`, which was found at `path`. Paths in errors are `/`-joined
+    /// keys, as in `Schema::validate`.
+    fn from_object_at(object: &Object<'_>, path: &str) -> Result<Self, ValidationError>;
+
+    fn from_object(object: &Object<'_>) -> Result<Self, ValidationError> {
+        Self::from_object_at(object, "")
+    }
+}
+
+/// Builds a field from every value stored under its key, in source order. An empty slice means
+/// the key is missing.
+pub trait FromValues: Sized {
+    fn from_values(values: &[&Value<'_>], path: &str) -> Result<Self, ValidationError>;
+}
+
+impl FromValues for String {
+    fn from_values(values: &[&Value<'_>], path: &str) -> Result<Self, ValidationError> {
+        match values.first() {
+            None => Err(ValidationError::MissingKey {
+                path: path.to_string(),
+            }),
+            Some(Value::String(string)) => Ok(string.to_string()),
+            Some(Value::Int(int)) => Ok(int.to_string()),
+            Some(Value::Float(float)) => Ok(float.to_string()),
+            Some(Value::Object(_)) => Err(ValidationError::WrongKind {
+                path: path.to_string(),
+                expected: ValueKind::String,
+                found: ValueKind::Object,
+            }),
+        }
+    }
+}
+
+impl<T: FromValues> FromValues for Option<T> {
+    fn from_values(values: &[&Value<'_>], path: &str) -> Result<Self, ValidationError> {
+        if values.is_empty() {
+            return Ok(None);
+        }
+
+        T::from_values(values, path).map(Some)
+    }
+}
+
+impl<T: FromValues> FromValues for Vec<T> {
+    fn from_values(values: &[&Value<'_>], path: &str) -> Result<Self, ValidationError> {
+        values
+            .iter()
+            .map(|value| T::from_values(&[*value], path))
+            .collect()
+    }
+}
+
+/// Support code for the `FromKeyValues` derive. Not public API.
+#[doc(hidden)]
+pub mod __private {
+    use super::{FromKeyValues, Object, ValidationError, Value, ValueKind};
+
+    pub fn entries<'o, 'a>(object: &'o Object<'a>, key: &str) -> Vec<&'o Value<'a>> {
+        object
+            .iter()
+            .filter(|(entry_key, _, _)| entry_key.eq_ignore_ascii_case(key))
+            .map(|(_, _, value)| value)
+            .collect()
+    }
+
+    pub fn join_path(prefix: &str, key: &str) -> String {
+        if prefix.is_empty() {
+            key.to_string()
+        } else {
+            format!("{prefix}/{key}")
+        }
+    }
+
+    pub fn from_object_values<T: FromKeyValues>(
+        values: &[&Value<'_>],
+        path: &str,
+    ) -> Result<T, ValidationError> {
+        match values.first() {
+            None => Err(ValidationError::MissingKey {
+                path: path.to_string(),
+            }),
+            Some(Value::Object(object)) => T::from_object_at(object, path),
+            Some(value) => Err(ValidationError::WrongKind {
+                path: path.to_string(),
+                expected: ValueKind::Object,
+                found: value.kind(),
+            }),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "derive"))]
+mod tests {
+    use super::FromKeyValues;
+    use crate::kv::{KeyValues, ValidationError, ValueKind};
+
+    #[derive(FromKeyValues, Debug, PartialEq)]
+    struct Sound {
+        wave: Vec<String>,
+        volume: String,
+    }
+
+    #[derive(FromKeyValues, Debug, PartialEq)]
+    struct Entry {
+        name: String,
+        #[kv(rename = "Sound")]
+        sound: Sound,
+    }
+
+    #[test]
+    fn derive_two_fields() {
+        let kv = r#"
+        name "Weapon.Fire"
+        Sound {
+            wave "fire1.wav"
+            wave "fire2.wav"
+            volume 0.8
+        }
+        "#
+        .as_bytes();
+
+        let entry = Entry::from_object(KeyValues::from_io(kv).unwrap().root()).unwrap();
+
+        assert_eq!(
+            entry,
+            Entry {
+                name: "Weapon.Fire".to_string(),
+                sound: Sound {
+                    wave: vec!["fire1.wav".to_string(), "fire2.wav".to_string()],
+                    volume: "0.8".to_string(),
+                },
+            }
+        );
+    }
+
+    #[test]
+    fn derive_errors() {
+        let missing = KeyValues::from_io("name x sound { wave a }".as_bytes()).unwrap();
+        assert_eq!(
+            Entry::from_object(missing.root()),
+            Err(ValidationError::MissingKey {
+                path: "Sound/volume".to_string()
+            })
+        );
+
+        let wrong_kind = KeyValues::from_io("name { } sound { }".as_bytes()).unwrap();
+        assert_eq!(
+            Entry::from_object(wrong_kind.root()),
+            Err(ValidationError::WrongKind {
+                path: "name".to_string(),
+                expected: ValueKind::String,
+                found: ValueKind::Object,
+            })
+        );
+    }
+}
@@ -1,3 +1,6 @@
+// Lets code generated by the derive macros, which names `::srcrs`, compile inside this crate.
+extern crate self as srcrs;
+
 pub mod kv;
 mod shared;
 pub mod vpk;
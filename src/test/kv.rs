@@ -1,5 +1,16 @@
 #[cfg(test)]
-use crate::kv::KeyValues;
+use crate::kv::{
+    Connection, KeyValues, KvEvent, KvEvents, LazyKeyValues, ParseOptions, ReaderError,
+    SearchPaths, Value,
+};
+#[cfg(test)]
+use std::{
+    collections::{HashMap, HashSet},
+    env, fs,
+    io::{self, ErrorKind},
+    path::{Path, PathBuf},
+    process,
+};
 
 #[test]
 fn test_long_vmt() {
@@ -14,3 +25,455 @@ fn test_long_vmf() {
 
     KeyValues::from_io(kv.as_slice()).unwrap();
 }
+
+#[test]
+fn test_vmf_duplicate_keys() {
+    let vmf = include_bytes!("../../test-data/outputtest.vmf");
+    let text = std::str::from_utf8(vmf).unwrap();
+    let kv = KeyValues::from_io(vmf.as_slice()).unwrap();
+
+    let Some(Value::Object(world)) = kv.get("world") else {
+        panic!()
+    };
+    let solids: Vec<_> = world.get_all("solid").collect();
+    let world_text = &text[text.find("\"world\"").unwrap()..text.find("\n\"entity\"").unwrap()];
+    assert_eq!(solids.len(), world_text.matches("\n\t\"solid\"").count());
+    assert!(solids.len() > 1);
+    assert_eq!(world.get("solid"), solids.first().copied());
+    assert!(solids.iter().all(|solid| solid.is_object()));
+
+    let entities = kv.get_all("entity").count();
+    assert_eq!(entities, text.matches("\n\"entity\"").count());
+    assert!(entities > 1);
+    assert_eq!(
+        kv.get_all_with_flags("entity", &HashSet::<&str>::new())
+            .count(),
+        entities
+    );
+    assert_eq!(world.get_all("missing").count(), 0);
+}
+
+#[test]
+fn test_vmf_vectors() {
+    let vmf = include_bytes!("../../test-data/outputtest.vmf");
+    let kv = KeyValues::from_io(vmf.as_slice()).unwrap();
+
+    let origins: Vec<[f32; 3]> = kv
+        .get_all("entity")
+        .filter_map(|entity| match entity {
+            Value::Object(entity) => entity.get("origin")?.as_vec3(),
+            _ => None,
+        })
+        .collect();
+    assert!(origins.contains(&[1037.0, -435.0, 8.0]));
+
+    let Some(Value::Object(world)) = kv.get("world") else {
+        panic!()
+    };
+    let Some(Value::Object(solid)) = world.get("solid") else {
+        panic!()
+    };
+    let Some(Value::Object(side)) = solid.get("side") else {
+        panic!()
+    };
+    assert_eq!(side.get("lightmapscale").and_then(Value::as_i32), Some(64));
+    assert_eq!(side.get("rotation").and_then(Value::as_f32), Some(0.0));
+}
+
+#[test]
+fn test_vmf_events() {
+    let kv = include_bytes!("../../test-data/outputtest.vmf");
+    let tree = KeyValues::from_io(kv.as_slice()).unwrap();
+
+    let mut events = KvEvents::new(kv.as_slice()).unwrap();
+    let (mut objects, mut strings, mut depth, mut max_arena) = (0, 0, 0usize, 0);
+    while let Some(event) = events.next_event().unwrap() {
+        match event {
+            KvEvent::ObjectStart => {
+                objects += 1;
+                depth += 1;
+            }
+            KvEvent::ObjectEnd => depth -= 1,
+            KvEvent::ValueString(_) => strings += 1,
+            _ => {}
+        }
+        max_arena = max_arena.max(events.allocated_bytes());
+    }
+    assert_eq!(depth, 0);
+
+    let walked = |object: bool| {
+        tree.walk()
+            .filter(|(_, _, value)| value.is_object() == object)
+            .count()
+    };
+    assert_eq!(objects, walked(true));
+    assert_eq!(strings, walked(false));
+
+    // Only the current token is ever held, unlike the tree.
+    assert!(max_arena < 16 * 1024, "{max_arena}");
+    assert!(tree.allocated_bytes() > 1024 * 1024);
+}
+
+#[test]
+fn test_vmf_arena_usage() {
+    let kv = include_bytes!("../../test-data/outputtest.vmf");
+
+    let compact = KeyValues::from_io(kv.as_slice()).unwrap();
+    let roomy = KeyValues::from_io_with_options(
+        kv.as_slice(),
+        &ParseOptions {
+            keep_string_capacity: true,
+            ..Default::default()
+        },
+    )
+    .unwrap();
+
+    assert_eq!(compact.root(), roomy.root());
+
+    // Strings are shrunk in place as they are read, so the arena holds little beyond the text
+    // itself, which is less than the document once whitespace, quotes and braces are dropped.
+    assert!(compact.allocated_bytes() < kv.len());
+    assert!(compact.allocated_bytes() < roomy.allocated_bytes());
+}
+
+#[test]
+fn test_lazy_vmf() {
+    let kv = include_bytes!("../../test-data/outputtest.vmf");
+
+    let lazy = LazyKeyValues::from_io(kv.as_slice()).unwrap();
+    let eager = KeyValues::from_io(kv.as_slice()).unwrap();
+
+    for key in ["versioninfo", "viewsettings", "world"] {
+        assert_eq!(lazy.get(key).unwrap(), eager.get(key));
+    }
+}
+
+#[test]
+fn test_vmf_connection() {
+    let kv = include_bytes!("../../test-data/outputtest.vmf");
+
+    let expected = Connection {
+        target: "!activator".to_string(),
+        input: "SetDamageFilter".to_string(),
+        parameter: "dmg".to_string(),
+        delay: 0.05,
+        times: -1,
+    };
+
+    let vmf = KeyValues::from_io(kv.as_slice()).unwrap();
+    let connections: Vec<Connection> = vmf
+        .find_all("OnTrigger")
+        .into_iter()
+        .filter_map(|(_, value)| value.as_connection())
+        .collect();
+
+    assert!(connections.contains(&expected));
+    assert_eq!(vmf.get("versioninfo").unwrap().as_connection(), None);
+}
+
+fn temp_dir(name: &str) -> PathBuf {
+    let dir = env::temp_dir().join(format!("srcrs-{}-{}", name, process::id()));
+    fs::create_dir_all(&dir).unwrap();
+
+    dir
+}
+
+#[test]
+fn test_search_path_priority() {
+    let dir = temp_dir("search-paths");
+    let (game, mod_dir) = (dir.join("game"), dir.join("mod"));
+
+    fs::create_dir_all(game.join("resource")).unwrap();
+    fs::create_dir_all(mod_dir.join("resource")).unwrap();
+
+    fs::write(
+        game.join("resource/main.res"),
+        r#"#base "base.res"
+        main { own value }"#,
+    )
+    .unwrap();
+    fs::write(
+        game.join("resource/base.res"),
+        "main { from game } base_only game",
+    )
+    .unwrap();
+    fs::write(
+        mod_dir.join("resource/base.res"),
+        "main { from mod own overridden } base_only mod",
+    )
+    .unwrap();
+
+    let mut search_paths = SearchPaths::new();
+    search_paths.push(&mod_dir);
+    search_paths.push(&game);
+
+    let kv = KeyValues::from_search_paths(&search_paths, Path::new("resource/main.res")).unwrap();
+
+    assert!(kv.get("#base").is_none());
+    assert!(matches!(kv.get("base_only").unwrap(), Value::String(v) if v == "mod"));
+
+    match kv.get("main").unwrap() {
+        Value::Object(main) => {
+            assert!(matches!(main.get("own").unwrap(), Value::String(v) if v == "value"));
+            assert!(matches!(main.get("from").unwrap(), Value::String(v) if v == "mod"));
+        }
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn test_rooted_vmt() {
+    let kv = include_bytes!("../../test-data/water_pretty1_beneath.vmt");
+
+    let (shader, body) = KeyValues::from_io_rooted(kv.as_slice()).unwrap();
+
+    assert_eq!(shader, "Water");
+    assert!(matches!(body.get("$forceexpensive").unwrap(), Value::String(v) if v == "1"));
+    assert!(matches!(body.get("Water_DX60").unwrap(), Value::Object(_)));
+
+    assert!(KeyValues::from_io_rooted("a b".as_bytes()).is_err());
+    assert!(KeyValues::from_io_rooted("a {} b {}".as_bytes()).is_err());
+}
+
+#[test]
+fn test_leading_bom() {
+    let kv = b"\xEF\xBB\xBF\"LightmappedGeneric\" { $basetexture brick }";
+
+    assert!(matches!(
+        KeyValues::from_io(kv.as_slice())
+            .unwrap()
+            .get("LightmappedGeneric"),
+        Some(Value::Object(_))
+    ));
+}
+
+#[test]
+fn test_top_level_keys() {
+    let kv = include_bytes!("../../test-data/water_pretty1_beneath.vmt");
+    assert_eq!(
+        KeyValues::top_level_keys(kv.as_slice()).unwrap(),
+        vec!["Water"]
+    );
+
+    // Bodies are only brace-counted, so an empty flag inside one is never seen.
+    let keys = KeyValues::top_level_keys(r#"first { a b [ ] } "second" value [$X360]"#.as_bytes());
+    assert_eq!(keys.unwrap(), vec!["first", "second"]);
+
+    assert!(KeyValues::top_level_keys("first { a b".as_bytes()).is_err());
+}
+
+#[test]
+fn test_include_and_base() {
+    let dir = temp_dir("include-base");
+    fs::create_dir_all(dir.join("cfg")).unwrap();
+
+    fs::write(
+        dir.join("cfg/main.txt"),
+        r#"#include "included.txt"
+        #base "base.txt"
+        shared { own 1 }"#,
+    )
+    .unwrap();
+    fs::write(
+        dir.join("cfg/included.txt"),
+        "shared { from_include 1 } include_only 1",
+    )
+    .unwrap();
+    fs::write(
+        dir.join("cfg/base.txt"),
+        "shared { from_base 1 } base_only 1",
+    )
+    .unwrap();
+
+    let mut search_paths = SearchPaths::new();
+    search_paths.push(&dir);
+
+    let kv = KeyValues::from_search_paths(&search_paths, Path::new("cfg/main.txt")).unwrap();
+
+    assert!(kv.get("#include").is_none());
+    assert!(kv.get("include_only").is_some());
+    assert!(kv.get("base_only").is_some());
+
+    // The include sits beside the file's own entry, while the base merges into it.
+    let shared = kv.find_all("shared");
+    assert_eq!(shared.len(), 2);
+
+    match kv.get("shared").unwrap() {
+        Value::Object(shared) => {
+            assert!(shared.get("own").is_some());
+            assert!(shared.get("from_base").is_some());
+            assert!(shared.get("from_include").is_none());
+        }
+        _ => panic!(),
+    }
+}
+
+#[test]
+fn test_from_path_directives() {
+    let dir = temp_dir("path-directives");
+    fs::create_dir_all(dir.join("scripts/shared")).unwrap();
+
+    fs::write(
+        dir.join("scripts/hud.res"),
+        r#"#base "shared/base.res"
+        #base "missing.res"
+        panel { wide 100 }"#,
+    )
+    .unwrap();
+    fs::write(
+        dir.join("scripts/shared/base.res"),
+        "panel { wide 50 tall 20 } base_only 1",
+    )
+    .unwrap();
+
+    let kv = KeyValues::from_path(&dir.join("scripts/hud.res")).unwrap();
+    assert!(kv.get("#base").is_none());
+    assert!(kv.get("base_only").is_some());
+    let Some(Value::Object(panel)) = kv.get("panel") else {
+        panic!()
+    };
+    assert_eq!(panel.get("wide").and_then(Value::raw_str), Some("100"));
+    assert_eq!(panel.get("tall").and_then(Value::raw_str), Some("20"));
+
+    fs::write(dir.join("scripts/broken.res"), r#"#include "missing.res""#).unwrap();
+    let err = KeyValues::from_path(&dir.join("scripts/broken.res")).err();
+    assert!(matches!(err, Some(ReaderError::IO(err)) if err.kind() == ErrorKind::NotFound));
+
+    fs::write(dir.join("scripts/a.res"), r#"#base "shared/../b.res" a 1"#).unwrap();
+    fs::write(dir.join("scripts/b.res"), r#"#include "a.res" b 1"#).unwrap();
+    assert!(matches!(
+        KeyValues::from_path(&dir.join("scripts/a.res")),
+        Err(ReaderError::IncludeCycle(_))
+    ));
+}
+
+#[test]
+fn test_from_resolver() {
+    let files: HashMap<&str, &str> = [
+        (
+            "materials/brick.vmt",
+            r#"#include "../shared/common.vmt" LightmappedGeneric { }"#,
+        ),
+        ("shared/common.vmt", r#"#base "./defaults.vmt" common 1"#),
+        ("shared/defaults.vmt", "common 0 defaults 1"),
+        ("loop/a.vmt", r#"#include "sub/../a.vmt""#),
+    ]
+    .into();
+    let resolver = |path: &str| {
+        files
+            .get(path)
+            .map(|text| text.as_bytes())
+            .ok_or_else(|| io::Error::new(ErrorKind::NotFound, path.to_string()))
+    };
+
+    let kv = KeyValues::from_resolver("materials/brick.vmt", resolver).unwrap();
+    assert!(kv.get("LightmappedGeneric").is_some());
+    assert_eq!(kv.get("common").and_then(Value::raw_str), Some("1"));
+    assert!(kv.get("defaults").is_some());
+
+    assert!(matches!(
+        KeyValues::from_resolver("loop/a.vmt", resolver),
+        Err(ReaderError::IncludeCycle(_))
+    ));
+    assert!(matches!(
+        KeyValues::from_resolver("missing.vmt", resolver),
+        Err(ReaderError::IO(_))
+    ));
+}
+
+#[test]
+fn test_from_dir_glob() {
+    let dir = temp_dir("dir-glob");
+    fs::create_dir_all(dir.join("materials/brick")).unwrap();
+
+    fs::write(dir.join("materials/concrete.vmt"), "LightmappedGeneric { }").unwrap();
+    fs::write(dir.join("materials/brick/wall.vmt"), "VertexLitGeneric { }").unwrap();
+    fs::write(
+        dir.join("materials/brick/broken.vmt"),
+        "Broken { $basetexture",
+    )
+    .unwrap();
+    fs::write(dir.join("materials/brick/wall.vtf"), "not a material").unwrap();
+
+    let results: Vec<(PathBuf, bool)> = KeyValues::from_dir_glob(&dir.join("materials"), "*.vmt")
+        .map(|(path, kv)| (path, kv.is_ok()))
+        .collect();
+
+    assert_eq!(
+        results,
+        [
+            (dir.join("materials/brick/broken.vmt"), false),
+            (dir.join("materials/brick/wall.vmt"), true),
+            (dir.join("materials/concrete.vmt"), true),
+        ]
+    );
+
+    let kv = KeyValues::from_path(&dir.join("materials/concrete.vmt")).unwrap();
+    assert!(kv.get("LightmappedGeneric").is_some());
+}
+
+#[test]
+fn test_count_entries() {
+    let vmf = include_bytes!("../../test-data/outputtest.vmf");
+    let kv = KeyValues::from_io(vmf.as_slice()).unwrap();
+
+    assert_eq!(
+        KeyValues::count_entries(vmf.as_slice()).unwrap(),
+        kv.walk().count()
+    );
+
+    assert_eq!(
+        KeyValues::count_entries("a b c { d e [$X360] } [!$PS3] f g".as_bytes()).unwrap(),
+        4
+    );
+    assert!(KeyValues::count_entries("a { b c".as_bytes()).is_err());
+    assert!(KeyValues::count_entries("a b }".as_bytes()).is_err());
+}
+
+#[test]
+fn test_resolve_texture() {
+    let vmt = include_bytes!("../../test-data/water_pretty1_beneath.vmt");
+    let kv = KeyValues::from_io(vmt.as_slice()).unwrap();
+    let Some(Value::Object(water)) = kv.get("Water") else {
+        panic!()
+    };
+
+    assert_eq!(
+        water.resolve_texture("$normalmap").as_deref(),
+        Some(Path::new("materials/dev/water_normal.vtf"))
+    );
+    assert_eq!(
+        water.resolve_texture("$bumpmap").as_deref(),
+        Some(Path::new("materials/dev/water_dudv.vtf"))
+    );
+    assert_eq!(water.resolve_texture("$refracttexture"), None);
+    assert_eq!(water.resolve_texture("$basetexture"), None);
+}
+
+#[test]
+fn test_write_to_round_trip() {
+    let vmt = include_bytes!("../../test-data/water_pretty1_beneath.vmt");
+    let mut kv = KeyValues::from_io(vmt.as_slice()).unwrap();
+    kv.insert("Patch", "nature/water");
+    assert!(kv.is_dirty());
+
+    let mut written = Vec::new();
+    kv.write_to(&mut written).unwrap();
+    assert!(!kv.is_dirty());
+    let text = String::from_utf8(written.clone()).unwrap();
+    assert!(text.contains("\t\t\"WaterLOD\"\n\t\t{\n\t\t}\n"));
+
+    let reparsed = KeyValues::from_io(written.as_slice()).unwrap();
+    assert_eq!(reparsed.root(), kv.root());
+
+    let options = ParseOptions {
+        typed_numbers: true,
+        ..Default::default()
+    };
+    let kv = KeyValues::from_io_with_options("a 1 b 0.25 c -3 d 2.0".as_bytes(), &options).unwrap();
+    let mut written = Vec::new();
+    kv.write_to(&mut written).unwrap();
+    let reparsed = KeyValues::from_io_with_options(written.as_slice(), &options).unwrap();
+    assert_eq!(reparsed.root(), kv.root());
+    assert_eq!(reparsed.get("d"), Some(&Value::Float(2.0)));
+}
@@ -1,16 +1,27 @@
+#[cfg(feature = "md5")]
+use crate::vpk::IntegrityError;
 #[cfg(test)]
-use crate::vpk::VPK;
+use crate::vpk::{
+    ChunkProvider, ChunkRead, DiffEntry, EntryFlags, EntryInfo, TextEncoding, VPKWriter, VpkDiff,
+    VpkSet, VPK,
+};
 
 use std::io::Seek;
 #[cfg(test)]
 use std::{
-    io::{Read, SeekFrom},
-    path::Path,
+    collections::HashMap,
+    env, fs,
+    io::{self, Cursor, ErrorKind, Read, SeekFrom},
+    ops::Deref,
+    path::{Path, PathBuf},
+    process,
+    sync::Arc,
+    thread,
 };
 
 #[test]
 fn test_chunk_vpk() {
-    let mut vpk = VPK::load(Path::new("test-data/Misc_dir.vpk")).unwrap();
+    let vpk = VPK::load(Path::new("test-data/Misc_dir.vpk")).unwrap();
 
     let mut chapter1 = vpk.get(Path::new("cfg/chapter1.cfg")).unwrap();
     chapter1.verify().unwrap();
@@ -37,7 +48,7 @@ fn test_chunk_vpk() {
 
 #[test]
 fn test_chunkless_vpk() {
-    let mut vpk = VPK::load(Path::new("test-data/blastoffold.vpk")).unwrap();
+    let vpk = VPK::load(Path::new("test-data/blastoffold.vpk")).unwrap();
 
     let mut blastoff = vpk.get(Path::new("blastoff.nut")).unwrap();
     blastoff.verify().unwrap();
@@ -52,3 +63,1682 @@ fn test_chunkless_vpk() {
 
     assert_eq!(blastoff_data, blastoff_truth);
 }
+
+#[test]
+fn test_shared_vpk() {
+    let vpk = Arc::new(VPK::load(Path::new("test-data/Misc_dir.vpk")).unwrap());
+
+    let entries = [
+        ("cfg/chapter1.cfg", 18),
+        ("cfg/modsettings.cfg", 422),
+        ("cfg/skill_manifest.cfg", 40),
+        ("scripts/chapterbackgrounds.txt", 35),
+    ];
+
+    let handles: Vec<_> = entries
+        .into_iter()
+        .map(|(path, len)| {
+            let vpk = Arc::clone(&vpk);
+
+            thread::spawn(move || {
+                let mut file = vpk.get(Path::new(path)).unwrap();
+                file.verify().unwrap();
+
+                assert_eq!(file.len(), len);
+
+                let mut data = vec![0u8; file.len()];
+                file.read_exact(data.as_mut_slice()).unwrap();
+
+                if path == "cfg/chapter1.cfg" {
+                    assert_eq!(data, include_bytes!("../../test-data/chapter1.cfg"));
+                }
+            })
+        })
+        .collect();
+
+    for handle in handles {
+        handle.join().unwrap();
+    }
+}
+
+#[test]
+fn test_copy_to() {
+    let vpk = VPK::load(Path::new("test-data/blastoffold.vpk")).unwrap();
+
+    let mut blastoff = vpk.get(Path::new("blastoff.nut")).unwrap();
+    let blastoff_truth = include_bytes!("../../test-data/blastoff.nut");
+
+    let mut blastoff_data = Vec::new();
+    assert_eq!(
+        blastoff.copy_to(&mut blastoff_data).unwrap(),
+        blastoff_truth.len() as u64
+    );
+    assert_eq!(blastoff_data, blastoff_truth);
+}
+
+#[test]
+fn test_verify_crcs() {
+    let vpk = VPK::load(Path::new("test-data/Misc_dir.vpk")).unwrap();
+    assert!(vpk.verify_crcs().unwrap().is_empty());
+
+    let vpk = VPK::load(Path::new("test-data/blastoffold.vpk")).unwrap();
+    assert!(vpk.verify_crcs().unwrap().is_empty());
+}
+
+#[cfg(feature = "md5")]
+#[test]
+fn test_entry_md5() {
+    let vpk = VPK::load(Path::new("test-data/Misc_dir.vpk")).unwrap();
+
+    assert_eq!(
+        vpk.entry_md5(Path::new("cfg/chapter1.cfg")).unwrap(),
+        [
+            0xc6, 0x18, 0x91, 0x05, 0xcc, 0x60, 0x0a, 0xc7, 0x5c, 0xf4, 0x6a, 0xe2, 0x9b, 0x7c,
+            0xc6, 0x07
+        ]
+    );
+}
+
+/// Writes a v2 VPK with one chunked entry whose preload data is `PRELOAD`, `INLINE` as unused
+/// inline data, an archive MD5 for the chunk, correct MD5s for the rest and a signature section.
+#[cfg(feature = "md5")]
+fn write_checksummed_vpk(name: &str) -> TestVpk {
+    let chunk = b"chunk data";
+    let tree = test_tree(&[TestEntry {
+        preload: b"PRELOAD",
+        archive_index: 0,
+        length: chunk.len() as u32,
+        ..TestEntry::new("cfg/checked.cfg")
+    }]);
+    let inline_data = b"INLINE";
+
+    let mut archive_md5 = Vec::new();
+    for field in [0u32, 0, chunk.len() as u32] {
+        archive_md5.extend_from_slice(&field.to_le_bytes());
+    }
+    archive_md5.extend_from_slice(&md5::compute(chunk).0);
+
+    let mut signature = Vec::new();
+    for part in [&b"public key"[..], b"signature"] {
+        signature.extend_from_slice(&(part.len() as u32).to_le_bytes());
+        signature.extend_from_slice(part);
+    }
+
+    let mut vpk = Vec::new();
+    for field in [
+        0x55aa1234u32,
+        2,
+        tree.len() as u32,
+        inline_data.len() as u32,
+        archive_md5.len() as u32,
+        48,
+        signature.len() as u32,
+    ] {
+        vpk.extend_from_slice(&field.to_le_bytes());
+    }
+    vpk.extend_from_slice(&tree);
+    vpk.extend_from_slice(inline_data);
+    vpk.extend_from_slice(&archive_md5);
+    vpk.extend_from_slice(&md5::compute(&tree).0);
+    vpk.extend_from_slice(&md5::compute(&archive_md5).0);
+    vpk.extend_from_slice(&md5::compute(&vpk).0);
+    vpk.extend_from_slice(&signature);
+
+    let path = TestVpk::new(name, &vpk);
+    write_test_chunk(&path, 0, chunk);
+
+    path
+}
+
+/// Overwrites the first occurrence of `find` in the file at `path` with `replace`.
+#[cfg(feature = "md5")]
+fn patch_file(path: &Path, find: &[u8], replace: &[u8]) {
+    let mut data = fs::read(path).unwrap();
+    let position = data
+        .windows(find.len())
+        .position(|window| window == find)
+        .unwrap();
+
+    data[position..position + replace.len()].copy_from_slice(replace);
+    fs::write(path, data).unwrap();
+}
+
+#[cfg(feature = "md5")]
+#[test]
+fn test_verify_integrity() {
+    let vpk = VPK::load(Path::new("test-data/Misc_dir.vpk")).unwrap();
+    assert!(vpk.verify_integrity().is_ok());
+
+    let path = write_checksummed_vpk("integrity-ok");
+    let vpk = VPK::load(&path).unwrap();
+    assert!(vpk.verify_integrity().is_ok());
+    assert_eq!(
+        vpk.read_to_vec(Path::new("cfg/checked.cfg")).unwrap(),
+        b"PRELOADchunk data"
+    );
+
+    let path = write_checksummed_vpk("integrity-tree");
+    patch_file(&path, b"PRELOAD", b"preload");
+    assert!(matches!(
+        VPK::load(&path).unwrap().verify_integrity(),
+        Err(IntegrityError::TreeMd5Mismatch)
+    ));
+
+    let path = write_checksummed_vpk("integrity-data");
+    patch_file(&path, b"INLINE", b"inline");
+    assert!(matches!(
+        VPK::load(&path).unwrap().verify_integrity(),
+        Err(IntegrityError::FileMd5Mismatch)
+    ));
+
+    let path = write_checksummed_vpk("integrity-md5-section");
+    patch_file(&path, &md5::compute(b"chunk data").0, &[0; 16]);
+    assert!(matches!(
+        VPK::load(&path).unwrap().verify_integrity(),
+        Err(IntegrityError::ArchiveMd5SectionMismatch)
+    ));
+
+    let path = write_checksummed_vpk("integrity-chunk");
+    patch_file(
+        &path.with_file_name("integrity-chunk_000.vpk"),
+        b"chunk",
+        b"CHUNK",
+    );
+    assert!(matches!(
+        VPK::load(&path).unwrap().verify_integrity(),
+        Err(IntegrityError::ArchiveMd5Mismatch { index: 0 })
+    ));
+
+    let path = write_checksummed_vpk("integrity-signature");
+    // The signature's length, which now runs past the end of the section.
+    patch_file(&path, b"\x09\x00\x00\x00signature", &99u32.to_le_bytes());
+    assert!(matches!(
+        VPK::load(&path).unwrap().verify_integrity(),
+        Err(IntegrityError::SignatureInvalid)
+    ));
+}
+
+#[cfg(feature = "md5")]
+#[test]
+fn test_verify_archive_md5() {
+    let vpk = VPK::load(Path::new("test-data/Misc_dir.vpk")).unwrap();
+    vpk.verify_archive_md5().unwrap();
+
+    let path = write_checksummed_vpk("archive-md5-ok");
+    VPK::load(&path).unwrap().verify_archive_md5().unwrap();
+
+    // Every mismatch is listed, not only the first.
+    let path = write_checksummed_vpk("archive-md5-bad");
+    patch_file(&path, b"PRELOAD", b"preload");
+    patch_file(
+        &path.with_file_name("archive-md5-bad_000.vpk"),
+        b"chunk",
+        b"CHUNK",
+    );
+    let vpk = VPK::load(&path).unwrap();
+    let err = vpk.verify_archive_md5().unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    let message = err.to_string();
+    assert!(message.contains(&IntegrityError::TreeMd5Mismatch.to_string()));
+    assert!(message.contains(&IntegrityError::FileMd5Mismatch.to_string()));
+    assert!(message.contains(&IntegrityError::ArchiveMd5Mismatch { index: 0 }.to_string()));
+    assert!(matches!(
+        vpk.verify_integrity(),
+        Err(IntegrityError::TreeMd5Mismatch)
+    ));
+}
+
+#[cfg(feature = "vfs")]
+#[test]
+fn test_vfs_adapter() {
+    use crate::vpk::VpkFileSystem;
+    use vfs::{VfsFileType, VfsPath};
+
+    let vpk = VPK::load(Path::new("test-data/Misc_dir.vpk")).unwrap();
+    let root = VfsPath::new(VpkFileSystem::new(vpk));
+
+    let mut chapter = String::new();
+    root.join("cfg/chapter1.cfg")
+        .unwrap()
+        .open_file()
+        .unwrap()
+        .read_to_string(&mut chapter)
+        .unwrap();
+    assert_eq!(
+        chapter.as_bytes(),
+        include_bytes!("../../test-data/chapter1.cfg")
+    );
+
+    let metadata = root.join("cfg/chapter1.cfg").unwrap().metadata().unwrap();
+    assert_eq!(metadata.file_type, VfsFileType::File);
+    assert_eq!(metadata.len, chapter.len() as u64);
+
+    let cfg = root.join("cfg").unwrap();
+    assert!(cfg.is_dir().unwrap());
+    assert!(cfg
+        .read_dir()
+        .unwrap()
+        .any(|child| child.filename() == "chapter1.cfg"));
+
+    assert!(!root.join("missing").unwrap().exists().unwrap());
+    assert!(root.join("cfg/new.cfg").unwrap().create_file().is_err());
+}
+
+#[test]
+fn test_entries_uncompressed() {
+    let vpk = VPK::load(Path::new("test-data/Misc_dir.vpk")).unwrap();
+
+    let chapter1 = vpk.get(Path::new("cfg/chapter1.cfg")).unwrap();
+    assert!(!chapter1.is_compressed());
+    assert_eq!(chapter1.flags(), EntryFlags::default());
+}
+
+#[test]
+fn test_read_to_string() {
+    let vpk = VPK::load(Path::new("test-data/Misc_dir.vpk")).unwrap();
+
+    assert_eq!(
+        vpk.read_to_string(Path::new("cfg/chapter1.cfg")).unwrap(),
+        include_str!("../../test-data/chapter1.cfg")
+    );
+
+    // UTF-16, so not valid UTF-8.
+    let err = vpk
+        .read_to_string(Path::new("resource/hl2mmod_lc_english.txt"))
+        .err()
+        .unwrap();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    assert!(err.to_string().contains("hl2mmod_lc_english.txt"));
+}
+
+#[test]
+fn test_check_chunks() {
+    let vpk = VPK::load(Path::new("test-data/Misc_dir.vpk")).unwrap();
+    assert_eq!(vpk.check_chunks(), Ok(()));
+
+    let entry = |path, archive_index| TestEntry {
+        archive_index,
+        length: 4,
+        ..TestEntry::new(path)
+    };
+    let tree = test_tree(&[
+        entry("cfg/present.cfg", 0),
+        entry("cfg/short.cfg", 1),
+        entry("cfg/missing.cfg", 2),
+    ]);
+
+    let path = write_test_vpk("check_chunks", &tree, &[]);
+    write_test_chunk(&path, 0, &[0u8; 4]);
+    write_test_chunk(&path, 1, &[0u8; 2]);
+    let _ = fs::remove_file(path.with_file_name("check_chunks_002.vpk"));
+
+    let vpk = VPK::load(&path).unwrap();
+    assert_eq!(vpk.check_chunks(), Err(vec![1, 2]));
+}
+
+#[test]
+fn test_load_with_resolver() {
+    let tree = test_tree(&[TestEntry {
+        preload: b"pre",
+        archive_index: 5,
+        offset: 2,
+        length: 6,
+        ..TestEntry::new("cfg/remote.cfg")
+    }]);
+
+    // No chunk files are written; every chunk is served from memory.
+    let path = write_test_vpk("resolver", &tree, &[]);
+    let chunks = HashMap::from([(5u16, b"--remote--".to_vec())]);
+
+    let vpk = VPK::load_with_resolver(&path, move |index| {
+        let chunk = chunks
+            .get(&index)
+            .ok_or_else(|| io::Error::new(ErrorKind::NotFound, format!("no chunk {index}")))?;
+
+        Ok(Box::new(Cursor::new(chunk.clone())) as Box<dyn ChunkRead>)
+    })
+    .unwrap();
+
+    assert_eq!(
+        vpk.read_to_vec(Path::new("cfg/remote.cfg")).unwrap(),
+        b"preremote"
+    );
+    assert_eq!(vpk.check_chunks(), Ok(()));
+}
+
+struct MemoryChunks {
+    dir: Vec<u8>,
+    chunks: HashMap<u16, Vec<u8>>,
+}
+
+impl ChunkProvider for MemoryChunks {
+    fn open_dir(&self) -> io::Result<Box<dyn ChunkRead>> {
+        Ok(Box::new(Cursor::new(self.dir.clone())))
+    }
+
+    fn open(&self, index: u16) -> io::Result<Box<dyn ChunkRead>> {
+        let chunk = self
+            .chunks
+            .get(&index)
+            .ok_or_else(|| io::Error::new(ErrorKind::NotFound, format!("no chunk {index}")))?;
+
+        Ok(Box::new(Cursor::new(chunk.clone())))
+    }
+}
+
+#[test]
+fn test_load_from_provider() {
+    let tree = test_tree(&[
+        TestEntry {
+            length: 6,
+            ..TestEntry::new("cfg/inline.cfg")
+        },
+        TestEntry {
+            archive_index: 1,
+            offset: 3,
+            length: 7,
+            ..TestEntry::new("scripts/chunked.txt")
+        },
+    ]);
+
+    // Only used to build the directory bytes, which are then served from memory.
+    let dir = fs::read(write_test_vpk("provider", &tree, b"inline")).unwrap();
+
+    let vpk = VPK::load_from_provider(MemoryChunks {
+        dir,
+        chunks: HashMap::from([(1u16, b"---chunked".to_vec())]),
+    })
+    .unwrap();
+
+    assert_eq!(
+        vpk.read_to_vec(Path::new("cfg/inline.cfg")).unwrap(),
+        b"inline"
+    );
+    assert_eq!(
+        vpk.read_to_vec(Path::new("scripts/chunked.txt")).unwrap(),
+        b"chunked"
+    );
+    assert_eq!(vpk.check_chunks(), Ok(()));
+}
+
+/// Serves at most `max` bytes per read, like a slow pipe or network stream.
+struct ShortReads {
+    data: Cursor<Vec<u8>>,
+    max: usize,
+}
+
+impl ShortReads {
+    fn new(data: &[u8], max: usize) -> ShortReads {
+        ShortReads {
+            data: Cursor::new(data.to_vec()),
+            max,
+        }
+    }
+}
+
+impl Read for ShortReads {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = usize::min(buf.len(), self.max);
+        self.data.read(&mut buf[..len])
+    }
+}
+
+impl Seek for ShortReads {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.data.seek(pos)
+    }
+}
+
+/// Serves chunks which return at most two bytes per read.
+struct ShortReadChunks(MemoryChunks);
+
+impl ChunkProvider for ShortReadChunks {
+    fn open_dir(&self) -> io::Result<Box<dyn ChunkRead>> {
+        self.0.open_dir()
+    }
+
+    fn open(&self, index: u16) -> io::Result<Box<dyn ChunkRead>> {
+        let chunk = self.0.chunks.get(&index).cloned().unwrap_or_default();
+        Ok(Box::new(ShortReads::new(&chunk, 2)))
+    }
+}
+
+/// Reads the whole of `file` with `read` calls of at most `step` bytes.
+fn read_in_steps<R: Read>(mut file: R, step: usize) -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut buffer = vec![0u8; step];
+
+    loop {
+        match file.read(&mut buffer).unwrap() {
+            0 => break data,
+            num_read => data.extend_from_slice(&buffer[..num_read]),
+        }
+    }
+}
+
+#[test]
+fn test_read_in_steps() {
+    let vpk = VPK::load(Path::new("test-data/Misc_dir.vpk")).unwrap();
+    let chapter1 = include_bytes!("../../test-data/chapter1.cfg");
+    for step in [1, 3, 7, 64] {
+        let file = vpk.get(Path::new("cfg/chapter1.cfg")).unwrap();
+        assert_eq!(read_in_steps(file, step), chapter1, "step {step}");
+    }
+
+    // Preload data followed by archive data, from an archive which returns short reads.
+    let tree = test_tree(&[TestEntry {
+        crc: crc32fast::hash(b"preloaded|archived data"),
+        preload: b"preloaded|",
+        archive_index: 1,
+        offset: 3,
+        length: 13,
+        ..TestEntry::new("scripts/split.txt")
+    }]);
+    let dir = fs::read(write_test_vpk("short-reads", &tree, &[])).unwrap();
+    let vpk = VPK::load_from_provider(ShortReadChunks(MemoryChunks {
+        dir,
+        chunks: HashMap::from([(1u16, b"---archived data".to_vec())]),
+    }))
+    .unwrap();
+
+    let path = Path::new("scripts/split.txt");
+    for step in [1, 2, 5, 9, 10, 11, 64] {
+        let file = vpk.get(path).unwrap();
+        assert_eq!(
+            read_in_steps(file, step),
+            b"preloaded|archived data",
+            "step {step}"
+        );
+    }
+    vpk.get(path).unwrap().verify().unwrap();
+}
+
+#[test]
+fn test_seek() {
+    let vpk = VPK::load(Path::new("test-data/Misc_dir.vpk")).unwrap();
+    let chapter1 = include_bytes!("../../test-data/chapter1.cfg");
+
+    let mut file = vpk.get(Path::new("cfg/chapter1.cfg")).unwrap();
+    file.seek(SeekFrom::Start(12)).unwrap();
+    assert_eq!(file.seek(SeekFrom::Current(-5)).unwrap(), 7);
+    assert_eq!(read_in_steps(&mut file, 4), &chapter1[7..]);
+
+    let err = file.seek(SeekFrom::Current(-100)).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    assert_eq!(file.stream_position().unwrap(), chapter1.len() as u64);
+
+    // The end of an entry with preload data counts both parts.
+    let data = b"preloaded|archived data";
+    let tree = test_tree(&[TestEntry {
+        crc: crc32fast::hash(data),
+        preload: b"preloaded|",
+        length: 13,
+        ..TestEntry::new("scripts/split.txt")
+    }]);
+    let path = write_test_vpk("seek-preload", &tree, b"archived data");
+    let vpk = VPK::load(&path).unwrap();
+
+    let mut file = vpk.get(Path::new("scripts/split.txt")).unwrap();
+    assert_eq!(file.len(), data.len());
+    assert_eq!(file.seek(SeekFrom::End(0)).unwrap(), file.len() as u64);
+    assert_eq!(
+        file.seek(SeekFrom::End(-10)).unwrap(),
+        data.len() as u64 - 10
+    );
+    assert_eq!(read_in_steps(&mut file, 3), &data[data.len() - 10..]);
+
+    // Back into the preload data, then on across into the archive.
+    assert_eq!(file.seek(SeekFrom::End(-20)).unwrap(), 3);
+    assert_eq!(read_in_steps(&mut file, 4), &data[3..]);
+    assert_eq!(
+        file.seek(SeekFrom::End(-24)).unwrap_err().kind(),
+        ErrorKind::InvalidInput
+    );
+}
+
+/// A directory file of `len` bytes, most of them zeros which are never stored: `prefix` at the
+/// start and `data` at `data_offset`. Lets offsets past 4 GiB be tested without such a file.
+#[derive(Clone)]
+struct SparseDir {
+    prefix: Vec<u8>,
+    data_offset: u64,
+    data: Vec<u8>,
+    len: u64,
+}
+
+struct SparseReader {
+    dir: SparseDir,
+    position: u64,
+}
+
+impl Read for SparseReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let dir = &self.dir;
+        let num_read = (dir.len.saturating_sub(self.position)).min(buf.len() as u64) as usize;
+
+        for (byte, position) in buf[..num_read].iter_mut().zip(self.position..) {
+            *byte = if position < dir.prefix.len() as u64 {
+                dir.prefix[position as usize]
+            } else if (dir.data_offset..dir.data_offset + dir.data.len() as u64).contains(&position)
+            {
+                dir.data[(position - dir.data_offset) as usize]
+            } else {
+                0
+            };
+        }
+
+        self.position += num_read as u64;
+        Ok(num_read)
+    }
+}
+
+impl Seek for SparseReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = match pos {
+            SeekFrom::Start(offset) => offset,
+            SeekFrom::End(offset) => self.dir.len.checked_add_signed(offset).unwrap(),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset).unwrap(),
+        };
+
+        Ok(self.position)
+    }
+}
+
+impl ChunkProvider for SparseDir {
+    fn open_dir(&self) -> io::Result<Box<dyn ChunkRead>> {
+        Ok(Box::new(SparseReader {
+            dir: self.clone(),
+            position: 0,
+        }))
+    }
+
+    fn open(&self, index: u16) -> io::Result<Box<dyn ChunkRead>> {
+        Err(io::Error::new(
+            ErrorKind::NotFound,
+            format!("no chunk {index}"),
+        ))
+    }
+}
+
+#[test]
+fn test_offset_past_u32() {
+    let entry_offset = u32::MAX - 8;
+    let tree = test_tree(&[TestEntry {
+        preload: b"pre",
+        offset: entry_offset,
+        length: 8,
+        ..TestEntry::new("maps/huge.bsp")
+    }]);
+
+    let prefix = fs::read(write_test_vpk_v2("past-u32", &tree, &[], u32::MAX)).unwrap();
+
+    // Inline data starts after the header and tree, so the entry lies past the u32 range.
+    let data_offset = prefix.len() as u64 + entry_offset as u64;
+    assert!(data_offset > u32::MAX as u64);
+
+    let vpk = VPK::load_from_provider(SparseDir {
+        prefix,
+        data_offset,
+        data: b"far away".to_vec(),
+        len: data_offset + 8,
+    })
+    .unwrap();
+
+    let path = Path::new("maps/huge.bsp");
+    assert_eq!(vpk.read_to_vec(path).unwrap(), b"prefar away");
+
+    let mut file = vpk.get(path).unwrap();
+    let mut buffer = [0u8; 4];
+
+    file.seek(SeekFrom::Start(7)).unwrap();
+    file.read_exact(&mut buffer).unwrap();
+    assert_eq!(&buffer, b"away");
+
+    file.seek(SeekFrom::End(-10)).unwrap();
+    file.read_exact(&mut buffer).unwrap();
+    assert_eq!(&buffer, b"refa");
+
+    // Far past the end: nothing to read, and no overflow adding the archive offset.
+    file.seek(SeekFrom::Start(u64::MAX)).unwrap();
+    assert_eq!(file.read(&mut buffer).unwrap(), 0);
+}
+
+#[test]
+fn test_v2_directory_data() {
+    let data = b"padding|inline data";
+    let tree = test_tree(&[TestEntry {
+        crc: crc32fast::hash(b"inline data"),
+        offset: 8,
+        length: 11,
+        ..TestEntry::new("cfg/inline.cfg")
+    }]);
+
+    let path = write_test_vpk_v2("v2_inline", &tree, data, data.len() as u32);
+    let vpk = VPK::load(&path).unwrap();
+
+    let mut file = vpk.get(Path::new("cfg/inline.cfg")).unwrap();
+    file.verify().unwrap();
+    assert_eq!(
+        vpk.read_to_vec(Path::new("cfg/inline.cfg")).unwrap(),
+        b"inline data"
+    );
+
+    // The header says the data section ends before the entry does.
+    let path = write_test_vpk_v2("v2_short_section", &tree, data, 10);
+    let err = VPK::load(&path).err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    assert!(err.to_string().contains("cfg/inline.cfg"));
+}
+
+#[test]
+fn test_get_checked() {
+    let vpk = VPK::load(Path::new("test-data/Misc_dir.vpk")).unwrap();
+    let path = Path::new("cfg/chapter1.cfg");
+
+    assert_eq!(
+        vpk.get_checked(path).unwrap(),
+        (vpk.read_to_vec(path).unwrap(), true)
+    );
+
+    let data = b"inline data";
+    let tree = test_tree(&[TestEntry {
+        crc: crc32fast::hash(b"other data"),
+        preload: b"pre",
+        length: data.len() as u32,
+        ..TestEntry::new("cfg/corrupt.cfg")
+    }]);
+
+    let path = write_test_vpk_v2("get_checked", &tree, data, data.len() as u32);
+    let vpk = VPK::load(&path).unwrap();
+
+    assert_eq!(
+        vpk.get_checked(Path::new("cfg/corrupt.cfg")).unwrap(),
+        (b"preinline data".to_vec(), false)
+    );
+}
+
+#[test]
+fn test_stream_entries() {
+    let vpk = VPK::load(Path::new("test-data/Misc_dir.vpk")).unwrap();
+    let paths: Vec<PathBuf> = vpk
+        .files_in_archive_order()
+        .map(Path::to_path_buf)
+        .collect();
+    assert!(paths.len() > 1);
+
+    let mut requested = paths.clone();
+    requested.reverse();
+    requested.insert(1, PathBuf::from("cfg/missing.cfg"));
+
+    let mut streamed = vpk.stream_entries(&requested);
+    let missing = streamed.next().unwrap().err().unwrap();
+    assert_eq!(missing.kind(), ErrorKind::NotFound);
+
+    let streamed: HashMap<PathBuf, Vec<u8>> = streamed.map(Result::unwrap).collect();
+    let individually: HashMap<PathBuf, Vec<u8>> = paths
+        .iter()
+        .map(|path| (path.clone(), vpk.read_to_vec(path).unwrap()))
+        .collect();
+    assert_eq!(streamed, individually);
+}
+
+#[test]
+fn test_write_manifest() {
+    let vpk = VPK::load(Path::new("test-data/Misc_dir.vpk")).unwrap();
+
+    let mut manifest = Vec::new();
+    vpk.write_manifest(&mut manifest).unwrap();
+    let manifest = String::from_utf8(manifest).unwrap();
+
+    let lines: Vec<&str> = manifest.lines().collect();
+    assert_eq!(lines.len(), 6);
+    assert_eq!(lines[0], "cfg/chapter1.cfg 6f9192c8 000 18");
+
+    let mut sorted = lines.clone();
+    sorted.sort();
+    assert_eq!(lines, sorted);
+}
+
+#[test]
+fn test_entry_crc_hex() {
+    let vpk = VPK::load(Path::new("test-data/Misc_dir.vpk")).unwrap();
+
+    assert_eq!(
+        vpk.entry_crc_hex(Path::new("cfg/chapter1.cfg")).as_deref(),
+        Some("6f9192c8")
+    );
+    assert_eq!(vpk.entry_crc_hex(Path::new("cfg/missing.cfg")), None);
+}
+
+#[test]
+fn test_entries() {
+    let vpk = VPK::load(Path::new("test-data/Misc_dir.vpk")).unwrap();
+
+    assert_eq!(vpk.paths().count(), vpk.entry_count());
+    assert!(vpk.paths().all(|path| vpk.contains(path)));
+    assert!(!vpk.contains(Path::new("cfg/missing.cfg")));
+
+    let (_, info) = vpk
+        .entries()
+        .find(|(path, _)| *path == Path::new("cfg/chapter1.cfg"))
+        .unwrap();
+    assert_eq!(
+        info,
+        EntryInfo {
+            crc: 0x6f9192c8,
+            len: 18,
+            preload_len: 0,
+            archive_index: 0,
+        }
+    );
+
+    let tree = test_tree(&[TestEntry::preloaded("cfg/preload.cfg", b"preloaded")]);
+    let path = write_test_vpk("entries_preload", &tree, &[]);
+    let vpk = VPK::load(&path).unwrap();
+
+    let entries: Vec<_> = vpk.entries().collect();
+    assert_eq!(
+        entries,
+        [(
+            Path::new("cfg/preload.cfg"),
+            EntryInfo {
+                crc: crc32fast::hash(b"preloaded"),
+                len: 9,
+                preload_len: 9,
+                archive_index: 0x7fff,
+            }
+        )]
+    );
+
+    // Preload bytes count towards the file's length.
+    let file = vpk.get(Path::new("cfg/preload.cfg")).unwrap();
+    assert_eq!(file.len(), 9);
+    assert!(!file.is_empty());
+}
+
+/// A directory for one test under the system's temp dir, removed with its contents on drop.
+struct TestDir(PathBuf);
+
+impl TestDir {
+    fn new(name: &str) -> TestDir {
+        let dir = env::temp_dir().join(format!("srcrs-{}-{}", name, process::id()));
+
+        // Anything left over from an earlier run which didn't get to clean up.
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        TestDir(dir)
+    }
+}
+
+impl Deref for TestDir {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for TestDir {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
+impl Drop for TestDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// The path of a directory file written for a test, which is removed along with its chunks
+/// when dropped.
+struct TestVpk {
+    path: PathBuf,
+    _dir: TestDir,
+}
+
+impl TestVpk {
+    /// Writes `contents` as `{name}_dir.vpk` in a fresh `TestDir`.
+    fn new(name: &str, contents: &[u8]) -> TestVpk {
+        let dir = TestDir::new(name);
+        let path = dir.join(format!("{}_dir.vpk", name));
+        fs::write(&path, contents).unwrap();
+
+        TestVpk { path, _dir: dir }
+    }
+}
+
+impl Deref for TestVpk {
+    type Target = Path;
+
+    fn deref(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl AsRef<Path> for TestVpk {
+    fn as_ref(&self) -> &Path {
+        &self.path
+    }
+}
+
+fn write_test_vpk(name: &str, tree: &[u8], data: &[u8]) -> TestVpk {
+    write_test_vpk_with_header(name, tree, data, &[1])
+}
+
+/// Writes a v2 directory whose header claims `data_section_size` bytes of inline data.
+fn write_test_vpk_v2(name: &str, tree: &[u8], data: &[u8], data_section_size: u32) -> TestVpk {
+    write_test_vpk_with_header(name, tree, data, &[2, data_section_size, 0, 0, 0])
+}
+
+fn write_test_vpk_with_header(name: &str, tree: &[u8], data: &[u8], header: &[u32]) -> TestVpk {
+    // The tree size goes between the version and the rest of the v2 header.
+    let mut vpk = Vec::new();
+    vpk.extend_from_slice(&0x55aa1234u32.to_le_bytes());
+    vpk.extend_from_slice(&header[0].to_le_bytes());
+    vpk.extend_from_slice(&(tree.len() as u32).to_le_bytes());
+    for field in &header[1..] {
+        vpk.extend_from_slice(&field.to_le_bytes());
+    }
+    vpk.extend_from_slice(tree);
+    vpk.extend_from_slice(data);
+
+    TestVpk::new(name, &vpk)
+}
+
+struct TestEntry<'a> {
+    path: &'a str,
+    extension: &'a str,
+    file_name: &'a str,
+    crc: u32,
+    preload: &'a [u8],
+    archive_index: u16,
+    offset: u32,
+    length: u32,
+}
+
+impl<'a> TestEntry<'a> {
+    /// An empty entry stored inline at `path`, such as `cfg/chapter1.cfg`. Fields are set on top
+    /// of it with struct update syntax.
+    fn new(path: &'a str) -> TestEntry<'a> {
+        let (dir, name) = path.rsplit_once('/').unwrap_or((" ", path));
+        let (file_name, extension) = name.rsplit_once('.').unwrap_or((name, " "));
+
+        TestEntry {
+            path: dir,
+            extension,
+            file_name,
+            crc: 0,
+            preload: &[],
+            archive_index: 0x7fff,
+            offset: 0,
+            length: 0,
+        }
+    }
+
+    /// An entry whose data is all preloaded, with a matching CRC.
+    fn preloaded(path: &'a str, preload: &'a [u8]) -> TestEntry<'a> {
+        TestEntry {
+            crc: crc32fast::hash(preload),
+            preload,
+            ..TestEntry::new(path)
+        }
+    }
+}
+
+/// Builds a directory tree, giving each entry its own extension and path group.
+fn test_tree(entries: &[TestEntry]) -> Vec<u8> {
+    let mut tree = Vec::new();
+
+    for entry in entries {
+        for name in [entry.extension, entry.path, entry.file_name] {
+            tree.extend_from_slice(name.as_bytes());
+            tree.push(0);
+        }
+
+        tree.extend_from_slice(&entry.crc.to_le_bytes());
+        tree.extend_from_slice(&(entry.preload.len() as u16).to_le_bytes());
+        tree.extend_from_slice(&entry.archive_index.to_le_bytes());
+        tree.extend_from_slice(&entry.offset.to_le_bytes());
+        tree.extend_from_slice(&entry.length.to_le_bytes());
+        tree.extend_from_slice(&0xffffu16.to_le_bytes());
+        tree.extend_from_slice(entry.preload);
+
+        // End of file names, then end of paths
+        tree.extend_from_slice(&[0, 0]);
+    }
+
+    // End of extensions
+    tree.push(0);
+    tree
+}
+
+fn write_test_chunk(dir_path: &Path, index: u16, data: &[u8]) -> PathBuf {
+    let name = dir_path.file_name().unwrap().to_str().unwrap();
+    let chunk_path =
+        dir_path.with_file_name(name.replace("_dir.vpk", &format!("_{:03}.vpk", index)));
+
+    fs::write(&chunk_path, data).unwrap();
+    chunk_path
+}
+
+#[test]
+fn test_unterminated_tree_string() {
+    let path = write_test_vpk("unterminated", b"cfg\0chapter", &[]);
+
+    let err = VPK::load(&path).err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    assert!(err.to_string().contains("offset 4"));
+}
+
+#[test]
+fn test_truncated_preload() {
+    // The entry claims more preload bytes than the tree has left.
+    let mut tree = test_tree(&[TestEntry::preloaded("cfg/cut.cfg", b"preloaded")]);
+    tree.truncate(tree.len() - 8);
+    let path = write_test_vpk("truncated_preload", &tree, &[]);
+
+    let err = VPK::load(&path).err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+}
+
+#[test]
+fn test_extract_all_with_progress() {
+    let vpk = VPK::load(Path::new("test-data/Misc_dir.vpk")).unwrap();
+    let dest = TestDir::new("extract-progress");
+
+    let mut calls = Vec::new();
+    vpk.extract_all_with_progress(&dest, |path, current, total| {
+        calls.push((path.to_path_buf(), current, total))
+    })
+    .unwrap();
+
+    assert_eq!(calls.len(), 6);
+    for (index, (path, current, total)) in calls.iter().enumerate() {
+        assert_eq!(*current, index as u64 + 1);
+        assert_eq!(*total, 6);
+        assert!(dest.join(path).is_file());
+    }
+
+    assert_eq!(
+        fs::read(dest.join("cfg/chapter1.cfg")).unwrap(),
+        include_bytes!("../../test-data/chapter1.cfg")
+    );
+}
+
+#[test]
+fn test_extract_all() {
+    let tree = test_tree(&[
+        TestEntry::preloaded("cfg/preloaded.cfg", b"preload only"),
+        TestEntry {
+            crc: crc32fast::hash(b"pre|archived"),
+            preload: b"pre|",
+            length: 8,
+            ..TestEntry::new("scripts/nested/split.txt")
+        },
+    ]);
+    let path = write_test_vpk("extract-all", &tree, b"archived");
+    let vpk = VPK::load(&path).unwrap();
+
+    let dest = TestDir::new("extract-all-dest");
+    vpk.extract_all_checked(&dest).unwrap();
+    assert_eq!(
+        fs::read(dest.join("cfg/preloaded.cfg")).unwrap(),
+        b"preload only"
+    );
+    assert_eq!(
+        fs::read(dest.join("scripts/nested/split.txt")).unwrap(),
+        b"pre|archived"
+    );
+
+    let single = dest.join("single/chapter1.cfg");
+    let vpk = VPK::load(Path::new("test-data/Misc_dir.vpk")).unwrap();
+    vpk.extract_checked(Path::new("cfg/chapter1.cfg"), &single)
+        .unwrap();
+    assert_eq!(
+        fs::read(&single).unwrap(),
+        include_bytes!("../../test-data/chapter1.cfg")
+    );
+
+    // A CRC mismatch only fails the checked variants.
+    let tree = test_tree(&[TestEntry {
+        crc: crc32fast::hash(b"other data"),
+        preload: b"data",
+        ..TestEntry::new("cfg/corrupt.cfg")
+    }]);
+    let path = write_test_vpk("extract-corrupt", &tree, &[]);
+    let vpk = VPK::load(&path).unwrap();
+    let entry = Path::new("cfg/corrupt.cfg");
+
+    vpk.extract_all(&dest).unwrap();
+    vpk.extract(entry, &dest.join("corrupt.cfg")).unwrap();
+
+    let err = vpk.extract_all_checked(&dest).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    assert!(err.to_string().contains("cfg/corrupt.cfg"));
+    let err = vpk
+        .extract_checked(entry, &dest.join("corrupt.cfg"))
+        .unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+}
+
+#[test]
+fn test_extract_all_outside_dest() {
+    let dir = TestDir::new("extract-escape");
+    let dest = dir.join("dest");
+
+    for (name, entry_path) in [
+        ("escape_parent", "../escaped.cfg"),
+        ("escape_nested", "cfg/../../escaped.cfg"),
+        ("escape_root", "/srcrs-escaped/escaped.cfg"),
+    ] {
+        let tree = test_tree(&[
+            TestEntry::preloaded("cfg/safe.cfg", b"safe"),
+            TestEntry::preloaded(entry_path, b"escaped"),
+        ]);
+        let path = write_test_vpk(name, &tree, &[]);
+        let vpk = VPK::load(&path).unwrap();
+
+        let err = vpk.extract_all(&dest).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData, "{entry_path}");
+        assert!(!dir.join("escaped.cfg").exists(), "{entry_path}");
+        assert!(!Path::new("/srcrs-escaped").exists(), "{entry_path}");
+        assert!(!dest.join("cfg/safe.cfg").exists(), "{entry_path}");
+    }
+}
+
+#[test]
+fn test_diff_against_dir() {
+    let vpk = VPK::load(Path::new("test-data/Misc_dir.vpk")).unwrap();
+    let dest = TestDir::new("diff-dir");
+
+    vpk.extract_all_with_progress(&dest, |_, _, _| {}).unwrap();
+    assert!(vpk.diff_against_dir(&dest).unwrap().is_empty());
+
+    fs::write(dest.join("cfg/chapter1.cfg"), "map d1_trainstation_01").unwrap();
+    fs::write(dest.join("cfg/extra.cfg"), "").unwrap();
+    fs::remove_file(dest.join("cfg/modsettings.cfg")).unwrap();
+
+    assert_eq!(
+        vpk.diff_against_dir(&dest).unwrap(),
+        vec![
+            DiffEntry::Changed(PathBuf::from("cfg/chapter1.cfg")),
+            DiffEntry::OnlyOnDisk(PathBuf::from("cfg/extra.cfg")),
+            DiffEntry::OnlyInVpk(PathBuf::from("cfg/modsettings.cfg")),
+        ]
+    );
+}
+
+#[test]
+fn test_truncated_chunk_error() {
+    let tree = test_tree(&[TestEntry {
+        archive_index: 3,
+        length: 100,
+        ..TestEntry::new("cfg/truncated.cfg")
+    }]);
+
+    let path = write_test_vpk("truncated", &tree, &[]);
+    let chunk_path = write_test_chunk(&path, 3, &[0u8; 100]);
+
+    let vpk = VPK::load(&path).unwrap();
+    let mut file = vpk.get(Path::new("cfg/truncated.cfg")).unwrap();
+
+    // Truncate the chunk after the entry was opened, so the short read happens mid-entry.
+    fs::OpenOptions::new()
+        .write(true)
+        .open(chunk_path)
+        .unwrap()
+        .set_len(10)
+        .unwrap();
+
+    let mut data = vec![0u8; file.len()];
+    let err = file.read_exact(&mut data).err().unwrap();
+
+    assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    assert!(err.to_string().contains("cfg/truncated.cfg"));
+    assert!(err.to_string().contains("archive 003"));
+    assert!(err.to_string().contains("position 10"));
+}
+
+#[test]
+fn test_entry_past_chunk_end() {
+    let tree = test_tree(&[TestEntry {
+        archive_index: 2,
+        offset: 8,
+        length: 16,
+        ..TestEntry::new("cfg/overrun.cfg")
+    }]);
+
+    let path = write_test_vpk("overrun", &tree, &[]);
+    write_test_chunk(&path, 2, &[0u8; 20]);
+
+    let vpk = VPK::load(&path).unwrap();
+    let err = vpk.get(Path::new("cfg/overrun.cfg")).err().unwrap();
+
+    assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    assert!(err.to_string().contains("cfg/overrun.cfg"));
+    assert!(err.to_string().contains("archive 002"));
+    assert!(err.to_string().contains("ends at byte 24"));
+}
+
+#[test]
+fn test_writer_add_dir() {
+    let dir = TestDir::new("writer");
+    let source = dir.join("source");
+
+    fs::create_dir_all(source.join("materials/brick")).unwrap();
+    fs::write(source.join("readme.txt"), "top level").unwrap();
+    fs::write(source.join("materials/brick/wall.tar.gz"), "multi-dot").unwrap();
+    fs::write(
+        source.join("materials/brick/wall.vmt"),
+        r#"LightmappedGeneric { $basetexture "brick/wall" }"#,
+    )
+    .unwrap();
+
+    let mut writer = VPKWriter::new();
+    writer.add_dir(&source).unwrap();
+
+    let path = dir.join("packed_dir.vpk");
+    writer.write(&path).unwrap();
+
+    let vpk = VPK::load(&path).unwrap();
+
+    assert_eq!(
+        vpk.read_to_string(Path::new("readme.txt")).unwrap(),
+        "top level"
+    );
+    assert_eq!(
+        vpk.read_to_vec(Path::new("materials/brick/wall.vmt"))
+            .unwrap(),
+        fs::read(source.join("materials/brick/wall.vmt")).unwrap()
+    );
+    assert_eq!(
+        vpk.read_to_string(Path::new("materials/brick/wall.tar.gz"))
+            .unwrap(),
+        "multi-dot"
+    );
+    assert!(vpk.verify_crcs().unwrap().is_empty());
+}
+
+#[test]
+fn test_chunk_names() {
+    let tree = test_tree(&[TestEntry {
+        archive_index: 0,
+        length: 4,
+        ..TestEntry::new("cfg/chunked.cfg")
+    }]);
+
+    // `_dir` elsewhere in the stem, dots in the stem and backslashes (which are only separators
+    // on Windows) must all leave the chunk name alone apart from the suffix.
+    let mut names = vec!["my_dir_pack", "foo.bar"];
+    if cfg!(unix) {
+        names.push("C:\\games\\pak01");
+    }
+
+    for name in names {
+        let path = write_test_vpk(name, &tree, &[]);
+        let chunk_path = write_test_chunk(&path, 0, b"data");
+
+        let vpk = VPK::load(&path).unwrap();
+        assert_eq!(
+            vpk.read_to_vec(Path::new("cfg/chunked.cfg")).unwrap(),
+            b"data",
+            "{}",
+            chunk_path.display()
+        );
+    }
+}
+
+#[test]
+fn test_non_utf8_names() {
+    let mut tree = test_tree(&[TestEntry {
+        crc: crc32fast::hash(b"data"),
+        archive_index: 0,
+        length: 4,
+        ..TestEntry::new("sound/caf?/men?.wav")
+    }]);
+    // Latin-1 `é`, which is not valid UTF-8 on its own.
+    for byte in tree.iter_mut().filter(|byte| **byte == b'?') {
+        *byte = 0xe9;
+    }
+
+    let path = write_test_vpk("non-utf8", &tree, &[]);
+    write_test_chunk(&path, 0, b"data");
+    let vpk = VPK::load(&path).unwrap();
+
+    let entry_path = vpk.paths().next().unwrap().to_path_buf();
+    assert_eq!(vpk.read_to_vec(&entry_path).unwrap(), b"data");
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStrExt;
+
+        let stored = Path::new(std::ffi::OsStr::from_bytes(b"sound/caf\xe9/men\xe9.wav"));
+        assert_eq!(entry_path, stored);
+    }
+    #[cfg(not(unix))]
+    assert_eq!(entry_path, Path::new("sound/caf\u{e9}/men\u{e9}.wav"));
+
+    // The directory file's own name need not be UTF-8 either.
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::unix::ffi::OsStrExt;
+
+        let renamed = path.with_file_name(std::ffi::OsStr::from_bytes(b"caf\xe9_dir.vpk"));
+        fs::copy(&path, &renamed).unwrap();
+        fs::write(
+            renamed.with_file_name(std::ffi::OsStr::from_bytes(b"caf\xe9_000.vpk")),
+            b"data",
+        )
+        .unwrap();
+
+        let vpk = VPK::load(&renamed).unwrap();
+        assert_eq!(vpk.read_to_vec(&entry_path).unwrap(), b"data");
+    }
+}
+
+#[test]
+fn test_read_text() {
+    let vpk = VPK::load(Path::new("test-data/Misc_dir.vpk")).unwrap();
+
+    let (text, encoding) = vpk
+        .read_text(Path::new("resource/hl2mmod_lc_english.txt"))
+        .unwrap();
+    assert_eq!(encoding, TextEncoding::Utf16Le);
+    assert!(text.starts_with("\"lang\" \r\n{"));
+
+    let (text, encoding) = vpk.read_text(Path::new("cfg/chapter1.cfg")).unwrap();
+    assert_eq!(encoding, TextEncoding::Utf8);
+    assert_eq!(text, include_str!("../../test-data/chapter1.cfg"));
+
+    // Packed fixtures for the byte orders not covered by the game files.
+    let dir = TestDir::new("read-text");
+    fs::create_dir_all(dir.join("source")).unwrap();
+
+    let mut utf16_be = vec![0xFE, 0xFF];
+    utf16_be.extend("\"Täst\"".encode_utf16().flat_map(u16::to_be_bytes));
+    fs::write(dir.join("source/be.txt"), utf16_be).unwrap();
+    fs::write(dir.join("source/bom.txt"), b"\xEF\xBB\xBFkey value").unwrap();
+    fs::write(dir.join("source/odd.txt"), b"\xFF\xFE\x41").unwrap();
+
+    let mut writer = VPKWriter::new();
+    writer.add_dir(&dir.join("source")).unwrap();
+    writer.write(&dir.join("text.vpk")).unwrap();
+
+    let vpk = VPK::load(&dir.join("text.vpk")).unwrap();
+    assert_eq!(
+        vpk.read_text(Path::new("be.txt")).unwrap(),
+        ("\"Täst\"".to_string(), TextEncoding::Utf16Be)
+    );
+    assert_eq!(
+        vpk.read_text(Path::new("bom.txt")).unwrap(),
+        ("key value".to_string(), TextEncoding::Utf8Bom)
+    );
+    assert_eq!(
+        vpk.read_text(Path::new("odd.txt")).unwrap_err().kind(),
+        ErrorKind::InvalidData
+    );
+}
+
+#[test]
+fn test_empty_tree() {
+    // Some tools write an empty archive as a header with no tree at all.
+    let vpk = VPK::load(&write_test_vpk("empty_tree", &[], &[])).unwrap();
+    assert_eq!(vpk.entry_count(), 0);
+
+    let vpk = VPK::load(&write_test_vpk_v2("empty_tree_v2", &[], &[], 0)).unwrap();
+    assert_eq!(vpk.entry_count(), 0);
+
+    let vpk = VPK::load(&write_test_vpk("terminated_tree", &[0], &[])).unwrap();
+    assert_eq!(vpk.entry_count(), 0);
+    assert!(vpk.verify_crcs().unwrap().is_empty());
+}
+
+#[test]
+fn test_read_into_shared_buffer() {
+    let vpk = VPK::load(Path::new("test-data/Misc_dir.vpk")).unwrap();
+    let mut buffer = Vec::new();
+
+    // The longest entry goes first, so leftover data from it would show up in the others.
+    for (path, len) in [
+        ("cfg/modsettings.cfg", 422),
+        ("cfg/chapter1.cfg", 18),
+        ("scripts/chapterbackgrounds.txt", 35),
+    ] {
+        vpk.read_into(Path::new(path), &mut buffer).unwrap();
+
+        assert_eq!(buffer.len(), len);
+        assert_eq!(buffer, vpk.read_to_vec(Path::new(path)).unwrap());
+
+        if path == "cfg/chapter1.cfg" {
+            assert_eq!(buffer, include_bytes!("../../test-data/chapter1.cfg"));
+        }
+    }
+}
+
+#[test]
+fn test_seek_across_preload() {
+    let preload = b"preload---";
+    let archived: Vec<u8> = (0..4000u32).map(|index| (index % 251) as u8).collect();
+    let expected: Vec<u8> = preload.iter().copied().chain(archived.clone()).collect();
+
+    let tree = test_tree(&[TestEntry {
+        crc: crc32fast::hash(&expected),
+        preload,
+        archive_index: 1,
+        offset: 16,
+        length: archived.len() as u32,
+        ..TestEntry::new("media/long.wav")
+    }]);
+
+    let path = write_test_vpk("seek_preload", &tree, &[]);
+    let mut chunk = vec![0xAA; 16];
+    chunk.extend_from_slice(&archived);
+    write_test_chunk(&path, 1, &chunk);
+
+    let vpk = VPK::load(&path).unwrap();
+    let mut file = vpk.get(Path::new("media/long.wav")).unwrap();
+    file.verify().unwrap();
+
+    // A read starting in the preload data continues into the archive.
+    let mut data = vec![0u8; 20];
+    file.seek(SeekFrom::Start(5)).unwrap();
+    file.read_exact(&mut data).unwrap();
+    assert_eq!(data, expected[5..25]);
+
+    file.seek(SeekFrom::Start(2000)).unwrap();
+    file.read_exact(&mut data).unwrap();
+    assert_eq!(data, expected[2000..2020]);
+
+    assert_eq!(file.seek(SeekFrom::Current(-30)).unwrap(), 1990);
+    file.read_exact(&mut data).unwrap();
+    assert_eq!(data, expected[1990..2010]);
+
+    let end = expected.len() as u64;
+    assert_eq!(file.seek(SeekFrom::End(-20)).unwrap(), end - 20);
+    file.read_exact(&mut data).unwrap();
+    assert_eq!(data, expected[expected.len() - 20..]);
+    assert_eq!(file.read(&mut data).unwrap(), 0);
+
+    assert_eq!(
+        file.seek(SeekFrom::Current(-(end as i64) - 1))
+            .unwrap_err()
+            .kind(),
+        ErrorKind::InvalidInput
+    );
+
+    assert_eq!(
+        vpk.read_to_vec(Path::new("media/long.wav")).unwrap(),
+        expected
+    );
+}
+
+#[test]
+fn test_diff() {
+    let original = VPK::load(Path::new("test-data/Misc_dir.vpk")).unwrap();
+    assert!(original.diff(&original).is_empty());
+
+    // The CRC directly follows the file name in the tree.
+    let mut data = fs::read("test-data/Misc_dir.vpk").unwrap();
+    let crc_offset = data
+        .windows(9)
+        .position(|window| window == b"chapter1\0")
+        .unwrap()
+        + 9;
+    data[crc_offset] ^= 0xff;
+
+    let path = TestVpk::new("diff-vpk", &data);
+
+    let altered = VPK::load(&path).unwrap();
+    assert_eq!(
+        original.diff(&altered),
+        VpkDiff {
+            changed: vec![PathBuf::from("cfg/chapter1.cfg")],
+            ..Default::default()
+        }
+    );
+
+    let empty = VPK::load(&write_test_vpk("diff_empty", &[], &[])).unwrap();
+    let diff = original.diff(&empty);
+    assert_eq!(diff.removed.len(), original.entry_count());
+    assert!(diff.added.is_empty() && diff.changed.is_empty());
+    assert_eq!(empty.diff(&original).added, diff.removed);
+}
+
+#[test]
+fn test_stream_position_after_short_reads() {
+    let tree = test_tree(&[TestEntry {
+        preload: b"pre",
+        archive_index: 2,
+        offset: 1,
+        length: 8,
+        ..TestEntry::new("cfg/slow.cfg")
+    }]);
+
+    let path = write_test_vpk("short_reads", &tree, &[]);
+    let vpk = VPK::load_with_resolver(&path, |_| {
+        Ok(Box::new(ShortReads::new(b"-archived", 3)) as Box<dyn ChunkRead>)
+    })
+    .unwrap();
+
+    let mut file = vpk.get(Path::new("cfg/slow.cfg")).unwrap();
+    let mut buffer = [0u8; 16];
+    let mut total = 0u64;
+
+    loop {
+        let num_read = file.read(&mut buffer).unwrap();
+        if num_read == 0 {
+            break;
+        }
+
+        total += num_read as u64;
+        assert_eq!(file.stream_position().unwrap(), total);
+    }
+
+    assert_eq!(total, 11);
+    assert_eq!(
+        vpk.read_to_vec(Path::new("cfg/slow.cfg")).unwrap(),
+        b"prearchived"
+    );
+}
+
+#[test]
+fn test_padded_tree() {
+    let data = b"inline data";
+    let mut tree = test_tree(&[
+        TestEntry {
+            crc: crc32fast::hash(data),
+            length: data.len() as u32,
+            ..TestEntry::new("cfg/inline.cfg")
+        },
+        TestEntry::preloaded("scripts/preloaded.txt", b"preload"),
+    ]);
+
+    // Pad the tree, including its size in the header, out to a 64 byte boundary.
+    tree.resize(tree.len().next_multiple_of(64) + 64, 0);
+
+    let path = write_test_vpk("padded_tree", &tree, data);
+    let vpk = VPK::load(&path).unwrap();
+
+    assert_eq!(vpk.entry_count(), 2);
+    assert_eq!(vpk.read_to_vec(Path::new("cfg/inline.cfg")).unwrap(), data);
+    assert_eq!(
+        vpk.read_to_vec(Path::new("scripts/preloaded.txt")).unwrap(),
+        b"preload"
+    );
+}
+
+#[test]
+fn test_files_in_archive_order() {
+    let entry = |path| TestEntry {
+        preload: b"x",
+        ..TestEntry::new(path)
+    };
+
+    // Deliberately out of alphabetical order.
+    let tree = test_tree(&[
+        entry("scripts/zebra.txt"),
+        entry("cfg/autoexec.cfg"),
+        entry("materials/brick.vmt"),
+        entry("cfg/apple.cfg"),
+    ]);
+
+    let path = write_test_vpk("archive_order", &tree, &[]);
+    let vpk = VPK::load(&path).unwrap();
+
+    assert_eq!(
+        vpk.files_in_archive_order().collect::<Vec<_>>(),
+        [
+            Path::new("scripts/zebra.txt"),
+            Path::new("cfg/autoexec.cfg"),
+            Path::new("materials/brick.vmt"),
+            Path::new("cfg/apple.cfg"),
+        ]
+    );
+}
+
+#[test]
+fn test_read_range() {
+    let vpk = VPK::load(Path::new("test-data/Misc_dir.vpk")).unwrap();
+    let path = Path::new("cfg/chapter1.cfg");
+    let expected = include_bytes!("../../test-data/chapter1.cfg");
+
+    assert_eq!(vpk.read_range(path, 4, 8).unwrap(), expected[4..12]);
+    assert_eq!(vpk.read_range(path, 0, expected.len()).unwrap(), expected);
+    assert!(vpk.read_range(path, 0, 0).unwrap().is_empty());
+
+    let err = vpk.read_range(path, 4, expected.len()).unwrap_err();
+    assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    assert!(vpk.read_range(path, u64::MAX, 1).is_err());
+}
+
+#[test]
+fn test_raw_tree() {
+    let path = Path::new("test-data/Misc_dir.vpk");
+    let data = fs::read(path).unwrap();
+    let tree_size = u32::from_le_bytes(data[8..12].try_into().unwrap()) as usize;
+
+    let vpk = VPK::load(path).unwrap();
+    assert_eq!(vpk.raw_tree().len(), tree_size);
+
+    // A v2 header, so the tree starts after 28 bytes.
+    assert_eq!(vpk.raw_tree(), &data[28..28 + tree_size]);
+}
+
+#[test]
+fn test_load_strict() {
+    let entry = |path, archive_index, offset| TestEntry {
+        archive_index,
+        offset,
+        length: 16,
+        ..TestEntry::new(path)
+    };
+
+    let tree = test_tree(&[entry("cfg/fits.cfg", 1, 0), entry("cfg/overrun.cfg", 1, 8)]);
+    let path = write_test_vpk("strict", &tree, &[]);
+    write_test_chunk(&path, 1, &[0u8; 20]);
+
+    // A plain load only notices when the entry is opened.
+    assert!(VPK::load(&path).is_ok());
+
+    let err = VPK::load_strict(&path).err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::InvalidData);
+    assert!(err.to_string().contains("cfg/overrun.cfg"));
+    assert!(err.to_string().contains("archive 001"));
+
+    let tree = test_tree(&[entry("cfg/fits.cfg", 1, 0), entry("cfg/missing.cfg", 3, 0)]);
+    let path = write_test_vpk("strict_missing", &tree, &[]);
+    write_test_chunk(&path, 1, &[0u8; 20]);
+
+    let err = VPK::load_strict(&path).err().unwrap();
+    assert_eq!(err.kind(), ErrorKind::NotFound);
+    assert!(err.to_string().contains("cfg/missing.cfg"));
+
+    assert!(VPK::load_strict(Path::new("test-data/Misc_dir.vpk")).is_ok());
+}
+
+#[test]
+fn test_lines() {
+    let vpk = VPK::load(Path::new("test-data/Misc_dir.vpk")).unwrap();
+    let expected = include_str!("../../test-data/chapter1.cfg");
+
+    let lines = vpk
+        .lines(Path::new("cfg/chapter1.cfg"))
+        .unwrap()
+        .collect::<io::Result<Vec<String>>>()
+        .unwrap();
+
+    assert_eq!(lines.len(), expected.lines().count());
+    assert_eq!(lines, expected.lines().collect::<Vec<_>>());
+}
+
+#[test]
+fn test_vpk_set() {
+    let mod_tree = test_tree(&[TestEntry::preloaded("cfg/config.cfg", b"mod")]);
+    let game_tree = test_tree(&[
+        TestEntry::preloaded("cfg/config.cfg", b"game"),
+        TestEntry::preloaded("cfg/game_only.cfg", b"game"),
+    ]);
+
+    let mut set = VpkSet::new();
+    set.mount(&write_test_vpk("set_mod", &mod_tree, &[]))
+        .unwrap();
+    set.mount(&write_test_vpk("set_game", &game_tree, &[]))
+        .unwrap();
+
+    // The mod was mounted first, so it shadows the game's copy.
+    let mut data = Vec::new();
+    set.get(Path::new("cfg/config.cfg"))
+        .unwrap()
+        .read_to_end(&mut data)
+        .unwrap();
+    assert_eq!(data, b"mod");
+
+    assert!(set.contains(Path::new("cfg/game_only.cfg")));
+    assert!(!set.contains(Path::new("cfg/missing.cfg")));
+    assert_eq!(
+        set.get(Path::new("cfg/missing.cfg")).err().unwrap().kind(),
+        ErrorKind::NotFound
+    );
+
+    assert_eq!(
+        set.files().collect::<Vec<_>>(),
+        [Path::new("cfg/config.cfg"), Path::new("cfg/game_only.cfg")]
+    );
+}
+
+#[test]
+fn test_read_prefix() {
+    let entry = |path, preload, archive_index, length| TestEntry {
+        preload,
+        archive_index,
+        length,
+        ..TestEntry::new(path)
+    };
+
+    let tree = test_tree(&[
+        entry("media/preloaded.dat", b"VTF\0rest", 1, 4),
+        entry("media/split.dat", b"ID", 2, 4),
+        entry("media/archived.dat", b"", 2, 4),
+        entry("media/tiny.dat", b"ab", 0x7fff, 0),
+    ]);
+
+    // Chunk 1 is missing, so any read of it would fail.
+    let path = write_test_vpk("prefix", &tree, &[]);
+    write_test_chunk(&path, 2, b"RIFF....");
+
+    let vpk = VPK::load(&path).unwrap();
+    let prefix = |name: &str, n| vpk.read_prefix(Path::new(name), n);
+
+    assert_eq!(prefix("media/preloaded.dat", 4).unwrap(), b"VTF\0");
+    assert_eq!(prefix("media/archived.dat", 4).unwrap(), b"RIFF");
+    assert_eq!(prefix("media/split.dat", 4).unwrap(), b"IDRI");
+    assert_eq!(prefix("media/tiny.dat", 4).unwrap(), b"ab");
+
+    // Past the preload data, the missing chunk is needed.
+    assert_eq!(
+        prefix("media/preloaded.dat", 9).unwrap_err().kind(),
+        ErrorKind::NotFound
+    );
+}
@@ -0,0 +1,123 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::io::{Cursor, Write};
+use std::path::{Component, Path, PathBuf};
+
+use vfs::error::VfsErrorKind;
+use vfs::{FileSystem, SeekAndRead, VfsFileType, VfsMetadata, VfsResult};
+
+use super::VPK;
+
+/// A read-only `vfs::FileSystem` over the entries of a VPK, so it can be passed to code written
+/// against a generic virtual filesystem, for example through `vfs::VfsPath::new`.
+///
+/// Directories are implied by entry paths rather than stored, so only directories containing at
+/// least one entry exist. Opened files are read fully into memory, since `vfs` needs readers
+/// which do not borrow from the filesystem.
+pub struct VpkFileSystem {
+    vpk: VPK,
+}
+
+impl VpkFileSystem {
+    pub fn new(vpk: VPK) -> VpkFileSystem {
+        VpkFileSystem { vpk }
+    }
+
+    pub fn vpk(&self) -> &VPK {
+        &self.vpk
+    }
+
+    /// Converts a `vfs` path, which is either empty for the root or starts with `/`, into the
+    /// relative form entries are stored under.
+    fn entry_path(path: &str) -> PathBuf {
+        PathBuf::from(path.trim_start_matches('/'))
+    }
+
+    /// Iterates over the names of the direct children of `directory`, with duplicates for
+    /// directories holding more than one entry.
+    fn children<'a>(&'a self, directory: &'a Path) -> impl Iterator<Item = String> + 'a {
+        self.vpk.files.keys().filter_map(move |entry_path| {
+            match entry_path
+                .strip_prefix(directory)
+                .ok()?
+                .components()
+                .next()?
+            {
+                Component::Normal(name) => Some(name.to_string_lossy().into_owned()),
+                _ => None,
+            }
+        })
+    }
+
+    fn is_dir(&self, directory: &Path) -> bool {
+        directory.as_os_str().is_empty() || self.children(directory).next().is_some()
+    }
+}
+
+impl fmt::Debug for VpkFileSystem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VpkFileSystem")
+            .field("entries", &self.vpk.entry_count())
+            .finish()
+    }
+}
+
+impl FileSystem for VpkFileSystem {
+    fn read_dir(&self, path: &str) -> VfsResult<Box<dyn Iterator<Item = String> + Send>> {
+        let directory = Self::entry_path(path);
+        if !self.is_dir(&directory) {
+            return Err(VfsErrorKind::FileNotFound.into());
+        }
+
+        let names: BTreeSet<String> = self.children(&directory).collect();
+        Ok(Box::new(names.into_iter()))
+    }
+
+    fn open_file(&self, path: &str) -> VfsResult<Box<dyn SeekAndRead + Send>> {
+        let data = self.vpk.read_to_vec(&Self::entry_path(path))?;
+        Ok(Box::new(Cursor::new(data)))
+    }
+
+    fn metadata(&self, path: &str) -> VfsResult<VfsMetadata> {
+        let entry_path = Self::entry_path(path);
+
+        if let Some(entry) = self.vpk.files.get(&entry_path) {
+            Ok(VfsMetadata {
+                file_type: VfsFileType::File,
+                len: entry.len(),
+            })
+        } else if self.is_dir(&entry_path) {
+            Ok(VfsMetadata {
+                file_type: VfsFileType::Directory,
+                len: 0,
+            })
+        } else {
+            Err(VfsErrorKind::FileNotFound.into())
+        }
+    }
+
+    fn exists(&self, path: &str) -> VfsResult<bool> {
+        let entry_path = Self::entry_path(path);
+        Ok(self.vpk.files.contains_key(&entry_path) || self.is_dir(&entry_path))
+    }
+
+    fn create_dir(&self, _path: &str) -> VfsResult<()> {
+        Err(VfsErrorKind::NotSupported.into())
+    }
+
+    fn create_file(&self, _path: &str) -> VfsResult<Box<dyn Write + Send>> {
+        Err(VfsErrorKind::NotSupported.into())
+    }
+
+    fn append_file(&self, _path: &str) -> VfsResult<Box<dyn Write + Send>> {
+        Err(VfsErrorKind::NotSupported.into())
+    }
+
+    fn remove_file(&self, _path: &str) -> VfsResult<()> {
+        Err(VfsErrorKind::NotSupported.into())
+    }
+
+    fn remove_dir(&self, _path: &str) -> VfsResult<()> {
+        Err(VfsErrorKind::NotSupported.into())
+    }
+}
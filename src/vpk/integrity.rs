@@ -0,0 +1,227 @@
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Seek, SeekFrom};
+
+use super::reader::{V2Sections, DIRECTORY_INDEX, V2_HEADER_SIZE};
+use super::VPK;
+
+/// Size of an entry in the archive MD5 section: the chunk index, offset and length of the
+/// checked range, then its MD5.
+const ARCHIVE_MD5_ENTRY_SIZE: usize = 28;
+/// The other MD5 section holds the MD5s of the tree, of the archive MD5 section and of the
+/// directory file up to that point.
+const OTHER_MD5_SECTION_SIZE: u64 = 48;
+
+/// Why `VPK::verify_integrity` rejected a VPK.
+#[derive(Debug)]
+pub enum IntegrityError {
+    IO(io::Error),
+    /// The directory tree does not match its stored MD5.
+    TreeMd5Mismatch,
+    /// The archive MD5 section does not match its stored MD5.
+    ArchiveMd5SectionMismatch,
+    /// The directory file does not match the MD5 stored for it.
+    FileMd5Mismatch,
+    /// A range of the chunk with this index does not match its MD5 in the archive MD5 section.
+    ArchiveMd5Mismatch {
+        index: u16,
+    },
+    /// The signature section is malformed.
+    SignatureInvalid,
+}
+
+impl From<io::Error> for IntegrityError {
+    fn from(err: io::Error) -> IntegrityError {
+        IntegrityError::IO(err)
+    }
+}
+
+impl fmt::Display for IntegrityError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            IntegrityError::IO(err) => write!(f, "IO error encountered in verifying: {err}"),
+            IntegrityError::TreeMd5Mismatch => write!(f, "Directory tree MD5 mismatch"),
+            IntegrityError::ArchiveMd5SectionMismatch => {
+                write!(f, "Archive MD5 section MD5 mismatch")
+            }
+            IntegrityError::FileMd5Mismatch => write!(f, "Directory file MD5 mismatch"),
+            IntegrityError::ArchiveMd5Mismatch { index } => {
+                write!(f, "Archive {index:03} MD5 mismatch")
+            }
+            IntegrityError::SignatureInvalid => write!(f, "Invalid signature section"),
+        }
+    }
+}
+
+impl Error for IntegrityError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            IntegrityError::IO(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl VPK {
+    /// Checks the MD5s a v2 directory stores for its tree, its archive MD5 section, itself and
+    /// the chunk ranges listed in the archive MD5 section, and that the signature section is
+    /// well formed. The signature itself is not verified, as that needs RSA. Checks are made in
+    /// that order and the first failure is returned. v1 directories hold no checksums, so
+    /// always pass.
+    pub fn verify_integrity(&self) -> Result<(), IntegrityError> {
+        let Some(sections) = self.v2_sections else {
+            return Ok(());
+        };
+
+        let mut dir = self.open_archive(DIRECTORY_INDEX)?;
+        if let Some(mismatch) = self.md5_mismatches(&mut dir, sections, true)?.pop() {
+            return Err(mismatch);
+        }
+
+        let signature_section = read_section(&mut dir, sections.signature)?;
+        if !signature_section.is_empty() && !is_signature_well_formed(&signature_section) {
+            return Err(IntegrityError::SignatureInvalid);
+        }
+
+        Ok(())
+    }
+
+    /// Checks the same MD5s as `verify_integrity`, the other MD5 section's and every range in
+    /// the archive MD5 section, but carries on past a mismatch so that the error lists all of
+    /// them. The signature section is not looked at. v1 directories hold no checksums, so
+    /// always pass.
+    pub fn verify_archive_md5(&self) -> io::Result<()> {
+        let Some(sections) = self.v2_sections else {
+            return Ok(());
+        };
+
+        let mut dir = self.open_archive(DIRECTORY_INDEX)?;
+        let mismatches = match self.md5_mismatches(&mut dir, sections, false) {
+            Ok(mismatches) => mismatches,
+            Err(IntegrityError::IO(err)) => return Err(err),
+            Err(err) => return Err(io::Error::new(io::ErrorKind::InvalidData, err)),
+        };
+
+        if mismatches.is_empty() {
+            return Ok(());
+        }
+
+        let list: Vec<String> = mismatches.iter().map(ToString::to_string).collect();
+        Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("MD5 mismatches: {}", list.join(", ")),
+        ))
+    }
+
+    /// Reads the directory file up to the end of the other MD5 section, leaving `dir` there,
+    /// and returns the MD5s which do not match. With `first_only`, returns as soon as one is
+    /// found.
+    fn md5_mismatches<R: Read + Seek>(
+        &self,
+        dir: &mut R,
+        sections: V2Sections,
+        first_only: bool,
+    ) -> Result<Vec<IntegrityError>, IntegrityError> {
+        let mut mismatches = Vec::new();
+        let mut file_md5 = md5::Context::new();
+
+        // The header, tree and inline data are only needed for the MD5 of the whole file.
+        let archive_md5_start = V2_HEADER_SIZE + self.tree.len() as u64 + sections.file_data;
+        let hashed = io::copy(&mut dir.take(archive_md5_start), &mut file_md5)?;
+        if hashed < archive_md5_start {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+
+        let archive_md5_section = read_section(dir, sections.archive_md5)?;
+        if archive_md5_section.len() % ARCHIVE_MD5_ENTRY_SIZE != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "archive MD5 section is not a whole number of entries",
+            )
+            .into());
+        }
+        file_md5.consume(&archive_md5_section);
+
+        if sections.other_md5 == OTHER_MD5_SECTION_SIZE {
+            let other_md5_section = read_section(dir, sections.other_md5)?;
+            file_md5.consume(&other_md5_section[..32]);
+
+            let checks = [
+                (
+                    md5::compute(&self.tree).0,
+                    &other_md5_section[..16],
+                    IntegrityError::TreeMd5Mismatch,
+                ),
+                (
+                    md5::compute(&archive_md5_section).0,
+                    &other_md5_section[16..32],
+                    IntegrityError::ArchiveMd5SectionMismatch,
+                ),
+                (
+                    file_md5.compute().0,
+                    &other_md5_section[32..],
+                    IntegrityError::FileMd5Mismatch,
+                ),
+            ];
+            for (computed, stored, mismatch) in checks {
+                if computed != stored {
+                    mismatches.push(mismatch);
+                    if first_only {
+                        return Ok(mismatches);
+                    }
+                }
+            }
+        } else {
+            dir.seek(SeekFrom::Current(sections.other_md5 as i64))?;
+        }
+
+        for entry in archive_md5_section.chunks_exact(ARCHIVE_MD5_ENTRY_SIZE) {
+            let field = |index: usize| {
+                u32::from_le_bytes(entry[index * 4..index * 4 + 4].try_into().unwrap())
+            };
+            let (index, offset, length) = (field(0) as u16, field(1), field(2));
+
+            let mut archive = self.open_archive(index)?;
+            archive.seek(SeekFrom::Start(offset as u64))?;
+
+            let mut range_md5 = md5::Context::new();
+            io::copy(&mut archive.take(length as u64), &mut range_md5)?;
+
+            if range_md5.compute().0 != entry[12..] {
+                mismatches.push(IntegrityError::ArchiveMd5Mismatch { index });
+                if first_only {
+                    return Ok(mismatches);
+                }
+            }
+        }
+
+        Ok(mismatches)
+    }
+}
+
+fn read_section<R: Read>(read: &mut R, size: u64) -> io::Result<Vec<u8>> {
+    let mut section = vec![0u8; size as usize];
+    read.read_exact(&mut section)?;
+
+    Ok(section)
+}
+
+/// The signature section is a length-prefixed public key followed by a length-prefixed
+/// signature, filling the section exactly.
+fn is_signature_well_formed(section: &[u8]) -> bool {
+    let mut rest = section;
+
+    for _ in 0..2 {
+        let Some((size, tail)) = rest.split_first_chunk::<4>() else {
+            return false;
+        };
+        let size = u32::from_le_bytes(*size) as usize;
+
+        if size == 0 || size > tail.len() {
+            return false;
+        }
+        rest = &tail[size..];
+    }
+
+    rest.is_empty()
+}
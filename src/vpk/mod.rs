@@ -1,3 +1,15 @@
+#[cfg(feature = "vfs")]
+mod filesystem;
+#[cfg(feature = "md5")]
+mod integrity;
 mod reader;
+mod set;
+mod writer;
 
+#[cfg(feature = "vfs")]
+pub use filesystem::*;
+#[cfg(feature = "md5")]
+pub use integrity::*;
 pub use reader::*;
+pub use set::*;
+pub use writer::*;
@@ -1,9 +1,11 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::ffi::{OsStr, OsString};
 use std::fs;
-use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom};
+use std::io::{
+    self, BufRead, BufReader, BufWriter, Error, ErrorKind, Read, Result, Seek, SeekFrom, Write,
+};
 use std::mem;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use std::str;
 
 use zerocopy::FromBytes;
@@ -28,6 +30,16 @@ struct VPKHeaderV2 {
     signature_section_size: u32,
 }
 
+/// Sizes of the sections which follow the tree and inline data in a v2 directory file.
+#[derive(Debug, Clone, Copy)]
+#[cfg_attr(not(feature = "md5"), allow(dead_code))]
+pub(super) struct V2Sections {
+    pub(super) file_data: u64,
+    pub(super) archive_md5: u64,
+    pub(super) other_md5: u64,
+    pub(super) signature: u64,
+}
+
 #[repr(C, packed)]
 #[derive(FromBytes)]
 struct VPKDirectoryEntry {
@@ -41,17 +53,286 @@ struct VPKDirectoryEntry {
     terminator: u16,
 }
 
-const VPK_SIGNATURE: u32 = 0x55aa1234;
+pub(super) const VPK_SIGNATURE: u32 = 0x55aa1234;
 
-pub struct VPK {
-    path: PathBuf,
+/// A difference between a VPK and a directory tree, found by `VPK::diff_against_dir`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DiffEntry {
+    OnlyInVpk(PathBuf),
+    OnlyOnDisk(PathBuf),
+    /// Present in both, but the file on disk does not match the CRC stored in the VPK.
+    Changed(PathBuf),
+}
+
+/// The encoding of a text entry, as detected by `VPK::read_text`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TextEncoding {
+    Utf8,
+    /// UTF-8 starting with a byte order mark.
+    Utf8Bom,
+    Utf16Le,
+    Utf16Be,
+}
+
+impl TextEncoding {
+    /// Detects the encoding from a byte order mark, returning it with the length of the mark.
+    /// Text without one is taken to be UTF-8.
+    fn sniff(data: &[u8]) -> (TextEncoding, usize) {
+        match data {
+            [0xEF, 0xBB, 0xBF, ..] => (TextEncoding::Utf8Bom, 3),
+            [0xFF, 0xFE, ..] => (TextEncoding::Utf16Le, 2),
+            [0xFE, 0xFF, ..] => (TextEncoding::Utf16Be, 2),
+            _ => (TextEncoding::Utf8, 0),
+        }
+    }
+
+    fn decode(self, data: &[u8]) -> std::result::Result<String, String> {
+        let from_u16 = |to_u16: fn([u8; 2]) -> u16| {
+            let pairs = data.chunks_exact(2);
+            if !pairs.remainder().is_empty() {
+                return Err("odd number of bytes in UTF-16 text".to_string());
+            }
+
+            let units = pairs.map(|pair| to_u16([pair[0], pair[1]]));
+            char::decode_utf16(units)
+                .collect::<std::result::Result<String, _>>()
+                .map_err(|err| err.to_string())
+        };
+
+        match self {
+            TextEncoding::Utf8 | TextEncoding::Utf8Bom => str::from_utf8(data)
+                .map(str::to_string)
+                .map_err(|err| err.to_string()),
+            TextEncoding::Utf16Le => from_u16(u16::from_le_bytes),
+            TextEncoding::Utf16Be => from_u16(u16::from_be_bytes),
+        }
+    }
+}
+
+/// The differences between two VPKs' directories, found by `VPK::diff`. Each list is sorted by
+/// path.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VpkDiff {
+    /// Entries only in the other VPK.
+    pub added: Vec<PathBuf>,
+    /// Entries only in this VPK.
+    pub removed: Vec<PathBuf>,
+    /// Entries in both whose stored CRC or length differs.
+    pub changed: Vec<PathBuf>,
+}
+
+impl VpkDiff {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty() && self.changed.is_empty()
+    }
+}
+
+/// What the directory records about an entry, as listed by `VPK::entries`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EntryInfo {
+    /// The CRC32 of the entry's whole data.
+    pub crc: u32,
+    /// The length of the entry's data, counting both preload and archive data.
+    pub len: u64,
+    /// How many bytes of the data are stored in the directory tree itself.
+    pub preload_len: usize,
+    /// The archive chunk holding the rest of the data, or `0x7FFF` for the directory file.
+    /// Entries with only preload data keep whatever index the packer wrote.
+    pub archive_index: u16,
+}
+
+/// Per-entry storage flags.
+///
+/// Standard Valve VPKs never compress entries, so these always report uncompressed for now. The
+/// type exists so that variants which do compress entries can be supported without an API break.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EntryFlags {
+    compressed: bool,
+}
+
+impl EntryFlags {
+    pub fn is_compressed(&self) -> bool {
+        self.compressed
+    }
+}
+
+/// Names an archive the way chunk files are numbered, or `dir` for the directory file.
+fn archive_label(archive_index: u16) -> String {
+    if archive_index == DIRECTORY_INDEX {
+        "dir".to_string()
+    } else {
+        format!("{:03}", archive_index)
+    }
+}
+
+fn crc_hex(crc: u32) -> String {
+    format!("{:08x}", crc)
+}
+
+/// Removes an ASCII `suffix` from the end of `name`, which need not be valid Unicode.
+fn strip_ascii_suffix(name: &OsStr, suffix: &str) -> Option<OsString> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::{OsStrExt, OsStringExt};
+
+        let stripped = name.as_bytes().strip_suffix(suffix.as_bytes())?;
+        Some(OsString::from_vec(stripped.to_vec()))
+    }
+
+    #[cfg(windows)]
+    {
+        use std::os::windows::ffi::{OsStrExt, OsStringExt};
+
+        let name: Vec<u16> = name.encode_wide().collect();
+        let suffix: Vec<u16> = suffix.encode_utf16().collect();
+        let stripped = name.strip_suffix(suffix.as_slice())?;
+        Some(OsString::from_wide(stripped))
+    }
+
+    #[cfg(not(any(unix, windows)))]
+    {
+        name.to_str()?.strip_suffix(suffix).map(OsString::from)
+    }
+}
+
+/// Turns a name stored in a directory tree into a path component. Names are raw bytes in no
+/// particular encoding, so on Unix they are used exactly as stored. Elsewhere, a name which is
+/// not UTF-8 is read as Latin-1, which maps every byte to a character.
+fn tree_name(name: &[u8]) -> OsString {
+    #[cfg(unix)]
+    {
+        use std::os::unix::ffi::OsStringExt;
+
+        OsString::from_vec(name.to_vec())
+    }
+
+    #[cfg(not(unix))]
+    {
+        match str::from_utf8(name) {
+            Ok(name) => OsString::from(name),
+            Err(_) => OsString::from(name.iter().map(|&byte| byte as char).collect::<String>()),
+        }
+    }
+}
+
+/// A reader over the directory file or one archive chunk, as returned by a `ChunkProvider` or a
+/// resolver passed to `VPK::load_with_resolver`.
+pub trait ChunkRead: Read + Seek + Send {}
+
+impl<T: Read + Seek + Send> ChunkRead for T {}
+
+/// Serves the directory file and archive chunks of a VPK, so they need not be files next to each
+/// other on disk. A provider could read them out of a zip, over http or from memory.
+pub trait ChunkProvider: Send + Sync {
+    /// Opens the directory file, which holds the tree and any entries stored alongside it.
+    fn open_dir(&self) -> Result<Box<dyn ChunkRead>>;
+
+    /// Opens the archive chunk with the given index, `_NNN` in the file name.
+    fn open(&self, index: u16) -> Result<Box<dyn ChunkRead>>;
+}
+
+/// The default `ChunkProvider`: a `_dir.vpk` file with its numbered chunks next to it.
+pub struct FsChunkProvider {
+    dir_path: PathBuf,
     base_path: PathBuf,
-    files: HashMap<PathBuf, VPKFile>,
 }
 
-const DIRECTORY_INDEX: u16 = 0x7FFF;
+impl FsChunkProvider {
+    pub fn new(dir_path: &Path) -> FsChunkProvider {
+        let base_path = {
+            // Only a `_dir` directly before the extension marks the directory file, so names
+            // like `my_dir_pack_dir.vpk` keep the rest of their stem.
+            let stem = dir_path.file_stem().unwrap_or_default();
+            let mut base_name =
+                strip_ascii_suffix(stem, "_dir").unwrap_or_else(|| stem.to_os_string());
+
+            if let Some(extension) = dir_path.extension() {
+                base_name.push(".");
+                base_name.push(extension);
+            }
+
+            dir_path.with_file_name(base_name)
+        };
+
+        FsChunkProvider {
+            dir_path: dir_path.into(),
+            base_path,
+        }
+    }
+
+    /// The path of the archive chunk with the given index.
+    pub fn chunk_path(&self, index: u16) -> PathBuf {
+        if index == DIRECTORY_INDEX {
+            return self.dir_path.clone();
+        }
+
+        // Built from the stem rather than with `with_extension`, which would treat anything
+        // after a dot in the stem (`foo.bar_000`) as the extension.
+        let mut chunk_name = self
+            .base_path
+            .file_stem()
+            .unwrap_or_default()
+            .to_os_string();
+        chunk_name.push(format!("_{:03}", index));
+
+        if let Some(extension) = self.base_path.extension() {
+            chunk_name.push(".");
+            chunk_name.push(extension);
+        }
+
+        self.base_path.with_file_name(chunk_name)
+    }
+}
+
+impl ChunkProvider for FsChunkProvider {
+    fn open_dir(&self) -> Result<Box<dyn ChunkRead>> {
+        Ok(Box::new(fs::File::open(&self.dir_path)?))
+    }
+
+    fn open(&self, index: u16) -> Result<Box<dyn ChunkRead>> {
+        Ok(Box::new(fs::File::open(self.chunk_path(index))?))
+    }
+}
+
+/// Reads the directory file from disk and chunks through a resolver, for `load_with_resolver`.
+struct ResolverChunkProvider<F> {
+    dir: FsChunkProvider,
+    resolver: F,
+}
+
+impl<F> ChunkProvider for ResolverChunkProvider<F>
+where
+    F: Fn(u16) -> Result<Box<dyn ChunkRead>> + Send + Sync,
+{
+    fn open_dir(&self) -> Result<Box<dyn ChunkRead>> {
+        self.dir.open_dir()
+    }
+
+    fn open(&self, index: u16) -> Result<Box<dyn ChunkRead>> {
+        (self.resolver)(index)
+    }
+}
 
-struct VPKFile {
+/// A loaded VPK directory.
+///
+/// The directory is immutable once loaded and every call to `get` opens its own handle onto the
+/// backing archive, so a `VPK` is `Send + Sync` and can be shared between threads through an `Arc`.
+pub struct VPK {
+    pub(super) files: HashMap<PathBuf, VPKFile>,
+    /// Entry paths in the order they appear in the tree.
+    tree_order: Vec<PathBuf>,
+    /// The directory tree exactly as stored in the file.
+    pub(super) tree: Vec<u8>,
+    /// Only set for v2 directories.
+    pub(super) v2_sections: Option<V2Sections>,
+    chunks: Box<dyn ChunkProvider>,
+}
+
+pub(super) const DIRECTORY_INDEX: u16 = 0x7FFF;
+pub(super) const V2_HEADER_SIZE: u64 = mem::size_of::<VPKHeaderV2>() as u64;
+const COPY_BUFFER_SIZE: usize = 8192;
+
+pub(super) struct VPKFile {
     crc: u32,
 
     preload_data: Vec<u8>,
@@ -59,35 +340,111 @@ struct VPKFile {
     archive_index: u16,
     archive_offset: u64, // Larger for DIRECTORY_INDEX case
     archive_length: u32,
+
+    flags: EntryFlags,
+}
+
+impl VPKFile {
+    /// Length of the entry's data, counting both preload and archive data.
+    pub(super) fn len(&self) -> u64 {
+        self.preload_data.len() as u64 + self.archive_length as u64
+    }
 }
 
 impl VPK {
     pub fn load(path: &Path) -> Result<VPK> {
-        let mut vpk_file = fs::File::open(path)?;
-
-        let base_path = {
-            let file_name = path
-                .file_name()
-                .unwrap()
-                .to_str()
-                .expect("Non-UTF8 paths not supported");
+        Self::load_from_provider(FsChunkProvider::new(path))
+    }
 
-            path.with_file_name::<OsString>(file_name.replace("_dir", "").into())
-        };
+    /// Loads a VPK whose directory file and chunks are both opened through `chunks`.
+    pub fn load_from_provider<P: ChunkProvider + 'static>(chunks: P) -> Result<VPK> {
+        let mut vpk_file = chunks.open_dir()?;
 
         let mut vpk = VPK {
-            path: path.into(),
-            base_path: base_path,
             files: HashMap::new(),
+            tree_order: Vec::new(),
+            tree: Vec::new(),
+            v2_sections: None,
+            chunks: Box::new(chunks),
         };
 
         vpk.load_internal(&mut vpk_file)?;
         Ok(vpk)
     }
 
-    fn load_internal(&mut self, vpk_file: &mut fs::File) -> Result<()> {
+    /// Loads the directory file at `path` like `load`, then checks that every entry lies within
+    /// its chunk file, so corruption is reported here instead of by a later `get`. Fails on the
+    /// first entry, in tree order, whose chunk is missing or too short, naming the entry.
+    pub fn load_strict(path: &Path) -> Result<VPK> {
+        let vpk = Self::load(path)?;
+        let chunks = FsChunkProvider::new(path);
+        let mut chunk_sizes: HashMap<u16, u64> = HashMap::new();
+
+        for entry_path in &vpk.tree_order {
+            let entry = &vpk.files[entry_path];
+            if entry.archive_length == 0 {
+                continue;
+            }
+
+            let archive = archive_label(entry.archive_index);
+            let chunk_size = match chunk_sizes.get(&entry.archive_index) {
+                Some(&chunk_size) => chunk_size,
+                None => {
+                    let chunk_path = chunks.chunk_path(entry.archive_index);
+                    let chunk_size = fs::metadata(&chunk_path)
+                        .map_err(|err| {
+                            Error::new(
+                                err.kind(),
+                                format!(
+                                    "{} (archive {}): cannot read {}: {}",
+                                    entry_path.display(),
+                                    archive,
+                                    chunk_path.display(),
+                                    err
+                                ),
+                            )
+                        })?
+                        .len();
+
+                    chunk_sizes.insert(entry.archive_index, chunk_size);
+                    chunk_size
+                }
+            };
+
+            let entry_end = entry.archive_offset + entry.archive_length as u64;
+            if entry_end > chunk_size {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "{} (archive {}) ends at byte {} but the archive is only {} bytes",
+                        entry_path.display(),
+                        archive,
+                        entry_end,
+                        chunk_size
+                    ),
+                ));
+            }
+        }
+
+        Ok(vpk)
+    }
+
+    /// Loads the directory file at `dir_path`, opening archive chunks through `resolver` instead
+    /// of looking for `_NNN.vpk` files next to it. `resolver` is given the chunk index. Entries
+    /// stored in the directory file itself are still read from `dir_path`.
+    pub fn load_with_resolver<F>(dir_path: &Path, resolver: F) -> Result<VPK>
+    where
+        F: Fn(u16) -> Result<Box<dyn ChunkRead>> + Send + Sync + 'static,
+    {
+        Self::load_from_provider(ResolverChunkProvider {
+            dir: FsChunkProvider::new(dir_path),
+            resolver,
+        })
+    }
+
+    fn load_internal(&mut self, vpk_file: &mut dyn Read) -> Result<()> {
         let mut header_data = [0u8; mem::size_of::<VPKHeaderV2>()];
-        vpk_file.read(&mut header_data[..mem::size_of::<VPKHeaderV1>()])?;
+        vpk_file.read_exact(&mut header_data[..mem::size_of::<VPKHeaderV1>()])?;
 
         let v1_header = VPKHeaderV1::read_from_prefix(header_data.as_slice()).unwrap();
 
@@ -101,7 +458,7 @@ impl VPK {
         let version = v1_header.version;
         match version {
             2 => {
-                vpk_file.read(&mut header_data[mem::size_of::<VPKHeaderV1>()..])?;
+                vpk_file.read_exact(&mut header_data[mem::size_of::<VPKHeaderV1>()..])?;
 
                 let v2_header = VPKHeaderV2::read_from_prefix(header_data.as_slice()).unwrap();
 
@@ -119,66 +476,70 @@ impl VPK {
         Ok(())
     }
 
-    fn read_string(data: &[u8]) -> Result<(usize, &str)> {
-        let terminator = data
-            .iter()
-            .position(|&byte| byte == 0x00)
-            .expect("Tree entry with unterminated name");
-
-        let parsed_str = str::from_utf8(&data[..terminator]).or_else(|_| {
-            Err(Error::new(
+    fn read_string(data: &[u8], position: usize) -> Result<(usize, &[u8])> {
+        let data = &data[position..];
+        let terminator = data.iter().position(|&byte| byte == 0x00).ok_or_else(|| {
+            Error::new(
                 ErrorKind::InvalidData,
-                "Invalid tree entry name (Non-UTF8)",
-            ))
+                format!("Unterminated tree entry name at tree offset {}", position),
+            )
         })?;
 
-        Ok((terminator + 1, parsed_str))
+        Ok((terminator + 1, &data[..terminator]))
     }
 
     fn load_tree(
         &mut self,
         tree_size: usize,
-        header_offset: usize,
-        vpk_file: &mut fs::File,
+        header_offset: u64,
+        vpk_file: &mut dyn Read,
     ) -> Result<()> {
         let mut loaded_data = vec![0u8; tree_size];
-        vpk_file.read(loaded_data.as_mut_slice())?;
+        vpk_file.read_exact(loaded_data.as_mut_slice())?;
         let loaded_data = loaded_data;
 
         let mut position = 0usize;
         while position < tree_size {
-            let (num_read, extension) = Self::read_string(&loaded_data[position..])?;
+            let (num_read, extension) = Self::read_string(&loaded_data, position)?;
             position += num_read;
 
+            // The end of the tree. Anything after this is alignment padding some packers add,
+            // which is covered by `tree_size` but holds no entries.
             if extension.is_empty() {
                 break;
             }
 
-            let extension = if extension == " " { "" } else { extension };
+            let extension = tree_name(if extension == b" " { b"" } else { extension });
 
             loop {
-                let (num_read, path) = Self::read_string(&loaded_data[position..])?;
+                let (num_read, path) = Self::read_string(&loaded_data, position)?;
                 position += num_read;
 
                 if path.is_empty() {
                     break;
                 }
 
-                let path = if path == " " { "" } else { path };
+                let path = tree_name(if path == b" " { b"" } else { path });
 
                 loop {
-                    let (num_read, file_name) = Self::read_string(&loaded_data[position..])?;
+                    let (num_read, file_name) = Self::read_string(&loaded_data, position)?;
                     position += num_read;
 
                     if file_name.is_empty() {
                         break;
                     }
 
-                    let file_name = if file_name == " " { "" } else { file_name };
+                    let mut file_name = tree_name(if file_name == b" " { b"" } else { file_name });
 
-                    let mut full_path = PathBuf::from(path);
-                    full_path.push(OsStr::new(file_name));
-                    full_path.set_extension(extension);
+                    // Appended rather than set, as `set_extension` would replace the last part of
+                    // a stem with a dot in it, such as the `tar` of `foo.tar.gz`.
+                    if !extension.is_empty() {
+                        file_name.push(".");
+                        file_name.push(&extension);
+                    }
+
+                    let mut full_path = PathBuf::from(&path);
+                    full_path.push(file_name);
 
                     let directory_entry = VPKDirectoryEntry::read_from_prefix(
                         &loaded_data[position..],
@@ -186,97 +547,718 @@ impl VPK {
                     .ok_or_else(|| Error::new(ErrorKind::InvalidData, "VPK tree malformed"))?;
                     position += mem::size_of::<VPKDirectoryEntry>();
 
-                    let preload_data = Vec::from(
-                        &loaded_data[position..position + directory_entry.preload_bytes as usize],
-                    );
+                    let preload_data = loaded_data
+                        .get(position..position + directory_entry.preload_bytes as usize)
+                        .map(Vec::from)
+                        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "VPK tree malformed"))?;
                     position += directory_entry.preload_bytes as usize;
 
                     let archive_offset = if directory_entry.archive_index == DIRECTORY_INDEX {
-                        directory_entry.entry_offset as u64 + header_offset as u64
+                        directory_entry.entry_offset as u64 + header_offset
                     } else {
                         directory_entry.entry_offset as u64
                     };
 
                     let vpkfile = VPKFile {
                         crc: directory_entry.crc,
-                        preload_data: preload_data,
+                        preload_data,
                         archive_index: directory_entry.archive_index,
-                        archive_offset: archive_offset,
+                        archive_offset,
                         archive_length: directory_entry.entry_length,
+                        flags: EntryFlags::default(),
                     };
 
-                    self.files.insert(full_path, vpkfile);
+                    // A path repeated in the tree keeps its first position, with the last data.
+                    if self.files.insert(full_path.clone(), vpkfile).is_none() {
+                        self.tree_order.push(full_path);
+                    }
                 }
             }
         }
 
+        self.tree = loaded_data;
         Ok(())
     }
 
-    fn load_v2(&mut self, header: VPKHeaderV2, vpk_file: &mut fs::File) -> Result<()> {
-        self.load_tree(
-            header.v1.tree_size as usize,
-            mem::size_of::<VPKHeaderV2>() + header.v1.tree_size as usize,
-            vpk_file,
-        )?;
+    fn load_v2(&mut self, header: VPKHeaderV2, vpk_file: &mut dyn Read) -> Result<()> {
+        // Data stored in the directory file starts directly after the tree, without padding.
+        let data_start = V2_HEADER_SIZE + header.v1.tree_size as u64;
+
+        self.load_tree(header.v1.tree_size as usize, data_start, vpk_file)?;
+
+        let data_end = data_start + header.file_data_section_size as u64;
+        let overrun = self.files.iter().find(|(_, entry)| {
+            entry.archive_index == DIRECTORY_INDEX
+                && entry.archive_offset + entry.archive_length as u64 > data_end
+        });
+
+        if let Some((path, _)) = overrun {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "{} extends past the end of the directory file's data section",
+                    path.display()
+                ),
+            ));
+        }
+
+        self.v2_sections = Some(V2Sections {
+            file_data: header.file_data_section_size as u64,
+            archive_md5: header.archive_md5_section_size as u64,
+            other_md5: header.other_md5_section_size as u64,
+            signature: header.signature_section_size as u64,
+        });
 
-        // Don't bother with the rest for now
         Ok(())
     }
 
-    fn load_v1(&mut self, header: VPKHeaderV1, vpk_file: &mut fs::File) -> Result<()> {
+    fn load_v1(&mut self, header: VPKHeaderV1, vpk_file: &mut dyn Read) -> Result<()> {
         self.load_tree(
             header.tree_size as usize,
-            mem::size_of::<VPKHeaderV1>() + header.tree_size as usize,
+            mem::size_of::<VPKHeaderV1>() as u64 + header.tree_size as u64,
             vpk_file,
         )?;
 
         Ok(())
     }
 
-    pub fn get(&mut self, path: &Path) -> Result<File<'_>> {
-        let entry = self.files.get(path).ok_or_else(|| {
-            Error::new(
-                ErrorKind::NotFound,
-                format!("{} not found in VPK", path.display()),
-            )
-        })?;
+    /// The number of entries in the directory.
+    pub fn entry_count(&self) -> usize {
+        self.files.len()
+    }
+
+    /// The directory tree exactly as stored in the directory file, without the header, as
+    /// covered by the v2 MD5 and signature sections.
+    pub fn raw_tree(&self) -> &[u8] {
+        &self.tree
+    }
+
+    /// Iterates over the entry paths in the order they are stored in the directory tree, which
+    /// packers may use as a load order hint. Repacking in this order reproduces the original
+    /// layout.
+    pub fn files_in_archive_order(&self) -> impl Iterator<Item = &Path> {
+        self.tree_order.iter().map(PathBuf::as_path)
+    }
+
+    /// Iterates over the path of every entry, in the order they are stored in the directory
+    /// tree.
+    pub fn paths(&self) -> impl Iterator<Item = &Path> {
+        self.files_in_archive_order()
+    }
+
+    /// Iterates over every entry with what the directory records about it, in the order they
+    /// are stored in the directory tree. Nothing is read from the archives.
+    pub fn entries(&self) -> impl Iterator<Item = (&Path, EntryInfo)> {
+        self.tree_order.iter().map(|path| {
+            let entry = &self.files[path];
+            let info = EntryInfo {
+                crc: entry.crc,
+                len: entry.len(),
+                preload_len: entry.preload_data.len(),
+                archive_index: entry.archive_index,
+            };
+
+            (path.as_path(), info)
+        })
+    }
+
+    pub fn contains(&self, path: &Path) -> bool {
+        self.files.contains_key(path)
+    }
+
+    pub fn get(&self, path: &Path) -> Result<File<'_>> {
+        let (path, entry) = self.readable_entry(path)?;
 
         // Handle preload data case
         if entry.archive_length == 0 {
             return Ok(File {
-                fs_file: None,
+                archive: None,
+                path,
                 metadata: entry,
                 position: 0,
             });
         }
 
-        let archive_name = if entry.archive_index == DIRECTORY_INDEX {
-            self.path.clone()
-        } else {
-            let mut file_prefix =
-                OsString::from(self.base_path.with_extension("").file_name().unwrap());
+        let mut archive = self.open_archive(entry.archive_index)?;
+        let archive_size = archive.seek(SeekFrom::End(0))?;
+        archive.seek(SeekFrom::Start(entry.archive_offset))?;
 
-            file_prefix.push(format!("_{:03}", entry.archive_index));
-            self.base_path
-                .with_file_name(file_prefix)
-                .with_extension(self.base_path.extension().unwrap())
+        let file = File {
+            archive: Some(archive),
+            path,
+            metadata: entry,
+            position: 0,
         };
 
-        let mut fs_file = fs::File::open(archive_name)?;
-        fs_file.seek(SeekFrom::Start(entry.archive_offset))?;
+        // Catch entries which run off the end of their archive up front, rather than part way
+        // through a read.
+        let entry_end = entry.archive_offset + entry.archive_length as u64;
+        if entry_end > archive_size {
+            return Err(file.error_with_context(Error::new(
+                ErrorKind::UnexpectedEof,
+                format!(
+                    "entry ends at byte {entry_end} but the archive is only {archive_size} bytes"
+                ),
+            )));
+        }
+
+        Ok(file)
+    }
 
-        Ok(File {
-            fs_file: Some(fs_file),
+    /// Looks up an entry which can be read, as compressed entries are not supported.
+    fn readable_entry(&self, path: &Path) -> Result<(&Path, &VPKFile)> {
+        let (path, entry) = self.files.get_key_value(path).ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!("{} not found in VPK", path.display()),
+            )
+        })?;
+
+        if entry.flags.is_compressed() {
+            return Err(Error::new(
+                ErrorKind::Unsupported,
+                format!("{} is compressed, which is not supported", path.display()),
+            ));
+        }
+
+        Ok((path, entry))
+    }
+
+    /// Reads the requested entries one at a time, yielding each path with its data, so only one
+    /// entry is held in memory however many are requested. Entries are read grouped by chunk and
+    /// in offset order within each, rather than in the order requested, and each chunk is opened
+    /// once. Paths which are not in the VPK come first, as errors. A consumer can write each
+    /// entry out before the next is read, or send them over a bounded channel to do so on
+    /// another thread:
+    ///
+    /// ```no_run
+    /// # use std::path::Path;
+    /// # use std::sync::{mpsc, Arc};
+    /// # use srcrs::vpk::VPK;
+    /// let vpk = Arc::new(VPK::load(Path::new("pak01_dir.vpk"))?);
+    /// let paths: Vec<_> = vpk.files_in_archive_order().map(Path::to_path_buf).collect();
+    ///
+    /// let (sender, receiver) = mpsc::sync_channel(4);
+    /// let reader = std::thread::spawn(move || {
+    ///     for entry in vpk.stream_entries(paths) {
+    ///         if sender.send(entry).is_err() {
+    ///             break;
+    ///         }
+    ///     }
+    /// });
+    ///
+    /// for entry in receiver {
+    ///     let (path, data) = entry?;
+    ///     // Write `data` out under `path`.
+    /// }
+    /// reader.join().unwrap();
+    /// # Ok::<(), std::io::Error>(())
+    /// ```
+    pub fn stream_entries<P: AsRef<Path>>(
+        &self,
+        paths: impl IntoIterator<Item = P>,
+    ) -> impl Iterator<Item = Result<(PathBuf, Vec<u8>)>> + '_ {
+        let mut paths: Vec<PathBuf> = paths
+            .into_iter()
+            .map(|path| path.as_ref().to_path_buf())
+            .collect();
+        paths.sort_by_key(|path| {
+            self.files
+                .get(path)
+                .map(|entry| (entry.archive_index, entry.archive_offset))
+        });
+
+        let mut open_archive: Option<(u16, Box<dyn ChunkRead>)> = None;
+
+        paths.into_iter().map(move |path| {
+            let data = self.read_streamed(&path, &mut open_archive)?;
+            Ok((path, data))
+        })
+    }
+
+    /// Reads a whole entry for `stream_entries`, reusing `open_archive` if it is the entry's
+    /// chunk and leaving the entry's chunk open in it otherwise.
+    fn read_streamed(
+        &self,
+        path: &Path,
+        open_archive: &mut Option<(u16, Box<dyn ChunkRead>)>,
+    ) -> Result<Vec<u8>> {
+        let (path, entry) = self.readable_entry(path)?;
+
+        let archive = if entry.archive_length == 0 {
+            None
+        } else {
+            let archive = match open_archive.take() {
+                Some((index, archive)) if index == entry.archive_index => archive,
+                _ => self.open_archive(entry.archive_index)?,
+            };
+            Some(archive)
+        };
+
+        let mut file = File {
+            archive,
+            path,
             metadata: entry,
             position: 0,
+        };
+        if let Some(archive) = file.archive.as_mut() {
+            archive.seek(SeekFrom::Start(entry.archive_offset))?;
+        }
+
+        let mut data = Vec::with_capacity(entry.len() as usize);
+        file.read_to_end(&mut data)?;
+
+        if (data.len() as u64) < entry.len() {
+            return Err(file.error_with_context(Error::new(
+                ErrorKind::UnexpectedEof,
+                "entry runs past the end of its archive",
+            )));
+        }
+
+        if let Some(archive) = file.archive.take() {
+            *open_archive = Some((entry.archive_index, archive));
+        }
+
+        Ok(data)
+    }
+
+    /// Checks that every chunk file referenced by the directory exists and is large enough to
+    /// hold all of its entries, returning the sorted indices of any which are missing or short.
+    pub fn check_chunks(&self) -> std::result::Result<(), Vec<u16>> {
+        let mut required_sizes: HashMap<u16, u64> = HashMap::new();
+
+        for entry in self.files.values() {
+            if entry.archive_index == DIRECTORY_INDEX || entry.archive_length == 0 {
+                continue;
+            }
+
+            let required_size = required_sizes.entry(entry.archive_index).or_default();
+            *required_size =
+                (*required_size).max(entry.archive_offset + entry.archive_length as u64);
+        }
+
+        let mut bad_chunks: Vec<u16> = required_sizes
+            .into_iter()
+            .filter(|&(index, required_size)| {
+                self.open_archive(index)
+                    .and_then(|mut archive| archive.seek(SeekFrom::End(0)))
+                    .map_or(true, |archive_size| archive_size < required_size)
+            })
+            .map(|(index, _)| index)
+            .collect();
+
+        if bad_chunks.is_empty() {
+            return Ok(());
+        }
+
+        bad_chunks.sort();
+        Err(bad_chunks)
+    }
+
+    pub(super) fn open_archive(&self, archive_index: u16) -> Result<Box<dyn ChunkRead>> {
+        if archive_index == DIRECTORY_INDEX {
+            self.chunks.open_dir()
+        } else {
+            self.chunks.open(archive_index)
+        }
+    }
+
+    /// Extracts every entry to the corresponding path under `dest`, creating directories as
+    /// needed. Entries with only preload data are extracted like any other.
+    pub fn extract_all(&self, dest: &Path) -> Result<()> {
+        self.extract_entries(dest, false, |_, _, _| {})
+    }
+
+    /// Like `extract_all`, but checks each entry's data against its CRC as it is written, and
+    /// stops with an `ErrorKind::InvalidData` error at the first which does not match. That
+    /// file is left as it was written.
+    pub fn extract_all_checked(&self, dest: &Path) -> Result<()> {
+        self.extract_entries(dest, true, |_, _, _| {})
+    }
+
+    /// Extracts every entry to the corresponding path under `dest`, creating directories as
+    /// needed. `progress` is called after each file is written with its entry path, the number
+    /// of files written so far and the total number of files.
+    pub fn extract_all_with_progress<F>(&self, dest: &Path, progress: F) -> Result<()>
+    where
+        F: FnMut(&Path, u64, u64),
+    {
+        self.extract_entries(dest, false, progress)
+    }
+
+    /// Writes the entry at `path` to the file `dest`, creating its directory if needed.
+    pub fn extract(&self, path: &Path, dest: &Path) -> Result<()> {
+        self.extract_entry(path, dest, false)
+    }
+
+    /// Like `extract`, but fails with `ErrorKind::InvalidData` if the data does not match the
+    /// entry's CRC. The file is left as it was written.
+    pub fn extract_checked(&self, path: &Path, dest: &Path) -> Result<()> {
+        self.extract_entry(path, dest, true)
+    }
+
+    fn extract_entries<F>(&self, dest: &Path, check_crc: bool, mut progress: F) -> Result<()>
+    where
+        F: FnMut(&Path, u64, u64),
+    {
+        let mut paths: Vec<&PathBuf> = self.files.keys().collect();
+        paths.sort();
+
+        // Checked before anything is written, so a bad tree leaves `dest` untouched.
+        let targets = paths
+            .iter()
+            .map(|path| Self::extract_target(dest, path))
+            .collect::<Result<Vec<_>>>()?;
+
+        let total = paths.len() as u64;
+
+        for (index, (path, target)) in paths.into_iter().zip(targets).enumerate() {
+            self.extract_entry(path, &target, check_crc)?;
+
+            progress(path, index as u64 + 1, total);
+        }
+
+        Ok(())
+    }
+
+    /// Where the entry at `path` is extracted to under `dest`. Fails with
+    /// `ErrorKind::InvalidData` for a path which is absolute or climbs out with `..`, as a
+    /// crafted tree could hold.
+    fn extract_target(dest: &Path, path: &Path) -> Result<PathBuf> {
+        let mut target = dest.to_path_buf();
+
+        for component in path.components() {
+            match component {
+                Component::Normal(name) => target.push(name),
+                Component::CurDir => {}
+                Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        format!(
+                            "{} would be extracted outside of the destination",
+                            path.display()
+                        ),
+                    ))
+                }
+            }
+        }
+
+        Ok(target)
+    }
+
+    fn extract_entry(&self, path: &Path, dest: &Path, check_crc: bool) -> Result<()> {
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let mut file = self.get(path)?;
+        let mut writer = CrcWriter {
+            inner: BufWriter::new(fs::File::create(dest)?),
+            hasher: crc32fast::Hasher::new(),
+        };
+        file.copy_to(&mut writer)?;
+        writer.flush()?;
+
+        let crc = writer.hasher.finalize();
+        if check_crc && crc != file.metadata.crc {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "{} has CRC {} but the directory records {}",
+                    path.display(),
+                    crc_hex(crc),
+                    crc_hex(file.metadata.crc)
+                ),
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Compares the entries of this VPK against the files under `dir`, such as a previous
+    /// extraction. Files present in both are compared by CRC. The result is sorted by path.
+    pub fn diff_against_dir(&self, dir: &Path) -> Result<Vec<DiffEntry>> {
+        let mut disk_files = Vec::new();
+        Self::collect_files(dir, Path::new(""), &mut disk_files)?;
+
+        let mut diff = Vec::new();
+
+        for disk_file in &disk_files {
+            match self.files.get(disk_file) {
+                None => diff.push(DiffEntry::OnlyOnDisk(disk_file.clone())),
+                Some(entry) => {
+                    if crc32fast::hash(&fs::read(dir.join(disk_file))?) != entry.crc {
+                        diff.push(DiffEntry::Changed(disk_file.clone()));
+                    }
+                }
+            }
+        }
+
+        let disk_files: HashSet<PathBuf> = disk_files.into_iter().collect();
+        for path in self.files.keys() {
+            if !disk_files.contains(path) {
+                diff.push(DiffEntry::OnlyInVpk(path.clone()));
+            }
+        }
+
+        diff.sort_by(|a, b| Self::diff_path(a).cmp(Self::diff_path(b)));
+        Ok(diff)
+    }
+
+    /// Compares this VPK's directory with `other`'s, treating `other` as the newer version. Only
+    /// the CRCs and lengths stored in the directories are compared, so no entry data is read.
+    pub fn diff(&self, other: &VPK) -> VpkDiff {
+        let mut diff = VpkDiff::default();
+
+        for (path, entry) in &self.files {
+            match other.files.get(path) {
+                None => diff.removed.push(path.clone()),
+                Some(other_entry) => {
+                    if entry.crc != other_entry.crc || entry.len() != other_entry.len() {
+                        diff.changed.push(path.clone());
+                    }
+                }
+            }
+        }
+
+        diff.added = other
+            .files
+            .keys()
+            .filter(|path| !self.files.contains_key(*path))
+            .cloned()
+            .collect();
+
+        diff.added.sort();
+        diff.removed.sort();
+        diff.changed.sort();
+
+        diff
+    }
+
+    fn diff_path(entry: &DiffEntry) -> &Path {
+        match entry {
+            DiffEntry::OnlyInVpk(path) | DiffEntry::OnlyOnDisk(path) | DiffEntry::Changed(path) => {
+                path
+            }
+        }
+    }
+
+    fn collect_files(root: &Path, relative: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+        for dir_entry in fs::read_dir(root.join(relative))? {
+            let dir_entry = dir_entry?;
+            let path = relative.join(dir_entry.file_name());
+
+            if dir_entry.file_type()?.is_dir() {
+                Self::collect_files(root, &path, files)?;
+            } else {
+                files.push(path);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Recomputes the CRC of every entry, returning the paths of all entries whose data does not
+    /// match the stored CRC rather than stopping at the first failure.
+    pub fn verify_crcs(&self) -> Result<Vec<PathBuf>> {
+        let mut mismatches = Vec::new();
+
+        for path in self.files.keys() {
+            let mut file = self.get(path)?;
+
+            if file.calc_crc32()? != file.metadata.crc {
+                mismatches.push(path.clone());
+            }
+        }
+
+        mismatches.sort();
+        Ok(mismatches)
+    }
+
+    /// Returns an entry's stored CRC as 8 lowercase hex digits, as manifests and tools show it,
+    /// or `None` if there is no such entry.
+    pub fn entry_crc_hex(&self, path: &Path) -> Option<String> {
+        self.files.get(path).map(|entry| crc_hex(entry.crc))
+    }
+
+    /// Writes a text listing of every entry, sorted by path, for diffing archive contents. Each
+    /// line is `path crc archive length`, with the CRC in hex, the archive as in chunk file names
+    /// (or `dir`) and the length including preload data.
+    pub fn write_manifest<W: Write>(&self, w: &mut W) -> Result<()> {
+        let mut entries: Vec<(&PathBuf, &VPKFile)> = self.files.iter().collect();
+        entries.sort_by_key(|(path, _)| *path);
+
+        for (path, entry) in entries {
+            writeln!(
+                w,
+                "{} {} {} {}",
+                path.display(),
+                crc_hex(entry.crc),
+                archive_label(entry.archive_index),
+                entry.len()
+            )?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads the whole of an entry into memory.
+    pub fn read_to_vec(&self, path: &Path) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        self.read_into(path, &mut data)?;
+
+        Ok(data)
+    }
+
+    /// Reads the whole of an entry into `buffer`, replacing its contents. Reusing one buffer
+    /// across many entries avoids allocating for each of them.
+    pub fn read_into(&self, path: &Path, buffer: &mut Vec<u8>) -> Result<()> {
+        let mut file = self.get(path)?;
+
+        buffer.clear();
+        buffer.reserve(file.len());
+        file.copy_to(buffer)?;
+
+        Ok(())
+    }
+
+    /// Reads the whole of an entry into memory, along with whether it matches its stored CRC.
+    /// The CRC is computed as the data is read, rather than by reading the entry again as
+    /// `File::verify` would.
+    pub fn get_checked(&self, path: &Path) -> Result<(Vec<u8>, bool)> {
+        let mut file = self.get(path)?;
+
+        let mut data = Vec::with_capacity(file.len());
+        let mut writer = CrcWriter {
+            inner: &mut data,
+            hasher: crc32fast::Hasher::new(),
+        };
+        file.copy_to(&mut writer)?;
+
+        let matches = writer.hasher.finalize() == file.metadata.crc;
+        Ok((data, matches))
+    }
+
+    /// Reads up to the first `n` bytes of an entry, such as to check a file's magic number. When
+    /// the entry's preload data covers them, they are served from memory without opening the
+    /// archive.
+    pub fn read_prefix(&self, path: &Path, n: usize) -> Result<Vec<u8>> {
+        let entry = self.files.get(path).ok_or_else(|| {
+            Error::new(
+                ErrorKind::NotFound,
+                format!("{} not found in VPK", path.display()),
+            )
+        })?;
+
+        if n <= entry.preload_data.len() && !entry.flags.is_compressed() {
+            return Ok(entry.preload_data[..n].to_vec());
+        }
+
+        let len = usize::try_from(entry.len()).map_or(n, |len| len.min(n));
+        self.read_range(path, 0, len)
+    }
+
+    /// Iterates over the lines of a text entry, read through a buffer rather than all at once.
+    /// Lines are split as by `BufRead::lines`, and a line which is not valid UTF-8 is an
+    /// `InvalidData` error.
+    pub fn lines(&self, path: &Path) -> Result<io::Lines<BufReader<File<'_>>>> {
+        Ok(BufReader::new(self.get(path)?).lines())
+    }
+
+    /// Reads `len` bytes starting `offset` bytes into an entry, counting preload and archive data
+    /// as one stream, such as just the header of a texture. Returns an `InvalidInput` error if
+    /// the range runs past the end of the entry.
+    pub fn read_range(&self, path: &Path, offset: u64, len: usize) -> Result<Vec<u8>> {
+        let mut file = self.get(path)?;
+
+        let entry_len = file.metadata.len();
+        let end = offset.checked_add(len as u64);
+        if end.is_none_or(|end| end > entry_len) {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!(
+                    "range {}..{} is past the end of {}, which is {} bytes",
+                    offset,
+                    offset as u128 + len as u128,
+                    path.display(),
+                    entry_len
+                ),
+            ));
+        }
+
+        let mut data = vec![0u8; len];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(&mut data)?;
+
+        Ok(data)
+    }
+
+    /// Reads the whole of a text entry into memory, returning an `InvalidData` error if it is not
+    /// valid UTF-8.
+    pub fn read_to_string(&self, path: &Path) -> Result<String> {
+        String::from_utf8(self.read_to_vec(path)?).map_err(|err| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "{} is not valid UTF-8: {}",
+                    path.display(),
+                    err.utf8_error()
+                ),
+            )
         })
     }
+
+    /// Reads the whole of a text entry into memory, decoding it according to its byte order mark
+    /// (UTF-8 or either UTF-16 byte order), or as UTF-8 if it has none. The mark is not included
+    /// in the returned text. Returns an `InvalidData` error if the text does not decode.
+    pub fn read_text(&self, path: &Path) -> Result<(String, TextEncoding)> {
+        let data = self.read_to_vec(path)?;
+        let (encoding, bom_length) = TextEncoding::sniff(&data);
+
+        let text = encoding.decode(&data[bom_length..]).map_err(|err| {
+            Error::new(
+                ErrorKind::InvalidData,
+                format!("{} is not valid {:?}: {}", path.display(), encoding, err),
+            )
+        })?;
+
+        Ok((text, encoding))
+    }
+
+    /// Computes the MD5 of an entry's data, for checking against manifests which track MD5
+    /// rather than CRC.
+    #[cfg(feature = "md5")]
+    pub fn entry_md5(&self, path: &Path) -> Result<[u8; 16]> {
+        self.get(path)?.calc_md5()
+    }
 }
 
+/// Passes writes through to `inner`, hashing them on the way.
+struct CrcWriter<W> {
+    inner: W,
+    hasher: crc32fast::Hasher,
+}
+
+impl<W: Write> Write for CrcWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// A readable, seekable view of one entry, whichever way its data is stored: preload data in the
+/// directory, data in an archive chunk or the directory file, or a mix of preload and archive
+/// data, which reads as one contiguous stream.
 // Should implement Read and Seek, CANNOT implement Write (just yet).
 pub struct File<'a> {
-    fs_file: Option<fs::File>, // None if preload data is all that is needed.
+    archive: Option<Box<dyn ChunkRead>>, // None if preload data is all that is needed.
+    path: &'a Path,
     metadata: &'a VPKFile,
 
     position: u64,
@@ -284,53 +1266,96 @@ pub struct File<'a> {
 
 impl<'a> Read for File<'a> {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        self.read_archive(buf)
+            .map_err(|err| self.error_with_context(err))
+    }
+}
+
+impl<'a> Seek for File<'a> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
+        self.seek_archive(pos)
+            .map_err(|err| self.error_with_context(err))
+    }
+
+    #[cfg(seek_stream_len)]
+    fn stream_len(&mut self) -> Result<u64> {
+        Ok(self.len() as u64)
+    }
+
+    fn stream_position(&mut self) -> Result<u64> {
+        Ok(self.position)
+    }
+}
+
+impl<'a> File<'a> {
+    fn read_archive(&mut self, buf: &mut [u8]) -> Result<usize> {
         let preload_len = self.metadata.preload_data.len();
-        let total_size = self.metadata.archive_length as usize + preload_len;
-        let position = self.position as usize;
 
-        let maximum_read = usize::min(total_size - position as usize, buf.len());
+        // Kept in u64 until known to be within the entry, as a seek can put the position
+        // anywhere.
+        let remaining = self.metadata.len().saturating_sub(self.position);
+        let maximum_read = u64::min(remaining, buf.len() as u64) as usize;
 
         let read_buf = &mut buf[..maximum_read];
 
-        if position < preload_len {
+        let num_read = if self.position < preload_len as u64 {
+            let position = self.position as usize;
             let maximum_preload_read = usize::min(preload_len - position, read_buf.len());
 
             read_buf[..maximum_preload_read].clone_from_slice(
                 &self.metadata.preload_data.as_slice()[position..position + maximum_preload_read],
             );
 
-            if let Some(file) = self.fs_file.as_mut() {
-                let num_read = file.read(
-                    &mut read_buf[maximum_preload_read..maximum_read - maximum_preload_read],
-                )?;
+            if let Some(file) = self.archive.as_mut() {
+                let num_read = file.read(&mut read_buf[maximum_preload_read..])?;
 
-                Ok(maximum_preload_read + num_read)
+                maximum_preload_read + num_read
             } else {
-                Ok(maximum_preload_read)
+                maximum_preload_read
             }
-        } else if let Some(file) = self.fs_file.as_mut() {
-            file.read(&mut read_buf[..maximum_read])?;
-
-            Ok(maximum_read)
+        } else if let Some(file) = self.archive.as_mut() {
+            file.read(read_buf)?
         } else {
-            Ok(0)
+            0
+        };
+
+        if num_read == 0 && maximum_read > 0 {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "archive ends before the end of the entry",
+            ));
         }
+
+        // The archive may deliver less than was asked for, so only count what actually arrived.
+        self.position += num_read as u64;
+        Ok(num_read)
     }
-}
 
-impl<'a> Seek for File<'a> {
-    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
-        self.position = match pos {
-            SeekFrom::Current(offset) => self.position + offset as u64,
-            SeekFrom::End(offset) => (self.metadata.archive_length as i128 + offset as i128) as u64,
-            SeekFrom::Start(offset) => offset,
+    fn seek_archive(&mut self, pos: SeekFrom) -> Result<u64> {
+        let total_size = self.len() as i128;
+
+        let position = match pos {
+            SeekFrom::Current(offset) => self.position as i128 + offset as i128,
+            SeekFrom::End(offset) => total_size + offset as i128,
+            SeekFrom::Start(offset) => offset as i128,
         };
 
-        if let Some(file) = self.fs_file.as_mut() {
-            let file_position = i128::max(
-                self.position as i128 - self.metadata.preload_data.len() as i128,
-                0,
-            ) as u64;
+        if position < 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                "seek to a negative position",
+            ));
+        }
+
+        self.position = position as u64;
+
+        if let Some(file) = self.archive.as_mut() {
+            // Positions past the end of the entry read nothing, so the archive only needs to be
+            // placed at its end, which also keeps the offset from overflowing.
+            let file_position = self
+                .position
+                .saturating_sub(self.metadata.preload_data.len() as u64)
+                .min(self.metadata.archive_length as u64);
 
             file.seek(SeekFrom::Start(
                 self.metadata.archive_offset + file_position,
@@ -340,19 +1365,60 @@ impl<'a> Seek for File<'a> {
         Ok(self.position)
     }
 
-    #[cfg(seek_stream_len)]
-    fn stream_len(&mut self) -> Result<u64> {
-        Ok(self.metadata.archive_length as u64)
+    /// Adds the entry path, archive index and position to an error from the backing archive.
+    fn error_with_context(&self, err: Error) -> Error {
+        let archive = archive_label(self.metadata.archive_index);
+
+        Error::new(
+            err.kind(),
+            format!(
+                "{} (archive {}, position {}): {}",
+                self.path.display(),
+                archive,
+                self.position,
+                err
+            ),
+        )
     }
 
-    fn stream_position(&mut self) -> Result<u64> {
-        Ok(self.position)
+    /// The size of the file's data, its preload bytes and its archive bytes together.
+    pub fn len(&self) -> usize {
+        self.metadata.preload_data.len() + self.metadata.archive_length as usize
     }
-}
 
-impl<'a> File<'a> {
-    pub fn len(&self) -> usize {
-        self.metadata.archive_length as usize
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn flags(&self) -> EntryFlags {
+        self.metadata.flags
+    }
+
+    pub fn is_compressed(&self) -> bool {
+        self.flags().is_compressed()
+    }
+
+    /// Streams the whole entry (preload and archive data) into `w`, returning the number of bytes
+    /// written. Data is copied through a fixed size buffer, so the entry is never fully resident.
+    pub fn copy_to<W: Write>(&mut self, w: &mut W) -> Result<u64> {
+        self.seek(SeekFrom::Start(0))?;
+
+        let mut buffer = [0u8; COPY_BUFFER_SIZE];
+        let mut written = 0u64;
+
+        loop {
+            let num_read = match self.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(num_read) => num_read,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            };
+
+            w.write_all(&buffer[..num_read])?;
+            written += num_read as u64;
+        }
+
+        Ok(written)
     }
 
     pub fn verify(&mut self) -> Result<()> {
@@ -382,9 +1448,37 @@ impl<'a> File<'a> {
     fn calc_crc32(&mut self) -> Result<u32> {
         self.seek(SeekFrom::Start(0))?;
 
-        let mut data = vec![0; self.len()];
-        self.read(data.as_mut_slice())?;
+        let mut buffer = [0u8; COPY_BUFFER_SIZE];
+        let mut hasher = crc32fast::Hasher::new();
+
+        loop {
+            match self.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(num_read) => hasher.update(&buffer[..num_read]),
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        }
+
+        Ok(hasher.finalize())
+    }
+
+    #[cfg(feature = "md5")]
+    fn calc_md5(&mut self) -> Result<[u8; 16]> {
+        self.seek(SeekFrom::Start(0))?;
+
+        let mut buffer = [0u8; COPY_BUFFER_SIZE];
+        let mut context = md5::Context::new();
+
+        loop {
+            match self.read(&mut buffer) {
+                Ok(0) => break,
+                Ok(num_read) => context.consume(&buffer[..num_read]),
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        }
 
-        Ok(crc32fast::hash(&data))
+        Ok(context.compute().0)
     }
 }
@@ -0,0 +1,65 @@
+use std::collections::HashSet;
+use std::io::{Error, ErrorKind, Result};
+use std::path::Path;
+
+use super::{File, VPK};
+
+/// Several VPKs mounted as one namespace, as Source mounts game, mod and DLC archives. When more
+/// than one contains a path, the VPK mounted first wins.
+#[derive(Default)]
+pub struct VpkSet {
+    vpks: Vec<VPK>,
+}
+
+impl VpkSet {
+    pub fn new() -> VpkSet {
+        VpkSet::default()
+    }
+
+    /// Loads the directory file at `path` and mounts it with a lower priority than every VPK
+    /// already mounted.
+    pub fn mount(&mut self, path: &Path) -> Result<()> {
+        self.push(VPK::load(path)?);
+        Ok(())
+    }
+
+    /// Mounts an already loaded VPK with a lower priority than every VPK already mounted.
+    pub fn push(&mut self, vpk: VPK) {
+        self.vpks.push(vpk);
+    }
+
+    /// The mounted VPKs, highest priority first.
+    pub fn vpks(&self) -> &[VPK] {
+        &self.vpks
+    }
+
+    /// Returns the highest priority VPK containing `path`.
+    pub fn find(&self, path: &Path) -> Option<&VPK> {
+        self.vpks.iter().find(|vpk| vpk.contains(path))
+    }
+
+    pub fn contains(&self, path: &Path) -> bool {
+        self.find(path).is_some()
+    }
+
+    /// Opens `path` from the highest priority VPK containing it.
+    pub fn get(&self, path: &Path) -> Result<File<'_>> {
+        match self.find(path) {
+            Some(vpk) => vpk.get(path),
+            None => Err(Error::new(
+                ErrorKind::NotFound,
+                format!("{} not found in any mounted VPK", path.display()),
+            )),
+        }
+    }
+
+    /// Iterates over every path in the set once, in mount order and then tree order.
+    pub fn files(&self) -> impl Iterator<Item = &Path> {
+        let mut seen = HashSet::new();
+
+        self.vpks
+            .iter()
+            .flat_map(VPK::files_in_archive_order)
+            .filter(move |path| seen.insert(*path))
+    }
+}
@@ -0,0 +1,204 @@
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs;
+use std::io::{self, BufWriter, Error, ErrorKind, Read, Result, Write};
+use std::path::{Component, Path, PathBuf};
+
+use super::reader::{DIRECTORY_INDEX, VPK_SIGNATURE};
+
+/// Entries grouped by extension, then `/`-joined directory, as laid out in the tree.
+type Tree<'a> = BTreeMap<String, BTreeMap<String, Vec<(String, &'a PendingEntry)>>>;
+
+struct PendingEntry {
+    source: PathBuf,
+    crc: u32,
+    length: u32,
+}
+
+/// Builds a single-file version 1 VPK, with every entry's data stored in the directory file.
+#[derive(Default)]
+pub struct VPKWriter {
+    entries: BTreeMap<PathBuf, PendingEntry>,
+}
+
+impl VPKWriter {
+    pub fn new() -> VPKWriter {
+        VPKWriter::default()
+    }
+
+    /// Adds the file at `source` as `entry_path`, replacing any entry already added under that
+    /// path. The file is read once here to compute its CRC and again when the VPK is written.
+    pub fn add_file(&mut self, entry_path: &Path, source: &Path) -> Result<()> {
+        let mut file = fs::File::open(source)?;
+        let mut hasher = crc32fast::Hasher::new();
+        let mut buffer = [0u8; 8192];
+        let mut length = 0u64;
+
+        loop {
+            let num_read = file.read(&mut buffer)?;
+            if num_read == 0 {
+                break;
+            }
+
+            hasher.update(&buffer[..num_read]);
+            length += num_read as u64;
+        }
+
+        let length = u32::try_from(length).map_err(|_| {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} is too large for a VPK entry", source.display()),
+            )
+        })?;
+
+        self.entries.insert(
+            entry_path.into(),
+            PendingEntry {
+                source: source.into(),
+                crc: hasher.finalize(),
+                length,
+            },
+        );
+
+        Ok(())
+    }
+
+    /// Recursively adds every file under `root`, using its path relative to `root` as the entry
+    /// path.
+    pub fn add_dir(&mut self, root: &Path) -> Result<()> {
+        self.add_dir_internal(root, Path::new(""))
+    }
+
+    fn add_dir_internal(&mut self, root: &Path, relative: &Path) -> Result<()> {
+        let mut dir_entries = fs::read_dir(root.join(relative))?.collect::<Result<Vec<_>>>()?;
+        dir_entries.sort_by_key(|dir_entry| dir_entry.file_name());
+
+        for dir_entry in dir_entries {
+            let path = relative.join(dir_entry.file_name());
+
+            if dir_entry.file_type()?.is_dir() {
+                self.add_dir_internal(root, &path)?;
+            } else {
+                self.add_file(&path, &root.join(&path))?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes the directory file to `path`. Since all data is stored inline, `path` is the whole
+    /// VPK and does not need a `_dir` suffix.
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let mut tree = Tree::new();
+
+        for (entry_path, entry) in &self.entries {
+            let (extension, directory, file_name) = Self::split_entry_path(entry_path)?;
+
+            tree.entry(extension)
+                .or_default()
+                .entry(directory)
+                .or_default()
+                .push((file_name, entry));
+        }
+
+        let mut tree_data = Vec::new();
+        let mut data_order = Vec::new();
+        let mut entry_offset = 0u32;
+
+        for (extension, directories) in &tree {
+            Self::write_name(&mut tree_data, extension);
+
+            for (directory, files) in directories {
+                Self::write_name(&mut tree_data, directory);
+
+                for (file_name, entry) in files {
+                    Self::write_name(&mut tree_data, file_name);
+
+                    tree_data.extend_from_slice(&entry.crc.to_le_bytes());
+                    tree_data.extend_from_slice(&0u16.to_le_bytes()); // preload_bytes
+                    tree_data.extend_from_slice(&DIRECTORY_INDEX.to_le_bytes());
+                    tree_data.extend_from_slice(&entry_offset.to_le_bytes());
+                    tree_data.extend_from_slice(&entry.length.to_le_bytes());
+                    tree_data.extend_from_slice(&0xFFFFu16.to_le_bytes());
+
+                    entry_offset = entry_offset.checked_add(entry.length).ok_or_else(|| {
+                        Error::new(ErrorKind::InvalidInput, "VPK data exceeds 4 GiB")
+                    })?;
+                    data_order.push(*entry);
+                }
+
+                tree_data.push(0);
+            }
+
+            tree_data.push(0);
+        }
+
+        tree_data.push(0);
+
+        let mut out = BufWriter::new(fs::File::create(path)?);
+
+        out.write_all(&VPK_SIGNATURE.to_le_bytes())?;
+        out.write_all(&1u32.to_le_bytes())?;
+        out.write_all(&(tree_data.len() as u32).to_le_bytes())?;
+        out.write_all(&tree_data)?;
+
+        for entry in data_order {
+            let copied = io::copy(&mut fs::File::open(&entry.source)?, &mut out)?;
+
+            if copied != entry.length as u64 {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("{} changed size after being added", entry.source.display()),
+                ));
+            }
+        }
+
+        out.flush()
+    }
+
+    /// Splits an entry path into the extension, `/`-joined directory and file stem stored in the
+    /// tree, with empty parts written as `" "`.
+    fn split_entry_path(entry_path: &Path) -> Result<(String, String, String)> {
+        let invalid = || {
+            Error::new(
+                ErrorKind::InvalidInput,
+                format!("{} is not a valid VPK entry path", entry_path.display()),
+            )
+        };
+
+        let mut directory = Vec::new();
+        if let Some(parent) = entry_path.parent() {
+            for component in parent.components() {
+                match component {
+                    Component::Normal(part) => directory.push(part.to_str().ok_or_else(invalid)?),
+                    _ => return Err(invalid()),
+                }
+            }
+        }
+
+        let name_part = |part: Option<&OsStr>| -> Result<String> {
+            match part {
+                Some(part) => Ok(part.to_str().ok_or_else(invalid)?.to_string()),
+                None => Ok(String::new()),
+            }
+        };
+
+        let file_name = name_part(entry_path.file_stem())?;
+        if file_name.is_empty() {
+            return Err(invalid());
+        }
+
+        Ok((
+            name_part(entry_path.extension())?,
+            directory.join("/"),
+            file_name,
+        ))
+    }
+
+    fn write_name(tree_data: &mut Vec<u8>, name: &str) {
+        let name = if name.is_empty() { " " } else { name };
+
+        tree_data.extend_from_slice(name.as_bytes());
+        tree_data.push(0);
+    }
+}
@@ -0,0 +1,90 @@
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, Data, DeriveInput, Error, Fields, LitStr};
+
+/// Derives `srcrs::kv::FromKeyValues` for a struct with named fields.
+///
+/// Each field is read from the entries whose key matches the field name, compared
+/// case-insensitively. `#[kv(rename = "$key")]` reads a field from a different key.
+#[proc_macro_derive(FromKeyValues, attributes(kv))]
+pub fn derive_from_key_values(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+
+    expand(input)
+        .unwrap_or_else(Error::into_compile_error)
+        .into()
+}
+
+fn expand(input: DeriveInput) -> syn::Result<TokenStream2> {
+    let fields = match &input.data {
+        Data::Struct(data) => match &data.fields {
+            Fields::Named(fields) => &fields.named,
+            _ => {
+                return Err(Error::new_spanned(
+                    &input.ident,
+                    "FromKeyValues can only be derived for structs with named fields",
+                ))
+            }
+        },
+        _ => {
+            return Err(Error::new_spanned(
+                &input.ident,
+                "FromKeyValues can only be derived for structs",
+            ))
+        }
+    };
+
+    let mut field_reads = Vec::new();
+    let mut field_names = Vec::new();
+
+    for field in fields {
+        let ident = field.ident.as_ref().unwrap();
+        let ty = &field.ty;
+
+        let mut key = ident.to_string();
+        for attr in field.attrs.iter().filter(|attr| attr.path().is_ident("kv")) {
+            attr.parse_nested_meta(|meta| {
+                if meta.path.is_ident("rename") {
+                    key = meta.value()?.parse::<LitStr>()?.value();
+                    Ok(())
+                } else {
+                    Err(meta.error("unsupported kv attribute"))
+                }
+            })?;
+        }
+
+        field_reads.push(quote! {
+            let #ident = <#ty as ::srcrs::kv::FromValues>::from_values(
+                &::srcrs::kv::__private::entries(object, #key),
+                &::srcrs::kv::__private::join_path(path, #key),
+            )?;
+        });
+        field_names.push(ident);
+    }
+
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+
+    Ok(quote! {
+        impl #impl_generics ::srcrs::kv::FromKeyValues for #name #ty_generics #where_clause {
+            fn from_object_at(
+                object: &::srcrs::kv::Object<'_>,
+                path: &str,
+            ) -> ::std::result::Result<Self, ::srcrs::kv::ValidationError> {
+                #(#field_reads)*
+
+                ::std::result::Result::Ok(Self { #(#field_names),* })
+            }
+        }
+
+        impl #impl_generics ::srcrs::kv::FromValues for #name #ty_generics #where_clause {
+            fn from_values(
+                values: &[&::srcrs::kv::Value<'_>],
+                path: &str,
+            ) -> ::std::result::Result<Self, ::srcrs::kv::ValidationError> {
+                ::srcrs::kv::__private::from_object_values(values, path)
+            }
+        }
+    })
+}